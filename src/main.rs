@@ -1,13 +1,27 @@
+use macroquad::audio::{load_sound, play_sound, stop_sound, PlaySoundParams, Sound};
 use macroquad::prelude::*;
 use macroquad::window::Conf;
 use ::rand::prelude::*;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
 const TOP_BAR_HEIGHT: f32 = 50.0;
 const BOTTOM_BAR_HEIGHT: f32 = 120.0;
 
 const DESIRED_TILE_SIZE: f32 = 20.0;
 
+// How far the player can see around them, in tiles
+const LIGHT_RADIUS: f32 = 10.0;
+
+// Zero-based index of the deepest floor, where the boss waits
+const FINAL_FLOOR: i32 = 9;
+
 fn calculate_tile_size(map_width: usize, map_height: usize, screen_width: f32, screen_height: f32) -> f32 {
     let available_width = screen_width;
     let available_height = screen_height - TOP_BAR_HEIGHT - BOTTOM_BAR_HEIGHT;
@@ -25,24 +39,106 @@ fn calculate_tile_size(map_width: usize, map_height: usize, screen_width: f32, s
 }
 
 
+// Picked when a run is set up, it decides how the player starts and grows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CharacterClass {
+    Warrior,
+    Rogue,
+    Mage,
+}
+
+// What a class gains on each level up
+struct LevelBonuses {
+    max_hp: i32,
+    attack: i32,
+    defense: i32,
+    max_mana: i32,
+    perception: f32,
+}
+
+impl CharacterClass {
+    const ALL: [CharacterClass; 3] = [CharacterClass::Warrior, CharacterClass::Rogue, CharacterClass::Mage];
+
+    fn name(&self) -> &'static str {
+        match self {
+            CharacterClass::Warrior => "Warrior",
+            CharacterClass::Rogue => "Rogue",
+            CharacterClass::Mage => "Mage",
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            CharacterClass::Warrior => "Tough and well armed, grows stronger with every fight",
+            CharacterClass::Rogue => "Sharp eyed and quick on their feet, starts with dash boots",
+            CharacterClass::Mage => "Frail, but starts with Magic Missile and a deep well of mana",
+        }
+    }
+
+    fn next(&self) -> Self {
+        let index = Self::ALL.iter().position(|c| c == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+
+    // Max HP, attack, defense, max mana and perception at the start of a run
+    fn starting_stats(&self) -> (i32, i32, i32, i32, f32) {
+        match self {
+            CharacterClass::Warrior => (38, 6, 3, 4, 8.0),
+            CharacterClass::Rogue => (30, 5, 2, 6, 10.0),
+            CharacterClass::Mage => (24, 3, 1, 20, 8.0),
+        }
+    }
+
+    // Item ids handed out at the start, equipment is put on straight away
+    fn starting_kit(&self) -> &'static [&'static str] {
+        match self {
+            CharacterClass::Warrior => &["sword", "leather_armor", "buckler", "minor_health_potion"],
+            CharacterClass::Rogue => &["dagger", "leather_boots", "dash_boots", "minor_health_potion", "minor_health_potion"],
+            CharacterClass::Mage => &["minor_health_potion", "identify_scroll"],
+        }
+    }
+
+    fn starting_spells(&self) -> &'static [Spell] {
+        match self {
+            CharacterClass::Mage => &[Spell::MagicMissile],
+            CharacterClass::Warrior | CharacterClass::Rogue => &[],
+        }
+    }
+
+    fn level_bonuses(&self) -> LevelBonuses {
+        match self {
+            CharacterClass::Warrior => LevelBonuses { max_hp: 7, attack: 2, defense: 1, max_mana: 1, perception: 0.5 },
+            CharacterClass::Rogue => LevelBonuses { max_hp: 5, attack: 2, defense: 1, max_mana: 2, perception: 1.0 },
+            CharacterClass::Mage => LevelBonuses { max_hp: 3, attack: 1, defense: 0, max_mana: 5, perception: 0.5 },
+        }
+    }
+}
+
 #[derive(Clone)]
 struct LevelSystem {
     level: i32,
     current_xp: i32,
     xp_to_next_level: i32,
+    total_xp: i32, // Everything earned over the run, for the summary
+    class: CharacterClass,
+    weapon_hits: HashMap<WeaponKind, u32>, // Blows landed with each kind of weapon, for its skill rank
 }
 
 impl LevelSystem {
-    fn new() -> Self {
+    fn new(class: CharacterClass) -> Self {
         Self {
             level: 1,
             current_xp: 0,
             xp_to_next_level: 100, // Base XP needed for level 2
+            total_xp: 0,
+            class,
+            weapon_hits: HashMap::new(),
         }
     }
 
     fn add_xp(&mut self, xp: i32) -> bool {
         self.current_xp += xp;
+        self.total_xp += xp;
         if self.current_xp >= self.xp_to_next_level {
             self.level_up();
             return true;
@@ -58,12 +154,26 @@ impl LevelSystem {
     }
 }
 // Add this new enum to represent different tile types
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 enum Tile {
     Wall,
     Floor,
     StairsUp,
     StairsDown,
+    DoorClosed,
+    DoorOpen,
+    DoorLocked, // Needs a key, guards a treasure room
+    Water,
+    Ice,
+    Grass,
+    Chasm, // Impassable until a boulder is pushed in
+}
+
+// How a tile reacts to the elements, consulted by the effect resolver
+struct TileProperties {
+    conducts: bool,            // Lightning spreads through connected tiles like this one
+    burns_to: Option<Tile>,    // What fire leaves behind
+    freezes_to: Option<Tile>,  // What cold leaves behind
 }
 
 impl Tile {
@@ -73,1678 +183,14392 @@ impl Tile {
             Tile::Floor => '.',
             Tile::StairsUp => '<',    // Changed from > to <
             Tile::StairsDown => '>',   // This is correct
+            Tile::DoorClosed | Tile::DoorLocked => '+',
+            Tile::DoorOpen => '\'',
+            Tile::Water => '~',
+            Tile::Ice => '=',
+            Tile::Grass => '"',
+            Tile::Chasm => ':',
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Tile::Wall => "Wall",
+            Tile::Floor => "Floor",
+            Tile::StairsUp => "Stairs up",
+            Tile::StairsDown => "Stairs down",
+            Tile::DoorClosed => "Closed door",
+            Tile::DoorLocked => "Locked door",
+            Tile::DoorOpen => "Open door",
+            Tile::Water => "Water",
+            Tile::Ice => "Ice",
+            Tile::Grass => "Grass",
+            Tile::Chasm => "Chasm",
+        }
+    }
+
+    fn properties(&self) -> TileProperties {
+        match self {
+            Tile::Water => TileProperties { conducts: true, burns_to: None, freezes_to: Some(Tile::Ice) },
+            Tile::Ice => TileProperties { conducts: false, burns_to: Some(Tile::Water), freezes_to: None },
+            Tile::Grass => TileProperties { conducts: false, burns_to: Some(Tile::Floor), freezes_to: None },
+            _ => TileProperties { conducts: false, burns_to: None, freezes_to: None },
+        }
+    }
+}
+
+// Temporary terrain laid over a map's tiles without changing them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OverlayKind {
+    Web, // Entangles anything but spiders, burns away
+}
+
+impl OverlayKind {
+    fn glyph(&self) -> (char, Color) {
+        match self {
+            OverlayKind::Web => ('%', LIGHTGRAY),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            OverlayKind::Web => "Web",
+        }
+    }
+
+    fn burns(&self) -> bool {
+        match self {
+            OverlayKind::Web => true,
+        }
+    }
+
+    // Extra pathing cost of crossing it, so walkers and hunters prefer a way around
+    fn step_cost(&self) -> i32 {
+        match self {
+            OverlayKind::Web => 4,
+        }
+    }
+}
+
+struct TerrainOverlay {
+    kind: OverlayKind,
+    turns_left: Option<u32>, // None lasts until destroyed
+}
+
+// Elements that scrolls bring to bear on creatures and terrain alike
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+enum Element {
+    Fire,
+    Lightning,
+    Cold,
+}
+
+impl Element {
+    fn name(&self) -> &'static str {
+        match self {
+            Element::Fire => "fire",
+            Element::Lightning => "lightning",
+            Element::Cold => "cold",
+        }
+    }
+}
+
+// Rule mutators, either rolled for a single floor or chosen for the whole run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Modifier {
+    Darkness,         // Light radius halved
+    Bloodlust,        // All monsters hasted
+    Frailty,          // Healing halved
+    NoShops,          // Shops never generate
+    DoubleMonsters,   // Twice as many monsters per room
+    FragileEquipment, // Equipped items can break
+    OneHp,            // Player max HP is locked to 1
+}
+
+impl Modifier {
+    const FLOOR: [Modifier; 3] = [Modifier::Darkness, Modifier::Bloodlust, Modifier::Frailty];
+    const RUN: [Modifier; 4] = [Modifier::NoShops, Modifier::DoubleMonsters, Modifier::FragileEquipment, Modifier::OneHp];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Modifier::Darkness => "Darkness",
+            Modifier::Bloodlust => "Bloodlust",
+            Modifier::Frailty => "Frailty",
+            Modifier::NoShops => "No Shops",
+            Modifier::DoubleMonsters => "Double Monsters",
+            Modifier::FragileEquipment => "Fragile Equipment",
+            Modifier::OneHp => "One HP",
+        }
+    }
+
+    fn announcement(&self) -> &'static str {
+        match self {
+            Modifier::Darkness => "Darkness: light radius halved",
+            Modifier::Bloodlust => "Bloodlust: all monsters hasted",
+            Modifier::Frailty => "Frailty: healing halved",
+            Modifier::NoShops => "No Shops: merchants have abandoned the dungeon",
+            Modifier::DoubleMonsters => "Double Monsters: twice the monsters on every floor",
+            Modifier::FragileEquipment => "Fragile Equipment: worn items may break",
+            Modifier::OneHp => "One HP: a single hit will kill you",
+        }
+    }
+
+    // Run modifiers make the game harder, so they multiply the final score
+    fn score_multiplier(&self) -> f32 {
+        match self {
+            Modifier::NoShops => 1.1,
+            Modifier::DoubleMonsters => 1.5,
+            Modifier::FragileEquipment => 1.25,
+            Modifier::OneHp => 3.0,
+            _ => 1.0,
+        }
+    }
+
+    // The first floor is always unmodified, deeper floors are cursed more often
+    fn roll_floor(depth: i32, rng: &mut impl Rng) -> Vec<Modifier> {
+        if depth == 0 || !rng.gen_bool((0.1 + depth as f64 * 0.03).min(0.4)) {
+            return Vec::new();
         }
+        Modifier::FLOOR.choose(rng).copied().into_iter().collect()
     }
 }
 
+// A floor of the main dungeon, or of the side branch it names
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FloorId {
+    branch: Option<usize>, // Index into the branch registry
+    level: i32, // Depth below the surface, a branch's floors carry on from the one it is entered from
+}
+
+impl FloorId {
+    const FIRST: FloorId = FloorId { branch: None, level: 0 };
+    const TOWN: FloorId = FloorId { branch: None, level: -1 }; // Above the dungeon, up the first floor's stairs
+}
+
+// A monster on its way to another floor by the stairs
+struct FloorTransfer {
+    entity: Entity,
+    arrive_turn: u32,
+    going_down: bool,
+    pursuing: bool, // Following the player rather than escaping
+    from: FloorId,
+}
+
 struct MapManager {
     maps: Vec<Map>,
+    town: Map,
     current_level: i32,
+    current_branch: Option<usize>,
+    branch_maps: HashMap<usize, Vec<Map>>, // Each branch's floors from the top down, keyed like current_branch
     config: GameConfig,
+    transfers: HashMap<FloorId, Vec<FloorTransfer>>, // Keyed by destination floor
+    prefabs: PrefabRegistry,
+    branches: BranchRegistry,
+    portal: Option<Portal>, // The dungeon end of an open town portal, where stepping back through it leads
+}
+
+// A town portal opened on a dungeon floor. Its other end sits beside the town's stairs down
+struct Portal {
+    floor: FloorId,
+    position: (i32, i32),
 }
 
 impl MapManager {
-    fn new(config: GameConfig) -> Self {
-        let initial_map = Map::new(config.map_width, config.map_height, 0, None);
-        let mut maps = Vec::new();
-        maps.push(initial_map);
+    // Where the town end of a portal opens, from the town's stairs down
+    const TOWN_PORTAL_OFFSET: (i32, i32) = (2, 0);
 
-        Self {
-            maps,
+    fn new(config: GameConfig) -> Self {
+        let mut town = Map::town(config.map_width, config.map_height, config.seed);
+        town.diagonal_movement = config.diagonal_movement;
+        let mut manager = Self {
+            maps: Vec::new(),
+            town,
             current_level: 0,
+            current_branch: None,
+            branch_maps: HashMap::new(),
             config,
+            transfers: HashMap::new(),
+            prefabs: PrefabRegistry::load(),
+            branches: BranchRegistry::load(),
+            portal: None,
+        };
+        let initial_map = manager.generate_main_floor(0, None);
+        manager.maps.push(initial_map);
+        manager
+    }
+
+    // A floor of the main dungeon, with the stairs down into any branch entered from it
+    fn generate_main_floor(&self, level: i32, stairs_up_pos: Option<(usize, usize)>) -> Map {
+        let mut map = Map::new(self.config.map_width, self.config.map_height, level, stairs_up_pos, self.config.seed);
+        map.diagonal_movement = self.config.diagonal_movement;
+        if self.branches.entered_from(level).is_some() {
+            map.place_branch_stairs();
+        }
+        // Small floors often have no room for the prefab picked, which is only worth hearing about while debugging
+        if let Some(name) = map.place_prefab(&self.prefabs).filter(|_| self.config.debug_mode) {
+            eprintln!("The {} prefab fit in no room on floor {}", name, level + 1);
+        }
+        map
+    }
+
+    // A floor of a side branch. Prefabs are left out, their monsters would be out of place there
+    fn generate_branch_floor(&self, branch: usize, level: i32, stairs_up_pos: Option<(usize, usize)>) -> Map {
+        let template = &self.branches.templates[branch];
+        let mut map = Map::new_branch(self.config.map_width, self.config.map_height, level, stairs_up_pos, self.config.seed, template);
+        map.diagonal_movement = self.config.diagonal_movement;
+        map
+    }
+
+    fn current_floor(&self) -> FloorId {
+        FloorId { branch: self.current_branch, level: self.current_level }
+    }
+
+    fn current_branch_template(&self) -> Option<&BranchTemplate> {
+        self.current_branch.map(|branch| &self.branches.templates[branch])
+    }
+
+    // The floor number as the HUD shows it, with the branch it is in
+    fn floor_label(&self) -> String {
+        match self.current_branch_template() {
+            Some(branch) => format!("{} ({})", self.current_level + 1, branch.name),
+            None if self.current_floor() == FloorId::TOWN => "Town".to_string(),
+            None => (self.current_level + 1).to_string(),
+        }
+    }
+
+    // Where the stairs on a tile of the current floor lead: the branch stairs down into their branch,
+    // a branch's first up stairs back out to the floor it was entered from, and the rest one floor along
+    fn floor_beyond(&self, stairs: (usize, usize), going_down: bool) -> FloorId {
+        let level = self.current_level + if going_down { 1 } else { -1 };
+        let branch = match self.current_branch_template() {
+            None if going_down && self.current_map().branch_stairs == Some(stairs) => self.branches.entered_from(self.current_level),
+            Some(template) if !going_down && level == template.entry_floor => None,
+            _ => self.current_branch,
+        };
+        FloorId { branch, level }
+    }
+
+    fn send_to_floor(&mut self, floor: FloorId, transfer: FloorTransfer) {
+        self.transfers.entry(floor).or_default().push(transfer);
+    }
+
+    // Pursuers still on the stairs behind the player turn around and follow them to the next floor
+    fn redirect_pursuers(&mut self, from: FloorId, to: FloorId, turn: u32) {
+        let Some(pending) = self.transfers.get_mut(&from) else {
+            return;
+        };
+        let (pursuers, others): (Vec<FloorTransfer>, Vec<FloorTransfer>) = std::mem::take(pending).into_iter().partition(|t| t.pursuing);
+        *pending = others;
+        for mut transfer in pursuers {
+            transfer.arrive_turn = transfer.arrive_turn.max(turn) + 2;
+            transfer.going_down = to.level > from.level;
+            transfer.from = from;
+            self.send_to_floor(to, transfer);
         }
     }
 
+    // Transfers to this floor that are due by the given turn
+    fn take_arrivals(&mut self, floor: FloorId, turn: u32) -> Vec<FloorTransfer> {
+        let Some(pending) = self.transfers.get_mut(&floor) else {
+            return Vec::new();
+        };
+        let (due, waiting) = std::mem::take(pending).into_iter().partition(|t| t.arrive_turn <= turn);
+        *pending = waiting;
+        due
+    }
+
+    // Where a floor sits in its branch's stack, the first one down being 0
+    fn branch_index(&self, branch: usize, level: i32) -> usize {
+        (level - self.branches.templates[branch].entry_floor - 1) as usize
+    }
+
     fn current_map(&self) -> &Map {
-        &self.maps[self.current_level as usize]
+        match self.current_branch {
+            Some(branch) => &self.branch_maps[&branch][self.branch_index(branch, self.current_level)],
+            None if self.current_level == FloorId::TOWN.level => &self.town,
+            None => &self.maps[self.current_level as usize],
+        }
     }
 
     fn current_map_mut(&mut self) -> &mut Map {
-        &mut self.maps[self.current_level as usize]
+        self.map_mut(self.current_floor()).expect("the current floor has been generated")
+    }
+
+    fn map_mut(&mut self, floor: FloorId) -> Option<&mut Map> {
+        match floor.branch {
+            Some(branch) => {
+                let index = self.branch_index(branch, floor.level);
+                self.branch_maps.get_mut(&branch)?.get_mut(index)
+            }
+            None if floor == FloorId::TOWN => Some(&mut self.town),
+            None => self.maps.get_mut(floor.level as usize),
+        }
     }
 
-    fn change_level(&mut self, new_level: i32) -> Option<(f32, f32)> {
-        if new_level < 0 || new_level >= 10 {
+    // Only one portal is open at a time, reading another scroll closes the last one
+    fn open_portal(&mut self, position: (i32, i32)) {
+        self.close_portal();
+        let Some((x, y)) = self.town.down_stairs else {
+            return;
+        };
+        let (dx, dy) = Self::TOWN_PORTAL_OFFSET;
+        self.town.portal = Some((x as i32 + dx, y as i32 + dy));
+        self.current_map_mut().portal = Some(position);
+        self.portal = Some(Portal { floor: self.current_floor(), position });
+    }
+
+    fn close_portal(&mut self) {
+        self.town.portal = None;
+        if let Some(portal) = self.portal.take() {
+            if let Some(map) = self.map_mut(portal.floor) {
+                map.portal = None;
+            }
+        }
+    }
+
+    // The floor and tile on the far side of a portal standing on this tile of the current floor
+    fn portal_exit(&self, position: (i32, i32)) -> Option<(FloorId, (i32, i32))> {
+        let portal = self.portal.as_ref()?;
+        if self.current_map().portal != Some(position) {
+            return None;
+        }
+        if self.current_floor() == FloorId::TOWN {
+            Some((portal.floor, portal.position))
+        } else {
+            Some((FloorId::TOWN, self.town.portal?))
+        }
+    }
+
+    fn change_floor(&mut self, floor: FloorId) -> Option<(f32, f32)> {
+        let in_range = match floor.branch {
+            Some(branch) => {
+                let template = &self.branches.templates[branch];
+                floor.level > template.entry_floor && floor.level <= template.deepest_floor()
+            }
+            None => (FloorId::TOWN.level..=FINAL_FLOOR).contains(&floor.level),
+        };
+        if !in_range {
             return None;
         }
 
-        let going_down = new_level > self.current_level;
-        self.current_level = new_level;
+        let going_down = floor.level > self.current_level;
+        let changing_branch = floor.branch != self.current_branch;
+        // A new floor's up stairs come out below the stairs the player took
+        let stairs_up_pos = match (going_down, changing_branch) {
+            (true, true) => self.current_map().branch_stairs,
+            (true, false) => self.current_map().down_stairs,
+            (false, _) => None,
+        };
+        self.current_branch = floor.branch;
+        self.current_level = floor.level;
 
         // Generate new map if it doesn't exist
-        if new_level as usize >= self.maps.len() {
-            let stairs_up_pos = if going_down {
-                Some(self.maps.last().unwrap().down_stairs.unwrap())
-            } else {
-                None
-            };
-            let new_map = Map::new(self.config.map_width, self.config.map_height, new_level, stairs_up_pos);
-            self.maps.push(new_map);
+        match floor.branch {
+            Some(branch) => {
+                let index = self.branch_index(branch, floor.level);
+                if self.branch_maps.get(&branch).is_none_or(|maps| index >= maps.len()) {
+                    let new_map = self.generate_branch_floor(branch, floor.level, stairs_up_pos);
+                    self.branch_maps.entry(branch).or_default().push(new_map);
+                }
+            }
+            None if floor == FloorId::TOWN => {}
+            None if floor.level as usize >= self.maps.len() => {
+                let new_map = self.generate_main_floor(floor.level, stairs_up_pos);
+                self.maps.push(new_map);
+            }
+            None => {}
         }
 
         // Return player spawn position
-        if going_down {
-            self.maps[new_level as usize].up_stairs.map(|(x, y)| (x as f32, y as f32))
-        } else {
-            self.maps[new_level as usize].down_stairs.map(|(x, y)| (x as f32, y as f32))
-        }
+        let map = self.current_map();
+        let arrival = match (going_down, changing_branch) {
+            (true, _) => map.up_stairs,
+            (false, true) => map.branch_stairs,
+            (false, false) => map.down_stairs,
+        };
+        arrival.map(|(x, y)| (x as f32, y as f32))
     }
 }
 
 // Define item types
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 pub enum ItemType {
     Weapon(i32),    // Attack bonus
     Armor(i32),     // Defense bonus
+    Helmet(i32),    // Defense bonus
+    Shield(i32),    // Defense bonus
+    Boots(i32),     // Defense bonus
+    Ring(i32),      // Attack bonus, worn on either hand
+    Amulet(i32),    // Attack and defense bonus
     Potion(i32),    // Healing amount
     Scroll(Effect), // Magic effect
+    Material(MaterialKind, MaterialQuality), // Crafting material, stored in the pouch
+    Artifact(Artifact), // Unique item with its own power
+    Key,                // Opens one locked door
+    ChestKey,           // Opens one locked chest, doors need the other kind
+    Gold(u32),          // Coins, counted on the inventory instead of taking a slot
+    Tool(Tool),         // Reusable, aimed at a tile
+    Relic,              // The Heart of the Forge, the goal of the run
+    Curio,              // Sought by a scenario's goal, worth nothing otherwise
+    Keepsake(u32),      // Wanted back by the bounty with this id
+    Pickaxe,            // Mines ore veins on cave floors
+    Rope,               // Climbs out of a chasm to the floor above
+    Spellbook(Spell),   // Teaches the spell when read
+    Antidote,           // Cures poison
+    Corpse(Carcass, u32), // Eaten where it lies, rots away on the given turn
+    Fuel(u32),          // Turns of light it adds to what the player carries
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum Effect {
-    Teleport,
-    Lightning(i32), // Damage
-    Fireball(i32),  // Damage and radius
-    Confusion(i32), // Duration
+impl ItemType {
+    // Attack and defense the item grants while worn
+    fn equipment_bonuses(&self) -> (i32, i32) {
+        match *self {
+            ItemType::Weapon(bonus) | ItemType::Ring(bonus) => (bonus, 0),
+            ItemType::Armor(bonus) | ItemType::Helmet(bonus) | ItemType::Shield(bonus) | ItemType::Boots(bonus) => (0, bonus),
+            ItemType::Amulet(bonus) => (bonus, bonus),
+            _ => (0, 0),
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
-pub struct Item {
-    name: String,
-    item_type: ItemType,
-    symbol: char,
-    color: Color,
+// Where equipment is worn, each slot holds one item
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EquipSlot {
+    Weapon,
+    Armor,
+    Helmet,
+    Shield,
+    Boots,
+    LeftRing,
+    RightRing,
+    Amulet,
 }
 
-impl Item {
-    fn new_sword() -> Self {
-        Self {
-            name: "Sword".to_string(),
-            item_type: ItemType::Weapon(2),
-            symbol: '/',
-            color: SKYBLUE,
+impl EquipSlot {
+    const ALL: [EquipSlot; 8] = [
+        EquipSlot::Weapon,
+        EquipSlot::Armor,
+        EquipSlot::Helmet,
+        EquipSlot::Shield,
+        EquipSlot::Boots,
+        EquipSlot::LeftRing,
+        EquipSlot::RightRing,
+        EquipSlot::Amulet,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            EquipSlot::Weapon => "Weapon",
+            EquipSlot::Armor => "Armor",
+            EquipSlot::Helmet => "Helmet",
+            EquipSlot::Shield => "Shield",
+            EquipSlot::Boots => "Boots",
+            EquipSlot::LeftRing => "Left ring",
+            EquipSlot::RightRing => "Right ring",
+            EquipSlot::Amulet => "Amulet",
         }
     }
 
-    fn new_armor() -> Self {
-        Self {
-            name: "Chain Mail".to_string(),
-            item_type: ItemType::Armor(2),
-            symbol: '[',
-            color: LIGHTGRAY,
+    // Slots an item can go in, in the order they are filled
+    fn for_item(item_type: &ItemType) -> &'static [EquipSlot] {
+        match item_type {
+            ItemType::Weapon(_) => &[EquipSlot::Weapon],
+            ItemType::Armor(_) => &[EquipSlot::Armor],
+            ItemType::Helmet(_) => &[EquipSlot::Helmet],
+            ItemType::Shield(_) => &[EquipSlot::Shield],
+            ItemType::Boots(_) => &[EquipSlot::Boots],
+            ItemType::Ring(_) => &[EquipSlot::LeftRing, EquipSlot::RightRing],
+            ItemType::Amulet(_) => &[EquipSlot::Amulet],
+            _ => &[],
         }
     }
+}
 
-    fn new_health_potion() -> Self {
-        Self {
-            name: "Health Potion".to_string(),
-            item_type: ItemType::Potion(10),
-            symbol: '!',
-            color: PINK,
+// Unique artifacts, each spawning at most once per run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+pub enum Artifact {
+    Heartstone,
+    FangOfTheHunt,
+    Stormcaller,
+}
+
+impl Artifact {
+    const ALL: [Artifact; 3] = [Artifact::Heartstone, Artifact::FangOfTheHunt, Artifact::Stormcaller];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Artifact::Heartstone => "The Heartstone",
+            Artifact::FangOfTheHunt => "Fang of the Hunt",
+            Artifact::Stormcaller => "Stormcaller",
         }
     }
 
-    fn new_lightning_scroll() -> Self {
-        Self {
-            name: "Lightning Scroll".to_string(),
-            item_type: ItemType::Scroll(Effect::Lightning(20)),
-            symbol: '?',
-            color: YELLOW,
+    fn power(&self) -> &'static str {
+        match self {
+            Artifact::Heartstone => "Passive: regenerate 1 HP every 5 turns",
+            Artifact::FangOfTheHunt => "Passive: heal 3 HP on every kill",
+            Artifact::Stormcaller => "Active: strike nearby monsters, recharges in 20 turns",
         }
     }
-}
 
-// Inventory struct to manage items
-#[derive(Clone)]
-pub struct Inventory {
-    items: Vec<Item>,
-    capacity: usize,
-    equipped_weapon: Option<Item>,
-    equipped_armor: Option<Item>,
-}
-
-impl Inventory {
-    pub fn new(capacity: usize) -> Self {
-        Self {
-            items: Vec::new(),
-            capacity,
-            equipped_weapon: None,
-            equipped_armor: None,
+    fn lore(&self) -> &'static str {
+        match self {
+            Artifact::Heartstone => "Still warm, it beats in time with whoever carries it.",
+            Artifact::FangOfTheHunt => "Torn from the first beast that ever stalked these halls.",
+            Artifact::Stormcaller => "The forge that made it was struck by lightning nine times.",
         }
     }
 
-    pub fn add_item(&mut self, item: Item) -> Result<(), String> {
-        if self.items.len() >= self.capacity {
-            Err("Inventory is full!".to_string())
-        } else {
-            self.items.push(item);
-            Ok(())
+    // Passive hook, called once per player turn while the artifact is carried
+    fn on_player_turn(&self, player: &mut Entity, turn: u32) -> Option<String> {
+        match self {
+            Artifact::Heartstone if turn.is_multiple_of(5) && player.stats.hp < player.stats.max_hp => {
+                player.stats.hp += 1;
+                None
+            }
+            _ => None,
         }
     }
 
-    pub fn remove_item(&mut self, index: usize) -> Option<Item> {
-        if index < self.items.len() {
-            Some(self.items.remove(index))
-        } else {
-            None
+    // Passive hook, called whenever the player kills a monster
+    fn on_kill(&self, player: &mut Entity) -> Option<String> {
+        match self {
+            Artifact::FangOfTheHunt => {
+                player.stats.hp = (player.stats.hp + 3).min(player.stats.max_hp);
+                Some("The Fang of the Hunt drinks deep. Healed 3 HP".to_string())
+            }
+            _ => None,
         }
     }
 
-    pub fn equip_item(&mut self, index: usize) -> Result<String, String> {
-        if index >= self.items.len() {
-            return Err("Invalid item index!".to_string());
-        }
+    // Active hook, called when the artifact is used from the inventory
+    fn activate(&self, entity: &mut Entity, game_state: &mut GameState) -> Result<String, String> {
+        match self {
+            Artifact::Stormcaller => {
+                if let Some(&ready_turn) = game_state.artifact_recharge.get(self) {
+                    if game_state.turn < ready_turn {
+                        return Err(format!("Stormcaller is recharging ({} turns left)", ready_turn - game_state.turn));
+                    }
+                }
 
-        let item = &self.items[index];
-        match item.item_type {
-            ItemType::Weapon(_) => {
-                let item = self.items.remove(index);
-                if let Some(old_weapon) = self.equipped_weapon.replace(item) {
-                    self.items.push(old_weapon);
+                let mut hits = 0;
+                for monster in game_state.monsters.iter_mut().filter(|m| m.is_alive()) {
+                    let distance = ((monster.x - entity.x).powi(2) + (monster.y - entity.y).powi(2)).sqrt();
+                    if distance <= 4.0 {
+                        let hp_before = monster.stats.hp;
+                        monster.stats.hp -= 8;
+                        monster.last_hit_by = Some(DamageSource::Used(entity.id, "Stormcaller"));
+                        game_state.damage_popups.extend(DamagePopup::after_hit(monster, hp_before, false));
+                        hits += 1;
+                    }
                 }
-                Ok("Weapon equipped!".to_string())
-            }
-            ItemType::Armor(_) => {
-                let item = self.items.remove(index);
-                if let Some(old_armor) = self.equipped_armor.replace(item) {
-                    self.items.push(old_armor);
+
+                if hits == 0 {
+                    return Err("No monster in range!".to_string());
                 }
-                Ok("Armor equipped!".to_string())
+                game_state.artifact_recharge.insert(*self, game_state.turn + 20);
+                Ok(format!("Stormcaller strikes {} monsters for 8 damage!", hits))
             }
-            _ => Err("This item cannot be equipped!".to_string()),
+            _ => Err(format!("{} has no active power", self.name())),
         }
     }
+}
 
-    pub fn use_item(&mut self, index: usize, entity: &mut Entity, game_state: &mut GameState) -> Result<String, String> {
-        if index >= self.items.len() {
-            return Err("Invalid item index!".to_string());
+// Movement tools, aimed at a tile with the mouse and reusable after a cooldown
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+pub enum Tool {
+    GrapplingHook,
+    DashBoots,
+}
+
+impl Tool {
+    const COOLDOWN: u32 = 8;
+
+    fn name(&self) -> &'static str {
+        match self {
+            Tool::GrapplingHook => "Grappling Hook",
+            Tool::DashBoots => "Dash Boots",
         }
+    }
 
-        // Clone the item type to avoid borrowing issues
-        let item_type = self.items[index].item_type.clone();
+    fn describe(&self) -> &'static str {
+        match self {
+            Tool::GrapplingHook => "Tool, hook a wall up to 7 tiles away and pull yourself to it",
+            Tool::DashBoots => "Tool, dash up to 3 tiles in a line, striking enemies you pass",
+        }
+    }
 
-        match item_type {
-            ItemType::Potion(heal_amount) => {
-                entity.stats.hp = (entity.stats.hp + heal_amount).min(entity.stats.max_hp);
-                self.items.remove(index);
-                Ok(format!("Used health potion! Healed for {} HP", heal_amount))
-            }
-            ItemType::Scroll(effect) => {
-                match effect {
-                    Effect::Lightning(damage) => {
-                        if let Some(closest_monster) = game_state.find_closest_monster(entity.x, entity.y, 5.0) {
-                            closest_monster.stats.hp -= damage;
-                            self.items.remove(index);
-                            Ok(format!("Lightning bolt hits monster for {} damage!", damage))
-                        } else {
-                            Err("No monster in range!".to_string())
-                        }
-                    }
-                    // Implement other scroll effects here
-                    _ => Err("Effect not implemented!".to_string()),
-                }
-            }
-            _ => Err("This item cannot be used!".to_string()),
+    fn range(&self) -> i32 {
+        match self {
+            Tool::GrapplingHook => 7,
+            Tool::DashBoots => 3,
         }
     }
+}
 
-    pub fn get_equipment_bonuses(&self) -> (i32, i32) {
-        let weapon_bonus = self.equipped_weapon
-            .as_ref()
-            .and_then(|w| match w.item_type {
-                ItemType::Weapon(bonus) => Some(bonus),
-                _ => None,
-            })
-            .unwrap_or(0);
+// Learned from spellbooks and cast from the spell menu for mana
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+pub enum Spell {
+    MagicMissile,
+    Heal,
+    Blink,
+}
 
-        let armor_bonus = self.equipped_armor
-            .as_ref()
-            .and_then(|a| match a.item_type {
-                ItemType::Armor(bonus) => Some(bonus),
-                _ => None,
-            })
-            .unwrap_or(0);
+impl Spell {
+    const MAGIC_MISSILE_DAMAGE: i32 = 8;
+    const HEAL_AMOUNT: i32 = 12;
 
-        (weapon_bonus, armor_bonus)
+    fn name(&self) -> &'static str {
+        match self {
+            Spell::MagicMissile => "Magic Missile",
+            Spell::Heal => "Heal",
+            Spell::Blink => "Blink",
+        }
     }
-}
-
-#[derive(Clone)]
-struct Stats {
-    hp: i32,
-    max_hp: i32,
-    attack: i32,
-    defense: i32,
-    speed: f32,
-    last_move: f32,
-    perception: f32,
-    level_system: Option<LevelSystem>,
-}
 
-// A* Node structure for pathfinding
-#[derive(Clone, Eq, PartialEq, Hash)]
-struct Node {
-    position: (i32, i32),
-    g_cost: i32,
-    f_cost: i32,
-    parent: Option<(i32, i32)>,
-}
+    fn describe(&self) -> String {
+        match self {
+            Spell::MagicMissile => format!("A bolt that never misses, {} damage to the first monster in its way", Self::MAGIC_MISSILE_DAMAGE),
+            Spell::Heal => format!("Restores {} HP", Self::HEAL_AMOUNT),
+            Spell::Blink => format!("Step through space to a lit tile up to {} tiles away", self.range()),
+        }
+    }
 
-impl Ord for Node {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        other.f_cost.cmp(&self.f_cost)  // Reverse for min-heap
+    fn mana_cost(&self) -> i32 {
+        match self {
+            Spell::MagicMissile => 3,
+            Spell::Heal => 5,
+            Spell::Blink => 4,
+        }
     }
-}
 
-impl PartialOrd for Node {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+    // How far an aimed spell reaches, zero for spells cast on the caster
+    fn range(&self) -> i32 {
+        match self {
+            Spell::MagicMissile => 7,
+            Spell::Heal => 0,
+            Spell::Blink => 5,
+        }
     }
 }
 
-fn manhattan_distance(a: (i32, i32), b: (i32, i32)) -> i32 {
-    (a.0 - b.0).abs() + (a.1 - b.1).abs()
+// What the player is aiming at a tile with the mouse
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Targeting {
+    Tool(Tool),
+    Throw(usize), // Index of the inventory item being thrown
+    Cast(Spell),
+    Dig,
 }
 
-#[derive(Clone)]
-struct Entity {
-    x: f32,
-    y: f32,
-    symbol: char,
-    color: Color,
-    stats: Stats,
-    is_player: bool,
-    inventory: Option<Inventory>,
+// A wall the player is working through with a pickaxe, a swing each turn
+struct Digging {
+    tile: (i32, i32),
+    swings_left: u32,
+    visible_monsters: usize, // Digging stops when more come into view than when it started
+    hp: i32, // Or when the player is hurt
 }
 
-impl Entity {
-    fn new_player() -> Self {
-        Self {
-            x: 5.0,
-            y: 5.0,
-            symbol: '@',
-            color: YELLOW,
-            stats: Stats {
-                hp: 30,
-                max_hp: 30,
-                attack: 5,
-                defense: 2,
-                speed: 10.0,
-                last_move: 0.0,
-                perception: 8.0,
-                level_system: Some(LevelSystem::new()),
-            },
-            is_player: true,
-            inventory: Some(Inventory::new(20))
-        }
-    }
-
-    fn new_monster(x: f32, y: f32) -> Self {
-        Self {
-            x,
-            y,
-            symbol: 'g',
-            color: RED,
-            stats: Stats {
-                hp: 15,
-                max_hp: 15,
-                attack: 3,
-                defense: 1,
-                speed: 2.0,
-                last_move: 0.0,
-                perception: 8.0,
-                level_system: None, // Monsters don't level up
-            },
-            is_player: false,
-            inventory: None,
-        }
-    }
+impl Digging {
+    const SWINGS: u32 = 5;
+}
 
-    // Add method to check if target is within perception range
-    fn can_perceive_target(&self, target_x: f32, target_y: f32) -> bool {
-        let dx = target_x - self.x;
-        let dy = target_y - self.y;
-        let distance = (dx * dx + dy * dy).sqrt();
-        distance <= self.stats.perception
-    }
+// A way off the floor other than the stairs, taken once the turn that set it off is over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Escape {
+    Recall, // The recall scroll ran its course
+    Rope,   // Climbing out of a chasm
+    Portal, // Stepping into an open town portal
+}
 
-    fn attack(&mut self, target: &mut Entity) -> Vec<String> {
-        let damage = (self.stats.attack - target.stats.defense).max(1);
-        target.stats.hp -= damage;
-        let mut messages = vec![format!("{} hits {} for {} damage!",
-                                        if self.is_player { "Player" } else { "Monster" },
-                                        if target.is_player { "Player" } else { "Monster" },
-                                        damage
-        )];
+// A save file's contents and when they were written, in seconds since the epoch
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SaveFile {
+    contents: String,
+    written: u64,
+}
 
-        // If player kills a monster, grant XP
-        if self.is_player && !target.is_alive() {
-            if let Some(ref mut level_system) = self.stats.level_system.as_mut() {
-                let xp_gained = 50; // Base XP for killing a monster
-                messages.push(format!("Gained {} XP!", xp_gained));
+// Somewhere the files that outlive a run can be kept
+trait SaveStorage {
+    fn read(&self, name: &str) -> Result<Option<SaveFile>, String>;
+    fn write(&self, name: &str, file: &SaveFile) -> Result<(), String>;
+}
 
-                // Store the current level before modification
-                let current_level = level_system.level;
-                if level_system.add_xp(xp_gained) {
-                    self.level_up();
-                    messages.push(format!("Level Up! You are now level {}!", current_level + 1));
-                }
-            }
-        }
+// Files next to the game, where saves have always lived
+struct LocalDisk;
 
-        messages
+impl SaveStorage for LocalDisk {
+    fn read(&self, name: &str) -> Result<Option<SaveFile>, String> {
+        let contents = match std::fs::read_to_string(name) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.to_string()),
+        };
+        let written = std::fs::metadata(name)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Ok(Some(SaveFile { contents, written }))
     }
 
-    fn level_up(&mut self) {
-        // Increase stats on level up
-        self.stats.max_hp += 5;
-        self.stats.hp = self.stats.max_hp; // Heal to full on level up
-        self.stats.attack += 2;
-        self.stats.defense += 1;
-        self.stats.perception += 0.5;
+    fn write(&self, name: &str, file: &SaveFile) -> Result<(), String> {
+        std::fs::write(name, &file.contents).map_err(|e| e.to_string())
     }
+}
 
-    fn is_alive(&self) -> bool {
-        self.stats.hp > 0
-    }
+// Any server that answers GET and PUT under a base URL: a WebDAV share, a
+// bucket behind a gateway, or a small save API. Each file gets a ".rev"
+// neighbour holding its written time, since plain stores keep no metadata
+struct HttpStorage {
+    base_url: String,
+}
 
-    fn can_move(&self, current_time: f32) -> bool {
-        current_time - self.stats.last_move >= 1.0 / self.stats.speed
+impl HttpStorage {
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn url(&self, name: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), name)
     }
 
-    fn update_last_move(&mut self, current_time: f32) {
-        self.stats.last_move = current_time;
+    fn get(&self, name: &str) -> Result<Option<String>, String> {
+        match ureq::get(&self.url(name)).timeout(Self::TIMEOUT).call() {
+            Ok(response) => response.into_string().map(Some).map_err(|e| e.to_string()),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
     }
 
-    fn with_inventory(mut self, capacity: usize) -> Self {
-        self.inventory = Some(Inventory::new(capacity));
-        self
+    fn put(&self, name: &str, contents: &str) -> Result<(), String> {
+        ureq::put(&self.url(name))
+            .timeout(Self::TIMEOUT)
+            .set("Content-Type", "text/plain")
+            .send_string(contents)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
     }
+}
 
-    fn get_total_attack(&self) -> i32 {
-        let (weapon_bonus, _) = self.inventory
-            .as_ref()
-            .map(|inv| inv.get_equipment_bonuses())
-            .unwrap_or((0, 0));
-        self.stats.attack + weapon_bonus
+impl SaveStorage for HttpStorage {
+    fn read(&self, name: &str) -> Result<Option<SaveFile>, String> {
+        let Some(contents) = self.get(name)? else {
+            return Ok(None);
+        };
+        let written = self
+            .get(&format!("{}.rev", name))?
+            .and_then(|rev| rev.trim().parse().ok())
+            .unwrap_or(0);
+        Ok(Some(SaveFile { contents, written }))
     }
 
-    fn get_total_defense(&self) -> i32 {
-        let (_, armor_bonus) = self.inventory
-            .as_ref()
-            .map(|inv| inv.get_equipment_bonuses())
-            .unwrap_or((0, 0));
-        self.stats.defense + armor_bonus
+    fn write(&self, name: &str, file: &SaveFile) -> Result<(), String> {
+        self.put(name, &file.contents)?;
+        self.put(&format!("{}.rev", name), &file.written.to_string())
     }
 }
 
-struct Camera {
-    x: f32,
-    y: f32,
-    viewport_width: usize,
-    viewport_height: usize,
+// How a local save and its remote copy relate to the last time they agreed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SyncOutcome {
+    InSync,
+    Push,     // Only this machine has changed it
+    Pull,     // Only another machine has changed it
+    Conflict, // Both have, so neither copy can simply win
 }
 
-impl Camera {
-    fn new(viewport_width: usize, viewport_height: usize) -> Self {
-        Self {
-            x: 0.0,
-            y: 0.0,
-            viewport_width,
-            viewport_height,
+impl SyncOutcome {
+    fn resolve(local: &SaveFile, remote: &SaveFile, last_sync: Option<SyncPoint>) -> Self {
+        if local.contents == remote.contents {
+            return SyncOutcome::InSync;
+        }
+        let Some(last_sync) = last_sync else {
+            // Never synced from here, so two different copies can't be told apart
+            return SyncOutcome::Conflict;
+        };
+        let local_changed = local.written != last_sync.local;
+        let remote_changed = remote.written != last_sync.remote;
+        match (local_changed, remote_changed) {
+            (true, false) => SyncOutcome::Push,
+            (false, true) => SyncOutcome::Pull,
+            _ => SyncOutcome::Conflict,
         }
     }
+}
 
+// The written times of both copies the last time a save was synced
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct SyncPoint {
+    local: u64,
+    remote: u64,
+}
 
-    fn follow(&mut self, target_x: f32, target_y: f32, map_width: usize, map_height: usize) {
-        // Center the camera on the target
-        self.x = target_x - self.viewport_width as f32 / 2.0;
-        self.y = target_y - self.viewport_height as f32 / 2.0;
+#[derive(Default, Serialize, Deserialize)]
+struct SyncRecord {
+    files: HashMap<String, SyncPoint>,
+}
 
-        // Calculate the maximum camera positions
-        let max_x = (map_width as f32).max(self.viewport_width as f32) - self.viewport_width as f32;
-        let max_y = (map_height as f32).max(self.viewport_height as f32) - self.viewport_height as f32;
+impl SyncRecord {
+    const PATH: &'static str = "sync.ron";
 
-        // Clamp camera position to map bounds
-        self.x = self.x.clamp(0.0, max_x.max(0.0));
-        self.y = self.y.clamp(0.0, max_y.max(0.0));
+    fn load() -> Self {
+        std::fs::read_to_string(Self::PATH)
+            .ok()
+            .and_then(|contents| ron::from_str(&contents).ok())
+            .unwrap_or_default()
     }
 
-    fn world_to_screen(&self, world_x: f32, world_y: f32, tile_size: f32) -> (f32, f32) {
-        (
-            (world_x - self.x) * tile_size,
-            (world_y - self.y) * tile_size + TOP_BAR_HEIGHT
-        )
+    fn save(&self) {
+        if let Ok(contents) = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()) {
+            let _ = std::fs::write(Self::PATH, contents);
+        }
     }
 
-    fn is_visible(&self, world_x: f32, world_y: f32) -> bool {
-        world_x >= self.x && world_x < self.x + self.viewport_width as f32 &&
-            world_y >= self.y && world_y < self.y + self.viewport_height as f32
+    fn mark(name: &str, point: SyncPoint) {
+        let mut record = Self::load();
+        record.files.insert(name.to_string(), point);
+        record.save();
     }
 }
 
-struct Map {
-    width: usize,
-    height: usize,
-    tiles: Vec<Vec<Tile>>,
-    rooms: Vec<Vec<Room>>,
-    level: i32,
-    up_stairs: Option<(usize, usize)>,
-    down_stairs: Option<(usize, usize)>,
+// Saves always land on local disk first. When a remote is configured they
+// are mirrored there too, so progress follows the player between machines
+struct Saves {
+    local: LocalDisk,
+    remote: Option<Box<dyn SaveStorage + Send>>,
 }
 
-impl Map {
-    fn new(width: usize, height: usize, level: i32, stairs_up_pos: Option<(usize, usize)>) -> Self {
-        let mut map = Map {
-            width,
-            height,
-            tiles: vec![vec![Tile::Wall; width]; height],
-            rooms: Vec::new(),
-            level,
-            up_stairs: stairs_up_pos,
-            down_stairs: None,
-        };
-
-        // Use level as seed for consistent but different layouts per level
-        let seed = level as u64;
-        let rng = StdRng::seed_from_u64(seed);
-        map.generate_dungeon_with_stairs_seeded(rng);
-        map
+impl Saves {
+    // Set to a base URL to sync saves, e.g. a WebDAV folder
+    const REMOTE_VAR: &'static str = "FORGE_SAVE_URL";
+    const SYNCED: [&'static str; 5] = [Profile::PATH, HighScores::PATH, Encyclopedia::PATH, KeyMacros::PATH, SharedStash::PATH];
+
+    fn open() -> Self {
+        let remote = std::env::var(Self::REMOTE_VAR)
+            .ok()
+            .filter(|url| !url.is_empty())
+            .map(|base_url| Box::new(HttpStorage { base_url }) as Box<dyn SaveStorage + Send>);
+        Self { local: LocalDisk, remote }
     }
 
-    fn generate_dungeon_with_stairs_seeded(&mut self, mut rng: impl Rng) {
-        // Existing generate_dungeon_with_stairs logic but using provided rng
-        let max_rooms = 15;
-        let min_room_size = 5;
-        let max_room_size = 10;
-
-        let mut temp_rooms = Vec::new();
-        self.tiles = vec![vec![Tile::Wall; self.width]; self.height];
-        self.rooms.clear();
-
-        for _ in 0..max_rooms {
-            let w = rng.gen_range(min_room_size..max_room_size);
-            let h = rng.gen_range(min_room_size..max_room_size);
-            let x = rng.gen_range(1..self.width as i32 - w - 1);
-            let y = rng.gen_range(1..self.height as i32 - h - 1);
-
-            let new_room = Room::new(x, y, w, h);
+    // Where the other machine's copy is kept when both changed
+    fn conflict_path(name: &str) -> String {
+        format!("{}.conflict", name)
+    }
 
-            if !temp_rooms.iter().any(|r: &Room| r.intersects(&new_room)) {
-                self.create_room(&new_room);
+    // Saves whose remote copy was set aside, for the menu to point out
+    fn conflicts() -> Vec<String> {
+        Self::SYNCED
+            .iter()
+            .map(|name| Self::conflict_path(name))
+            .filter(|path| std::path::Path::new(path).exists())
+            .collect()
+    }
 
-                if let Some(prev_room) = temp_rooms.last() {
-                    let (prev_x, prev_y) = prev_room.center();
-                    let (new_x, new_y) = new_room.center();
+    // Brings every synced save in line with its remote copy. Run once as the game starts, so
+    // loading during play only ever reads the disk. Stops at the first failed fetch, offline
+    // play carries on with what is on disk
+    fn sync_all() {
+        let saves = Self::open();
+        if saves.remote.is_none() {
+            return;
+        }
+        for name in Self::SYNCED {
+            if let Err(e) = saves.sync(name) {
+                eprintln!("Failed to fetch remote {}: {}", name, e);
+                return;
+            }
+        }
+    }
 
-                    if rng.gen_bool(0.5) {
-                        self.create_horizontal_tunnel(prev_x, new_x, prev_y);
-                        self.create_vertical_tunnel(prev_y, new_y, new_x);
-                    } else {
-                        self.create_vertical_tunnel(prev_y, new_y, prev_x);
-                        self.create_horizontal_tunnel(prev_x, new_x, new_y);
+    fn sync(&self, name: &str) -> Result<(), String> {
+        let Some(remote_storage) = &self.remote else {
+            return Ok(());
+        };
+        let local = self.local.read(name).unwrap_or_else(|e| {
+            eprintln!("Failed to read {}: {}", name, e);
+            None
+        });
+        let remote = remote_storage.read(name)?;
+
+        match (local, remote) {
+            (None, None) => {}
+            (Some(local), None) => self.push(name, &local),
+            (None, Some(remote)) => self.pull(name, &remote),
+            (Some(local), Some(remote)) => {
+                let last_sync = SyncRecord::load().files.get(name).copied();
+                match SyncOutcome::resolve(&local, &remote, last_sync) {
+                    SyncOutcome::InSync => SyncRecord::mark(name, SyncPoint { local: local.written, remote: remote.written }),
+                    SyncOutcome::Push => self.push(name, &local),
+                    SyncOutcome::Pull => self.pull(name, &remote),
+                    SyncOutcome::Conflict => {
+                        // Keep playing on this machine's copy and leave the other beside it
+                        let _ = std::fs::write(Self::conflict_path(name), &remote.contents);
+                        eprintln!("{} changed here and remotely, remote copy kept as {}", name, Self::conflict_path(name));
                     }
                 }
-
-                temp_rooms.push(new_room);
             }
         }
+        Ok(())
+    }
 
-        self.rooms = vec![temp_rooms];
+    fn load(&self, name: &str) -> Option<String> {
+        self.local
+            .read(name)
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to read {}: {}", name, e);
+                None
+            })
+            .map(|file| file.contents)
+    }
 
-        // Place stairs
-        if self.level > 0 {
-            if let Some((x, y)) = self.up_stairs {
-                self.tiles[y][x] = Tile::StairsUp;
-            } else if let Some(first_row) = self.rooms.first() {
-                if let Some(first_room) = first_row.first() {
-                    let (x, y) = first_room.center();
-                    let (x, y) = (x as usize, y as usize);
-                    self.tiles[y][x] = Tile::StairsUp;
-                    self.up_stairs = Some((x, y));
-                }
+    fn push(&self, name: &str, local: &SaveFile) {
+        if let Some(remote) = &self.remote {
+            match remote.write(name, local) {
+                Ok(_) => SyncRecord::mark(name, SyncPoint { local: local.written, remote: local.written }),
+                Err(e) => eprintln!("Failed to upload {}: {}", name, e),
             }
         }
+    }
 
-        if self.level < 9 {
-            if let Some(last_row) = self.rooms.last() {
-                if let Some(last_room) = last_row.last() {
-                    let (x, y) = last_room.center();
-                    let (x, y) = (x as usize, y as usize);
-                    self.tiles[y][x] = Tile::StairsDown;
-                    self.down_stairs = Some((x, y));
-                }
-            }
+    fn pull(&self, name: &str, remote: &SaveFile) {
+        if let Err(e) = self.local.write(name, remote) {
+            eprintln!("Failed to write {}: {}", name, e);
+            return;
+        }
+        if let Ok(Some(local)) = self.local.read(name) {
+            SyncRecord::mark(name, SyncPoint { local: local.written, remote: remote.written });
         }
     }
 
-    fn check_for_stairs(&self, x: f32, y: f32) -> Option<i32> {
-        let x = x as usize;
-        let y = y as usize;
+    // Uploads are queued for one worker thread, so a slow server never stalls a turn and
+    // the sync record is only ever updated from one place at a time
+    fn save(self, name: &'static str, contents: String) {
+        static UPLOADS: OnceLock<Sender<&'static str>> = OnceLock::new();
 
-        if y >= self.height || x >= self.width {
-            return None;
+        let file = SaveFile { contents, written: 0 };
+        if let Err(e) = self.local.write(name, &file) {
+            eprintln!("Failed to write {}: {}", name, e);
         }
-
-        match self.tiles[y][x] {
-            Tile::StairsDown => Some(self.level + 1),
-            Tile::StairsUp => Some(self.level - 1),
-            _ => None,
+        if self.remote.is_none() {
+            return;
         }
+        let uploads = UPLOADS.get_or_init(move || {
+            let (sender, receiver) = mpsc::channel::<&'static str>();
+            std::thread::spawn(move || {
+                for name in receiver {
+                    if let Ok(Some(local)) = self.local.read(name) {
+                        self.push(name, &local);
+                    }
+                }
+            });
+            sender
+        });
+        let _ = uploads.send(name);
     }
+}
 
-    fn organize_rooms(&mut self, temp_rooms: Vec<Room>) {
-        let mut organized_rooms: Vec<Vec<Room>> = Vec::new();
-        let room_height = 10;
+// Whether something the player does spends a turn. Looking through menus, examining
+// and aiming are free and the world waits, anything that changes the world costs a turn
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ActionCost {
+    Free,
+    Turn,
+}
 
-        let mut sorted_rooms = temp_rooms;
-        sorted_rooms.sort_by_key(|room| room.y);
+// Knowledge that persists between runs
+struct Encyclopedia {
+    found_artifacts: HashSet<String>,
+    met_monsters: HashSet<String>,
+}
 
-        if sorted_rooms.is_empty() {
-            self.rooms = Vec::new();
-            return;
+impl Encyclopedia {
+    const PATH: &'static str = "encyclopedia.txt";
+    const MONSTER_PREFIX: &'static str = "monster: "; // Lines without it are artifacts, as they always were
+
+    fn load() -> Self {
+        let contents = Saves::open().load(Self::PATH).unwrap_or_default();
+        let mut encyclopedia = Self { found_artifacts: HashSet::new(), met_monsters: HashSet::new() };
+        for line in contents.lines() {
+            match line.strip_prefix(Self::MONSTER_PREFIX) {
+                Some(monster) => encyclopedia.met_monsters.insert(monster.to_string()),
+                None => encyclopedia.found_artifacts.insert(line.to_string()),
+            };
         }
+        encyclopedia
+    }
 
-        let mut current_row: Vec<Room> = Vec::new();
-        let mut current_y = sorted_rooms[0].y;
-
-        for room in sorted_rooms {
-            if (room.y - current_y).abs() > room_height {
-                if !current_row.is_empty() {
-                    organized_rooms.push(current_row);
-                    current_row = Vec::new();
-                }
-                current_y = room.y;
-            }
-            current_row.push(room);
-        }
+    fn save(&self) {
+        let mut artifacts: Vec<&String> = self.found_artifacts.iter().collect();
+        artifacts.sort();
+        let mut monsters: Vec<&String> = self.met_monsters.iter().collect();
+        monsters.sort();
+        let mut contents: Vec<String> = artifacts.iter().map(|name| name.to_string()).collect();
+        contents.extend(monsters.iter().map(|name| format!("{}{}", Self::MONSTER_PREFIX, name)));
+        Saves::open().save(Self::PATH, contents.join("\n"));
+    }
 
-        if !current_row.is_empty() {
-            organized_rooms.push(current_row);
+    // Returns true the first time a kind of monster is ever met
+    fn record_monster(&mut self, name: &str) -> bool {
+        let is_new = self.met_monsters.insert(name.to_string());
+        if is_new {
+            self.save();
         }
+        is_new
+    }
 
-        for row in &mut organized_rooms {
-            row.sort_by_key(|room| room.x);
+    // Returns true the first time an artifact is ever found
+    fn record_artifact(&mut self, artifact: Artifact) -> bool {
+        let is_new = self.found_artifacts.insert(artifact.name().to_string());
+        if is_new {
+            self.save();
         }
+        is_new
+    }
 
-        self.rooms = organized_rooms;
+    fn has_found(&self, artifact: Artifact) -> bool {
+        self.found_artifacts.contains(artifact.name())
     }
+}
 
-    fn generate_dungeon_with_stairs(&mut self) {
-        let mut rng = thread_rng();
-        let max_rooms = 15;
-        let min_room_size = 5;
-        let max_room_size = 10;
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub enum Effect {
+    Teleport,
+    Lightning(i32), // Damage
+    Fireball(i32),  // Damage and radius
+    Confusion(i32), // Duration
+    Summon,         // Calls a spirit wolf to the player's side
+    Frost(i32),     // Radius of water frozen around the reader
+    Identify,       // Reveals one unidentified item in the pack
+    RemoveCurse,    // Lifts the curse from everything the reader carries
+    Recall(i32),    // Turns before the reader is pulled back to the first floor
+    Portal,         // Opens a way to the town and back beside the reader
+    Blink(i32),     // Furthest the reader can land from where they stood
+}
 
-        let mut temp_rooms = Vec::new();
-        self.tiles = vec![vec![Tile::Wall; self.width]; self.height];
-        self.rooms.clear();
+// Crafting materials dropped by monsters
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+pub enum MaterialKind {
+    Hide,
+    Fang,
+    Ectoplasm,
+    Ore, // Mined from veins on cave floors
+    Gem(GemKind),
+}
 
-        for _ in 0..max_rooms {
-            let w = rng.gen_range(min_room_size..max_room_size);
-            let h = rng.gen_range(min_room_size..max_room_size);
-            let x = rng.gen_range(1..self.width as i32 - w - 1);
-            let y = rng.gen_range(1..self.height as i32 - h - 1);
+impl MaterialKind {
+    fn name(&self) -> &'static str {
+        match self {
+            MaterialKind::Hide => "Hide",
+            MaterialKind::Fang => "Fang",
+            MaterialKind::Ectoplasm => "Ectoplasm",
+            MaterialKind::Ore => "Ore",
+            MaterialKind::Gem(gem) => gem.name(),
+        }
+    }
+}
 
-            let new_room = Room::new(x, y, w, h);
+// Gems turn up in ore veins and go into the sockets of weapons and armor at a forge.
+// In a weapon an elemental gem adds damage of its element, in armor it resists it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum GemKind {
+    Ruby,
+    Sapphire,
+    Topaz,
+    Diamond, // Plain attack in a weapon, defense in armor
+}
 
-            if !temp_rooms.iter().any(|r: &Room| r.intersects(&new_room)) {
-                self.create_room(&new_room);
+impl GemKind {
+    const ALL: [GemKind; 4] = [GemKind::Ruby, GemKind::Sapphire, GemKind::Topaz, GemKind::Diamond];
+    const ELEMENT_PER_TIER: i32 = 2;
 
-                if let Some(prev_room) = temp_rooms.last() {
-                    let (prev_x, prev_y) = prev_room.center();
-                    let (new_x, new_y) = new_room.center();
+    fn name(&self) -> &'static str {
+        match self {
+            GemKind::Ruby => "Ruby",
+            GemKind::Sapphire => "Sapphire",
+            GemKind::Topaz => "Topaz",
+            GemKind::Diamond => "Diamond",
+        }
+    }
 
-                    if rng.gen_bool(0.5) {
-                        self.create_horizontal_tunnel(prev_x, new_x, prev_y);
-                        self.create_vertical_tunnel(prev_y, new_y, new_x);
-                    } else {
-                        self.create_vertical_tunnel(prev_y, new_y, prev_x);
-                        self.create_horizontal_tunnel(prev_x, new_x, new_y);
-                    }
-                }
+    fn element(&self) -> Option<Element> {
+        match self {
+            GemKind::Ruby => Some(Element::Fire),
+            GemKind::Sapphire => Some(Element::Cold),
+            GemKind::Topaz => Some(Element::Lightning),
+            GemKind::Diamond => None,
+        }
+    }
 
-                temp_rooms.push(new_room);
-            }
+    // What the gem grants set in a weapon or in armor
+    fn describe(&self, quality: MaterialQuality, in_weapon: bool) -> String {
+        let tier = quality.tier();
+        match (self.element(), in_weapon) {
+            (Some(element), true) => format!("+{} {} damage", tier * Self::ELEMENT_PER_TIER, element.name()),
+            (Some(element), false) => format!("resist {} {}", tier * Self::ELEMENT_PER_TIER, element.name()),
+            (None, true) => format!("+{} attack", tier),
+            (None, false) => format!("+{} defense", tier),
         }
+    }
+}
 
-        self.rooms = vec![temp_rooms];
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum MaterialQuality {
+    Crude,
+    Fine,
+    Pristine,
+}
 
-        // Place stairs
-        if self.level > 0 {
-            if let Some((x, y)) = self.up_stairs {
-                self.tiles[y][x] = Tile::StairsUp;
-            }
+impl MaterialQuality {
+    fn name(&self) -> &'static str {
+        match self {
+            MaterialQuality::Crude => "Crude",
+            MaterialQuality::Fine => "Fine",
+            MaterialQuality::Pristine => "Pristine",
         }
+    }
 
-        if self.level < 9 {
-            if let Some(first_row) = self.rooms.first() {
-                if let Some(last_room) = first_row.last() {
-                    let (x, y) = last_room.center();
-                    let (x, y) = (x as usize, y as usize);
-                    self.tiles[y][x] = Tile::StairsDown;
-                    self.down_stairs = Some((x, y));
-                }
-            }
+    fn tier(&self) -> i32 {
+        match self {
+            MaterialQuality::Crude => 1,
+            MaterialQuality::Fine => 2,
+            MaterialQuality::Pristine => 3,
         }
     }
 
-    fn place_stairs(&mut self) {
-        if self.level > 0 {
-            if let Some((x, y)) = self.up_stairs {
-                self.tiles[y][x] = Tile::StairsUp;
-            } else if let Some(first_row) = self.rooms.first() {
-                if let Some(first_room) = first_row.first() {
-                    let (x, y) = first_room.center();
-                    let (x, y) = (x as usize, y as usize);
-                    if y < self.height && x < self.width {
-                        self.tiles[y][x] = Tile::StairsUp;
-                        self.up_stairs = Some((x, y));
-                    }
-                }
-            }
+    fn color(&self) -> Color {
+        match self {
+            MaterialQuality::Crude => BEIGE,
+            MaterialQuality::Fine => SKYBLUE,
+            MaterialQuality::Pristine => GOLD,
         }
+    }
 
-        if self.level < 9 {
-            if let Some(last_row) = self.rooms.last() {
-                if let Some(last_room) = last_row.last() {
-                    let (x, y) = last_room.center();
-                    let (x, y) = (x as usize, y as usize);
-                    if y < self.height && x < self.width {
-                        self.tiles[y][x] = Tile::StairsDown;
-                        self.down_stairs = Some((x, y));
-                    }
-                }
-            }
+    // Deeper floors shift the odds towards better tiers, elites bump the result one tier
+    fn roll(depth: i32, elite: bool, rng: &mut impl Rng) -> Self {
+        let roll = rng.gen_range(0..100) + depth * 8;
+        let tier = if roll >= 110 {
+            2
+        } else if roll >= 60 {
+            1
+        } else {
+            0
+        };
+        let tier = if elite { (tier + 1).min(2) } else { tier };
+
+        match tier {
+            0 => MaterialQuality::Crude,
+            1 => MaterialQuality::Fine,
+            _ => MaterialQuality::Pristine,
         }
     }
+}
 
-    fn generate_dungeon(&mut self) {
-        let mut rng = thread_rng();
-        let max_rooms = 15;
-        let min_room_size = 5;
-        let max_room_size = 10;
+// Materials live in their own pouch so they don't take up inventory slots
+#[derive(Clone, Default)]
+pub struct MaterialPouch {
+    materials: HashMap<(MaterialKind, MaterialQuality), u32>,
+}
 
-        let mut temp_rooms = Vec::new();
+impl MaterialPouch {
+    pub fn add(&mut self, kind: MaterialKind, quality: MaterialQuality, amount: u32) {
+        *self.materials.entry((kind, quality)).or_insert(0) += amount;
+    }
 
-        for _ in 0..max_rooms {
-            let w = rng.gen_range(min_room_size..max_room_size);
-            let h = rng.gen_range(min_room_size..max_room_size);
-            let x = rng.gen_range(1..self.width as i32 - w - 1);
-            let y = rng.gen_range(1..self.height as i32 - h - 1);
+    // Takes one of the material out, if there is one
+    pub fn take(&mut self, kind: MaterialKind, quality: MaterialQuality) -> bool {
+        match self.materials.get_mut(&(kind, quality)) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
 
-            let new_room = Room::new(x, y, w, h);
+    // Sorted by kind then quality so the inventory listing is stable
+    pub fn entries(&self) -> Vec<(MaterialKind, MaterialQuality, u32)> {
+        let mut entries: Vec<_> = self.materials
+            .iter()
+            .filter(|(_, &count)| count > 0)
+            .map(|(&(kind, quality), &count)| (kind, quality, count))
+            .collect();
+        entries.sort();
+        entries
+    }
+}
 
-            if !temp_rooms.iter().any(|r: &Room| r.intersects(&new_room)) {
-                self.create_room(&new_room);
+// Extra damage a weapon rolls on every hit, on top of its flat attack bonus
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+struct Dice {
+    count: u32,
+    sides: u32,
+}
 
-                if let Some(prev_room) = temp_rooms.last() {
-                    let (prev_x, prev_y) = prev_room.center();
-                    let (new_x, new_y) = new_room.center();
+impl Dice {
+    fn roll(&self, rng: &mut impl Rng) -> i32 {
+        (0..self.count).map(|_| rng.gen_range(1..=self.sides.max(1)) as i32).sum()
+    }
 
-                    if rng.gen_bool(0.5) {
-                        self.create_horizontal_tunnel(prev_x, new_x, prev_y);
-                        self.create_vertical_tunnel(prev_y, new_y, new_x);
-                    } else {
-                        self.create_vertical_tunnel(prev_y, new_y, prev_x);
-                        self.create_horizontal_tunnel(prev_x, new_x, new_y);
-                    }
-                }
+    fn describe(&self) -> String {
+        format!("{}d{}", self.count, self.sides)
+    }
+}
 
-                temp_rooms.push(new_room);
-            }
-        }
+#[derive(Clone, Debug)]
+pub struct Item {
+    name: String,
+    item_type: ItemType,
+    symbol: char,
+    color: Color,
+    set: Option<ItemSet>,
+    cursed: bool,
+    curse_known: bool, // Revealed by equipping or identifying the item
+    sockets: u32,
+    gems: Vec<(GemKind, MaterialQuality)>, // Set at a forge, never more than the sockets
+    dice: Option<Dice>, // Weapons only
+    inflicts: Option<Affliction>, // Weapons only
+    weapon_kind: Option<WeaponKind>, // Weapons only, picks the verbs for its blows
+}
 
-        self.organize_rooms(temp_rooms);
-    }
+// What a weapon does to what it hits, which picks how its blows read
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+pub enum WeaponKind {
+    Slashing,
+    Piercing,
+    Chopping,
+    Crushing, // Bare fists too
+}
 
-    fn create_room(&mut self, room: &Room) {
-        for y in room.y..room.y + room.height {
-            let y_idx = y as usize;
-            if y_idx >= self.height {
-                continue;
-            }
-            for x in room.x..room.x + room.width {
-                let x_idx = x as usize;
-                if x_idx >= self.width {
-                    continue;
-                }
-                self.tiles[y_idx][x_idx] = Tile::Floor;
-            }
+impl WeaponKind {
+    fn name(&self) -> &'static str {
+        match self {
+            WeaponKind::Slashing => "Slashing",
+            WeaponKind::Piercing => "Piercing",
+            WeaponKind::Chopping => "Chopping",
+            WeaponKind::Crushing => "Crushing",
         }
     }
+}
 
-    fn create_horizontal_tunnel(&mut self, x1: i32, x2: i32, y: i32) {
-        let y_idx = y as usize;
-        if y_idx >= self.height {
-            return;
-        }
-        for x in x1.min(x2)..=x1.max(x2) {
-            let x_idx = x as usize;
-            if x_idx >= self.width {
-                continue;
-            }
-            self.tiles[y_idx][x_idx] = Tile::Floor;
+// How practiced the player is with a kind of weapon, earned by landing blows with it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum SkillRank {
+    Novice,
+    Practiced,
+    Expert,
+    Master,
+}
+
+impl SkillRank {
+    const ALL: [SkillRank; 4] = [SkillRank::Novice, SkillRank::Practiced, SkillRank::Expert, SkillRank::Master];
+
+    fn hits_needed(&self) -> u32 {
+        match self {
+            SkillRank::Novice => 0,
+            SkillRank::Practiced => 15,
+            SkillRank::Expert => 50,
+            SkillRank::Master => 120,
         }
     }
 
-    fn create_vertical_tunnel(&mut self, y1: i32, y2: i32, x: i32) {
-        let x_idx = x as usize;
-        if x_idx >= self.width {
-            return;
-        }
-        for y in y1.min(y2)..=y1.max(y2) {
-            let y_idx = y as usize;
-            if y_idx >= self.height {
-                continue;
-            }
-            self.tiles[y_idx][x_idx] = Tile::Floor;
-        }
+    fn for_hits(hits: u32) -> Self {
+        Self::ALL.into_iter().rev().find(|rank| hits >= rank.hits_needed()).unwrap_or(SkillRank::Novice)
     }
 
-    fn is_walkable(&self, x: i32, y: i32) -> bool {
-        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
-            return false;
-        }
-        match self.tiles[y as usize][x as usize] {
-            Tile::Floor | Tile::StairsUp | Tile::StairsDown => true,
-            Tile::Wall => false,
+    fn name(&self) -> &'static str {
+        match self {
+            SkillRank::Novice => "Novice",
+            SkillRank::Practiced => "Practiced",
+            SkillRank::Expert => "Expert",
+            SkillRank::Master => "Master",
         }
     }
 
-    fn is_wall(&self, x: usize, y: usize) -> bool {
-        if x >= self.width || y >= self.height {
-            return true;
-        }
-        self.tiles[y][x] == Tile::Wall
+    // Experts and masters finish a blow with a flourish
+    fn flourish(&self) -> bool {
+        *self >= SkillRank::Expert
     }
+}
 
-    fn place_monsters(&self) -> (Option<(f32, f32)>, Vec<(f32, f32)>) {
-        let mut monster_positions = Vec::new();
-        let mut rng = thread_rng();
-        let mut used_positions = HashSet::new();
-
-        // Get first room from first row
-        let player_spawn = self.rooms.get(0)
-            .and_then(|row| row.get(0))
-            .and_then(|room| {
-                let center = room.center();
-                if self.is_walkable(center.0, center.1) {
-                    used_positions.insert((center.0, center.1));
-                    Some((center.0 as f32, center.1 as f32))
-                } else {
-                    room.inner_tiles().into_iter()
-                        .find(|&(x, y)| self.is_walkable(x, y))
-                        .map(|(x, y)| {
-                            used_positions.insert((x, y));
-                            (x as f32, y as f32)
-                        })
-                }
-            });
+// Named sets whose pieces grant escalating bonuses when equipped together
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ItemSet {
+    Warden,
+    Shadow,
+}
 
-        // Skip first room when placing monsters
-        for room_row in self.rooms.iter().skip(1) {
-            for room in room_row {
-                let num_monsters = rng.gen_range(1..4);
-                let walkable_tiles: Vec<_> = room.inner_tiles().into_iter()
-                    .filter(|&(x, y)|
-                        self.is_walkable(x, y) &&
-                            !used_positions.contains(&(x, y))
-                    )
-                    .collect();
+// Bonuses granted once at least `pieces` items of a set are equipped
+struct SetBonus {
+    pieces: usize,
+    attack: i32,
+    defense: i32,
+}
 
-                if walkable_tiles.is_empty() {
-                    continue;
-                }
+impl ItemSet {
+    const ALL: [ItemSet; 2] = [ItemSet::Warden, ItemSet::Shadow];
 
-                for _ in 0..num_monsters {
-                    if let Some(&(x, y)) = walkable_tiles.choose(&mut rng) {
-                        if used_positions.insert((x, y)) {
-                            monster_positions.push((x as f32, y as f32));
-                        }
-                    }
-                }
-            }
+    fn name(&self) -> &'static str {
+        match self {
+            ItemSet::Warden => "Warden's Regalia",
+            ItemSet::Shadow => "Shadowstalker's Garb",
         }
+    }
 
-        (player_spawn, monster_positions)
+    fn pieces(&self) -> Vec<Item> {
+        match self {
+            ItemSet::Warden => vec![
+                Item { dice: Some(Dice { count: 1, sides: 6 }), weapon_kind: Some(WeaponKind::Slashing), ..Item::new_set_piece("Warden's Blade", ItemType::Weapon(3), '/', *self) },
+                Item::new_set_piece("Warden's Plate", ItemType::Armor(3), '[', *self),
+                Item::new_set_piece("Warden's Helm", ItemType::Helmet(2), '[', *self),
+                Item::new_set_piece("Warden's Bulwark", ItemType::Shield(2), '[', *self),
+            ],
+            ItemSet::Shadow => vec![
+                Item { dice: Some(Dice { count: 2, sides: 3 }), weapon_kind: Some(WeaponKind::Piercing), ..Item::new_set_piece("Shadow Fang", ItemType::Weapon(3), '/', *self) },
+                Item::new_set_piece("Shadow Cloak", ItemType::Armor(1), '[', *self),
+                Item::new_set_piece("Shadow Hood", ItemType::Helmet(1), '[', *self),
+                Item::new_set_piece("Shadow Treads", ItemType::Boots(1), '[', *self),
+            ],
+        }
     }
 
-    fn find_path(&self, start: (i32, i32), goal: (i32, i32)) -> Option<Vec<(i32, i32)>> {
-        use std::collections::{BinaryHeap, HashSet};
+    // Tiers stack, so a full set has every one of them
+    fn bonuses(&self) -> Vec<SetBonus> {
+        match self {
+            ItemSet::Warden => vec![
+                SetBonus { pieces: 2, attack: 0, defense: 2 },
+                SetBonus { pieces: 3, attack: 1, defense: 2 },
+                SetBonus { pieces: 4, attack: 1, defense: 4 },
+            ],
+            ItemSet::Shadow => vec![
+                SetBonus { pieces: 2, attack: 2, defense: 0 },
+                SetBonus { pieces: 3, attack: 2, defense: 1 },
+                SetBonus { pieces: 4, attack: 4, defense: 0 },
+            ],
+        }
+    }
+}
 
-        let mut open_set = BinaryHeap::new();
-        let mut closed_set: HashSet<Node> = HashSet::new();
+impl Item {
+    // Attack or defense lost while a cursed item is equipped
+    const CURSE_PENALTY: i32 = 3;
+    const CURSE_CHANCE: f64 = 0.15;
+    const THROW_RANGE: i32 = 6;
+    const THROWN_WEAPON_DAMAGE: i32 = 3; // On top of the weapon's own bonus
 
-        // Initialize start node
-        let start_node = Node {
-            position: start,
-            g_cost: 0,
-            f_cost: manhattan_distance(start, goal),
-            parent: None,
-        };
+    fn new_set_piece(name: &str, item_type: ItemType, symbol: char, set: ItemSet) -> Self {
+        Self {
+            name: name.to_string(),
+            item_type,
+            symbol,
+            color: LIME,
+            set: Some(set),
+            cursed: false,
+            curse_known: false,
+            sockets: 0,
+            gems: Vec::new(),
+            dice: None,
+            inflicts: None,
+            weapon_kind: None,
+        }
+    }
 
-        open_set.push(start_node);
+    // One-line summary of what the item does, used by the item detail view
+    fn describe(&self) -> String {
+        if self.is_equipment() && self.cursed && self.curse_known {
+            let (attack, defense) = self.curse_penalty();
+            let penalty = match (attack > 0, defense > 0) {
+                (true, true) => format!("-{} attack and defense", Self::CURSE_PENALTY),
+                (true, false) => format!("-{} attack", Self::CURSE_PENALTY),
+                _ => format!("-{} defense", Self::CURSE_PENALTY),
+            };
+            return format!("{}, cursed: {} and won't come off", self.base_description(), penalty);
+        }
+        self.base_description()
+    }
 
-        while let Some(current) = open_set.pop() {
-            if current.position == goal {
-                // Reconstruct path
-                let mut path = Vec::new();
-                let mut current_pos = current.position;
-                let mut current_node = Some(current);
-
-                while let Some(node) = current_node {
-                    path.push(node.position);
-                    if let Some(parent_pos) = node.parent {
-                        current_pos = parent_pos;
-                        current_node = closed_set.iter()
-                            .find(|n| n.position == parent_pos)
-                            .cloned();
-                    } else {
-                        break;
-                    }
+    fn base_description(&self) -> String {
+        match &self.item_type {
+            ItemType::Weapon(bonus) => {
+                let mut description = match self.dice {
+                    Some(dice) => format!("Weapon, +{} attack, {} damage", bonus, dice.describe()),
+                    None => format!("Weapon, +{} attack", bonus),
+                };
+                if let Some(affliction) = self.inflicts {
+                    description += &format!(", can leave foes {}", affliction.status.name());
                 }
-
-                path.reverse();
-                return Some(path);
+                description
             }
+            ItemType::Armor(bonus) => format!("Armor, +{} defense", bonus),
+            ItemType::Helmet(bonus) => format!("Helmet, +{} defense", bonus),
+            ItemType::Shield(bonus) => format!("Shield, +{} defense", bonus),
+            ItemType::Boots(bonus) => format!("Boots, +{} defense", bonus),
+            ItemType::Ring(bonus) => format!("Ring, +{} attack", bonus),
+            ItemType::Amulet(bonus) => format!("Amulet, +{} attack and defense", bonus),
+            ItemType::Potion(amount) => format!("Potion, heals {} HP", amount),
+            ItemType::Scroll(Effect::Teleport) => "Scroll of teleportation".to_string(),
+            ItemType::Scroll(Effect::Lightning(damage)) => format!("Scroll, {} lightning damage", damage),
+            ItemType::Scroll(Effect::Fireball(damage)) => format!("Scroll, {} fire damage", damage),
+            ItemType::Scroll(Effect::Confusion(turns)) => format!("Scroll, confuses for {} turns", turns),
+            ItemType::Scroll(Effect::Summon) => "Scroll, summons a spirit wolf ally".to_string(),
+            ItemType::Scroll(Effect::Frost(radius)) => format!("Scroll, freezes water within {} tiles", radius),
+            ItemType::Scroll(Effect::Identify) => "Scroll, identifies an item in your pack".to_string(),
+            ItemType::Scroll(Effect::RemoveCurse) => "Scroll, lifts curses from your equipment".to_string(),
+            ItemType::Scroll(Effect::Recall(turns)) => format!("Scroll, returns you to the first floor after {} turns", turns),
+            ItemType::Scroll(Effect::Portal) => "Scroll, opens a portal to the town that lasts until you come back through it".to_string(),
+            ItemType::Scroll(Effect::Blink(radius)) => format!("Scroll, teleports you up to {} tiles away", radius),
+            ItemType::Material(_, _) => "Crafting material".to_string(),
+            ItemType::Artifact(artifact) => artifact.power().to_string(),
+            ItemType::Key => "Key, walk into a locked door to open it".to_string(),
+            ItemType::ChestKey => "Key, open a locked chest with it".to_string(),
+            ItemType::Pickaxe => "Pickaxe, walk into an ore vein to mine it or press [G] to dig through a wall".to_string(),
+            ItemType::Rope => "Rope, use it beside a chasm to climb to the floor above".to_string(),
+            ItemType::Gold(_) => "Gold coins".to_string(),
+            ItemType::Tool(tool) => tool.describe().to_string(),
+            ItemType::Relic => "Carry it out through the first floor's up stairs to win".to_string(),
+            ItemType::Curio => "Gather enough of these to complete the scenario".to_string(),
+            ItemType::Keepsake(_) => "Bring it back to the bounty board".to_string(),
+            ItemType::Spellbook(spell) => format!("Spellbook, teaches {} ({} mana)", spell.name(), spell.mana_cost()),
+            ItemType::Antidote => "Potion, cures poison".to_string(),
+            ItemType::Corpse(carcass, _) => format!("Corpse, eating it heals {} HP", carcass.heals),
+            ItemType::Fuel(turns) => format!("Fuel, {} turns of light", turns),
+        }
+    }
 
-            closed_set.insert(current.clone());
-
-            // Check neighbors
-            for &(dx, dy) in &[(0, 1), (1, 0), (0, -1), (-1, 0)] {
-                let next_pos = (
-                    current.position.0 + dx,
-                    current.position.1 + dy
-                );
-
-                if !self.is_walkable(next_pos.0, next_pos.1) {
-                    continue;
-                }
-
-                if closed_set.iter().any(|n| n.position == next_pos) {
-                    continue;
-                }
-
-                let g_cost = current.g_cost + 1;
-                let h_cost = manhattan_distance(next_pos, goal);
-                let f_cost = g_cost + h_cost;
-
-                let next_node = Node {
-                    position: next_pos,
-                    g_cost,
-                    f_cost,
-                    parent: Some(current.position),
-                };
-
-                open_set.push(next_node);
-            }
+    // Tag shown after the item's name once a curse has been revealed
+    fn curse_marker(&self) -> &'static str {
+        if self.cursed && self.curse_known {
+            " (cursed)"
+        } else {
+            ""
         }
+    }
 
-        None
+    fn is_equipment(&self) -> bool {
+        !EquipSlot::for_item(&self.item_type).is_empty()
     }
 
-    // Update the draw method to use different colors for different tiles
-    fn draw(&self, camera: &Camera, tile_size: f32) {
-        let start_x = camera.x.floor() as usize;
-        let start_y = camera.y.floor() as usize;
-        let end_x = (camera.x + camera.viewport_width as f32).ceil() as usize;
-        let end_y = (camera.y + camera.viewport_height as f32).ceil() as usize;
+    // Gems in a weapon add to its hits, in anything else worn they protect
+    fn is_weapon(&self) -> bool {
+        matches!(self.item_type, ItemType::Weapon(_))
+    }
 
-        for y in start_y..end_y.min(self.height) {
-            for x in start_x..end_x.min(self.width) {
-                let tile = &self.tiles[y][x];
-                let (screen_x, screen_y) = camera.world_to_screen(x as f32, y as f32, tile_size);
+    // A curse takes its toll on whichever of attack and defense the item would raise
+    fn curse_penalty(&self) -> (i32, i32) {
+        if !self.cursed {
+            return (0, 0);
+        }
+        let (attack, defense) = self.item_type.equipment_bonuses();
+        (
+            if attack > 0 { Self::CURSE_PENALTY } else { 0 },
+            if defense > 0 { Self::CURSE_PENALTY } else { 0 },
+        )
+    }
 
-                let (char, color) = match tile {
-                    Tile::Wall => ('#', DARKGRAY),
-                    Tile::Floor => ('.', GRAY),
-                    Tile::StairsUp => ('<', YELLOW),
-                    Tile::StairsDown => ('>', YELLOW),
-                };
+    // "Ruby (Fine), empty" for a two-socket item with one gem set
+    fn socket_summary(&self) -> String {
+        let mut sockets: Vec<String> = self.gems
+            .iter()
+            .map(|(gem, quality)| format!("{} ({})", gem.name(), quality.name()))
+            .collect();
+        sockets.resize(self.sockets as usize, "empty".to_string());
+        sockets.join(", ")
+    }
 
-                draw_text(
-                    &char.to_string(),
-                    screen_x,
-                    screen_y + tile_size,
-                    tile_size,
-                    color,
-                );
-            }
+    fn new_artifact(artifact: Artifact) -> Self {
+        Self {
+            name: artifact.name().to_string(),
+            item_type: ItemType::Artifact(artifact),
+            symbol: '*',
+            color: MAGENTA,
+            set: None,
+            cursed: false,
+            curse_known: false,
+            sockets: 0,
+            gems: Vec::new(),
+            dice: None,
+            inflicts: None,
+            weapon_kind: None,
         }
     }
-}
-
-#[derive(Clone, Debug)]
-struct Room {
-    x: i32,
-    y: i32,
-    width: i32,
-    height: i32,
-}
 
-impl Room {
-    fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
-        Room { x, y, width, height }
+    fn new_material(kind: MaterialKind, quality: MaterialQuality) -> Self {
+        Self {
+            name: format!("{} {}", quality.name(), kind.name()),
+            item_type: ItemType::Material(kind, quality),
+            symbol: '%',
+            color: quality.color(),
+            set: None,
+            cursed: false,
+            curse_known: false,
+            sockets: 0,
+            gems: Vec::new(),
+            dice: None,
+            inflicts: None,
+            weapon_kind: None,
+        }
     }
 
-    fn random_position(&self, rng: &mut impl Rng) -> (i32, i32) {
-        // Get inner positions to avoid placing items on walls
-        let x = rng.gen_range((self.x + 1)..(self.x + self.width - 1));
-        let y = rng.gen_range((self.y + 1)..(self.y + self.height - 1));
-        (x, y)
+    // What a shop would charge for the item before any discount
+    fn value(&self) -> u32 {
+        match &self.item_type {
+            ItemType::Weapon(bonus) => 20 * *bonus as u32,
+            ItemType::Armor(bonus) => 25 * *bonus as u32,
+            ItemType::Helmet(bonus) | ItemType::Shield(bonus) => 20 * *bonus as u32,
+            ItemType::Boots(bonus) => 15 * *bonus as u32,
+            ItemType::Ring(bonus) => 40 * *bonus as u32,
+            ItemType::Amulet(bonus) => 60 * *bonus as u32,
+            ItemType::Potion(amount) => 5 + 2 * *amount as u32,
+            ItemType::Scroll(_) => 30,
+            ItemType::Material(_, MaterialQuality::Crude) => 5,
+            ItemType::Material(_, MaterialQuality::Fine) => 10,
+            ItemType::Material(_, MaterialQuality::Pristine) => 20,
+            ItemType::Artifact(_) => 200,
+            ItemType::Key | ItemType::ChestKey => 10,
+            ItemType::Pickaxe => 40,
+            ItemType::Rope => 25,
+            ItemType::Gold(amount) => *amount,
+            ItemType::Tool(_) => 60,
+            ItemType::Spellbook(spell) => 20 * spell.mana_cost() as u32,
+            ItemType::Antidote => 15,
+            ItemType::Fuel(turns) => *turns / 10,
+            ItemType::Relic | ItemType::Curio | ItemType::Keepsake(_) | ItemType::Corpse(_, _) => 0,
+        }
     }
 
-    fn center(&self) -> (i32, i32) {
-        (
-            self.x + self.width / 2,
-            self.y + self.height / 2,
-        )
+    fn new_corpse(name: &str, color: Color, carcass: Carcass, rots_at: u32) -> Self {
+        Self {
+            name: format!("{} corpse", name),
+            item_type: ItemType::Corpse(carcass, rots_at),
+            symbol: '%',
+            color: Color::new(color.r * 0.6, color.g * 0.6, color.b * 0.6, 1.0),
+            set: None,
+            cursed: false,
+            curse_known: false,
+            sockets: 0,
+            gems: Vec::new(),
+            dice: None,
+            inflicts: None,
+            weapon_kind: None,
+        }
     }
 
-    fn intersects(&self, other: &Room) -> bool {
-        self.x <= other.x + other.width &&
-            self.x + self.width >= other.x &&
-            self.y <= other.y + other.height &&
-            self.y + self.height >= other.y
+    fn new_gold(amount: u32) -> Self {
+        Self {
+            name: format!("{} gold", amount),
+            item_type: ItemType::Gold(amount),
+            symbol: '$',
+            color: GOLD,
+            set: None,
+            cursed: false,
+            curse_known: false,
+            sockets: 0,
+            gems: Vec::new(),
+            dice: None,
+            inflicts: None,
+            weapon_kind: None,
+        }
     }
+}
 
-    fn inner_tiles(&self) -> Vec<(i32, i32)> {
-        let mut tiles = Vec::new();
-        for y in (self.y + 1)..(self.y + self.height - 1) {
-            for x in (self.x + 1)..(self.x + self.width - 1) {
-                tiles.push((x, y));
-            }
+// Item definition loaded from data/items.ron
+#[derive(Clone, Debug, Deserialize)]
+struct ItemTemplate {
+    id: String,
+    name: String,
+    item_type: ItemType,
+    symbol: char,
+    color: (u8, u8, u8),
+    #[serde(default)]
+    sockets: u32, // Gem sockets on weapons and armor
+    #[serde(default)]
+    dice: Option<Dice>,
+    #[serde(default)]
+    inflicts: Option<Affliction>,
+    #[serde(default)]
+    weapon_kind: Option<WeaponKind>,
+}
+
+impl ItemTemplate {
+    fn create(&self) -> Item {
+        let (r, g, b) = self.color;
+        Item {
+            name: self.name.clone(),
+            item_type: self.item_type.clone(),
+            symbol: self.symbol,
+            color: Color::from_rgba(r, g, b, 255),
+            set: None,
+            cursed: false,
+            curse_known: false,
+            sockets: self.sockets,
+            gems: Vec::new(),
+            dice: self.dice,
+            inflicts: self.inflicts,
+            weapon_kind: self.weapon_kind,
         }
-        tiles
     }
 }
 
-struct GameState {
-    player: Entity,
-    monsters: Vec<Entity>,
-    combat_log: Vec<String>,
-    player_turn: bool,
-    ground_items: Vec<(f32, f32, Item)>,
-    inventory_open: bool,
-    map_manager: MapManager,
-    level_states: Vec<LevelState>,
+// Weighted item ids that can spawn between two depths
+#[derive(Clone, Debug, Deserialize)]
+struct LootTable {
+    min_depth: i32,
+    max_depth: i32,
+    entries: Vec<(String, u32)>,
 }
 
-impl GameState {
-    fn new(config: GameConfig) -> Self {
-        let map_manager = MapManager::new(config);
-        let mut game_state = Self {
-            player: Entity::new_player(),
-            monsters: Vec::new(),
-            combat_log: Vec::new(),
-            player_turn: true,
-            ground_items: Vec::new(),
-            inventory_open: false,
-            map_manager,
-            level_states: vec![],
-        };
+// Weighted item ids found in one kind of room at any depth
+#[derive(Clone, Debug, Deserialize)]
+struct RoomLoot {
+    purpose: RoomPurpose,
+    entries: Vec<(String, u32)>,
+}
 
-        game_state.initialize_current_level();
-        game_state
-    }
+// How one kind of weapon's blows read and look, with a list of verbs for each skill rank
+#[derive(Clone, Debug, Deserialize)]
+struct WeaponStyle {
+    kind: WeaponKind,
+    glyph: char, // Drawn over whatever a blow lands on
+    color: (u8, u8, u8),
+    verbs: Vec<Vec<String>>, // "skewers" reads as "Player skewers Goblin for 5 damage!"
+}
 
-    fn save_current_level_state(&mut self) {
-        let current_level = self.map_manager.current_level as usize;
-        // Ensure we have space for this level
-        while self.level_states.len() <= current_level {
-            self.level_states.push(LevelState {
-                monsters: Vec::new(),
-                ground_items: Vec::new(),
-            });
-        }
+#[derive(Deserialize)]
+struct ItemRegistry {
+    items: Vec<ItemTemplate>,
+    loot_tables: Vec<LootTable>,
+    #[serde(default)]
+    room_loot: Vec<RoomLoot>,
+    #[serde(default)]
+    weapon_styles: Vec<WeaponStyle>,
+}
 
-        // Create new state with cloned data
-        let new_state = LevelState {
-            monsters: self.monsters.clone(),
-            ground_items: self.ground_items.clone(),
-        };
+impl ItemRegistry {
+    const PATH: &'static str = "data/items.ron";
+    const BUILTIN: &'static str = include_str!("../data/items.ron");
+    const ROOM_LOOT_CHANCE: f64 = 0.6;
+
+    // Same lookup order as the monster registry: file on disk, then the built-in copy
+    fn load() -> Self {
+        let contents = std::fs::read_to_string(Self::PATH).unwrap_or_else(|_| Self::BUILTIN.to_string());
+        ron::from_str(&contents).unwrap_or_else(|e| {
+            eprintln!("Failed to parse {}: {}, using built-in items", Self::PATH, e);
+            ron::from_str(Self::BUILTIN).expect("built-in item definitions are valid")
+        })
+    }
 
-        // Save the state
-        self.level_states[current_level] = new_state;
+    fn create(&self, id: &str) -> Option<Item> {
+        self.items.iter().find(|t| t.id == id).map(|t| t.create())
     }
 
-    fn load_level_state(&mut self, level: usize) {
-        if level < self.level_states.len() {
-            let state = &self.level_states[level];
-            self.monsters = state.monsters.clone();
-            self.ground_items = state.ground_items.clone();
-        }
+    fn weapon_style(&self, kind: WeaponKind) -> Option<&WeaponStyle> {
+        self.weapon_styles.iter().find(|style| style.kind == kind)
     }
 
-    fn get_current_level_state(&self) -> Option<&LevelState> {
-        self.level_states.get(self.map_manager.current_level as usize)
+    // Items kept on disk only remember their name, so only those made from a template come back
+    fn create_named(&self, name: &str) -> Option<Item> {
+        self.items.iter().find(|t| t.name == name).map(|t| t.create())
     }
 
-    // Helper method to get current level state mutably
-    fn get_current_level_state_mut(&mut self) -> Option<&mut LevelState> {
-        self.level_states.get_mut(self.map_manager.current_level as usize)
+    fn roll(&self, depth: i32, rng: &mut impl Rng) -> Option<Item> {
+        let table = self.loot_tables
+            .iter()
+            .find(|t| depth >= t.min_depth && depth <= t.max_depth)?;
+        self.roll_entries(&table.entries, rng)
     }
 
-    fn initialize_current_level(&mut self) {
-        if let Some(first_row) = self.map_manager.current_map().rooms.first() {
-            if let Some(first_room) = first_row.first() {
-                let (center_x, center_y) = first_room.center();
-                self.player.x = center_x as f32;
-                self.player.y = center_y as f32;
-            }
+    // Rooms with a purpose mostly hold things that belong there, the rest is the depth's usual loot
+    fn roll_for_room(&self, depth: i32, purpose: RoomPurpose, rng: &mut impl Rng) -> Option<Item> {
+        match self.room_loot.iter().find(|t| t.purpose == purpose) {
+            Some(table) if rng.gen_bool(Self::ROOM_LOOT_CHANCE) => self.roll_entries(&table.entries, rng),
+            _ => self.roll(depth, rng),
         }
+    }
 
-        let mut rng = thread_rng();
-        let mut new_monsters = Vec::new();
-        let map = self.map_manager.current_map();
+    fn roll_entries(&self, entries: &[(String, u32)], rng: &mut impl Rng) -> Option<Item> {
+        let (id, _) = entries.choose_weighted(rng, |(_, weight)| *weight).ok()?;
+        let mut item = self.create(id)?;
+        item.cursed = item.is_equipment() && rng.gen_bool(Item::CURSE_CHANCE);
+        Some(item)
+    }
+}
 
-        for row in &map.rooms {
-            for room in row.iter().skip(1) {
-                let num_monsters = rng.gen_range(0..3);
-                for _ in 0..num_monsters {
-                    let (x, y) = room.random_position(&mut rng);
-                    if map.is_walkable(x, y) {
-                        new_monsters.push(Entity::new_monster(x as f32, y as f32));
-                    }
-                }
+// Unidentified potions and scrolls go by a made-up look, shuffled for each run's seed,
+// until one of the kind is used or identified
+struct Identification {
+    appearances: HashMap<String, (String, Color)>, // True name to the look it hides behind
+    known: HashSet<String>,
+}
+
+impl Identification {
+    const POTION_LOOKS: [(&'static str, Color); 8] = [
+        ("Fizzy Blue", BLUE),
+        ("Murky Green", DARKGREEN),
+        ("Bubbling Red", RED),
+        ("Cloudy White", WHITE),
+        ("Smoky Grey", GRAY),
+        ("Glowing Yellow", YELLOW),
+        ("Viscous Purple", PURPLE),
+        ("Oily Brown", BROWN),
+    ];
+    const SCROLL_LABELS: [&'static str; 10] = [
+        "ZELGO MER",
+        "XIXAXA",
+        "FOOBIE BLETCH",
+        "KIRJE",
+        "VELOX NEB",
+        "ELBIB YLOH",
+        "JUYED AWK",
+        "PRATYAVAYAH",
+        "GARVEN DEH",
+        "THARR",
+    ];
+    const SCROLL_COLOR: Color = BEIGE;
+
+    fn new(registry: &ItemRegistry, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut potion_looks = Self::POTION_LOOKS.to_vec();
+        let mut scroll_labels = Self::SCROLL_LABELS.to_vec();
+        potion_looks.shuffle(&mut rng);
+        scroll_labels.shuffle(&mut rng);
+
+        let mut appearances = HashMap::new();
+        for template in &registry.items {
+            let look = match template.item_type {
+                ItemType::Potion(_) => potion_looks.pop().map(|(look, color)| (format!("{} Potion", look), color)),
+                ItemType::Scroll(Effect::Identify) => None,
+                ItemType::Scroll(_) => scroll_labels.pop().map(|label| (format!("Scroll labelled {}", label), Self::SCROLL_COLOR)),
+                _ => None,
+            };
+            if let Some(look) = look {
+                appearances.insert(template.name.clone(), look);
             }
         }
-
-        self.monsters = new_monsters;
-        self.spawn_items_for_current_level();
+        Self { appearances, known: HashSet::new() }
     }
 
-    fn spawn_items_for_current_level(&mut self) {
-        let mut rng = thread_rng();
-        self.ground_items.clear();
-
-        let rooms = self.map_manager.current_map().rooms.clone();
+    fn is_known(&self, item: &Item) -> bool {
+        !self.appearances.contains_key(&item.name) || self.known.contains(&item.name)
+    }
 
-        for row in &rooms {
-            for room in row {
-                if rng.gen_bool(0.6) {
-                    let (x, y) = room.random_position(&mut rng);
-                    let item = match rng.gen_range(0..4) {
-                        0 => Item::new_sword(),
-                        1 => Item::new_armor(),
-                        2 => Item::new_health_potion(),
-                        _ => Item::new_lightning_scroll(),
-                    };
-                    self.ground_items.push((x as f32, y as f32, item));
-                }
-            }
+    // What the player calls the item
+    fn name(&self, item: &Item) -> String {
+        match self.appearances.get(&item.name) {
+            Some((look, _)) if !self.is_known(item) => look.clone(),
+            _ => item.name.clone(),
         }
     }
 
-    fn handle_level_transition(&mut self) {
-        let player_pos = (self.player.x as usize, self.player.y as usize);
-        let current_level = self.map_manager.current_level;
-        let map = self.map_manager.current_map();
+    fn color(&self, item: &Item) -> Color {
+        match self.appearances.get(&item.name) {
+            Some(&(_, color)) if !self.is_known(item) => color,
+            _ => item.color,
+        }
+    }
 
-        if player_pos.1 >= map.height || player_pos.0 >= map.width {
-            return;
+    fn describe(&self, item: &Item) -> String {
+        match item.item_type {
+            ItemType::Potion(_) if !self.is_known(item) => "Unidentified potion, drink it to find out".to_string(),
+            ItemType::Scroll(_) if !self.is_known(item) => "Unidentified scroll, read it to find out".to_string(),
+            _ => item.describe(),
         }
+    }
 
-        match map.tiles[player_pos.1][player_pos.0] {
-            Tile::StairsDown => {
-                if is_key_pressed(KeyCode::Period) {
-                    self.save_current_level_state();
-                    let next_level = current_level + 1;
-                    let is_new_level = next_level as usize >= self.level_states.len();
+    // Returns whether the kind was new to the player
+    fn learn(&mut self, name: &str) -> bool {
+        self.appearances.contains_key(name) && self.known.insert(name.to_string())
+    }
+}
+
+// Inventory struct to manage items
+#[derive(Clone)]
+pub struct Inventory {
+    items: Vec<Item>,
+    capacity: usize,
+    equipment: BTreeMap<EquipSlot, Item>, // What is worn, by slot
+    materials: MaterialPouch,
+    gold: u32,
+}
+
+impl Inventory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            items: Vec::new(),
+            capacity,
+            equipment: BTreeMap::new(),
+            materials: MaterialPouch::default(),
+            gold: 0,
+        }
+    }
+
+    pub fn add_item(&mut self, item: Item) -> Result<(), String> {
+        if let ItemType::Material(kind, quality) = item.item_type {
+            self.materials.add(kind, quality, 1);
+            Ok(())
+        } else if let ItemType::Gold(amount) = item.item_type {
+            self.gold += amount;
+            Ok(())
+        } else if self.items.len() >= self.capacity {
+            Err("Inventory is full!".to_string())
+        } else {
+            self.items.push(item);
+            Ok(())
+        }
+    }
+
+    pub fn remove_item(&mut self, index: usize) -> Option<Item> {
+        if index < self.items.len() {
+            Some(self.items.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn equip_item(&mut self, index: usize) -> Result<String, String> {
+        if index >= self.items.len() {
+            return Err("Invalid item index!".to_string());
+        }
+
+        let slots = EquipSlot::for_item(&self.items[index].item_type);
+        if slots.is_empty() {
+            return Err("This item cannot be equipped!".to_string());
+        }
+        // An empty slot if there is one, otherwise the first whose item will come off
+        let free = slots.iter().find(|slot| !self.equipment.contains_key(slot));
+        let Some(&slot) = free.or_else(|| slots.iter().find(|slot| !self.equipment[slot].cursed)) else {
+            return Err(format!("Your {} is cursed and won't come off!", self.equipment[&slots[0]].name));
+        };
+
+        let mut item = self.items.remove(index);
+        item.curse_known = true;
+        let message = if item.cursed {
+            format!("The {} is cursed! It binds itself to you.", item.name)
+        } else {
+            format!("{} equipped!", slot.name())
+        };
+        if let Some(old) = self.equipment.insert(slot, item) {
+            self.items.push(old);
+        }
+        Ok(message)
+    }
+
+    // Clears every curse in the pack, returning how many were lifted
+    fn remove_curses(&mut self) -> usize {
+        let mut lifted = 0;
+        for item in self.items.iter_mut().chain(self.equipment.values_mut()) {
+            if item.cursed {
+                item.cursed = false;
+                item.curse_known = true;
+                lifted += 1;
+            }
+        }
+        lifted
+    }
+
+    pub fn use_item(&mut self, index: usize, entity: &mut Entity, game_state: &mut GameState) -> Result<String, String> {
+        if index >= self.items.len() {
+            return Err("Invalid item index!".to_string());
+        }
+
+        // Clone the item type to avoid borrowing issues
+        let item_type = self.items[index].item_type.clone();
+
+        match item_type {
+            ItemType::Potion(heal_amount) => {
+                let heal_amount = if game_state.has_modifier(Modifier::Frailty) {
+                    heal_amount / 2
+                } else {
+                    heal_amount
+                };
+                entity.stats.hp = (entity.stats.hp + heal_amount).min(entity.stats.max_hp);
+                self.items.remove(index);
+                Ok(format!("Used health potion! Healed for {} HP", heal_amount))
+            }
+            ItemType::Scroll(effect) => {
+                match effect {
+                    Effect::Lightning(damage) => {
+                        if let Some(closest_monster) = game_state.find_closest_monster(entity.x, entity.y, 5.0) {
+                            let hp_before = closest_monster.stats.hp;
+                            closest_monster.stats.hp -= damage;
+                            closest_monster.last_hit_by = Some(DamageSource::Used(entity.id, "a lightning bolt"));
+                            let popup = DamagePopup::after_hit(closest_monster, hp_before, false);
+                            let name = closest_monster.name.clone();
+                            let target = (closest_monster.x as i32, closest_monster.y as i32);
+                            game_state.damage_popups.extend(popup);
+                            self.items.remove(index);
+                            let shocked = game_state.resolve_element(Element::Lightning, target, 0, damage / 2, DamageSource::Used(entity.id, "a lightning bolt"));
+                            if shocked.contains(&(entity.x as i32, entity.y as i32)) {
+                                let hp_before = entity.stats.hp;
+                                entity.stats.hp -= (damage / 2 - entity.gem_resistance(Element::Lightning)).max(0);
+                                entity.last_hit_by = Some(DamageSource::Used(entity.id, "their own lightning"));
+                                game_state.damage_popups.extend(DamagePopup::after_hit(entity, hp_before, true));
+                            }
+                            Ok(format!("Lightning bolt hits {} for {} damage!", name, damage))
+                        } else {
+                            Err("No monster in range!".to_string())
+                        }
+                    }
+                    Effect::Fireball(damage) => {
+                        if let Some(closest_monster) = game_state.find_closest_monster(entity.x, entity.y, 6.0) {
+                            let name = closest_monster.name.clone();
+                            let target = (closest_monster.x as i32, closest_monster.y as i32);
+                            self.items.remove(index);
+                            let burned = game_state.resolve_element(Element::Fire, target, 1, damage, DamageSource::Used(entity.id, "a fireball"));
+                            if burned.contains(&(entity.x as i32, entity.y as i32)) {
+                                let hp_before = entity.stats.hp;
+                                entity.stats.hp -= (damage - entity.gem_resistance(Element::Fire)).max(0);
+                                entity.last_hit_by = Some(DamageSource::Used(entity.id, "their own fireball"));
+                                game_state.damage_popups.extend(DamagePopup::after_hit(entity, hp_before, true));
+                            }
+                            Ok(format!("A fireball bursts around {}!", name))
+                        } else {
+                            Err("No monster in range!".to_string())
+                        }
+                    }
+                    Effect::Frost(radius) => {
+                        let frozen = game_state.resolve_element(Element::Cold, (entity.x as i32, entity.y as i32), radius, 0, DamageSource::Used(entity.id, "a frost scroll"));
+                        self.items.remove(index);
+                        if frozen.is_empty() {
+                            Ok("A chill passes over you.".to_string())
+                        } else {
+                            Ok(format!("Frost spreads from the scroll, freezing {} tiles of water.", frozen.len()))
+                        }
+                    }
+                    Effect::Teleport => {
+                        if let Some((x, y)) = game_state.random_free_tile() {
+                            entity.x = x;
+                            entity.y = y;
+                            self.items.remove(index);
+                            Ok("You read the scroll and vanish in a flash of light!".to_string())
+                        } else {
+                            Err("The scroll fizzles, there is nowhere to go!".to_string())
+                        }
+                    }
+                    Effect::Blink(radius) => {
+                        if let Some((x, y)) = game_state.random_free_tile_within((entity.x as i32, entity.y as i32), radius) {
+                            entity.x = x as f32;
+                            entity.y = y as f32;
+                            self.items.remove(index);
+                            Ok("You read the scroll and blink a short way off!".to_string())
+                        } else {
+                            Err("The scroll fizzles, there is nowhere to go!".to_string())
+                        }
+                    }
+                    Effect::Recall(delay) => {
+                        if game_state.map_manager.current_floor() == FloorId::FIRST {
+                            return Err("You are already on the first floor.".to_string());
+                        }
+                        if entity.has_status(StatusKind::Recalling) {
+                            return Err("You are already being pulled back.".to_string());
+                        }
+                        entity.add_status(StatusKind::Recalling, delay);
+                        self.items.remove(index);
+                        Ok(format!("The air around you starts to hum. In {} turns it will pull you back to the first floor.", delay))
+                    }
+                    Effect::Portal => {
+                        if game_state.in_town() {
+                            return Err("You are already in town.".to_string());
+                        }
+                        let Some(spot) = game_state.random_free_tile_within((entity.x as i32, entity.y as i32), 1) else {
+                            return Err("There is no room around you for a portal.".to_string());
+                        };
+                        game_state.map_manager.open_portal(spot);
+                        self.items.remove(index);
+                        Ok("A shimmering portal to the town opens beside you.".to_string())
+                    }
+                    Effect::Summon => {
+                        let result = game_state.summon_ally(entity.x as i32, entity.y as i32);
+                        if result.is_ok() {
+                            self.items.remove(index);
+                        }
+                        result
+                    }
+                    Effect::Identify => {
+                        let unknown = self.items
+                            .iter()
+                            .enumerate()
+                            .find(|&(i, item)| i != index && !game_state.identification.is_known(item))
+                            .map(|(_, item)| (game_state.identification.name(item), item.name.clone()));
+                        if let Some((appearance, name)) = unknown {
+                            game_state.identification.learn(&name);
+                            self.items.remove(index);
+                            return Ok(format!("The {} is a {}.", appearance, name));
+                        }
+
+                        // With every potion and scroll known, reveal whether a piece of gear is cursed
+                        let Some(item) = self.items.iter_mut().find(|item| item.is_equipment() && !item.curse_known) else {
+                            return Err("You have nothing left to identify.".to_string());
+                        };
+                        item.curse_known = true;
+                        let message = if item.cursed {
+                            format!("The {} is cursed!", item.name)
+                        } else {
+                            format!("The {} is free of curses.", item.name)
+                        };
+                        self.items.remove(index);
+                        Ok(message)
+                    }
+                    Effect::RemoveCurse => {
+                        self.items.remove(index);
+                        match self.remove_curses() {
+                            0 => Ok("You feel as if someone is watching over you.".to_string()),
+                            1 => Ok("You feel a malevolent aura fade.".to_string()),
+                            lifted => Ok(format!("{} malevolent auras fade.", lifted)),
+                        }
+                    }
+                    Effect::Confusion(duration) => {
+                        if let Some(closest_monster) = game_state.find_closest_monster(entity.x, entity.y, 5.0) {
+                            closest_monster.add_status(StatusKind::Confused, duration);
+                            let name = closest_monster.name.clone();
+                            self.items.remove(index);
+                            Ok(format!("{} is confused for {} turns!", name, duration))
+                        } else {
+                            Err("No monster in range!".to_string())
+                        }
+                    }
+                }
+            }
+            ItemType::Artifact(artifact) => artifact.activate(entity, game_state),
+            ItemType::Tool(tool) => game_state.begin_targeting(tool),
+            ItemType::Spellbook(spell) => {
+                if game_state.known_spells.contains(&spell) {
+                    return Err(format!("You already know {}.", spell.name()));
+                }
+                game_state.known_spells.push(spell);
+                self.items.remove(index);
+                Ok(format!("You learn {}! Press [Z] to cast it.", spell.name()))
+            }
+            ItemType::Antidote => {
+                self.items.remove(index);
+                if entity.cure(StatusKind::Poisoned(0)) {
+                    Ok("The antidote purges the poison.".to_string())
+                } else {
+                    Ok("The antidote tastes bitter, but you weren't poisoned.".to_string())
+                }
+            }
+            ItemType::Rope => {
+                if game_state.map_manager.current_floor() == FloorId::FIRST {
+                    return Err("There is no floor above to climb to.".to_string());
+                }
+                let map = game_state.map_manager.current_map();
+                let by_chasm = map.directions().iter().any(|(dx, dy)| map.tile_at(entity.x as i32 + dx, entity.y as i32 + dy) == Some(&Tile::Chasm));
+                if !by_chasm {
+                    return Err("You need a chasm beside you to climb.".to_string());
+                }
+                self.items.remove(index);
+                game_state.escape = Some(Escape::Rope);
+                Ok("You hook the rope into the chasm wall and start to climb.".to_string())
+            }
+            ItemType::Fuel(turns) => {
+                if game_state.light_fuel >= GameState::MAX_LIGHT_FUEL {
+                    return Err("Your light can't burn any longer than it already will.".to_string());
+                }
+                let name = self.items.remove(index).name;
+                let relit = game_state.light_fuel == 0;
+                game_state.light_fuel = (game_state.light_fuel + turns).min(GameState::MAX_LIGHT_FUEL);
+                if relit {
+                    Ok(format!("You light the {}. The darkness draws back.", name))
+                } else {
+                    Ok(format!("You add the {} to your light. ({} turns left)", name, game_state.light_fuel))
+                }
+            }
+            _ => Err("This item cannot be used!".to_string()),
+        }
+    }
+
+    fn equipped_items(&self) -> impl Iterator<Item = &Item> {
+        self.equipment.values()
+    }
+
+    pub fn equipped_set_pieces(&self, set: ItemSet) -> usize {
+        self.equipped_items().filter(|item| item.set == Some(set)).count()
+    }
+
+    // Attack and defense from every set bonus tier currently reached
+    fn get_set_bonuses(&self) -> (i32, i32) {
+        let mut bonuses = (0, 0);
+        for set in ItemSet::ALL {
+            let equipped = self.equipped_set_pieces(set);
+            for bonus in set.bonuses().iter().filter(|b| equipped >= b.pieces) {
+                bonuses.0 += bonus.attack;
+                bonuses.1 += bonus.defense;
+            }
+        }
+        bonuses
+    }
+
+    // Attack from Diamonds in the weapon and defense from Diamonds in everything else worn
+    fn gem_bonuses(&self) -> (i32, i32) {
+        let diamonds = |weapon: bool| -> i32 {
+            self.equipped_items()
+                .filter(|item| item.is_weapon() == weapon)
+                .flat_map(|item| item.gems.iter())
+                .filter(|(gem, _)| gem.element().is_none())
+                .map(|(_, quality)| quality.tier())
+                .sum()
+        };
+        (diamonds(true), diamonds(false))
+    }
+
+    // Elemental damage added to every hit by the weapon's gems
+    fn weapon_dice(&self) -> Option<Dice> {
+        self.equipment.get(&EquipSlot::Weapon).and_then(|item| item.dice)
+    }
+
+    fn weapon_affliction(&self) -> Option<Affliction> {
+        self.equipment.get(&EquipSlot::Weapon).and_then(|item| item.inflicts)
+    }
+
+    fn weapon_kind(&self) -> WeaponKind {
+        self.equipment.get(&EquipSlot::Weapon).and_then(|item| item.weapon_kind).unwrap_or(WeaponKind::Crushing)
+    }
+
+    fn gem_damage(&self) -> Vec<(Element, i32)> {
+        let mut damage: Vec<(Element, i32)> = Vec::new();
+        let gems = self.equipment.get(&EquipSlot::Weapon).into_iter().flat_map(|item| item.gems.iter());
+        for (element, quality) in gems.filter_map(|(gem, quality)| gem.element().map(|e| (e, quality))) {
+            let amount = quality.tier() * GemKind::ELEMENT_PER_TIER;
+            match damage.iter_mut().find(|(e, _)| *e == element) {
+                Some((_, total)) => *total += amount,
+                None => damage.push((element, amount)),
+            }
+        }
+        damage
+    }
+
+    // How much damage of an element the gems in worn armor, helmet and the rest turn aside
+    fn gem_resistance(&self, element: Element) -> i32 {
+        self.equipped_items()
+            .filter(|item| !item.is_weapon())
+            .flat_map(|item| item.gems.iter())
+            .filter(|(gem, _)| gem.element() == Some(element))
+            .map(|(_, quality)| quality.tier() * GemKind::ELEMENT_PER_TIER)
+            .sum()
+    }
+
+    // Equipment in the order a forge lists it, worn pieces first
+    fn equipment(&self) -> impl Iterator<Item = &Item> {
+        self.equipped_items().chain(self.items.iter().filter(|item| item.is_equipment()))
+    }
+
+    fn equipment_mut(&mut self, index: usize) -> Option<&mut Item> {
+        self.equipment
+            .values_mut()
+            .chain(self.items.iter_mut().filter(|item| item.is_equipment()))
+            .nth(index)
+    }
+
+    pub fn get_equipment_bonuses(&self) -> (i32, i32) {
+        let (mut attack, mut defense) = (0, 0);
+        for item in self.equipped_items() {
+            let (item_attack, item_defense) = item.item_type.equipment_bonuses();
+            let (attack_penalty, defense_penalty) = item.curse_penalty();
+            attack += item_attack - attack_penalty;
+            defense += item_defense - defense_penalty;
+        }
+
+        let (set_attack, set_defense) = self.get_set_bonuses();
+        let (gem_attack, gem_defense) = self.gem_bonuses();
+        (attack + set_attack + gem_attack, defense + set_defense + gem_defense)
+    }
+}
+
+#[derive(Clone)]
+struct Stats {
+    hp: i32,
+    max_hp: i32,
+    attack: i32,
+    defense: i32,
+    speed: f32,
+    last_move: f32,
+    perception: f32,
+    xp_value: i32,
+    level_system: Option<LevelSystem>,
+    mana: i32,
+    max_mana: i32, // Zero for monsters, they don't cast
+}
+
+impl Stats {
+    const MANA_REGEN_TURNS: u32 = 3; // Turns per point of mana recovered
+}
+
+// Monster definition loaded from data/monsters.ron
+#[derive(Clone, Debug, Deserialize)]
+struct MonsterTemplate {
+    name: String,
+    symbol: char,
+    color: (u8, u8, u8),
+    hp: i32,
+    attack: i32,
+    defense: i32,
+    speed: f32,
+    perception: f32,
+    xp_value: i32,
+    min_depth: i32,
+    max_depth: i32,
+    #[serde(default)]
+    materials: Vec<MaterialKind>,
+    #[serde(default)]
+    ranged: Option<RangedAttack>,
+    #[serde(default)]
+    aura: Option<Aura>,
+    #[serde(default)]
+    boss: bool, // Placed by hand on the final floor instead of rolled
+    #[serde(default)]
+    spins_webs: bool, // Leaves webs behind and walks through them freely
+    #[serde(default)]
+    barks: Option<Barks>, // Only monsters that can talk have any
+    #[serde(default)]
+    haunts: Vec<RoomPurpose>, // Rooms the monster is usually found in
+    #[serde(default)]
+    inflicts: Option<Affliction>, // Poisonous bites, tearing claws and the like
+    #[serde(default)]
+    drops: Vec<(String, u32)>, // Weighted item ids, beasts carry nothing
+    #[serde(default)]
+    corpse: Option<Carcass>, // Spirits leave nothing behind to eat
+    #[serde(default)]
+    bribable: bool, // Humanoids with wits enough to be bought off or hired
+    #[serde(default)]
+    burrows: bool, // Digs through rock towards its prey, or a meal it can smell
+    #[serde(default)]
+    lurks_in_dark: bool, // Hunts down a player whose light has gone out
+}
+
+impl MonsterTemplate {
+    // What the monster costs out of a floor's threat budget. Its XP already tracks how dangerous it is
+    fn threat(&self) -> i32 {
+        self.xp_value.max(1)
+    }
+
+    // What to watch out for when fighting it, for hints. None for monsters that just hit back
+    fn dangers(&self) -> Option<String> {
+        let mut dangers = Vec::new();
+        if let Some(ranged) = &self.ranged {
+            dangers.push(format!("it attacks from up to {} tiles away", ranged.range));
+        }
+        if let Some(affliction) = self.inflicts {
+            dangers.push(format!("its blows can leave you {}", affliction.status.name()));
+        }
+        match self.aura {
+            Some(Aura::Frost) => dangers.push("its chill slows anything next to it".to_string()),
+            Some(Aura::Command) => dangers.push("it rallies the monsters around it to hit harder".to_string()),
+            Some(Aura::Fear) => dangers.push("its dread makes you miss more often".to_string()),
+            None => {}
+        }
+        if self.spins_webs {
+            dangers.push("it spins webs that hold you fast".to_string());
+        }
+        if self.burrows {
+            dangers.push("it tunnels through solid rock".to_string());
+        }
+        if self.lurks_in_dark {
+            dangers.push("it hunts anyone whose light has gone out".to_string());
+        }
+
+        let last = dangers.pop()?;
+        if dangers.is_empty() {
+            Some(last)
+        } else {
+            Some(format!("{} and {}", dangers.join(", "), last))
+        }
+    }
+}
+
+// Lines a monster shouts when it spots the player, flees, or lands a heavy blow
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Barks {
+    #[serde(default)]
+    spot: Vec<String>,
+    #[serde(default)]
+    flee: Vec<String>,
+    #[serde(default)]
+    crit: Vec<String>,
+}
+
+impl Barks {
+    const CHANCE: f64 = 0.4; // Every moment getting a line would wear thin fast
+
+    fn pick(name: &str, lines: &[String]) -> Option<String> {
+        let mut rng = thread_rng();
+        if !rng.gen_bool(Self::CHANCE) {
+            return None;
+        }
+        lines.choose(&mut rng).map(|line| format!("The {} shouts: \"{}\"", name, line))
+    }
+}
+
+// Archers and casters hit from a distance when they have a clear line to the target
+#[derive(Clone, Debug, Deserialize)]
+struct RangedAttack {
+    range: i32,
+    verb: String, // "looses an arrow" reads as "The Goblin Archer looses an arrow!"
+    color: (u8, u8, u8),
+    #[serde(default)]
+    element: Option<Element>, // Resisted by gems in the target's armor
+}
+
+// A shot drawn briefly between shooter and target
+struct ProjectileTrace {
+    from: (f32, f32),
+    to: (f32, f32),
+    color: Color,
+    time_left: f32,
+}
+
+impl ProjectileTrace {
+    const DURATION: f32 = 0.25;
+}
+
+// One line of the message log, kept for the history viewer
+struct LoggedMessage {
+    text: String,
+    turn: u32,
+    category: MessageCategory,
+    repeats: u32, // How many times in a row it came up, collapsed into one line
+}
+
+impl LoggedMessage {
+    const PAGE_LINES: usize = 20;
+
+    fn new(category: MessageCategory, text: String, turn: u32) -> Self {
+        Self { text, turn, category, repeats: 1 }
+    }
+
+    // Lines that differ only in their numbers, like the damage of each blow, count as the same
+    fn repeated_by(&self, text: &str) -> bool {
+        fn shape(text: &str) -> String {
+            let mut shape = String::new();
+            for c in text.chars() {
+                if !c.is_ascii_digit() {
+                    shape.push(c);
+                } else if !shape.ends_with('#') {
+                    shape.push('#');
+                }
+            }
+            shape
+        }
+        shape(&self.text) == shape(text)
+    }
+
+    // "Player hits Rat for 3 damage! x3" once the same line came up more than once
+    fn display(&self) -> String {
+        if self.repeats > 1 {
+            format!("{} x{}", self.text, self.repeats)
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+// What a log message is about, which picks its color and whether repeats of it run together
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MessageCategory {
+    Combat,
+    Item,
+    System,
+    Warning, // Something failed or something dangerous is coming
+}
+
+impl MessageCategory {
+    const ALL: [MessageCategory; 4] = [MessageCategory::Combat, MessageCategory::Item, MessageCategory::System, MessageCategory::Warning];
+
+    fn name(&self) -> &'static str {
+        match self {
+            MessageCategory::Combat => "Combat",
+            MessageCategory::Item => "Items",
+            MessageCategory::System => "System",
+            MessageCategory::Warning => "Warnings",
+        }
+    }
+
+    fn color(&self) -> Color {
+        match self {
+            MessageCategory::Combat => ORANGE,
+            MessageCategory::Item => GOLD,
+            MessageCategory::System => LIGHTGRAY,
+            MessageCategory::Warning => RED,
+        }
+    }
+
+    // A system notice always gets a line of its own, even said twice
+    fn collapses(&self) -> bool {
+        *self != MessageCategory::System
+    }
+}
+
+// What the player has done on one floor, counted as they go
+#[derive(Clone, Copy, Default)]
+struct FloorStats {
+    kills: u32,
+    turns: u32,
+}
+
+// How a floor was left, so the player can tell whether it is worth coming back to
+struct FloorSummary {
+    floor: String,
+    stats: FloorStats,
+    monsters_left: usize,
+    items_left: usize,
+    unexplored_percent: u32,
+    shown_at: f32,
+}
+
+impl FloorSummary {
+    const SECONDS: f32 = 5.0;
+    const FADE_SECONDS: f32 = 1.0;
+}
+
+// The mark a landed blow leaves on its target for a moment, with sparks flying off it at high skill
+struct StrikeMark {
+    position: (f32, f32),
+    glyph: char,
+    color: Color,
+    flourish: bool,
+    time_left: f32,
+}
+
+impl StrikeMark {
+    const DURATION: f32 = 0.3;
+}
+
+// The damage a landed blow did, drifting up off the target and fading
+struct DamagePopup {
+    position: (f32, f32),
+    amount: i32,
+    color: Color,
+    time_left: f32,
+}
+
+impl DamagePopup {
+    const DURATION: f32 = 0.8;
+    const RISE: f32 = 0.75; // In tiles, over the whole duration
+
+    // Nothing when the blow drew no blood. Hurting the player shows in red
+    fn after_hit(target: &Entity, hp_before: i32, player: bool) -> Option<Self> {
+        let amount = hp_before - target.stats.hp;
+        (amount > 0).then_some(Self {
+            position: (target.x, target.y),
+            amount,
+            color: if player { RED } else { WHITE },
+            time_left: Self::DURATION,
+        })
+    }
+}
+
+// Time spent in each part of one update, so slow turns on low-end machines can be traced
+// to pathological maps or AI loops
+#[derive(Default)]
+struct TurnProfile {
+    ai: Duration,
+    pathfinding: Duration,
+    effects: Duration,
+    spawning: Duration,
+}
+
+impl TurnProfile {
+    const BUDGET: Duration = Duration::from_millis(8);
+
+    // A breakdown of the turn when it ran over budget
+    fn budget_warning(&self) -> Option<String> {
+        let total = self.ai + self.pathfinding + self.effects + self.spawning;
+        if total <= Self::BUDGET {
+            return None;
+        }
+        let ms = |d: Duration| d.as_secs_f32() * 1000.0;
+        Some(format!(
+            "Turn took {:.1}ms, over the {:.0}ms budget: AI {:.1}ms, pathfinding {:.1}ms, effects {:.1}ms, spawning {:.1}ms",
+            ms(total),
+            ms(Self::BUDGET),
+            ms(self.ai),
+            ms(self.pathfinding),
+            ms(self.effects),
+            ms(self.spawning),
+        ))
+    }
+}
+
+// Where an entity is drawn, sliding from its last tile to the current one. Game logic only sees the grid
+#[derive(Clone, Copy)]
+struct Tween {
+    from: (f32, f32),
+    to: (f32, f32),
+    started: f32,
+}
+
+impl Tween {
+    const DURATION: f32 = 0.1;
+    const MAX_SLIDE: f32 = 2.5; // Longer jumps, like stairs or teleports, snap instead
+
+    fn new(position: (f32, f32)) -> Self {
+        Self { from: position, to: position, started: 0.0 }
+    }
+
+    fn position(&self, now: f32) -> (f32, f32) {
+        let t = ((now - self.started) / Self::DURATION).clamp(0.0, 1.0);
+        (
+            self.from.0 + (self.to.0 - self.from.0) * t,
+            self.from.1 + (self.to.1 - self.from.1) * t,
+        )
+    }
+
+    // Start a new slide from wherever the entity is drawn right now
+    fn retarget(&mut self, target: (f32, f32), now: f32) {
+        if target == self.to {
+            return;
+        }
+        let current = self.position(now);
+        let distance = ((target.0 - current.0).powi(2) + (target.1 - current.1).powi(2)).sqrt();
+        self.from = if distance > Self::MAX_SLIDE { target } else { current };
+        self.to = target;
+        self.started = now;
+    }
+}
+
+struct MonsterRegistry {
+    templates: Vec<MonsterTemplate>,
+}
+
+impl MonsterRegistry {
+    const PATH: &'static str = "data/monsters.ron";
+    const BUILTIN: &'static str = include_str!("../data/monsters.ron");
+    const THEMED_CHANCE: f64 = 0.75;
+
+    // Prefer the file on disk so monsters can be tweaked without rebuilding,
+    // falling back to the copy compiled into the binary
+    fn load() -> Self {
+        let contents = std::fs::read_to_string(Self::PATH).unwrap_or_else(|_| Self::BUILTIN.to_string());
+        let templates = ron::from_str(&contents).unwrap_or_else(|e| {
+            eprintln!("Failed to parse {}: {}, using built-in monsters", Self::PATH, e);
+            ron::from_str(Self::BUILTIN).expect("built-in monster definitions are valid")
+        });
+        Self { templates }
+    }
+
+    fn for_depth(&self, depth: i32) -> Vec<&MonsterTemplate> {
+        self.templates
+            .iter()
+            .filter(|t| !t.boss && depth >= t.min_depth && depth <= t.max_depth)
+            .collect()
+    }
+
+    fn choose(&self, depth: i32, rng: &mut impl Rng) -> Option<&MonsterTemplate> {
+        self.for_depth(depth).choose(rng).copied()
+    }
+
+    // What turns up on a floor: a branch's own monsters whatever their usual depths, otherwise the depth's
+    fn for_floor(&self, depth: i32, branch: Option<&BranchTemplate>) -> Vec<&MonsterTemplate> {
+        match branch {
+            Some(branch) => self.templates.iter().filter(|t| !t.boss && branch.monsters.contains(&t.name)).collect(),
+            None => self.for_depth(depth),
+        }
+    }
+
+    // A monster for the depth, or from a branch's own set, that fits in what is left of a threat budget,
+    // most often one that haunts the kind of room it is placed in
+    fn choose_affordable(&self, depth: i32, budget: i32, purpose: RoomPurpose, branch: Option<&BranchTemplate>, rng: &mut impl Rng) -> Option<&MonsterTemplate> {
+        let affordable: Vec<&MonsterTemplate> = self.for_floor(depth, branch).into_iter().filter(|t| t.threat() <= budget).collect();
+        let themed: Vec<&MonsterTemplate> = affordable.iter().copied().filter(|t| t.haunts.contains(&purpose)).collect();
+        if !themed.is_empty() && rng.gen_bool(Self::THEMED_CHANCE) {
+            themed.choose(rng).copied()
+        } else {
+            affordable.choose(rng).copied()
+        }
+    }
+}
+
+// A field some monsters project around themselves, recomputed every turn
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+enum Aura {
+    Frost,   // Slows everything next to the bearer
+    Command, // Other monsters nearby hit harder
+    Fear,    // The player and allies nearby sometimes miss
+}
+
+impl Aura {
+    const MAX_RADIUS: i32 = 3;
+    const COMMAND_BONUS: i32 = 2;
+    const FEAR_MISS_CHANCE: f64 = 0.25;
+
+    fn name(&self) -> &'static str {
+        match self {
+            Aura::Frost => "Frost aura",
+            Aura::Command => "Command aura",
+            Aura::Fear => "Fear aura",
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Aura::Frost => "slows adjacent creatures",
+            Aura::Command => "nearby monsters deal +2 damage",
+            Aura::Fear => "nearby foes miss 25% of attacks",
+        }
+    }
+
+    fn radius(&self) -> i32 {
+        match self {
+            Aura::Frost => 1,
+            Aura::Command | Aura::Fear => 3,
+        }
+    }
+
+    // Frost chills anyone, command only rallies monsters, fear only shakes their foes
+    fn affects(&self, target: &Entity, is_monster: bool) -> bool {
+        match self {
+            Aura::Frost => true,
+            Aura::Command => is_monster,
+            Aura::Fear => !is_monster || target.is_player,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum StatusKind {
+    Confused,    // Moves randomly
+    Poisoned(i32), // Damage per turn
+    Hasted,      // Acts twice as often
+    Entangled,   // Stuck in a web, every turn goes into trying to break free
+    Bleeding(i32), // Damage per turn
+    Burning(i32),  // Damage per turn, put out by stepping into water
+    Recalling,   // Pulled back to the first floor when it runs out
+}
+
+impl StatusKind {
+    fn name(&self) -> &'static str {
+        match self {
+            StatusKind::Confused => "confused",
+            StatusKind::Poisoned(_) => "poisoned",
+            StatusKind::Hasted => "hasted",
+            StatusKind::Entangled => "entangled",
+            StatusKind::Bleeding(_) => "bleeding",
+            StatusKind::Burning(_) => "burning",
+            StatusKind::Recalling => "recalling",
+        }
+    }
+
+    // Short tag for the status row under the HP readout
+    fn abbreviation(&self) -> &'static str {
+        match self {
+            StatusKind::Confused => "CNF",
+            StatusKind::Poisoned(_) => "PSN",
+            StatusKind::Hasted => "HST",
+            StatusKind::Entangled => "WEB",
+            StatusKind::Bleeding(_) => "BLD",
+            StatusKind::Burning(_) => "BRN",
+            StatusKind::Recalling => "RCL",
+        }
+    }
+
+    fn color(&self) -> Color {
+        match self {
+            StatusKind::Confused => VIOLET,
+            StatusKind::Poisoned(_) => LIME,
+            StatusKind::Hasted => SKYBLUE,
+            StatusKind::Entangled => LIGHTGRAY,
+            StatusKind::Bleeding(_) => RED,
+            StatusKind::Burning(_) => ORANGE,
+            StatusKind::Recalling => GOLD,
+        }
+    }
+
+    fn damage_per_turn(&self) -> Option<i32> {
+        match *self {
+            StatusKind::Poisoned(damage) | StatusKind::Bleeding(damage) | StatusKind::Burning(damage) => Some(damage),
+            _ => None,
+        }
+    }
+
+    fn hurt_message(&self, name: &str, damage: i32) -> String {
+        match self {
+            StatusKind::Bleeding(_) => format!("{} bleeds for {} damage!", name, damage),
+            StatusKind::Burning(_) => format!("{} burns for {} damage!", name, damage),
+            _ => format!("{} takes {} poison damage!", name, damage),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StatusEffect {
+    kind: StatusKind,
+    turns_left: i32,
+    inflicted_by: Option<EntityId>, // Who gets the credit if the effect kills
+}
+
+// A lasting effect a blow can leave behind, from a monster's bite or a weapon's edge
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+struct Affliction {
+    status: StatusKind,
+    turns: i32,
+    chance: f64,
+}
+
+// What eating a monster's corpse does, the risk is rolled like a bite
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub struct Carcass {
+    heals: i32,
+    #[serde(default)]
+    risk: Option<Affliction>,
+}
+
+// A* Node structure for pathfinding
+#[derive(Clone, Eq, PartialEq)]
+struct Node {
+    position: (i32, i32),
+    g_cost: i32,
+    f_cost: i32,
+}
+
+impl Ord for Node {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other.f_cost.cmp(&self.f_cost)  // Reverse for min-heap
+            .then_with(|| self.g_cost.cmp(&other.g_cost)) // Prefer nodes closer to the goal on ties
+            .then_with(|| self.position.cmp(&other.position))
+    }
+}
+
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn manhattan_distance(a: (i32, i32), b: (i32, i32)) -> i32 {
+    (a.0 - b.0).abs() + (a.1 - b.1).abs()
+}
+
+// FNV-1a, unlike the std hasher its output doesn't change between Rust releases
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+// Enums and items are fed in as their Debug text, so they don't all need to derive Hash
+impl std::fmt::Write for StableHasher {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        Hasher::write(self, s.as_bytes());
+        Ok(())
+    }
+}
+
+// Path costs are in tenths of a step, a diagonal step is about 1.4 straight ones
+const STRAIGHT_STEP_COST: i32 = 10;
+const DIAGONAL_STEP_COST: i32 = 14;
+
+const ORTHOGONAL_DIRECTIONS: [(i32, i32); 4] = [(0, 1), (1, 0), (0, -1), (-1, 0)];
+const ALL_DIRECTIONS: [(i32, i32); 8] = [(0, 1), (1, 0), (0, -1), (-1, 0), (1, 1), (1, -1), (-1, 1), (-1, -1)];
+
+// The cheapest walk between two tiles on an open floor when diagonal steps are allowed
+fn octile_distance(a: (i32, i32), b: (i32, i32)) -> i32 {
+    let (dx, dy) = ((a.0 - b.0).abs(), (a.1 - b.1).abs());
+    STRAIGHT_STEP_COST * dx.max(dy) + (DIAGONAL_STEP_COST - STRAIGHT_STEP_COST) * dx.min(dy)
+}
+
+// Tiles on the Bresenham line from one tile to another, excluding the start
+fn bresenham_line(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+    let (step_x, step_y) = ((to.0 - from.0).signum(), (to.1 - from.1).signum());
+    let (mut x, mut y) = from;
+    let mut err = dx + dy;
+    let mut line = Vec::new();
+
+    while (x, y) != to {
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += step_x;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += step_y;
+        }
+        line.push((x, y));
+    }
+    line
+}
+
+// How clever monsters are, chosen before a run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AiDifficulty {
+    Basic,    // Chase whatever is in perception range
+    Standard, // Needs line of sight, remembers where the player was
+    Cunning,  // Also flanks, drinks potions and falls back to allies
+}
+
+impl AiDifficulty {
+    fn name(&self) -> &'static str {
+        match self {
+            AiDifficulty::Basic => "Basic",
+            AiDifficulty::Standard => "Standard",
+            AiDifficulty::Cunning => "Cunning",
+        }
+    }
+
+    // Smarter monsters also come in larger numbers
+    fn threat_scale(&self) -> f32 {
+        match self {
+            AiDifficulty::Basic => 0.8,
+            AiDifficulty::Standard => 1.0,
+            AiDifficulty::Cunning => 1.2,
+        }
+    }
+
+    // Levels the player can outgrow a monster by before killing it is worth less XP, and how much
+    // less for each level beyond that. Harder runs are stricter about farming the early floors
+    fn xp_grace_levels(&self) -> i32 {
+        match self {
+            AiDifficulty::Basic => 3,
+            AiDifficulty::Standard => 2,
+            AiDifficulty::Cunning => 1,
+        }
+    }
+
+    fn xp_falloff(&self) -> f32 {
+        match self {
+            AiDifficulty::Basic => 0.75,
+            AiDifficulty::Standard => 0.6,
+            AiDifficulty::Cunning => 0.5,
+        }
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            AiDifficulty::Basic => AiDifficulty::Standard,
+            AiDifficulty::Standard => AiDifficulty::Cunning,
+            AiDifficulty::Cunning => AiDifficulty::Basic,
+        }
+    }
+}
+
+// How much the game warns about the dangers of monsters as they come into view
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+enum HintMode {
+    Full,      // The first time each kind shows up in every run
+    #[default]
+    FirstTime, // Only kinds never met in any run before
+    Off,
+}
+
+impl HintMode {
+    fn name(&self) -> &'static str {
+        match self {
+            HintMode::Full => "Full",
+            HintMode::FirstTime => "First time only",
+            HintMode::Off => "Off",
+        }
+    }
+
+    fn next(&self) -> Self {
+        match self {
+            HintMode::Full => HintMode::FirstTime,
+            HintMode::FirstTime => HintMode::Off,
+            HintMode::Off => HintMode::Full,
+        }
+    }
+}
+
+// Whose side a monster is on. Bought-off monsters keep out of the fight until attacked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Faction {
+    Hostile,
+    Neutral,
+}
+
+// Where a monster's head is at, which decides the goals it picks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AiState {
+    Asleep,
+    Wandering,
+    Hunting,
+    Fleeing,
+}
+
+// What a monster is trying to do this turn
+enum MonsterGoal {
+    Idle,
+    Chase,
+    MoveTo((i32, i32)),
+    Flee,
+    Wander,
+}
+
+// The world as monster AI sees it when choosing a goal
+struct AiContext<'a> {
+    map: &'a Map,
+    player: (i32, i32),
+    monsters: &'a [(i32, i32)],
+    potions: &'a [(i32, i32)],
+    light_radius: f32, // Only transitions within sight are logged
+    sneaking: bool, // A sneaking player is only spotted from the front
+    in_darkness: bool, // The player's light has burned out
+}
+
+// Walking distance from one origin to every nearby tile, so many seekers can share one search
+struct DijkstraMap {
+    level: i32,
+    revision: u32, // Of the map it was flooded over, any change to the ground makes it stale
+    origin: (i32, i32),
+    distances: Vec<Vec<Option<i32>>>,
+    directions: &'static [(i32, i32)],
+}
+
+impl DijkstraMap {
+    const MAX_DISTANCE: i32 = 30 * STRAIGHT_STEP_COST; // Seekers further out than this fall back to A*
+
+    fn new(map: &Map, origin: (i32, i32)) -> Self {
+        use std::cmp::Reverse;
+        use std::collections::BinaryHeap;
+
+        let mut distances: Vec<Vec<Option<i32>>> = vec![vec![None; map.width]; map.height];
+        let mut frontier = BinaryHeap::new();
+        if map.is_walkable(origin.0, origin.1) {
+            distances[origin.1 as usize][origin.0 as usize] = Some(0);
+            frontier.push(Reverse((0, origin)));
+        }
+
+        // Webs and the like cost extra to cross, so the flood goes cheapest first
+        while let Some(Reverse((distance, (x, y)))) = frontier.pop() {
+            if distance >= Self::MAX_DISTANCE || distances[y as usize][x as usize].is_some_and(|known| known < distance) {
+                continue;
+            }
+            for &(dx, dy) in map.directions() {
+                let (nx, ny) = (x + dx, y + dy);
+                if !map.is_walkable(nx, ny) {
+                    continue;
+                }
+                let next = distance + map.step_cost((x, y), (nx, ny));
+                if distances[ny as usize][nx as usize].is_none_or(|known| next < known) {
+                    distances[ny as usize][nx as usize] = Some(next);
+                    frontier.push(Reverse((next, (nx, ny))));
+                }
+            }
+        }
+
+        Self { level: map.level, revision: map.revision, origin, distances, directions: map.directions() }
+    }
+
+    // The neighbouring tile that gets furthest from the origin, for fleeing
+    fn uphill(&self, pos: (i32, i32), is_blocked: impl Fn((i32, i32)) -> bool) -> Option<(i32, i32)> {
+        let current = self.distance(pos)?;
+        self.directions
+            .iter()
+            .map(|(dx, dy)| (pos.0 + dx, pos.1 + dy))
+            .filter_map(|next| self.distance(next).map(|d| (d, next)))
+            .filter(|&(d, next)| d > current && !is_blocked(next))
+            .max_by_key(|&(d, _)| d)
+            .map(|(_, next)| next)
+    }
+
+    fn distance(&self, pos: (i32, i32)) -> Option<i32> {
+        if pos.0 < 0 || pos.1 < 0 {
+            return None;
+        }
+        *self.distances.get(pos.1 as usize)?.get(pos.0 as usize)?
+    }
+
+    // The neighbouring tile that gets closest to the origin, avoiding blocked ones
+    fn downhill(&self, pos: (i32, i32), is_blocked: impl Fn((i32, i32)) -> bool) -> Option<(i32, i32)> {
+        let current = self.distance(pos)?;
+        self.directions
+            .iter()
+            .map(|(dx, dy)| (pos.0 + dx, pos.1 + dy))
+            .filter_map(|next| self.distance(next).map(|d| (d, next)))
+            .filter(|&(d, next)| d < current && (next == self.origin || !is_blocked(next)))
+            .min_by_key(|&(d, _)| d)
+            .map(|(_, next)| next)
+    }
+}
+
+// A handle that keeps pointing at the same creature while vectors are
+// filtered and reordered around it. Never reused, even across floors and runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct EntityId(u32);
+
+impl EntityId {
+    fn next() -> Self {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        EntityId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+// Who or what dealt a blow, kept on the victim so a death can be explained and the kill credited
+#[derive(Clone, Debug, PartialEq)]
+enum DamageSource {
+    Attack(EntityId, String),        // A creature's own blow or shot, and its name at the time
+    Used(EntityId, &'static str),    // Something a creature set off: a thrown weapon, spell, scroll or boulder
+    Affliction(StatusKind, Option<EntityId>), // Poison, bleeding or burns, and whoever inflicted them
+}
+
+impl DamageSource {
+    // How the death screen puts it
+    fn describe(&self) -> String {
+        match self {
+            DamageSource::Attack(_, name) => name.clone(),
+            DamageSource::Used(_, what) => what.to_string(),
+            DamageSource::Affliction(StatusKind::Bleeding(_), _) => "blood loss".to_string(),
+            DamageSource::Affliction(StatusKind::Burning(_), _) => "burns".to_string(),
+            DamageSource::Affliction(_, _) => "poison".to_string(),
+        }
+    }
+
+    // Who gets the credit when this blow kills
+    fn responsible(&self) -> Option<EntityId> {
+        match self {
+            DamageSource::Attack(id, _) | DamageSource::Used(id, _) => Some(*id),
+            DamageSource::Affliction(_, by) => *by,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Entity {
+    id: EntityId,
+    name: String,
+    x: f32,
+    y: f32,
+    symbol: char,
+    color: Color,
+    stats: Stats,
+    is_player: bool,
+    level: i32, // For monsters, how deep they first turn up, weighed against the player's level for XP
+    inventory: Option<Inventory>,
+    elite: bool,
+    material_drops: Vec<MaterialKind>,
+    loot: Vec<(String, u32)>, // Weighted item ids the monster may leave behind
+    corpse: Option<Carcass>,
+    status_effects: Vec<StatusEffect>,
+    last_seen_player: Option<(i32, i32)>, // Where a monster last saw the player, for AI with memory
+    heard_noise: Option<(i32, i32)>, // A noise the monster goes to look into
+    ai_state: AiState,
+    facing: (i32, i32), // Direction of the last step or blow, monsters see best that way
+    ranged: Option<RangedAttack>,
+    aura: Option<Aura>,
+    under_auras: Vec<Aura>, // Auras of nearby monsters, as of the last recompute
+    last_hit_by: Option<DamageSource>, // What hurt this entity most recently, the cause if it died
+    spins_webs: bool,
+    bounty: Option<u32>, // Id of the bounty posted on this monster's head
+    tween: Tween,
+    barks: Option<Barks>,
+    inflicts: Option<Affliction>, // Left on whatever the entity hits, now and then
+    bribable: bool,
+    faction: Faction,
+    burrows: bool,
+    lurks_in_dark: bool,
+}
+
+impl Entity {
+    const WEB_ESCAPE_CHANCE: f64 = 0.4;
+    const MAX_ENTANGLED_TURNS: i32 = 6; // Webs give way on their own eventually
+    const BASE_HIT_CHANCE: f64 = 0.85;
+    const MIN_HIT_CHANCE: f64 = 0.5;
+    const MAX_HIT_CHANCE: f64 = 0.95;
+    const SPEED_HIT_BONUS: f64 = 0.04; // For each doubling of speed over the target
+    const LEVEL_HIT_BONUS: f64 = 0.02; // For each level above the target
+    const CRIT_CHANCE: f64 = 0.05;
+    const CRIT_MULTIPLIER: i32 = 2;
+    const DAMAGE_SPREAD: i32 = 1; // Either way, for blows without a weapon's dice
+    const VISION_CONE_COS: f32 = 0.5; // Sees 60 degrees either side of where it faces
+    const HIRE_PRICE_MULTIPLIER: u32 = 3; // Fighting for the player costs more than staying out of it
+
+    fn new_player(class: CharacterClass) -> Self {
+        let (max_hp, attack, defense, max_mana, perception) = class.starting_stats();
+        Self {
+            id: EntityId::next(),
+            name: "Player".to_string(),
+            x: 5.0,
+            y: 5.0,
+            symbol: '@',
+            color: YELLOW,
+            stats: Stats {
+                hp: max_hp,
+                max_hp,
+                attack,
+                defense,
+                speed: 10.0,
+                last_move: 0.0,
+                perception,
+                xp_value: 0,
+                level_system: Some(LevelSystem::new(class)),
+                mana: max_mana,
+                max_mana,
+            },
+            is_player: true,
+            level: 1,
+            inventory: Some(Inventory::new(20)),
+            elite: false,
+            material_drops: Vec::new(),
+            loot: Vec::new(),
+            corpse: None,
+            status_effects: Vec::new(),
+            last_seen_player: None,
+            heard_noise: None,
+            ai_state: AiState::Wandering,
+            facing: (0, 1),
+            ranged: None,
+            aura: None,
+            under_auras: Vec::new(),
+            last_hit_by: None,
+            spins_webs: false,
+            bounty: None,
+            tween: Tween::new((5.0, 5.0)),
+            barks: None,
+            inflicts: None,
+            bribable: false,
+            faction: Faction::Hostile,
+            burrows: false,
+            lurks_in_dark: false,
+        }
+    }
+
+    fn new_monster(template: &MonsterTemplate, x: f32, y: f32) -> Self {
+        let (r, g, b) = template.color;
+        Self {
+            id: EntityId::next(),
+            name: template.name.clone(),
+            x,
+            y,
+            symbol: template.symbol,
+            color: Color::from_rgba(r, g, b, 255),
+            stats: Stats {
+                hp: template.hp,
+                max_hp: template.hp,
+                attack: template.attack,
+                defense: template.defense,
+                speed: template.speed,
+                last_move: 0.0,
+                perception: template.perception,
+                xp_value: template.xp_value,
+                level_system: None, // Monsters don't level up
+                mana: 0,
+                max_mana: 0,
+            },
+            is_player: false,
+            level: template.min_depth + 1,
+            inventory: None,
+            elite: false,
+            material_drops: template.materials.clone(),
+            loot: template.drops.clone(),
+            corpse: template.corpse,
+            status_effects: Vec::new(),
+            last_seen_player: None,
+            heard_noise: None,
+            ai_state: AiState::Wandering,
+            facing: (0, 1),
+            ranged: template.ranged.clone(),
+            aura: template.aura,
+            under_auras: Vec::new(),
+            last_hit_by: None,
+            spins_webs: template.spins_webs,
+            bounty: None,
+            tween: Tween::new((x, y)),
+            barks: template.barks.clone(),
+            inflicts: template.inflicts,
+            bribable: template.bribable,
+            faction: Faction::Hostile,
+            burrows: template.burrows,
+            lurks_in_dark: template.lurks_in_dark,
+        }
+    }
+
+    // Monsters met below the depth they first appear at are tougher, and worth more for it
+    fn scaled_for_depth(mut self, floors_deeper: i32) -> Self {
+        let floors_deeper = floors_deeper.max(0);
+        self.stats.max_hp += self.stats.max_hp * floors_deeper / 5;
+        self.stats.hp = self.stats.max_hp;
+        self.stats.attack += floors_deeper / 2;
+        self.stats.xp_value += self.stats.xp_value * floors_deeper / 5;
+        self
+    }
+
+    // Elites are tougher and drop better materials
+    fn make_elite(mut self) -> Self {
+        self.elite = true;
+        self.symbol = self.symbol.to_ascii_uppercase();
+        self.color = ORANGE;
+        self.stats.max_hp *= 2;
+        self.stats.hp = self.stats.max_hp;
+        self.stats.attack += 2;
+        self.stats.xp_value *= 2;
+        self.level += 1;
+        self.name = format!("Elite {}", self.name);
+        self
+    }
+
+    // Add method to check if target is within perception range
+    fn can_perceive_target(&self, target_x: f32, target_y: f32) -> bool {
+        let dx = target_x - self.x;
+        let dy = target_y - self.y;
+        let distance = (dx * dx + dy * dy).sqrt();
+        distance <= self.stats.perception
+    }
+
+    // Gold a bribable monster asks to leave the player be, more for the strong, less for the badly hurt
+    fn truce_price(&self) -> u32 {
+        let health = self.stats.hp.max(0) as f32 / self.stats.max_hp.max(1) as f32;
+        (self.stats.xp_value as f32 * (0.5 + 0.5 * health)).round().max(1.0) as u32
+    }
+
+    // How hurt it looks, without giving away exact numbers
+    fn health_state(&self) -> &'static str {
+        let health = self.stats.hp.max(0) as f32 / self.stats.max_hp.max(1) as f32;
+        match health {
+            h if h >= 1.0 => "unhurt",
+            h if h > 0.6 => "lightly wounded",
+            h if h > 0.25 => "badly wounded",
+            _ => "near death",
+        }
+    }
+
+    fn hire_price(&self) -> u32 {
+        self.truce_price() * Self::HIRE_PRICE_MULTIPLIER
+    }
+
+    // Turn towards the tile being stepped onto or struck at
+    fn face_towards(&mut self, target: (i32, i32)) {
+        let (dx, dy) = (target.0 - self.x as i32, target.1 - self.y as i32);
+        if (dx, dy) != (0, 0) {
+            self.facing = (dx.signum(), dy.signum());
+        }
+    }
+
+    // Whether a tile lies in the arc the entity is looking along. Nothing right beside it slips by
+    fn faces(&self, target: (i32, i32)) -> bool {
+        let (dx, dy) = (target.0 as f32 - self.x, target.1 as f32 - self.y);
+        if dx.abs() <= 1.0 && dy.abs() <= 1.0 {
+            return true;
+        }
+        let (fx, fy) = (self.facing.0 as f32, self.facing.1 as f32);
+        (dx * fx + dy * fy) / ((dx * dx + dy * dy).sqrt() * (fx * fx + fy * fy).sqrt()) >= Self::VISION_CONE_COS
+    }
+
+    // Whether the monster spots the player standing on a tile. Burrowers feel footsteps through the rock
+    fn sees(&self, target: (i32, i32), difficulty: AiDifficulty, map: &Map, sneaking: bool) -> bool {
+        let pos = (self.x as i32, self.y as i32);
+        self.can_perceive_target(target.0 as f32, target.1 as f32)
+            && (difficulty == AiDifficulty::Basic || self.burrows || map.has_line_of_sight(pos, target))
+            && (!sneaking || self.faces(target))
+    }
+
+    // Every tile the monster would spot a sneaking player on, none while it sleeps
+    fn watched_tiles(&self, map: &Map, difficulty: AiDifficulty) -> Vec<(i32, i32)> {
+        if self.ai_state == AiState::Asleep {
+            return Vec::new();
+        }
+        let pos = (self.x as i32, self.y as i32);
+        let in_range = if difficulty == AiDifficulty::Basic || self.burrows {
+            map.tiles_within(pos, self.stats.perception)
+        } else {
+            map.field_of_view(pos, self.stats.perception)
+        };
+        in_range.into_iter().filter(|&tile| self.faces(tile)).collect()
+    }
+
+    // Run the AI state machine, then decide what this monster is after this turn
+    fn choose_goal(&mut self, difficulty: AiDifficulty, ctx: &AiContext, messages: &mut Vec<String>) -> MonsterGoal {
+        let pos = (self.x as i32, self.y as i32);
+        // Darkness dwellers find a player without a light anywhere within their senses
+        let hunts_in_dark = ctx.in_darkness && self.lurks_in_dark && manhattan_distance(pos, ctx.player) as f32 <= self.stats.perception;
+        let sees_player = hunts_in_dark || self.sees(ctx.player, difficulty, ctx.map, ctx.sneaking);
+
+        if difficulty != AiDifficulty::Basic {
+            if sees_player {
+                self.last_seen_player = Some(ctx.player);
+            } else if self.last_seen_player == Some(pos) {
+                // Reached the last known spot and the trail has gone cold
+                self.last_seen_player = None;
+            }
+        }
+
+        if self.heard_noise == Some(pos) {
+            self.heard_noise = None;
+        }
+
+        // Being hurt, hearing a noise or having the player close by wakes a sleeper, as does the dark for those at home in it
+        let disturbed = self.stats.hp < self.stats.max_hp
+            || hunts_in_dark
+            || self.heard_noise.is_some()
+            || manhattan_distance(pos, ctx.player) as f32 <= self.stats.perception / 2.0;
+        let next_state = match self.ai_state {
+            AiState::Asleep if !disturbed => AiState::Asleep,
+            AiState::Asleep if sees_player => AiState::Hunting,
+            AiState::Asleep => AiState::Wandering,
+            _ if self.stats.hp * 4 < self.stats.max_hp => AiState::Fleeing,
+            AiState::Fleeing if self.stats.hp * 2 < self.stats.max_hp => AiState::Fleeing,
+            _ if sees_player || self.last_seen_player.is_some() => AiState::Hunting,
+            _ => AiState::Wandering,
+        };
+        let previous = self.ai_state;
+        if let Some(message) = self.set_ai_state(next_state) {
+            if manhattan_distance(pos, ctx.player) as f32 <= ctx.light_radius {
+                messages.push(message);
+                messages.extend(self.bark(previous, next_state));
+            }
+        }
+
+        match self.ai_state {
+            AiState::Asleep => MonsterGoal::Idle,
+            AiState::Fleeing => self.flee_goal(difficulty, ctx),
+            AiState::Hunting | AiState::Wandering => {
+                if difficulty == AiDifficulty::Cunning {
+                    if let Some(goal) = self.cunning_goal(sees_player, ctx) {
+                        return goal;
+                    }
+                }
+
+                match self.last_seen_player {
+                    _ if sees_player => MonsterGoal::Chase,
+                    Some(spot) => MonsterGoal::MoveTo(spot),
+                    None => self.heard_noise.map_or(MonsterGoal::Wander, MonsterGoal::MoveTo),
+                }
+            }
+        }
+    }
+
+    // Change state, describing the transitions worth telling the player about
+    fn set_ai_state(&mut self, state: AiState) -> Option<String> {
+        let previous = std::mem::replace(&mut self.ai_state, state);
+        match (previous, state) {
+            (AiState::Asleep, AiState::Asleep) => None,
+            (AiState::Asleep, _) => Some(format!("The {} wakes up!", self.name)),
+            (AiState::Wandering, AiState::Hunting) => Some(format!("The {} notices you!", self.name)),
+            (AiState::Fleeing, AiState::Fleeing) => None,
+            (_, AiState::Fleeing) => Some(format!("The {} flees!", self.name)),
+            (AiState::Hunting, AiState::Wandering) => Some(format!("The {} loses track of you.", self.name)),
+            _ => None,
+        }
+    }
+
+    fn bark(&self, previous: AiState, next: AiState) -> Option<String> {
+        let barks = self.barks.as_ref()?;
+        let lines = match (previous, next) {
+            (AiState::Fleeing, AiState::Fleeing) => return None,
+            (_, AiState::Fleeing) => &barks.flee,
+            (AiState::Asleep | AiState::Wandering, AiState::Hunting) => &barks.spot,
+            _ => return None,
+        };
+        Barks::pick(&self.name, lines)
+    }
+
+    // Run for the stairs if they lead away from the player, otherwise just away
+    fn flee_goal(&self, difficulty: AiDifficulty, ctx: &AiContext) -> MonsterGoal {
+        let pos = (self.x as i32, self.y as i32);
+        let player_distance = manhattan_distance(pos, ctx.player);
+
+        // Cunning monsters fall back towards their friends first
+        if difficulty == AiDifficulty::Cunning {
+            let ally = ctx.monsters
+                .iter()
+                .filter(|m| **m != pos && manhattan_distance(pos, **m) > 1)
+                .min_by_key(|m| manhattan_distance(pos, **m));
+            if let Some(&ally) = ally {
+                return MonsterGoal::MoveTo(ally);
+            }
+        }
+
+        let stairs = [ctx.map.up_stairs, ctx.map.down_stairs]
+            .into_iter()
+            .flatten()
+            .map(|(x, y)| (x as i32, y as i32))
+            .filter(|s| manhattan_distance(*s, ctx.player) > player_distance)
+            .min_by_key(|s| manhattan_distance(pos, *s));
+        match stairs {
+            Some(stairs) if stairs != pos => MonsterGoal::MoveTo(stairs),
+            _ => MonsterGoal::Flee,
+        }
+    }
+
+    fn cunning_goal(&self, sees_player: bool, ctx: &AiContext) -> Option<MonsterGoal> {
+        let pos = (self.x as i32, self.y as i32);
+        let is_ally = |other: &(i32, i32)| *other != pos;
+
+        // Go for a nearby potion when wounded
+        if self.stats.hp * 2 < self.stats.max_hp {
+            let potion = ctx.potions
+                .iter()
+                .filter(|p| self.can_perceive_target(p.0 as f32, p.1 as f32))
+                .min_by_key(|p| manhattan_distance(pos, **p));
+            if let Some(&potion) = potion {
+                return Some(MonsterGoal::MoveTo(potion));
+            }
+        }
+
+        if !sees_player {
+            return None;
+        }
+
+        // Flank: take the side of the player furthest from allies already in melee
+        let engaged: Vec<(i32, i32)> = ctx.monsters
+            .iter()
+            .copied()
+            .filter(|m| is_ally(m) && manhattan_distance(*m, ctx.player) == 1)
+            .collect();
+        if engaged.is_empty() || manhattan_distance(pos, ctx.player) <= 1 {
+            return None;
+        }
+        [(0, 1), (1, 0), (0, -1), (-1, 0)]
+            .iter()
+            .map(|(dx, dy)| (ctx.player.0 + dx, ctx.player.1 + dy))
+            .filter(|tile| ctx.map.is_walkable(tile.0, tile.1) && !ctx.monsters.contains(tile))
+            .max_by_key(|tile| engaged.iter().map(|m| manhattan_distance(*m, *tile)).min().unwrap_or(0))
+            .map(MonsterGoal::MoveTo)
+    }
+
+    fn attack(&mut self, target: &mut Entity, rng: &mut impl Rng) -> Vec<String> {
+        self.attack_with(target, "hits", rng)
+    }
+
+    // The same blow, with the verb a plain hit reads as
+    fn attack_with(&mut self, target: &mut Entity, verb: &str, rng: &mut impl Rng) -> Vec<String> {
+        if self.under_auras.contains(&Aura::Fear) && rng.gen_bool(Aura::FEAR_MISS_CHANCE) {
+            return vec![format!("{} flinches and misses {}!", self.name, target.name)];
+        }
+        if !rng.gen_bool(self.hit_chance(target)) {
+            return vec![format!("{} misses {}.", self.name, target.name)];
+        }
+
+        let roll = match self.weapon_dice() {
+            Some(dice) => dice.roll(rng),
+            None => rng.gen_range(-Self::DAMAGE_SPREAD..=Self::DAMAGE_SPREAD),
+        };
+        let critical = rng.gen_bool(Self::CRIT_CHANCE);
+        let mut damage = (self.get_total_attack() + roll - target.get_total_defense()).max(1);
+        if critical {
+            damage *= Self::CRIT_MULTIPLIER;
+        }
+        target.stats.hp -= damage;
+        target.last_hit_by = Some(DamageSource::Attack(self.id, self.name.clone()));
+        let mut messages = vec![if critical {
+            format!("{} lands a critical hit on {} for {} damage!", self.name, target.name, damage)
+        } else {
+            format!("{} {} {} for {} damage!", self.name, verb, target.name, damage)
+        }];
+
+        // Gems in the weapon add elemental damage on top, gems in the target's armor resist it
+        for (element, amount) in self.gem_damage() {
+            let dealt = (amount - target.gem_resistance(element)).max(0);
+            if dealt > 0 {
+                target.stats.hp -= dealt;
+                messages.push(format!("The {} burns {} for {} more.", element.name(), target.name, dealt));
+            }
+        }
+
+        // Bites and some weapons leave something lasting behind
+        let weapon_affliction = self.inventory.as_ref().and_then(|inv| inv.weapon_affliction());
+        for affliction in self.inflicts.into_iter().chain(weapon_affliction) {
+            if target.is_alive() && rng.gen_bool(affliction.chance) {
+                if !target.has_status(affliction.status) {
+                    messages.push(format!("{} is {}!", target.name, affliction.status.name()));
+                }
+                target.afflict(affliction.status, affliction.turns, Some(self.id));
+            }
+        }
+
+        if critical && target.is_alive() {
+            messages.extend(self.barks.as_ref().and_then(|barks| Barks::pick(&self.name, &barks.crit)));
+        }
+
+        messages
+    }
+
+    fn level_up(&mut self) {
+        // Increase stats on level up, by how much depends on the class
+        let class = self.stats.level_system.as_ref().map_or(CharacterClass::Warrior, |ls| ls.class);
+        let bonuses = class.level_bonuses();
+        self.stats.max_hp += bonuses.max_hp;
+        self.stats.hp = self.stats.max_hp; // Heal to full on level up
+        self.stats.attack += bonuses.attack;
+        self.stats.defense += bonuses.defense;
+        self.stats.perception += bonuses.perception;
+        self.stats.max_mana += bonuses.max_mana;
+        self.stats.mana = self.stats.max_mana;
+    }
+
+    fn is_alive(&self) -> bool {
+        self.stats.hp > 0
+    }
+
+    fn regenerate_mana(&mut self, turn: u32) {
+        if turn.is_multiple_of(Stats::MANA_REGEN_TURNS) {
+            self.stats.mana = (self.stats.mana + 1).min(self.stats.max_mana);
+        }
+    }
+
+    fn draw_position(&self, now: f32) -> (f32, f32) {
+        self.tween.position(now)
+    }
+
+    // Everything about the entity that affects play, positions go in bit for bit
+    fn hash_state(&self, hasher: &mut StableHasher) {
+        let statuses: Vec<(StatusKind, i32)> = self.status_effects.iter().map(|e| (e.kind, e.turns_left)).collect();
+        let _ = write!(hasher, "{}|{:?}|{:?}|{:?}|{:?}|", self.name, self.ai_state, statuses, self.last_seen_player, self.facing);
+        hasher.write_u32(self.x.to_bits());
+        hasher.write_u32(self.y.to_bits());
+        for stat in [self.stats.hp, self.stats.max_hp, self.stats.attack, self.stats.defense, self.stats.mana] {
+            hasher.write_i32(stat);
+        }
+        if let Some(inventory) = &self.inventory {
+            let _ = write!(hasher, "{:?}|{:?}|", inventory.items, inventory.equipment);
+            hasher.write_u32(inventory.gold);
+        }
+    }
+
+    fn can_move(&self, current_time: f32) -> bool {
+        self.can_move_with_multiplier(current_time, 1.0)
+    }
+
+    // Floor-wide effects can speed an entity up on top of its own status effects
+    fn can_move_with_multiplier(&self, current_time: f32, speed_multiplier: f32) -> bool {
+        current_time - self.stats.last_move >= self.move_interval(speed_multiplier)
+    }
+
+    // Seconds between actions, after haste, frost auras and floor-wide speed effects
+    fn move_interval(&self, speed_multiplier: f32) -> f32 {
+        let mut speed = if self.has_status(StatusKind::Hasted) {
+            self.stats.speed * 2.0
+        } else {
+            self.stats.speed
+        };
+        if self.under_auras.contains(&Aura::Frost) {
+            speed /= 2.0;
+        }
+        1.0 / (speed * speed_multiplier)
+    }
+
+    // Re-applying an effect the entity already has refreshes its duration
+    fn add_status(&mut self, kind: StatusKind, turns: i32) {
+        let same_kind = |e: &&mut StatusEffect| std::mem::discriminant(&e.kind) == std::mem::discriminant(&kind);
+        if let Some(effect) = self.status_effects.iter_mut().find(same_kind) {
+            effect.kind = kind;
+            effect.turns_left = effect.turns_left.max(turns);
+        } else {
+            self.status_effects.push(StatusEffect { kind, turns_left: turns, inflicted_by: None });
+        }
+    }
+
+    // A harmful effect that remembers who caused it, for the kill credit
+    fn afflict(&mut self, kind: StatusKind, turns: i32, by: Option<EntityId>) {
+        self.add_status(kind, turns);
+        if let Some(effect) = self.status_effects.iter_mut().find(|e| e.kind == kind) {
+            effect.inflicted_by = by;
+        }
+    }
+
+    // Returns whether there was anything to cure
+    fn cure(&mut self, kind: StatusKind) -> bool {
+        let had = self.has_status(kind);
+        self.status_effects.retain(|e| std::mem::discriminant(&e.kind) != std::mem::discriminant(&kind));
+        had
+    }
+
+    fn has_status(&self, kind: StatusKind) -> bool {
+        self.status_effects
+            .iter()
+            .any(|e| std::mem::discriminant(&e.kind) == std::mem::discriminant(&kind))
+    }
+
+    // Stepping into a web catches anything that doesn't spin them
+    fn enter_tile(&mut self, map: &Map) -> Option<String> {
+        if map.tile_at(self.x as i32, self.y as i32) == Some(&Tile::Water) && self.cure(StatusKind::Burning(0)) {
+            return Some(format!("{} douses the flames in the water.", self.name));
+        }
+        if self.spins_webs || !map.has_web(self.x as i32, self.y as i32) {
+            return None;
+        }
+        self.add_status(StatusKind::Entangled, Self::MAX_ENTANGLED_TURNS);
+        Some(format!("{} is caught in a web!", self.name))
+    }
+
+    // An entangled entity spends its turn struggling. Returns whether it broke free,
+    // which tears the web apart
+    fn struggle_free(&mut self, rng: &mut impl Rng) -> bool {
+        if !rng.gen_bool(Self::WEB_ESCAPE_CHANCE) {
+            return false;
+        }
+        self.status_effects.retain(|e| e.kind != StatusKind::Entangled);
+        true
+    }
+
+    // Apply per-turn effects and count down durations, returning log messages
+    fn tick_status_effects(&mut self) -> Vec<String> {
+        let mut messages = Vec::new();
+        let name = &self.name;
+
+        for effect in &mut self.status_effects {
+            if let Some(damage) = effect.kind.damage_per_turn() {
+                self.stats.hp -= damage;
+                self.last_hit_by = Some(DamageSource::Affliction(effect.kind, effect.inflicted_by));
+                messages.push(effect.kind.hurt_message(name, damage));
+            }
+            effect.turns_left -= 1;
+            // The recall announces itself when it takes hold
+            if effect.turns_left <= 0 && effect.kind != StatusKind::Recalling {
+                messages.push(format!("{} is no longer {}.", name, effect.kind.name()));
+            }
+        }
+
+        self.status_effects.retain(|e| e.turns_left > 0);
+        messages
+    }
+
+    fn update_last_move(&mut self, current_time: f32) {
+        self.stats.last_move = current_time;
+    }
+
+    fn with_inventory(mut self, capacity: usize) -> Self {
+        self.inventory = Some(Inventory::new(capacity));
+        self
+    }
+
+    fn get_total_attack(&self) -> i32 {
+        let (weapon_bonus, _) = self.inventory
+            .as_ref()
+            .map(|inv| inv.get_equipment_bonuses())
+            .unwrap_or((0, 0));
+        let command_bonus = if self.under_auras.contains(&Aura::Command) { Aura::COMMAND_BONUS } else { 0 };
+        self.stats.attack + weapon_bonus + command_bonus
+    }
+
+    fn gem_damage(&self) -> Vec<(Element, i32)> {
+        self.inventory.as_ref().map_or(Vec::new(), |inv| inv.gem_damage())
+    }
+
+    fn weapon_dice(&self) -> Option<Dice> {
+        self.inventory.as_ref().and_then(|inv| inv.weapon_dice())
+    }
+
+    fn weapon_kind(&self) -> WeaponKind {
+        self.inventory.as_ref().map_or(WeaponKind::Crushing, |inv| inv.weapon_kind())
+    }
+
+    fn weapon_rank(&self) -> SkillRank {
+        let hits = self.stats.level_system.as_ref().and_then(|ls| ls.weapon_hits.get(&self.weapon_kind()).copied());
+        SkillRank::for_hits(hits.unwrap_or(0))
+    }
+
+    fn combat_level(&self) -> i32 {
+        self.stats.level_system.as_ref().map_or(1, |ls| ls.level)
+    }
+
+    // Faster and more experienced fighters land more of their blows
+    fn hit_chance(&self, target: &Entity) -> f64 {
+        let speed = (self.stats.speed / target.stats.speed.max(0.1)).log2() as f64 * Self::SPEED_HIT_BONUS;
+        let level = (self.combat_level() - target.combat_level()) as f64 * Self::LEVEL_HIT_BONUS;
+        (Self::BASE_HIT_CHANCE + speed + level).clamp(Self::MIN_HIT_CHANCE, Self::MAX_HIT_CHANCE)
+    }
+
+    fn gem_resistance(&self, element: Element) -> i32 {
+        self.inventory.as_ref().map_or(0, |inv| inv.gem_resistance(element))
+    }
+
+    fn get_total_defense(&self) -> i32 {
+        let (_, armor_bonus) = self.inventory
+            .as_ref()
+            .map(|inv| inv.get_equipment_bonuses())
+            .unwrap_or((0, 0));
+        self.stats.defense + armor_bonus
+    }
+}
+
+// Draws map and entity glyphs, from a tileset when one is loaded and as text otherwise.
+// The tileset is a 16x16 grid of cells in code page order, so a glyph's char code is its cell
+struct GlyphRenderer {
+    tileset: Option<Texture2D>,
+}
+
+impl GlyphRenderer {
+    const ATLAS_COLUMNS: u32 = 16;
+    const ATLAS_CELLS: u32 = 256;
+    const DEFAULT_TILESET: &'static str = "data/tileset.png";
+
+    async fn load(config: &GameConfig) -> Self {
+        if config.ascii_renderer {
+            return Self { tileset: None };
+        }
+        let path = config.tileset_path.as_deref().unwrap_or(Self::DEFAULT_TILESET);
+        match load_texture(path).await {
+            Ok(texture) => {
+                texture.set_filter(FilterMode::Nearest);
+                Self { tileset: Some(texture) }
+            }
+            // No tileset ships with the game, so only a missing one that was asked for is worth a word
+            Err(_) => {
+                if config.tileset_path.is_some() {
+                    eprintln!("No tileset at {}, drawing ASCII", path);
+                }
+                Self { tileset: None }
+            }
+        }
+    }
+
+    fn sprite_index(glyph: char) -> Option<u32> {
+        Some(glyph as u32).filter(|&index| index < Self::ATLAS_CELLS)
+    }
+
+    // Draw one glyph filling the tile whose top left corner is at the given screen position.
+    // Sprites share one texture, so macroquad batches them into a few draw calls
+    fn draw(&self, glyph: char, screen_x: f32, screen_y: f32, tile_size: f32, color: Color) {
+        let (Some(tileset), Some(index)) = (self.tileset.as_ref(), Self::sprite_index(glyph)) else {
+            draw_text(&glyph.to_string(), screen_x, screen_y + tile_size, tile_size, color);
+            return;
+        };
+        let cell_width = tileset.width() / Self::ATLAS_COLUMNS as f32;
+        let cell_height = tileset.height() / (Self::ATLAS_CELLS / Self::ATLAS_COLUMNS) as f32;
+        let source = Rect::new(
+            (index % Self::ATLAS_COLUMNS) as f32 * cell_width,
+            (index / Self::ATLAS_COLUMNS) as f32 * cell_height,
+            cell_width,
+            cell_height,
+        );
+        draw_texture_ex(*tileset, screen_x, screen_y, color, DrawTextureParams {
+            dest_size: Some(vec2(tile_size, tile_size)),
+            source: Some(source),
+            ..Default::default()
+        });
+    }
+}
+
+struct Camera {
+    x: f32,
+    y: f32,
+    viewport_width: usize,
+    viewport_height: usize,
+    shake_offset: (f32, f32), // Screen-space jitter, set by VisualEffects
+}
+
+impl Camera {
+    fn new(viewport_width: usize, viewport_height: usize) -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            viewport_width,
+            viewport_height,
+            shake_offset: (0.0, 0.0),
+        }
+    }
+
+
+    fn follow(&mut self, target_x: f32, target_y: f32, map_width: usize, map_height: usize) {
+        // Center the camera on the target
+        self.x = target_x - self.viewport_width as f32 / 2.0;
+        self.y = target_y - self.viewport_height as f32 / 2.0;
+
+        // Calculate the maximum camera positions
+        let max_x = (map_width as f32).max(self.viewport_width as f32) - self.viewport_width as f32;
+        let max_y = (map_height as f32).max(self.viewport_height as f32) - self.viewport_height as f32;
+
+        // Clamp camera position to map bounds
+        self.x = self.x.clamp(0.0, max_x.max(0.0));
+        self.y = self.y.clamp(0.0, max_y.max(0.0));
+    }
+
+    fn world_to_screen(&self, world_x: f32, world_y: f32, tile_size: f32) -> (f32, f32) {
+        (
+            (world_x - self.x) * tile_size + self.shake_offset.0,
+            (world_y - self.y) * tile_size + TOP_BAR_HEIGHT + self.shake_offset.1
+        )
+    }
+
+    fn screen_to_world(&self, screen_x: f32, screen_y: f32, tile_size: f32) -> (i32, i32) {
+        (
+            ((screen_x - self.shake_offset.0) / tile_size + self.x).floor() as i32,
+            ((screen_y - TOP_BAR_HEIGHT - self.shake_offset.1) / tile_size + self.y).floor() as i32
+        )
+    }
+
+    fn is_visible(&self, world_x: f32, world_y: f32) -> bool {
+        world_x >= self.x && world_x < self.x + self.viewport_width as f32 &&
+            world_y >= self.y && world_y < self.y + self.viewport_height as f32
+    }
+}
+
+struct Map {
+    width: usize,
+    height: usize,
+    tiles: Vec<Vec<Tile>>,
+    rooms: Vec<Vec<Room>>,
+    level: i32,
+    up_stairs: Option<(usize, usize)>,
+    down_stairs: Option<(usize, usize)>,
+    modifiers: Vec<Modifier>,
+    seed: u64,
+    treasure_room: Option<Room>, // Behind locked doors, its key lies elsewhere on the floor
+    shop: Option<Shop>,
+    shrine: Option<Shrine>,
+    terrain_overlays: HashMap<(i32, i32), TerrainOverlay>,
+    boulders: HashSet<(i32, i32)>,
+    claimed_rooms: Vec<Room>, // Taken by a lair or cellar, on top of the features tracked above
+    bounty_board: Option<(i32, i32)>, // Only on the first floor
+    diagonal_movement: bool, // Whether paths may take diagonal steps
+    cave: bool, // Rough rock walls, seamed with ore veins
+    forge: Option<(i32, i32)>, // Where gems are set into equipment, only in caves
+    ore_veins: HashMap<(i32, i32), OreVein>, // Keyed by the wall tile the vein runs through
+    room_purposes: BTreeMap<Room, RoomPurpose>, // Rooms left out are plain, ordered so seeded runs repeat
+    decorations: HashMap<(i32, i32), Decoration>,
+    vaults: Vec<Room>, // Laid out in mirror image around a centerpiece
+    prefab_spawns: Vec<((i32, i32), PrefabSpawn)>, // What a stamped prefab room calls for, placed with the monsters
+    revision: u32, // Counts changes to the ground during play, so cached paths know to start over
+    branch_stairs: Option<(usize, usize)>, // Down into the side branch entered from this floor
+    palette: Option<Palette>, // Only branch floors have colors of their own
+    secret_doors: HashSet<(i32, i32)>, // Wall tiles until the player finds them
+    secret_rooms: Vec<Room>, // Behind the secret doors, left out of the rooms monsters spawn in
+    features: HashMap<(i32, i32), Feature>, // Placed with the monsters, in plain side rooms
+    explored: HashSet<(i32, i32)>, // Tiles the player's light has fallen on, for auto-explore
+    healer: Option<(i32, i32)>, // Only in the town, like the stash
+    stash: Option<Stash>,
+    residents: Vec<Resident>, // The town's shopkeeper and healer, keeping the shop and healer spots above in step
+    portal: Option<(i32, i32)>, // This floor's end of an open town portal
+}
+
+impl Map {
+    const VAULT_PILLAR_CHANCE: f64 = 0.6;
+    const SECRET_ROOM_CHANCE: f64 = 0.5;
+    const SECRET_ROOM_SALT: u64 = 0xc2b2_ae35; // Keeps the hidden room apart from the rest of the layout
+    const TOWN_WIDTH: i32 = 29;
+    const TOWN_HEIGHT: i32 = 15;
+    const TOWN_BUILDING_SIZE: (i32, i32) = (5, 4);
+
+    fn new(width: usize, height: usize, level: i32, stairs_up_pos: Option<(usize, usize)>, run_seed: u64) -> Self {
+        // Offset the run seed by the level for consistent but different layouts per level
+        let seed = run_seed.wrapping_add(level as u64);
+        Self::generated(width, height, level, stairs_up_pos, seed, Self::generator_for(level, seed))
+    }
+
+    // A floor of a side branch, seeded apart from the main floor at the same depth and laid out
+    // the branch's way. Its deepest floor has no stairs further down
+    fn new_branch(width: usize, height: usize, level: i32, stairs_up_pos: Option<(usize, usize)>, run_seed: u64, branch: &BranchTemplate) -> Self {
+        let seed = run_seed.wrapping_add(level as u64) ^ BranchRegistry::SEED_SALT.wrapping_add(branch.entry_floor as u64);
+        let mut map = Self::generated(width, height, level, stairs_up_pos, seed, branch.layout.generator());
+        map.palette = Some(branch.palette);
+        if level >= branch.deepest_floor() {
+            if let Some((x, y)) = map.down_stairs.take() {
+                map.tiles[y][x] = Tile::Floor;
+            }
+        }
+        map
+    }
+
+    fn generated(width: usize, height: usize, level: i32, stairs_up_pos: Option<(usize, usize)>, seed: u64, generator: Box<dyn MapGenerator>) -> Self {
+        let mut map = Self::blank(width, height, level, stairs_up_pos, seed);
+        map.cave = generator.is_cave();
+        let rng = StdRng::seed_from_u64(map.seed);
+        map.generate_dungeon_with_stairs_seeded(generator.as_ref(), rng);
+        map
+    }
+
+    // The town above the dungeon: an open square around the stairs down with the stash chest at its
+    // top, the shop and the healer's in buildings above it and the houses their keepers sleep in below.
+    // The shop is stocked once the player first climbs out
+    fn town(width: usize, height: usize, seed: u64) -> Self {
+        let mut map = Self::blank(width, height, FloorId::TOWN.level, None, seed);
+        let (square_width, square_height) = ((width as i32 - 4).min(Self::TOWN_WIDTH), (height as i32 - 4).min(Self::TOWN_HEIGHT));
+        let square = Room::new((width as i32 - square_width) / 2, (height as i32 - square_height) / 2, square_width, square_height);
+        map.create_room(&square);
+        for x in square.x..square.x + square.width {
+            map.tiles[(square.y + square.height - 1) as usize][x as usize] = Tile::Grass;
+        }
+
+        let (cx, cy) = square.center();
+        map.tiles[cy as usize][cx as usize] = Tile::StairsDown;
+        map.down_stairs = Some((cx as usize, cy as usize));
+        map.stash = Some(Stash { position: (cx, square.y + 1), items: Vec::new() });
+
+        // Without room for a building its keeper works and sleeps out on the square
+        let spacing = square.width / 4;
+        let mut rooms = vec![square.clone()];
+        let mut residents = Vec::new();
+        for (kind, x) in [(Townsfolk::Shopkeeper, cx - spacing), (Townsfolk::Healer, cx + spacing)] {
+            let workplace = map.add_building((x, square.y - 1), -1);
+            let house = map.add_building((x, square.y + square.height), 1);
+            let post = workplace.as_ref().map_or((x, square.y + 1), |room| room.center());
+            let home = house.as_ref().map_or(post, |room| room.center());
+            rooms.extend(workplace.into_iter().chain(house));
+            residents.push(Resident { kind, position: post, post, home, stroll_to: None });
+        }
+        map.shop = Some(Shop { keeper: residents[0].post, stock: Vec::new() });
+        map.healer = Some(residents[1].post);
+        map.residents = residents;
+        map.rooms = vec![rooms];
+        map
+    }
+
+    // A small building through a door in the wall of the town square, on the side dy points to
+    fn add_building(&mut self, door: (i32, i32), dy: i32) -> Option<Room> {
+        let (width, height) = Self::TOWN_BUILDING_SIZE;
+        let top = if dy < 0 { door.1 - height } else { door.1 + 1 };
+        let room = Room::new(door.0 - width / 2, top, width, height);
+        let fits = room.x >= 1 && room.y >= 1 && room.x + room.width < self.width as i32 && room.y + room.height < self.height as i32;
+        if !fits {
+            return None;
+        }
+        self.create_room(&room);
+        self.tiles[door.1 as usize][door.0 as usize] = Tile::DoorOpen;
+        Some(room)
+    }
+
+    fn blank(width: usize, height: usize, level: i32, stairs_up_pos: Option<(usize, usize)>, seed: u64) -> Self {
+        Map {
+            width,
+            height,
+            tiles: vec![vec![Tile::Wall; width]; height],
+            rooms: Vec::new(),
+            level,
+            up_stairs: stairs_up_pos,
+            down_stairs: None,
+            modifiers: Vec::new(),
+            seed,
+            treasure_room: None,
+            shop: None,
+            shrine: None,
+            terrain_overlays: HashMap::new(),
+            boulders: HashSet::new(),
+            claimed_rooms: Vec::new(),
+            bounty_board: None,
+            diagonal_movement: true,
+            cave: false,
+            forge: None,
+            ore_veins: HashMap::new(),
+            room_purposes: BTreeMap::new(),
+            decorations: HashMap::new(),
+            vaults: Vec::new(),
+            prefab_spawns: Vec::new(),
+            revision: 0,
+            branch_stairs: None,
+            palette: None,
+            secret_doors: HashSet::new(),
+            secret_rooms: Vec::new(),
+            features: HashMap::new(),
+            explored: HashSet::new(),
+            healer: None,
+            stash: None,
+            residents: Vec::new(),
+            portal: None,
+        }
+    }
+
+    // The first floor is always built rooms and the last has rooms too, its last one is the boss's lair.
+    // In between one floor in three is a cave, and the rest get stranger the deeper they are
+    fn generator_for(level: i32, seed: u64) -> Box<dyn MapGenerator> {
+        match (level, seed % 3) {
+            (0, _) => Box::new(RoomsAndCorridors),
+            (FINAL_FLOOR, _) => Box::new(BspRooms),
+            (_, 0) => Box::new(CellularCaves),
+            (1..=3, 1) => Box::new(RoomsAndCorridors),
+            (1..=3, _) | (4..=6, 1) | (7.., 2) => Box::new(BspRooms),
+            _ => Box::new(MazeTunnels),
+        }
+    }
+
+    fn generate_dungeon_with_stairs_seeded(&mut self, generator: &dyn MapGenerator, mut rng: StdRng) {
+        self.tiles = vec![vec![Tile::Wall; self.width]; self.height];
+        self.rooms.clear();
+        let mut rooms = generator.carve(self, &mut rng);
+
+        // The stairs down from the floor above come out here, so they get a room of their own joined to the rest
+        if let Some((x, y)) = self.up_stairs {
+            let arrival = Room::new(x as i32 - 2, y as i32 - 2, 5, 5);
+            rooms.retain(|room| !room.intersects(&arrival));
+            self.create_room(&arrival);
+            if let Some(nearest) = rooms.iter().min_by_key(|room| manhattan_distance(room.center(), arrival.center())) {
+                self.connect_rooms(arrival.center(), nearest.center(), &mut rng);
+            }
+            rooms.insert(0, arrival);
+        }
+        self.rooms = vec![rooms];
+
+        // Place stairs, on the first floor the up stairs are the way out of the dungeon
+        if let Some((x, y)) = self.up_stairs {
+            self.tiles[y][x] = Tile::StairsUp;
+        } else if let Some(first_row) = self.rooms.first() {
+            if let Some(first_room) = first_row.first() {
+                let (x, y) = first_room.center();
+                let (x, y) = (x as usize, y as usize);
+                self.tiles[y][x] = Tile::StairsUp;
+                self.up_stairs = Some((x, y));
+            }
+        }
+
+        if self.level < FINAL_FLOOR {
+            if let Some(last_row) = self.rooms.last() {
+                if let Some(last_room) = last_row.last() {
+                    let (x, y) = last_room.center();
+                    let (x, y) = (x as usize, y as usize);
+                    self.tiles[y][x] = Tile::StairsDown;
+                    self.down_stairs = Some((x, y));
+                }
+            }
+        }
+
+        self.place_doors(&mut rng);
+        if rng.gen_bool(0.5) {
+            self.lock_treasure_room(&mut rng);
+        }
+        self.hide_secret_room();
+        self.tag_rooms(&mut rng);
+        self.carve_vaults(&mut rng);
+        self.place_terrain(&mut rng);
+        self.decorate_rooms();
+    }
+
+    // Shrine rooms and the boss's lair on the final floor are built as vaults
+    fn carve_vaults(&mut self, rng: &mut impl Rng) {
+        let mut vaults: Vec<Room> = self.room_purposes
+            .iter()
+            .filter(|(_, purpose)| **purpose == RoomPurpose::Shrine)
+            .map(|(room, _)| room.clone())
+            .collect();
+        if self.level == FINAL_FLOOR {
+            vaults.extend(self.rooms.iter().flatten().last().cloned());
+        }
+        // Purposes are kept in a hash map, so put the rooms back in a fixed order for the seeded rng
+        vaults.sort_by_key(|room| (room.y, room.x));
+        vaults.dedup();
+
+        for room in &vaults {
+            self.carve_vault(room, rng);
+        }
+        self.vaults = vaults;
+    }
+
+    // Pillars scattered over one quarter of the room and mirrored into the other three.
+    // A cross through the center and a ring inside the room's edge stay clear, and no two
+    // pillars touch, so nothing in the room gets walled off
+    fn carve_vault(&mut self, room: &Room, rng: &mut impl Rng) {
+        let (cx, cy) = room.center();
+        let reach_x = (cx - room.x - 1).min(room.x + room.width - 2 - cx);
+        let reach_y = (cy - room.y - 1).min(room.y + room.height - 2 - cy);
+        for dy in (1..=reach_y).step_by(2) {
+            for dx in (1..=reach_x).step_by(2) {
+                if !rng.gen_bool(Self::VAULT_PILLAR_CHANCE) {
+                    continue;
+                }
+                for (sx, sy) in [(1, 1), (-1, 1), (1, -1), (-1, -1)] {
+                    self.tiles[(cy + dy * sy) as usize][(cx + dx * sx) as usize] = Tile::Wall;
+                }
+            }
+        }
+    }
+
+    // The stairs down into a side branch, in the middle of a side room given over to them
+    fn place_branch_stairs(&mut self) {
+        let mut rng = StdRng::seed_from_u64(self.seed ^ BranchRegistry::SEED_SALT);
+        let start = self.up_stairs.map(|(x, y)| (x as i32, y as i32)).unwrap_or_default();
+        let reachable = self.reachable_from(start);
+        let mut rooms = self.side_rooms();
+        rooms.retain(|room| !self.vaults.contains(room) && reachable.contains(&room.center()));
+        let Some(room) = rooms.choose(&mut rng).cloned() else {
+            return;
+        };
+        let (x, y) = room.center();
+        self.tiles[y as usize][x as usize] = Tile::StairsDown;
+        self.terrain_overlays.remove(&(x, y));
+        self.decorations.remove(&(x, y));
+        self.branch_stairs = Some((x as usize, y as usize));
+        self.room_purposes.remove(&room);
+        self.claimed_rooms.push(room);
+    }
+
+    // Now and then a side room is rebuilt from a hand-drawn prefab, turned and mirrored at random.
+    // Each try that would wall off part of the floor is taken back out before the next. Returns the
+    // name of a prefab that fit in no room
+    fn place_prefab<'a>(&mut self, prefabs: &'a PrefabRegistry) -> Option<&'a str> {
+        let mut rng = StdRng::seed_from_u64(self.seed ^ PrefabRegistry::SEED_SALT);
+        if !rng.gen_bool(PrefabRegistry::FLOOR_CHANCE) {
+            return None;
+        }
+        let template = prefabs.for_depth(self.level).choose(&mut rng).copied()?;
+        let mut rooms = self.side_rooms();
+        rooms.retain(|room| !self.vaults.contains(room));
+        rooms.shuffle(&mut rng);
+        let mut orientations: Vec<(usize, bool)> = (0..4).flat_map(|turns| [(turns, false), (turns, true)]).collect();
+        orientations.shuffle(&mut rng);
+
+        let start = self.up_stairs.map(|(x, y)| (x as i32, y as i32)).unwrap_or_default();
+        let reachable = self.reachable_from(start);
+        for room in &rooms {
+            for &(turns, mirrored) in &orientations {
+                let grid = template.oriented(turns, mirrored);
+                let (width, height) = (grid.first().map_or(0, Vec::len) as i32, grid.len() as i32);
+                if width > room.width - 2 || height > room.height - 2 {
+                    continue;
+                }
+                let left = room.x + 1 + (room.width - 2 - width) / 2;
+                let top = room.y + 1 + (room.height - 2 - height) / 2;
+                let area = Room::new(left, top, width, height);
+                let saved = self.tiles.clone();
+                let spawns = self.stamp_prefab(template, &grid, (left, top));
+
+                let still_reachable = self.reachable_from(start);
+                let cut_off = reachable.iter().any(|&(x, y)| !area.contains(x, y) && !still_reachable.contains(&(x, y)));
+                if cut_off || spawns.iter().any(|(tile, _)| !still_reachable.contains(tile)) {
+                    self.tiles = saved;
+                    continue;
+                }
+
+                self.decorations.retain(|&(x, y), _| !room.contains(x, y));
+                for (tile, spawn) in spawns {
+                    match spawn {
+                        PrefabSpawn::Decoration(decoration) => {
+                            self.decorations.insert(tile, decoration);
+                        }
+                        spawn => self.prefab_spawns.push((tile, spawn)),
+                    }
+                }
+                self.room_purposes.remove(room);
+                self.claimed_rooms.push(room.clone());
+                return None;
+            }
+        }
+        Some(&template.name)
+    }
+
+    // Lay the prefab's tiles down with its top left corner at the given tile. Returns what the legend puts where
+    fn stamp_prefab(&mut self, template: &PrefabTemplate, grid: &[Vec<char>], (left, top): (i32, i32)) -> Vec<((i32, i32), PrefabSpawn)> {
+        let mut spawns = Vec::new();
+        for (dy, row) in grid.iter().enumerate() {
+            for (dx, &symbol) in row.iter().enumerate() {
+                let (x, y) = (left + dx as i32, top + dy as i32);
+                let tile = match symbol {
+                    ' ' => continue,
+                    '#' => Tile::Wall,
+                    '~' => Tile::Water,
+                    '"' => Tile::Grass,
+                    ':' => Tile::Chasm,
+                    '+' => Tile::DoorClosed,
+                    '.' => Tile::Floor,
+                    _ => {
+                        spawns.extend(template.legend.get(&symbol).cloned().map(|spawn| ((x, y), spawn)));
+                        Tile::Floor
+                    }
+                };
+                self.tiles[y as usize][x as usize] = tile;
+            }
+        }
+        spawns
+    }
+
+    // A random spot in the room that can be stood on, around any pillars
+    fn open_position(&self, room: &Room, rng: &mut impl Rng) -> (i32, i32) {
+        self.free_position(room, &HashSet::new(), rng).unwrap_or_else(|| room.center())
+    }
+
+    // An open tile in the room that isn't one of the taken ones, if a few tries turn one up
+    fn free_position(&self, room: &Room, taken: &HashSet<(i32, i32)>, rng: &mut impl Rng) -> Option<(i32, i32)> {
+        (0..20)
+            .map(|_| room.random_position(rng))
+            .find(|&(x, y)| self.is_walkable(x, y) && !taken.contains(&(x, y)))
+    }
+
+    // Give about half the side rooms a purpose, which decides what is found in them
+    fn tag_rooms(&mut self, rng: &mut impl Rng) {
+        self.room_purposes.clear();
+        let rooms: Vec<Room> = self.rooms.iter().flatten().skip(1).cloned().collect();
+        for room in rooms {
+            if self.treasure_room.as_ref() == Some(&room) || !rng.gen_bool(RoomPurpose::TAG_CHANCE) {
+                continue;
+            }
+            if let Some(&purpose) = RoomPurpose::THEMED.choose(rng) {
+                self.room_purposes.insert(room, purpose);
+            }
+        }
+    }
+
+    fn room_purpose(&self, room: &Room) -> RoomPurpose {
+        self.room_purposes.get(room).copied().unwrap_or(RoomPurpose::Plain)
+    }
+
+    // Pools of water and patches of grass, and flooded rooms mostly under water
+    fn place_terrain(&mut self, rng: &mut impl Rng) {
+        let rooms: Vec<Room> = self.rooms.iter().flatten().skip(1).cloned().collect();
+        for room in &rooms {
+            // Room centers hold stairs, shopkeepers and shrines, so they stay bare
+            let tiles: Vec<(i32, i32)> = room.inner_tiles().into_iter().filter(|&t| t != room.center()).collect();
+            if self.vaults.contains(room) {
+                continue; // Patches would spoil the symmetry
+            }
+            if self.room_purpose(room) == RoomPurpose::Flooded {
+                self.grow_patch(&tiles, Tile::Water, tiles.len() * 2 / 3, rng);
+                continue;
+            }
+            for (tile, chance) in [(Tile::Water, 0.25), (Tile::Grass, 0.25)] {
+                if rng.gen_bool(chance) {
+                    self.grow_patch(&tiles, tile, rng.gen_range(4..=9), rng);
+                }
+            }
+        }
+    }
+
+    // Furnishings that show what a room is for. They are only drawn, nothing trips over them
+    fn decorate_rooms(&mut self) {
+        self.decorations.clear();
+        let tagged: Vec<(Room, RoomPurpose)> = self.room_purposes.iter().map(|(r, p)| (r.clone(), *p)).collect();
+        for (room, purpose) in tagged {
+            let (left, right) = (room.x + 1, room.x + room.width - 2);
+            let (top, bottom) = (room.y + 1, room.y + room.height - 2);
+            let spots: Vec<((i32, i32), Decoration)> = match purpose {
+                // Bedrolls in a line along the top of the room
+                RoomPurpose::Barracks => (left..=right).step_by(2).map(|x| ((x, top), Decoration::Bedroll)).collect(),
+                RoomPurpose::Storeroom => [(left, top), (right, top), (left, bottom), (right, bottom)]
+                    .into_iter()
+                    .map(|p| (p, Decoration::Crate))
+                    .collect(),
+                RoomPurpose::Shrine => [(left, top), (right, top), (left, bottom), (right, bottom)]
+                    .into_iter()
+                    .map(|p| (p, Decoration::Candle))
+                    .collect(),
+                // Shelves stand in the walls above and below the room
+                RoomPurpose::Library => (room.x..room.x + room.width)
+                    .flat_map(|x| [((x, room.y - 1), Decoration::Bookshelf), ((x, room.y + room.height), Decoration::Bookshelf)])
+                    .filter(|&((x, y), _)| self.tile_at(x, y) == Some(&Tile::Wall))
+                    .collect(),
+                RoomPurpose::Flooded | RoomPurpose::Plain => Vec::new(),
+            };
+            for (position, decoration) in spots {
+                let on_floor = self.tile_at(position.0, position.1) == Some(&Tile::Floor);
+                if decoration == Decoration::Bookshelf || on_floor {
+                    self.decorations.insert(position, decoration);
+                }
+            }
+        }
+    }
+
+    // Spread a tile outwards from a random spot over plain floor within the given tiles
+    fn grow_patch(&mut self, tiles: &[(i32, i32)], tile: Tile, size: usize, rng: &mut impl Rng) {
+        let Some(&start) = tiles.choose(rng) else {
+            return;
+        };
+        let mut patch = vec![start];
+        while patch.len() < size {
+            let &(x, y) = patch.choose(rng).unwrap();
+            let (dx, dy) = *[(0, 1), (1, 0), (0, -1), (-1, 0)].choose(rng).unwrap();
+            let next = (x + dx, y + dy);
+            if tiles.contains(&next) && !patch.contains(&next) {
+                patch.push(next);
+            } else if patch.iter().all(|&(px, py)| {
+                [(0, 1), (1, 0), (0, -1), (-1, 0)].iter().all(|(dx, dy)| !tiles.contains(&(px + dx, py + dy)) || patch.contains(&(px + dx, py + dy)))
+            }) {
+                break; // Nowhere left to grow
+            }
+        }
+        for (x, y) in patch {
+            if self.tiles[y as usize][x as usize] == Tile::Floor {
+                self.tiles[y as usize][x as usize] = tile.clone();
+            }
+        }
+    }
+
+    // Tiles just outside a room where a one-wide corridor comes in
+    fn room_entrances(&self, room: &Room) -> Vec<(i32, i32)> {
+        let is_open = |x: i32, y: i32| self.tile_at(x, y).is_some_and(|t| *t != Tile::Wall);
+        let mut entrances = Vec::new();
+
+        for x in room.x..room.x + room.width {
+            for y in [room.y - 1, room.y + room.height] {
+                if is_open(x, y) && !is_open(x - 1, y) && !is_open(x + 1, y) {
+                    entrances.push((x, y));
+                }
+            }
+        }
+        for y in room.y..room.y + room.height {
+            for x in [room.x - 1, room.x + room.width] {
+                if is_open(x, y) && !is_open(x, y - 1) && !is_open(x, y + 1) {
+                    entrances.push((x, y));
+                }
+            }
+        }
+        entrances
+    }
+
+    fn place_doors(&mut self, rng: &mut impl Rng) {
+        let rooms: Vec<Room> = self.rooms.iter().flatten().cloned().collect();
+        for room in &rooms {
+            for (x, y) in self.room_entrances(room) {
+                if self.tiles[y as usize][x as usize] == Tile::Floor && rng.gen_bool(0.6) {
+                    self.tiles[y as usize][x as usize] = Tile::DoorClosed;
+                }
+            }
+        }
+    }
+
+    // Lock every way into one side room, as long as the rest of the floor stays connected
+    fn lock_treasure_room(&mut self, rng: &mut impl Rng) {
+        let rooms: Vec<Room> = self.rooms.iter().flatten().cloned().collect();
+        if rooms.len() < 3 {
+            return;
+        }
+        let mut candidates: Vec<Room> = rooms[1..rooms.len() - 1].to_vec();
+        candidates.shuffle(rng);
+
+        for candidate in candidates {
+            let entrances = self.room_entrances(&candidate);
+            if entrances.is_empty() {
+                continue;
+            }
+
+            let previous: Vec<Tile> = entrances.iter().map(|&(x, y)| self.tiles[y as usize][x as usize].clone()).collect();
+            for &(x, y) in &entrances {
+                self.tiles[y as usize][x as usize] = Tile::DoorLocked;
+            }
+
+            let reachable = self.reachable_from(rooms[0].center());
+            let others_connected = rooms
+                .iter()
+                .filter(|r| **r != candidate)
+                .all(|r| reachable.contains(&r.center()));
+            let sealed = !reachable.contains(&candidate.center());
+            if others_connected && sealed {
+                self.treasure_room = Some(candidate);
+                return;
+            }
+
+            for (&(x, y), tile) in entrances.iter().zip(previous) {
+                self.tiles[y as usize][x as usize] = tile;
+            }
+        }
+    }
+
+    // Now and then a small room is carved out of the rock beside another, behind a door that looks
+    // like any other stretch of wall. Caves are left alone, their walls are too rough to hide one
+    fn hide_secret_room(&mut self) {
+        let mut rng = StdRng::seed_from_u64(self.seed ^ Self::SECRET_ROOM_SALT);
+        if self.cave || !rng.gen_bool(Self::SECRET_ROOM_CHANCE) {
+            return;
+        }
+
+        // A door in the wall of a room, with the new room two tiles beyond it and solid rock all around
+        let solid = |x: i32, y: i32| x > 0 && y > 0 && x < self.width as i32 - 1 && y < self.height as i32 - 1 && self.tile_at(x, y) == Some(&Tile::Wall);
+        let mut spots = Vec::new();
+        for room in self.rooms.iter().flatten().skip(1) {
+            let sides = (room.y..room.y + room.height)
+                .flat_map(|y| [((room.x - 1, y), (-1, 0)), ((room.x + room.width, y), (1, 0))])
+                .chain((room.x..room.x + room.width).flat_map(|x| [((x, room.y - 1), (0, -1)), ((x, room.y + room.height), (0, 1))]));
+            for (door, (dx, dy)) in sides {
+                let hidden = Room::new(door.0 + dx * 2 - 1, door.1 + dy * 2 - 1, 3, 3);
+                let enclosed = (hidden.y - 1..=hidden.y + hidden.height).all(|y| (hidden.x - 1..=hidden.x + hidden.width).all(|x| solid(x, y)));
+                if enclosed && solid(door.0, door.1) {
+                    spots.push((door, hidden));
+                }
+            }
+        }
+
+        let Some((door, hidden)) = spots.choose(&mut rng).cloned() else {
+            return;
+        };
+        self.create_room(&hidden);
+        self.secret_doors.insert(door);
+        self.secret_rooms.push(hidden);
+    }
+
+    // Every tile the player could reach without a key
+    fn reachable_from(&self, start: (i32, i32)) -> HashSet<(i32, i32)> {
+        let mut reachable = HashSet::from([start]);
+        let mut frontier = vec![start];
+        while let Some((x, y)) = frontier.pop() {
+            for (dx, dy) in [(0, 1), (1, 0), (0, -1), (-1, 0)] {
+                let next = (x + dx, y + dy);
+                if self.is_passable_for_player(next.0, next.1) && reachable.insert(next) {
+                    frontier.push(next);
+                }
+            }
+        }
+        reachable
+    }
+
+    fn check_for_stairs(&self, x: f32, y: f32) -> Option<i32> {
+        let x = x as usize;
+        let y = y as usize;
+
+        if y >= self.height || x >= self.width {
+            return None;
+        }
+
+        match self.tiles[y][x] {
+            Tile::StairsDown => Some(self.level + 1),
+            Tile::StairsUp => Some(self.level - 1),
+            _ => None,
+        }
+    }
+
+    fn organize_rooms(&mut self, temp_rooms: Vec<Room>) {
+        let mut organized_rooms: Vec<Vec<Room>> = Vec::new();
+        let room_height = 10;
+
+        let mut sorted_rooms = temp_rooms;
+        sorted_rooms.sort_by_key(|room| room.y);
+
+        if sorted_rooms.is_empty() {
+            self.rooms = Vec::new();
+            return;
+        }
+
+        let mut current_row: Vec<Room> = Vec::new();
+        let mut current_y = sorted_rooms[0].y;
+
+        for room in sorted_rooms {
+            if (room.y - current_y).abs() > room_height {
+                if !current_row.is_empty() {
+                    organized_rooms.push(current_row);
+                    current_row = Vec::new();
+                }
+                current_y = room.y;
+            }
+            current_row.push(room);
+        }
+
+        if !current_row.is_empty() {
+            organized_rooms.push(current_row);
+        }
+
+        for row in &mut organized_rooms {
+            row.sort_by_key(|room| room.x);
+        }
+
+        self.rooms = organized_rooms;
+    }
+
+    fn generate_dungeon_with_stairs(&mut self) {
+        let mut rng = thread_rng();
+        let max_rooms = 15;
+        let min_room_size = 5;
+        let max_room_size = 10;
+
+        let mut temp_rooms = Vec::new();
+        self.tiles = vec![vec![Tile::Wall; self.width]; self.height];
+        self.rooms.clear();
+
+        for _ in 0..max_rooms {
+            let w = rng.gen_range(min_room_size..max_room_size);
+            let h = rng.gen_range(min_room_size..max_room_size);
+            let x = rng.gen_range(1..self.width as i32 - w - 1);
+            let y = rng.gen_range(1..self.height as i32 - h - 1);
+
+            let new_room = Room::new(x, y, w, h);
+
+            if !temp_rooms.iter().any(|r: &Room| r.intersects(&new_room)) {
+                self.create_room(&new_room);
+
+                if let Some(prev_room) = temp_rooms.last() {
+                    let (prev_x, prev_y) = prev_room.center();
+                    let (new_x, new_y) = new_room.center();
+
+                    if rng.gen_bool(0.5) {
+                        self.create_horizontal_tunnel(prev_x, new_x, prev_y);
+                        self.create_vertical_tunnel(prev_y, new_y, new_x);
+                    } else {
+                        self.create_vertical_tunnel(prev_y, new_y, prev_x);
+                        self.create_horizontal_tunnel(prev_x, new_x, new_y);
+                    }
+                }
+
+                temp_rooms.push(new_room);
+            }
+        }
+
+        self.rooms = vec![temp_rooms];
+
+        // Place stairs
+        if self.level > 0 {
+            if let Some((x, y)) = self.up_stairs {
+                self.tiles[y][x] = Tile::StairsUp;
+            }
+        }
+
+        if self.level < 9 {
+            if let Some(first_row) = self.rooms.first() {
+                if let Some(last_room) = first_row.last() {
+                    let (x, y) = last_room.center();
+                    let (x, y) = (x as usize, y as usize);
+                    self.tiles[y][x] = Tile::StairsDown;
+                    self.down_stairs = Some((x, y));
+                }
+            }
+        }
+    }
+
+    fn place_stairs(&mut self) {
+        if self.level > 0 {
+            if let Some((x, y)) = self.up_stairs {
+                self.tiles[y][x] = Tile::StairsUp;
+            } else if let Some(first_row) = self.rooms.first() {
+                if let Some(first_room) = first_row.first() {
+                    let (x, y) = first_room.center();
+                    let (x, y) = (x as usize, y as usize);
+                    if y < self.height && x < self.width {
+                        self.tiles[y][x] = Tile::StairsUp;
+                        self.up_stairs = Some((x, y));
+                    }
+                }
+            }
+        }
+
+        if self.level < 9 {
+            if let Some(last_row) = self.rooms.last() {
+                if let Some(last_room) = last_row.last() {
+                    let (x, y) = last_room.center();
+                    let (x, y) = (x as usize, y as usize);
+                    if y < self.height && x < self.width {
+                        self.tiles[y][x] = Tile::StairsDown;
+                        self.down_stairs = Some((x, y));
+                    }
+                }
+            }
+        }
+    }
+
+    fn generate_dungeon(&mut self) {
+        let mut rng = thread_rng();
+        let max_rooms = 15;
+        let min_room_size = 5;
+        let max_room_size = 10;
+
+        let mut temp_rooms = Vec::new();
+
+        for _ in 0..max_rooms {
+            let w = rng.gen_range(min_room_size..max_room_size);
+            let h = rng.gen_range(min_room_size..max_room_size);
+            let x = rng.gen_range(1..self.width as i32 - w - 1);
+            let y = rng.gen_range(1..self.height as i32 - h - 1);
+
+            let new_room = Room::new(x, y, w, h);
+
+            if !temp_rooms.iter().any(|r: &Room| r.intersects(&new_room)) {
+                self.create_room(&new_room);
+
+                if let Some(prev_room) = temp_rooms.last() {
+                    let (prev_x, prev_y) = prev_room.center();
+                    let (new_x, new_y) = new_room.center();
+
+                    if rng.gen_bool(0.5) {
+                        self.create_horizontal_tunnel(prev_x, new_x, prev_y);
+                        self.create_vertical_tunnel(prev_y, new_y, new_x);
+                    } else {
+                        self.create_vertical_tunnel(prev_y, new_y, prev_x);
+                        self.create_horizontal_tunnel(prev_x, new_x, new_y);
+                    }
+                }
+
+                temp_rooms.push(new_room);
+            }
+        }
+
+        self.organize_rooms(temp_rooms);
+    }
+
+    fn create_room(&mut self, room: &Room) {
+        for y in room.y..room.y + room.height {
+            let y_idx = y as usize;
+            if y_idx >= self.height {
+                continue;
+            }
+            for x in room.x..room.x + room.width {
+                let x_idx = x as usize;
+                if x_idx >= self.width {
+                    continue;
+                }
+                self.tiles[y_idx][x_idx] = Tile::Floor;
+            }
+        }
+    }
+
+    // Dig a corridor between two room centers in a style that suits the floor
+    fn connect_rooms(&mut self, from: (i32, i32), to: (i32, i32), rng: &mut impl Rng) {
+        match CorridorStyle::roll(self.cave, rng) {
+            CorridorStyle::Narrow => self.carve_bent_tunnel(from, to, 1, rng),
+            CorridorStyle::Wide(width) => self.carve_bent_tunnel(from, to, width, rng),
+            CorridorStyle::Winding => self.carve_winding_tunnel(from, to, rng),
+            CorridorStyle::Bridge => self.carve_bridge(from, to, rng),
+        }
+    }
+
+    // An L-shaped tunnel, widened to the right of and below its center line
+    fn carve_bent_tunnel(&mut self, from: (i32, i32), to: (i32, i32), width: i32, rng: &mut impl Rng) {
+        // Keep the extra width off the outer edge of the map
+        let lines = |center: i32, limit: usize| (center..center + width).filter(move |&line| line < limit as i32 - 1);
+        let horizontal_first = rng.gen_bool(0.5);
+        let (corner_x, corner_y) = if horizontal_first { (to.0, from.1) } else { (from.0, to.1) };
+        for y in lines(corner_y, self.height) {
+            self.create_horizontal_tunnel(from.0, to.0, y);
+        }
+        for x in lines(corner_x, self.width) {
+            self.create_vertical_tunnel(from.1, to.1, x);
+        }
+    }
+
+    // A rough passage that stumbles towards its end, sometimes opening out to the side
+    fn carve_winding_tunnel(&mut self, from: (i32, i32), to: (i32, i32), rng: &mut impl Rng) {
+        let (max_x, max_y) = (self.width as i32 - 2, self.height as i32 - 2);
+        let mut position = from;
+        for _ in 0..CorridorStyle::WINDING_MAX_STEPS {
+            if position == to {
+                return;
+            }
+            let toward = ((to.0 - position.0).signum(), (to.1 - position.1).signum());
+            let step = if rng.gen_bool(CorridorStyle::WINDING_STRAIGHTNESS) {
+                // Along whichever axis still has distance to cover
+                match toward {
+                    (0, dy) => (0, dy),
+                    (dx, 0) => (dx, 0),
+                    (dx, dy) => *[(dx, 0), (0, dy)].choose(rng).unwrap(),
+                }
+            } else {
+                *ORTHOGONAL_DIRECTIONS.choose(rng).unwrap()
+            };
+            position = ((position.0 + step.0).clamp(1, max_x), (position.1 + step.1).clamp(1, max_y));
+            self.tiles[position.1 as usize][position.0 as usize] = Tile::Floor;
+            if rng.gen_bool(CorridorStyle::WINDING_WIDEN_CHANCE) {
+                let (dx, dy) = *ORTHOGONAL_DIRECTIONS.choose(rng).unwrap();
+                let side = ((position.0 + dx).clamp(1, max_x), (position.1 + dy).clamp(1, max_y));
+                self.tiles[side.1 as usize][side.0 as usize] = Tile::Floor;
+            }
+        }
+        // Wandered too long, finish the way straight
+        self.carve_bent_tunnel(position, to, 1, rng);
+    }
+
+    // A corridor whose horizontal stretch crosses a pit or a pool on a one-tile span
+    fn carve_bridge(&mut self, from: (i32, i32), to: (i32, i32), rng: &mut impl Rng) {
+        let y = from.1;
+        // Only solid rock gets dug out under the bridge, so no room or earlier corridor is cut off
+        let solid = |map: &Map, x: i32| {
+            (-CorridorStyle::BRIDGE_DEPTH..=CorridorStyle::BRIDGE_DEPTH).all(|dy| map.tile_at(x, y + dy) == Some(&Tile::Wall))
+        };
+        let mut span: Vec<i32> = Vec::new();
+        let mut longest: Vec<i32> = Vec::new();
+        for x in from.0.min(to.0)..=from.0.max(to.0) {
+            if solid(self, x) {
+                span.push(x);
+                if span.len() > longest.len() {
+                    longest = span.clone();
+                }
+            } else {
+                span.clear();
+            }
+        }
+
+        self.create_horizontal_tunnel(from.0, to.0, y);
+        self.create_vertical_tunnel(from.1, to.1, to.0);
+
+        // Leave a tile of rock at each end of the span to stand on
+        if longest.len() < 3 {
+            return;
+        }
+        let below = if !self.cave && rng.gen_bool(0.5) { Tile::Water } else { Tile::Chasm };
+        for &x in &longest[1..longest.len() - 1] {
+            for dy in (1..=CorridorStyle::BRIDGE_DEPTH).flat_map(|d| [-d, d]) {
+                self.tiles[(y + dy) as usize][x as usize] = below.clone();
+            }
+        }
+    }
+
+    fn create_horizontal_tunnel(&mut self, x1: i32, x2: i32, y: i32) {
+        let y_idx = y as usize;
+        if y_idx >= self.height {
+            return;
+        }
+        for x in x1.min(x2)..=x1.max(x2) {
+            let x_idx = x as usize;
+            if x_idx >= self.width {
+                continue;
+            }
+            self.tiles[y_idx][x_idx] = Tile::Floor;
+        }
+    }
+
+    fn create_vertical_tunnel(&mut self, y1: i32, y2: i32, x: i32) {
+        let x_idx = x as usize;
+        if x_idx >= self.width {
+            return;
+        }
+        for y in y1.min(y2)..=y1.max(y2) {
+            let y_idx = y as usize;
+            if y_idx >= self.height {
+                continue;
+            }
+            self.tiles[y_idx][x_idx] = Tile::Floor;
+        }
+    }
+
+    // Tiles no further from the origin than the radius, as the crow flies
+    fn tiles_within(&self, origin: (i32, i32), radius: f32) -> Vec<(i32, i32)> {
+        let reach = radius as i32;
+        let mut tiles = Vec::new();
+        for y in (origin.1 - reach).max(0)..=(origin.1 + reach).min(self.height as i32 - 1) {
+            for x in (origin.0 - reach).max(0)..=(origin.0 + reach).min(self.width as i32 - 1) {
+                let (dx, dy) = ((x - origin.0) as f32, (y - origin.1) as f32);
+                if (dx * dx + dy * dy).sqrt() <= radius {
+                    tiles.push((x, y));
+                }
+            }
+        }
+        tiles
+    }
+
+    // What can be seen from a tile out to the radius, for whoever stands on it
+    fn field_of_view(&self, origin: (i32, i32), radius: f32) -> Vec<(i32, i32)> {
+        let mut tiles = self.tiles_within(origin, radius);
+        tiles.retain(|&tile| self.has_line_of_sight(origin, tile));
+        tiles
+    }
+
+    // Change a tile during play. Generation writes tiles directly, nothing has found a path yet
+    fn set_tile(&mut self, x: i32, y: i32, tile: Tile) {
+        self.tiles[y as usize][x as usize] = tile;
+        self.revision += 1;
+    }
+
+    // Solid rock a burrower can dig through, never the edge of the map
+    fn is_diggable(&self, x: i32, y: i32) -> bool {
+        let inside = x > 0 && y > 0 && x < self.width as i32 - 1 && y < self.height as i32 - 1;
+        inside && self.tile_at(x, y) == Some(&Tile::Wall)
+    }
+
+    // Whatever ore ran through the rock goes with it
+    fn dig(&mut self, x: i32, y: i32) {
+        self.set_tile(x, y, Tile::Floor);
+        self.ore_veins.remove(&(x, y));
+        self.secret_doors.remove(&(x, y));
+    }
+
+    // The first step along a straight line between two tiles, walls and all, kept to the grid's allowed moves
+    fn straight_step(&self, from: (i32, i32), to: (i32, i32)) -> Option<(i32, i32)> {
+        let (x, y) = *bresenham_line(from, to).first()?;
+        if self.diagonal_movement || x == from.0 || y == from.1 {
+            Some((x, y))
+        } else if (to.0 - from.0).abs() >= (to.1 - from.1).abs() {
+            Some((x, from.1))
+        } else {
+            Some((from.0, y))
+        }
+    }
+
+    // Blocked by any wall between the two tiles
+    fn has_line_of_sight(&self, from: (i32, i32), to: (i32, i32)) -> bool {
+        let line = bresenham_line(from, to);
+        line.iter()
+            .take(line.len().saturating_sub(1))
+            .all(|&(x, y)| self.is_walkable(x, y))
+    }
+
+    fn is_walkable(&self, x: i32, y: i32) -> bool {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return false;
+        }
+        let open_tile = match self.tiles[y as usize][x as usize] {
+            Tile::Floor | Tile::StairsUp | Tile::StairsDown | Tile::DoorOpen | Tile::Water | Tile::Ice | Tile::Grass => true,
+            Tile::Wall | Tile::DoorClosed | Tile::DoorLocked | Tile::Chasm => false,
+        };
+        // Boulders are obstacles wherever they currently sit
+        open_tile && !self.boulders.contains(&(x, y))
+    }
+
+    fn tile_at(&self, x: i32, y: i32) -> Option<&Tile> {
+        if x < 0 || y < 0 {
+            return None;
+        }
+        self.tiles.get(y as usize)?.get(x as usize)
+    }
+
+    // The player can walk into closed doors, which opens them, but paths go around the shopkeeper
+    fn is_passable_for_player(&self, x: i32, y: i32) -> bool {
+        let is_shopkeeper = self.shop.as_ref().is_some_and(|shop| shop.keeper == (x, y));
+        let is_shrine = self.shrine.as_ref().is_some_and(|shrine| shrine.position == (x, y));
+        let is_board = self.bounty_board == Some((x, y));
+        let is_townsfolk = self.healer == Some((x, y)) || self.stash.as_ref().is_some_and(|stash| stash.position == (x, y));
+        (self.is_walkable(x, y) || self.tile_at(x, y) == Some(&Tile::DoorClosed)) && !is_shopkeeper && !is_shrine && !is_board && !is_townsfolk
+    }
+
+    fn is_wall(&self, x: usize, y: usize) -> bool {
+        if x >= self.width || y >= self.height {
+            return true;
+        }
+        self.tiles[y][x] == Tile::Wall
+    }
+
+    fn place_monsters(&self) -> (Option<(f32, f32)>, Vec<(f32, f32)>) {
+        let mut monster_positions = Vec::new();
+        let mut rng = thread_rng();
+        let mut used_positions = HashSet::new();
+
+        // Get first room from first row
+        let player_spawn = self.rooms.get(0)
+            .and_then(|row| row.get(0))
+            .and_then(|room| {
+                let center = room.center();
+                if self.is_walkable(center.0, center.1) {
+                    used_positions.insert((center.0, center.1));
+                    Some((center.0 as f32, center.1 as f32))
+                } else {
+                    room.inner_tiles().into_iter()
+                        .find(|&(x, y)| self.is_walkable(x, y))
+                        .map(|(x, y)| {
+                            used_positions.insert((x, y));
+                            (x as f32, y as f32)
+                        })
+                }
+            });
+
+        // Skip first room when placing monsters
+        for room_row in self.rooms.iter().skip(1) {
+            for room in room_row {
+                let num_monsters = rng.gen_range(1..4);
+                let walkable_tiles: Vec<_> = room.inner_tiles().into_iter()
+                    .filter(|&(x, y)|
+                        self.is_walkable(x, y) &&
+                            !used_positions.contains(&(x, y))
+                    )
+                    .collect();
+
+                if walkable_tiles.is_empty() {
+                    continue;
+                }
+
+                for _ in 0..num_monsters {
+                    if let Some(&(x, y)) = walkable_tiles.choose(&mut rng) {
+                        if used_positions.insert((x, y)) {
+                            monster_positions.push((x as f32, y as f32));
+                        }
+                    }
+                }
+            }
+        }
+
+        (player_spawn, monster_positions)
+    }
+
+    fn find_path(&self, start: (i32, i32), goal: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+        self.find_path_through(start, goal, |x, y| self.is_walkable(x, y))
+    }
+
+    // Paths for the player may go through closed doors, monsters have to go around
+    fn find_player_path(&self, start: (i32, i32), goal: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+        self.find_path_through(start, goal, |x, y| self.is_passable_for_player(x, y))
+    }
+
+    fn find_path_through(
+        &self,
+        start: (i32, i32),
+        goal: (i32, i32),
+        is_passable: impl Fn(i32, i32) -> bool,
+    ) -> Option<Vec<(i32, i32)>> {
+        use std::collections::BinaryHeap;
+
+        let mut open_set = BinaryHeap::new();
+        let mut came_from: HashMap<(i32, i32), (i32, i32)> = HashMap::new();
+        let mut g_score: HashMap<(i32, i32), i32> = HashMap::new();
+
+        g_score.insert(start, 0);
+        open_set.push(Node {
+            position: start,
+            g_cost: 0,
+            f_cost: self.estimate_cost(start, goal),
+        });
+
+        while let Some(current) = open_set.pop() {
+            if current.position == goal {
+                // Walk the came-from links back to the start
+                let mut path = vec![goal];
+                let mut position = goal;
+                while let Some(&previous) = came_from.get(&position) {
+                    path.push(previous);
+                    position = previous;
+                }
+
+                path.reverse();
+                return Some(path);
+            }
+
+            // Skip stale heap entries superseded by a cheaper route
+            if current.g_cost > g_score[&current.position] {
+                continue;
+            }
+
+            for &(dx, dy) in self.directions() {
+                let next_pos = (
+                    current.position.0 + dx,
+                    current.position.1 + dy
+                );
+
+                if !is_passable(next_pos.0, next_pos.1) {
+                    continue;
+                }
+
+                let g_cost = current.g_cost + self.step_cost(current.position, next_pos);
+                if g_score.get(&next_pos).is_some_and(|&known| known <= g_cost) {
+                    continue;
+                }
+
+                g_score.insert(next_pos, g_cost);
+                came_from.insert(next_pos, current.position);
+                open_set.push(Node {
+                    position: next_pos,
+                    g_cost,
+                    f_cost: g_cost + self.estimate_cost(next_pos, goal),
+                });
+            }
+        }
+
+        None
+    }
+
+    fn has_web(&self, x: i32, y: i32) -> bool {
+        self.terrain_overlays.get(&(x, y)).is_some_and(|o| o.kind == OverlayKind::Web)
+    }
+
+    // Webs only go on open floor, and never replace a longer-lived one
+    fn spin_web(&mut self, x: i32, y: i32, turns: Option<u32>) {
+        if self.tile_at(x, y) == Some(&Tile::Floor) && !self.terrain_overlays.contains_key(&(x, y)) {
+            self.terrain_overlays.insert((x, y), TerrainOverlay { kind: OverlayKind::Web, turns_left: turns });
+        }
+    }
+
+    fn resident_at(&self, x: i32, y: i32) -> Option<&Resident> {
+        self.residents.iter().find(|resident| resident.position == (x, y))
+    }
+
+    fn move_resident(&mut self, index: usize, to: (i32, i32)) {
+        let resident = &mut self.residents[index];
+        resident.position = to;
+        match resident.kind {
+            Townsfolk::Shopkeeper => {
+                if let Some(shop) = self.shop.as_mut() {
+                    shop.keeper = to;
+                }
+            }
+            Townsfolk::Healer => self.healer = Some(to),
+        }
+    }
+
+    fn tick_terrain_overlays(&mut self) {
+        for overlay in self.terrain_overlays.values_mut() {
+            if let Some(turns) = overlay.turns_left.as_mut() {
+                *turns = turns.saturating_sub(1);
+            }
+        }
+        self.terrain_overlays.retain(|_, o| o.turns_left != Some(0));
+    }
+
+    // What it costs a path to step from one tile onto the next
+    fn step_cost(&self, from: (i32, i32), to: (i32, i32)) -> i32 {
+        let base = if from.0 != to.0 && from.1 != to.1 { DIAGONAL_STEP_COST } else { STRAIGHT_STEP_COST };
+        base * (1 + self.terrain_overlays.get(&to).map_or(0, |o| o.kind.step_cost()))
+    }
+
+    // Steps a sound takes to travel between two tiles over anything but walls, None past max_distance
+    fn sound_distance(&self, from: (i32, i32), to: (i32, i32), max_distance: i32) -> Option<i32> {
+        let mut distances = HashMap::from([(from, 0)]);
+        let mut frontier = std::collections::VecDeque::from([from]);
+        while let Some(pos) = frontier.pop_front() {
+            let distance = distances[&pos];
+            if pos == to {
+                return Some(distance);
+            }
+            if distance >= max_distance {
+                continue;
+            }
+            for &(dx, dy) in &ALL_DIRECTIONS {
+                let next = (pos.0 + dx, pos.1 + dy);
+                let open = self.tile_at(next.0, next.1).is_some_and(|tile| *tile != Tile::Wall);
+                if open && !distances.contains_key(&next) {
+                    distances.insert(next, distance + 1);
+                    frontier.push_back(next);
+                }
+            }
+        }
+        None
+    }
+
+    fn explore_around(&mut self, center: (i32, i32), radius: f32) {
+        let reach = radius.ceil() as i32;
+        for y in center.1 - reach..=center.1 + reach {
+            for x in center.0 - reach..=center.0 + reach {
+                let distance = (((x - center.0).pow(2) + (y - center.1).pow(2)) as f32).sqrt();
+                if distance <= radius && self.tile_at(x, y).is_some() {
+                    self.explored.insert((x, y));
+                }
+            }
+        }
+    }
+
+    // How much of the open ground the player's light never fell on, as a percentage
+    fn unexplored_percent(&self) -> u32 {
+        let open: Vec<(i32, i32)> = (0..self.height as i32)
+            .flat_map(|y| (0..self.width as i32).map(move |x| (x, y)))
+            .filter(|&(x, y)| self.is_walkable(x, y))
+            .collect();
+        let unexplored = open.iter().filter(|tile| !self.explored.contains(tile)).count();
+        (unexplored * 100 / open.len().max(1)) as u32
+    }
+
+    // The closest tile the player could walk to that their light hasn't fallen on yet
+    fn nearest_unexplored(&self, from: (i32, i32)) -> Option<(i32, i32)> {
+        let mut visited = HashSet::from([from]);
+        let mut frontier = std::collections::VecDeque::from([from]);
+        while let Some((x, y)) = frontier.pop_front() {
+            if !self.explored.contains(&(x, y)) {
+                return Some((x, y));
+            }
+            for (dx, dy) in self.directions() {
+                let next = (x + dx, y + dy);
+                if self.is_passable_for_player(next.0, next.1) && visited.insert(next) {
+                    frontier.push_back(next);
+                }
+            }
+        }
+        None
+    }
+
+    fn directions(&self) -> &'static [(i32, i32)] {
+        if self.diagonal_movement {
+            &ALL_DIRECTIONS
+        } else {
+            &ORTHOGONAL_DIRECTIONS
+        }
+    }
+
+    // A* heuristic, never more than the real cost of the walk
+    fn estimate_cost(&self, from: (i32, i32), to: (i32, i32)) -> i32 {
+        if self.diagonal_movement {
+            octile_distance(from, to)
+        } else {
+            manhattan_distance(from, to) * STRAIGHT_STEP_COST
+        }
+    }
+
+    // Side rooms free for special features: not the entrance, the stairs room or one already taken
+    fn side_rooms(&self) -> Vec<Room> {
+        let rooms: Vec<&Room> = self.rooms.iter().flatten().collect();
+        let taken = |r: &Room| {
+            self.treasure_room.as_ref() == Some(r)
+                || self.shop.as_ref().is_some_and(|shop| r.contains(shop.keeper.0, shop.keeper.1))
+                || self.shrine.as_ref().is_some_and(|shrine| shrine.room == *r)
+                || self.claimed_rooms.contains(r)
+        };
+        rooms
+            .iter()
+            .skip(1)
+            .take(rooms.len().saturating_sub(2))
+            .filter(|r| !taken(r))
+            .map(|r| (*r).clone())
+            .collect()
+    }
+
+    // Mined out veins grow back once their time comes around
+    fn regrow_ore_veins(&mut self, turn: u32) {
+        for vein in self.ore_veins.values_mut() {
+            if vein.regrows_at.is_some_and(|at| turn >= at) {
+                *vein = OreVein::new();
+            }
+        }
+    }
+
+    // What a tile shows, with whatever lies on it, dimmed outside the light
+    fn glyph_at(&self, x: usize, y: usize, light_center: (f32, f32), light_radius: f32) -> (char, Color) {
+        let tile = &self.tiles[y][x];
+        let (char, color) = match tile {
+            Tile::Wall if self.cave => ('#', BROWN),
+            Tile::Wall => ('#', DARKGRAY),
+            Tile::Floor => ('.', GRAY),
+            Tile::StairsUp => ('<', YELLOW),
+            Tile::StairsDown => ('>', YELLOW),
+            Tile::DoorClosed => ('+', BROWN),
+            Tile::DoorOpen => ('\'', BROWN),
+            Tile::DoorLocked => ('+', GOLD),
+            Tile::Water => ('~', BLUE),
+            Tile::Ice => ('=', SKYBLUE),
+            Tile::Grass => ('"', DARKGREEN),
+            Tile::Chasm => (':', DARKPURPLE),
+        };
+        let color = match (tile, &self.palette) {
+            (Tile::Wall, Some(palette)) => palette.wall(),
+            (Tile::Floor, Some(palette)) => palette.floor(),
+            _ => color,
+        };
+        let (char, color) = match self.terrain_overlays.get(&(x as i32, y as i32)) {
+            Some(overlay) => overlay.kind.glyph(),
+            None => (char, color),
+        };
+        let (char, color) = match self.decorations.get(&(x as i32, y as i32)) {
+            Some(decoration) => decoration.glyph(),
+            None => (char, color),
+        };
+        let (char, color) = match self.features.get(&(x as i32, y as i32)) {
+            Some(feature) => feature.glyph(),
+            None => (char, color),
+        };
+        let (char, color) = if self.boulders.contains(&(x as i32, y as i32)) { ('0', BEIGE) } else { (char, color) };
+        let (char, color) = match self.ore_veins.get(&(x as i32, y as i32)) {
+            Some(vein) if vein.swings_left > 0 => ('*', ORANGE),
+            _ => (char, color),
+        };
+        let (char, color) = if self.portal == Some((x as i32, y as i32)) { ('O', VIOLET) } else { (char, color) };
+
+        let distance = ((x as f32 - light_center.0).powi(2) + (y as f32 - light_center.1).powi(2)).sqrt();
+        if distance <= light_radius {
+            (char, color)
+        } else {
+            (char, Color::new(color.r * 0.3, color.g * 0.3, color.b * 0.3, 1.0))
+        }
+    }
+
+    // Update the draw method to use different colors for different tiles
+    fn draw(&self, camera: &Camera, glyphs: &GlyphRenderer, tile_size: f32, light_center: (f32, f32), light_radius: f32) {
+        let start_x = camera.x.floor() as usize;
+        let start_y = camera.y.floor() as usize;
+        let end_x = (camera.x + camera.viewport_width as f32).ceil() as usize;
+        let end_y = (camera.y + camera.viewport_height as f32).ceil() as usize;
+
+        for y in start_y..end_y.min(self.height) {
+            for x in start_x..end_x.min(self.width) {
+                let (screen_x, screen_y) = camera.world_to_screen(x as f32, y as f32, tile_size);
+                let (char, color) = self.glyph_at(x, y, light_center, light_radius);
+                glyphs.draw(char, screen_x, screen_y, tile_size, color);
+            }
+        }
+    }
+}
+
+// A seam of ore in a cave wall, dug out over several swings and grown back some time later
+#[derive(Clone, Debug)]
+struct OreVein {
+    swings_left: u32,
+    regrows_at: Option<u32>, // Turn the vein can be mined again, once it is mined out
+}
+
+impl OreVein {
+    const SWINGS: u32 = 4;
+    const REGROW_TURNS: u32 = 300;
+    const GEM_CHANCE: f64 = 0.15;
+
+    fn new() -> Self {
+        Self { swings_left: Self::SWINGS, regrows_at: None }
+    }
+}
+
+// What a character in a prefab's legend stands for, always on bare floor
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+enum PrefabSpawn {
+    Monster(String), // A monster template, by name
+    Guard,           // Any monster found at the depth
+    Item(String),    // An item, by id
+    Loot,            // Whatever treasure rooms at the depth hold
+    Decoration(Decoration),
+}
+
+// A hand-drawn room from data/prefabs.ron. Walls, floor, water, grass, chasms and doors are drawn
+// as they are shown on the map, a space leaves the generated tile alone, and the legend covers the rest
+#[derive(Clone, Debug, Deserialize)]
+struct PrefabTemplate {
+    name: String,
+    min_depth: i32,
+    max_depth: i32,
+    layout: Vec<String>,
+    #[serde(default)]
+    legend: HashMap<char, PrefabSpawn>,
+}
+
+impl PrefabTemplate {
+    // The layout turned a quarter turn clockwise so many times, then flipped left to right if asked.
+    // Short rows are padded with spaces first so every turn stays rectangular
+    fn oriented(&self, turns: usize, mirrored: bool) -> Vec<Vec<char>> {
+        let width = self.layout.iter().map(|row| row.chars().count()).max().unwrap_or(0);
+        let mut grid: Vec<Vec<char>> = self.layout
+            .iter()
+            .map(|row| row.chars().chain(std::iter::repeat(' ')).take(width).collect())
+            .collect();
+        for _ in 0..turns % 4 {
+            let (height, width) = (grid.len(), grid.first().map_or(0, Vec::len));
+            grid = (0..width).map(|x| (0..height).rev().map(|y| grid[y][x]).collect()).collect();
+        }
+        if mirrored {
+            for row in &mut grid {
+                row.reverse();
+            }
+        }
+        grid
+    }
+}
+
+struct PrefabRegistry {
+    templates: Vec<PrefabTemplate>,
+}
+
+impl PrefabRegistry {
+    const PATH: &'static str = "data/prefabs.ron";
+    const BUILTIN: &'static str = include_str!("../data/prefabs.ron");
+    const FLOOR_CHANCE: f64 = 0.4;
+    const SEED_SALT: u64 = 0x9e37_79b9; // Keeps the prefab rolls apart from the floor's own layout
+
+    // Same lookup order as the monster registry: file on disk, then the built-in copy
+    fn load() -> Self {
+        let contents = std::fs::read_to_string(Self::PATH).unwrap_or_else(|_| Self::BUILTIN.to_string());
+        let templates = ron::from_str(&contents).unwrap_or_else(|e| {
+            eprintln!("Failed to parse {}: {}, using built-in prefabs", Self::PATH, e);
+            ron::from_str(Self::BUILTIN).expect("built-in prefab definitions are valid")
+        });
+        Self { templates }
+    }
+
+    fn for_depth(&self, depth: i32) -> Vec<&PrefabTemplate> {
+        self.templates.iter().filter(|t| depth >= t.min_depth && depth <= t.max_depth).collect()
+    }
+}
+
+// How a side branch's floors are laid out
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+enum BranchLayout {
+    Rooms,
+    Caves,
+    Maze,
+}
+
+impl BranchLayout {
+    fn generator(&self) -> Box<dyn MapGenerator> {
+        match self {
+            BranchLayout::Rooms => Box::new(BspRooms),
+            BranchLayout::Caves => Box::new(CellularCaves),
+            BranchLayout::Maze => Box::new(MazeTunnels),
+        }
+    }
+}
+
+// Wall and floor colors that set a branch apart from the main dungeon
+#[derive(Clone, Copy, Debug, Deserialize)]
+struct Palette {
+    wall: (u8, u8, u8),
+    floor: (u8, u8, u8),
+}
+
+impl Palette {
+    fn wall(&self) -> Color {
+        let (r, g, b) = self.wall;
+        Color::from_rgba(r, g, b, 255)
+    }
+
+    fn floor(&self) -> Color {
+        let (r, g, b) = self.floor;
+        Color::from_rgba(r, g, b, 255)
+    }
+}
+
+// A side branch from data/branches.ron, with its own stack of floors below the one it is entered from
+#[derive(Clone, Debug, Deserialize)]
+struct BranchTemplate {
+    name: String,
+    entry_floor: i32, // The main dungeon floor holding the stairs down into it
+    floors: i32,
+    layout: BranchLayout,
+    palette: Palette,
+    monsters: Vec<String>, // The only ones found in the branch, whatever their usual depths
+    reward: String, // An item id, left at the bottom
+}
+
+impl BranchTemplate {
+    fn deepest_floor(&self) -> i32 {
+        self.entry_floor + self.floors
+    }
+}
+
+struct BranchRegistry {
+    templates: Vec<BranchTemplate>,
+}
+
+impl BranchRegistry {
+    const PATH: &'static str = "data/branches.ron";
+    const BUILTIN: &'static str = include_str!("../data/branches.ron");
+    const SEED_SALT: u64 = 0x85eb_ca6b; // Keeps branch floors apart from the main floors at the same depths
+
+    fn load() -> Self {
+        let contents = std::fs::read_to_string(Self::PATH).unwrap_or_else(|_| Self::BUILTIN.to_string());
+        let templates = ron::from_str(&contents).unwrap_or_else(|e| {
+            eprintln!("Failed to parse {}: {}, using built-in branches", Self::PATH, e);
+            ron::from_str(Self::BUILTIN).expect("built-in branch definitions are valid")
+        });
+        Self { templates }
+    }
+
+    // The branch whose stairs lie on the given main dungeon floor
+    fn entered_from(&self, floor: i32) -> Option<usize> {
+        self.templates.iter().position(|t| t.entry_floor == floor && t.floors > 0 && t.deepest_floor() < FINAL_FLOOR)
+    }
+}
+
+// Lays out a floor's rooms and the passages joining them, and returns the rooms. Stairs, doors,
+// purposes and terrain all go into those rooms afterwards, the first being where the player starts
+trait MapGenerator {
+    fn carve(&self, map: &mut Map, rng: &mut StdRng) -> Vec<Room>;
+
+    // Caves get rough walls, winding passages, ore veins and a forge
+    fn is_cave(&self) -> bool {
+        false
+    }
+}
+
+// Rooms dropped wherever they fit, each dug through to the one before
+struct RoomsAndCorridors;
+
+impl RoomsAndCorridors {
+    const MAX_ROOMS: usize = 15;
+    const MIN_ROOM_SIZE: i32 = 5;
+    const MAX_ROOM_SIZE: i32 = 10;
+}
+
+impl MapGenerator for RoomsAndCorridors {
+    fn carve(&self, map: &mut Map, rng: &mut StdRng) -> Vec<Room> {
+        let mut rooms: Vec<Room> = Vec::new();
+        for _ in 0..Self::MAX_ROOMS {
+            let w = rng.gen_range(Self::MIN_ROOM_SIZE..Self::MAX_ROOM_SIZE);
+            let h = rng.gen_range(Self::MIN_ROOM_SIZE..Self::MAX_ROOM_SIZE);
+            let x = rng.gen_range(1..map.width as i32 - w - 1);
+            let y = rng.gen_range(1..map.height as i32 - h - 1);
+            let new_room = Room::new(x, y, w, h);
+
+            if !rooms.iter().any(|r| r.intersects(&new_room)) {
+                map.create_room(&new_room);
+                if let Some(prev_room) = rooms.last() {
+                    map.connect_rooms(prev_room.center(), new_room.center(), rng);
+                }
+                rooms.push(new_room);
+            }
+        }
+        rooms
+    }
+}
+
+// The floor cut in halves again and again, one room to each piece, so rooms spread evenly
+// and each is joined to its neighbour in the split
+struct BspRooms;
+
+impl BspRooms {
+    const MIN_LEAF: i32 = 10;
+    const MAX_ROOM_SIZE: i32 = 10;
+
+    fn split(area: Room, rng: &mut StdRng, leaves: &mut Vec<Room>) {
+        let (wide, tall) = (area.width >= Self::MIN_LEAF * 2, area.height >= Self::MIN_LEAF * 2);
+        if !wide && !tall {
+            leaves.push(area);
+            return;
+        }
+        let across = if wide && tall { area.width > area.height || (area.width == area.height && rng.gen_bool(0.5)) } else { wide };
+        if across {
+            let cut = rng.gen_range(Self::MIN_LEAF..=area.width - Self::MIN_LEAF);
+            Self::split(Room::new(area.x, area.y, cut, area.height), rng, leaves);
+            Self::split(Room::new(area.x + cut, area.y, area.width - cut, area.height), rng, leaves);
+        } else {
+            let cut = rng.gen_range(Self::MIN_LEAF..=area.height - Self::MIN_LEAF);
+            Self::split(Room::new(area.x, area.y, area.width, cut), rng, leaves);
+            Self::split(Room::new(area.x, area.y + cut, area.width, area.height - cut), rng, leaves);
+        }
+    }
+}
+
+impl MapGenerator for BspRooms {
+    fn carve(&self, map: &mut Map, rng: &mut StdRng) -> Vec<Room> {
+        let mut leaves = Vec::new();
+        Self::split(Room::new(0, 0, map.width as i32, map.height as i32), rng, &mut leaves);
+
+        // A tile of rock on every side keeps neighbouring rooms apart
+        let mut rooms: Vec<Room> = Vec::new();
+        for leaf in leaves {
+            let w = rng.gen_range(5..=(leaf.width - 2).min(Self::MAX_ROOM_SIZE));
+            let h = rng.gen_range(5..=(leaf.height - 2).min(Self::MAX_ROOM_SIZE));
+            let x = rng.gen_range(leaf.x + 1..=leaf.x + leaf.width - 1 - w);
+            let y = rng.gen_range(leaf.y + 1..=leaf.y + leaf.height - 1 - h);
+            let room = Room::new(x, y, w, h);
+            map.create_room(&room);
+            if let Some(previous) = rooms.last() {
+                map.connect_rooms(previous.center(), room.center(), rng);
+            }
+            rooms.push(room);
+        }
+        rooms
+    }
+}
+
+// Random rock smoothed into caverns, with chambers cut into it for the rooms and linked by
+// winding passages. Pockets nothing leads to are filled back in
+struct CellularCaves;
+
+impl CellularCaves {
+    const ROCK_CHANCE: f64 = 0.5;
+    const SMOOTHING_PASSES: usize = 4;
+    const ROCK_NEIGHBOURS: usize = 5; // Rock stays where this many of the nine tiles around it, itself included, are rock
+    const CHAMBERS: usize = 8;
+}
+
+impl MapGenerator for CellularCaves {
+    fn carve(&self, map: &mut Map, rng: &mut StdRng) -> Vec<Room> {
+        let (width, height) = (map.width, map.height);
+        let inside = |x: usize, y: usize| x > 0 && y > 0 && x < width - 1 && y < height - 1;
+        let mut rock: Vec<Vec<bool>> = (0..height)
+            .map(|y| (0..width).map(|x| !inside(x, y) || rng.gen_bool(Self::ROCK_CHANCE)).collect())
+            .collect();
+        for _ in 0..Self::SMOOTHING_PASSES {
+            rock = (0..height)
+                .map(|y| {
+                    (0..width)
+                        .map(|x| {
+                            let neighbours = ALL_DIRECTIONS
+                                .iter()
+                                .filter(|(dx, dy)| {
+                                    let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                                    nx < 0 || ny < 0 || rock.get(ny as usize).and_then(|row| row.get(nx as usize)).is_none_or(|&r| r)
+                                })
+                                .count();
+                            !inside(x, y) || neighbours + rock[y][x] as usize >= Self::ROCK_NEIGHBOURS
+                        })
+                        .collect()
+                })
+                .collect();
+        }
+        for (y, row) in rock.iter().enumerate() {
+            for (x, &solid) in row.iter().enumerate() {
+                if !solid {
+                    map.tiles[y][x] = Tile::Floor;
+                }
+            }
+        }
+
+        let mut rooms: Vec<Room> = Vec::new();
+        for _ in 0..Self::CHAMBERS * 4 {
+            if rooms.len() == Self::CHAMBERS {
+                break;
+            }
+            let (w, h) = (rng.gen_range(5..9), rng.gen_range(5..9));
+            let chamber = Room::new(rng.gen_range(1..width as i32 - w - 1), rng.gen_range(1..height as i32 - h - 1), w, h);
+            if rooms.iter().any(|r| r.intersects(&chamber)) {
+                continue;
+            }
+            map.create_room(&chamber);
+            if let Some(previous) = rooms.last() {
+                map.connect_rooms(previous.center(), chamber.center(), rng);
+            }
+            rooms.push(chamber);
+        }
+
+        if let Some(first) = rooms.first() {
+            let reachable = map.reachable_from(first.center());
+            for y in 0..height {
+                for x in 0..width {
+                    if !reachable.contains(&(x as i32, y as i32)) {
+                        map.tiles[y][x] = Tile::Wall;
+                    }
+                }
+            }
+        }
+        rooms
+    }
+
+    fn is_cave(&self) -> bool {
+        true
+    }
+}
+
+// A maze of one-tile tunnels over the whole floor, with a few walls knocked through so it loops,
+// and rooms opened up on top of it. Every room lies on the maze, so all of them connect
+struct MazeTunnels;
+
+impl MazeTunnels {
+    const ROOMS: usize = 7;
+    const LOOP_CHANCE: f64 = 0.1;
+}
+
+impl MapGenerator for MazeTunnels {
+    fn carve(&self, map: &mut Map, rng: &mut StdRng) -> Vec<Room> {
+        // Cells sit on odd tiles, with the walls between them on the even ones
+        let (columns, rows) = ((map.width as i32 - 1) / 2, (map.height as i32 - 1) / 2);
+        let tile = |(cx, cy): (i32, i32)| ((cx * 2 + 1) as usize, (cy * 2 + 1) as usize);
+        let start = (rng.gen_range(0..columns), rng.gen_range(0..rows));
+        let mut visited = HashSet::from([start]);
+        let mut stack = vec![start];
+        map.tiles[tile(start).1][tile(start).0] = Tile::Floor;
+        while let Some(&(cx, cy)) = stack.last() {
+            let unvisited: Vec<(i32, i32)> = ORTHOGONAL_DIRECTIONS
+                .iter()
+                .map(|(dx, dy)| (cx + dx, cy + dy))
+                .filter(|&(nx, ny)| nx >= 0 && ny >= 0 && nx < columns && ny < rows && !visited.contains(&(nx, ny)))
+                .collect();
+            let Some(&next) = unvisited.choose(rng) else {
+                stack.pop();
+                continue;
+            };
+            let ((x, y), (nx, ny)) = (tile((cx, cy)), tile(next));
+            map.tiles[(y + ny) / 2][(x + nx) / 2] = Tile::Floor;
+            map.tiles[ny][nx] = Tile::Floor;
+            visited.insert(next);
+            stack.push(next);
+        }
+
+        for y in 1..map.height - 1 {
+            for x in 1..map.width - 1 {
+                let open = |x: usize, y: usize| map.tiles[y][x] == Tile::Floor;
+                let between = (open(x - 1, y) && open(x + 1, y)) || (open(x, y - 1) && open(x, y + 1));
+                if map.tiles[y][x] == Tile::Wall && between && rng.gen_bool(Self::LOOP_CHANCE) {
+                    map.tiles[y][x] = Tile::Floor;
+                }
+            }
+        }
+
+        // Odd sizes on odd tiles, so a room's edges run along the maze's cells
+        let mut rooms: Vec<Room> = Vec::new();
+        for _ in 0..Self::ROOMS * 4 {
+            if rooms.len() == Self::ROOMS {
+                break;
+            }
+            let (w, h) = (*[5, 7, 9].choose(rng).unwrap(), *[5, 7].choose(rng).unwrap());
+            let x = rng.gen_range(0..(map.width as i32 - w - 1) / 2) * 2 + 1;
+            let y = rng.gen_range(0..(map.height as i32 - h - 1) / 2) * 2 + 1;
+            let room = Room::new(x, y, w, h);
+            if !rooms.iter().any(|r| r.intersects(&room)) {
+                map.create_room(&room);
+                rooms.push(room);
+            }
+        }
+        rooms
+    }
+}
+
+// How a corridor between two rooms is dug
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CorridorStyle {
+    Narrow,
+    Wide(i32), // Two or three tiles across, enough room to be surrounded in
+    Winding,   // Wanders on its way, the usual passage in caves
+    Bridge,    // Crosses a pit or a pool on a span one tile wide
+}
+
+impl CorridorStyle {
+    const WINDING_MAX_STEPS: usize = 400;
+    const WINDING_STRAIGHTNESS: f64 = 0.7;
+    const WINDING_WIDEN_CHANCE: f64 = 0.3;
+    const BRIDGE_DEPTH: i32 = 2; // Tiles of pit or pool on each side of the span
+
+    fn roll(cave: bool, rng: &mut impl Rng) -> Self {
+        let width = rng.gen_range(2..=3);
+        let weights = if cave {
+            [(CorridorStyle::Winding, 6), (CorridorStyle::Wide(width), 2), (CorridorStyle::Bridge, 1), (CorridorStyle::Narrow, 1)]
+        } else {
+            [(CorridorStyle::Narrow, 6), (CorridorStyle::Wide(width), 2), (CorridorStyle::Bridge, 1), (CorridorStyle::Winding, 1)]
+        };
+        weights.choose_weighted(rng, |(_, weight)| *weight).map_or(CorridorStyle::Narrow, |(style, _)| *style)
+    }
+}
+
+// What a side room was used for, picked once the floor is laid out
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+enum RoomPurpose {
+    Plain,
+    Barracks,  // Bedrolls, and the fighters who sleep in them
+    Storeroom, // Crates, and more to pick up than most rooms
+    Shrine,    // Candles, and whatever haunts them
+    Library,   // Shelves in the walls, scrolls on the floor
+    Flooded,   // Mostly under water
+}
+
+impl RoomPurpose {
+    const THEMED: [RoomPurpose; 5] = [
+        RoomPurpose::Barracks,
+        RoomPurpose::Storeroom,
+        RoomPurpose::Shrine,
+        RoomPurpose::Library,
+        RoomPurpose::Flooded,
+    ];
+    const TAG_CHANCE: f64 = 0.5;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+enum Decoration {
+    Bedroll,
+    Crate,
+    Candle,
+    Bookshelf,
+}
+
+impl Decoration {
+    fn glyph(&self) -> (char, Color) {
+        match self {
+            Decoration::Bedroll => ('_', BROWN),
+            Decoration::Crate => ('&', BROWN),
+            Decoration::Candle => ('i', YELLOW),
+            Decoration::Bookshelf => ('|', BROWN),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Decoration::Bedroll => "Bedroll",
+            Decoration::Crate => "Crate",
+            Decoration::Candle => "Candle",
+            Decoration::Bookshelf => "Bookshelf",
+        }
+    }
+}
+
+// Something set into a room that the player uses with [F] from beside it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Feature {
+    Fountain { dry: bool },
+    Altar { holy: bool, used: bool }, // Holy ones bless the kneeler's gear, the rest curse it
+    Chest { locked: bool, opened: bool },
+}
+
+impl Feature {
+    const FOUNTAIN_CHANCE: f64 = 0.4;
+    const ALTAR_CHANCE: f64 = 0.25;
+    const CHEST_CHANCE: f64 = 0.5;
+    const HOLY_CHANCE: f64 = 0.65;
+    const LOCKED_CHANCE: f64 = 0.4;
+    const DRY_UP_CHANCE: f64 = 0.35;
+    const CHEST_ITEMS: usize = 2;
+
+    // Spent ones are drawn dim
+    fn glyph(&self) -> (char, Color) {
+        match *self {
+            Feature::Fountain { dry } => ('{', if dry { DARKGRAY } else { SKYBLUE }),
+            Feature::Altar { used, .. } => ('_', if used { DARKGRAY } else { WHITE }),
+            Feature::Chest { opened: true, .. } => (']', DARKGRAY),
+            Feature::Chest { locked, .. } => (']', if locked { GOLD } else { BROWN }),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Feature::Fountain { .. } => "fountain",
+            Feature::Altar { .. } => "altar",
+            Feature::Chest { .. } => "chest",
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        match self {
+            Feature::Fountain { .. } => "drink from",
+            Feature::Altar { .. } => "kneel at",
+            Feature::Chest { .. } => "open",
+        }
+    }
+
+    fn spent(&self) -> bool {
+        matches!(self, Feature::Fountain { dry: true } | Feature::Altar { used: true, .. } | Feature::Chest { opened: true, .. })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Room {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+impl Room {
+    fn new(x: i32, y: i32, width: i32, height: i32) -> Self {
+        Room { x, y, width, height }
+    }
+
+    fn random_position(&self, rng: &mut impl Rng) -> (i32, i32) {
+        // Get inner positions to avoid placing items on walls
+        let x = rng.gen_range((self.x + 1)..(self.x + self.width - 1));
+        let y = rng.gen_range((self.y + 1)..(self.y + self.height - 1));
+        (x, y)
+    }
+
+    fn center(&self) -> (i32, i32) {
+        (
+            self.x + self.width / 2,
+            self.y + self.height / 2,
+        )
+    }
+
+    fn intersects(&self, other: &Room) -> bool {
+        self.x <= other.x + other.width &&
+            self.x + self.width >= other.x &&
+            self.y <= other.y + other.height &&
+            self.y + self.height >= other.y
+    }
+
+    fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+
+    fn inner_tiles(&self) -> Vec<(i32, i32)> {
+        let mut tiles = Vec::new();
+        for y in (self.y + 1)..(self.y + self.height - 1) {
+            for x in (self.x + 1)..(self.x + self.width - 1) {
+                tiles.push((x, y));
+            }
+        }
+        tiles
+    }
+}
+
+struct GameState {
+    player: Entity,
+    monsters: Vec<Entity>,
+    combat_log: Vec<LoggedMessage>,
+    player_turn: bool,
+    ground_items: Vec<(f32, f32, Item)>,
+    inventory_open: bool,
+    inventory_selection: usize,
+    map_manager: MapManager,
+    level_states: HashMap<FloorId, LevelState>,
+    turn: u32,
+    spawned_artifacts: HashSet<Artifact>,
+    artifact_recharge: HashMap<Artifact, u32>,
+    encyclopedia: Encyclopedia,
+    encyclopedia_open: bool,
+    hinted_monsters: HashSet<String>, // Kinds already looked over this run
+    feedback_open: bool,
+    feedback_text: String,
+    monster_registry: MonsterRegistry,
+    item_registry: ItemRegistry,
+    identification: Identification, // Which potions and scrolls the player knows, and what the rest look like
+    run_modifiers: Vec<Modifier>,
+    auto_path: Vec<(i32, i32)>,
+    auto_path_visible_monsters: usize,
+    action_queue: Vec<MacroAction>, // A macro being played back, one action per turn
+    action_queue_danger: (usize, i32), // Visible monsters and HP when playback started
+    recording: Option<(usize, Vec<MacroAction>)>, // Macro slot and the actions taken so far
+    events: Vec<GameEvent>,
+    kills: u32,
+    ally_kills: u32, // The share of the kills made by allies
+    deepest_level: i32,
+    victorious: bool, // Escaped with the Heart of the Forge
+    items_collected: u32,
+    score_recorded: bool,
+    player_dijkstra: Option<DijkstraMap>, // Rebuilt whenever the player changes tile
+    projectiles: Vec<ProjectileTrace>,
+    strike_marks: Vec<StrikeMark>,
+    damage_popups: Vec<DamagePopup>,
+    held_verb: Option<(WeaponKind, SkillRank, String)>, // The player's attack verb for this weapon and rank
+    allies: Vec<Ally>,
+    party_open: bool,
+    party_selection: usize,
+    targeting: Option<Targeting>, // Waiting for a target tile
+    look_cursor: Option<(i32, i32)>, // The tile being looked at with [;], free of the player
+    spawn_preview: Option<SpawnPreview>, // Wizard mode only
+    scenario: Option<ScenarioController>,
+    digging: Option<Digging>,
+    escape: Option<Escape>,
+    exploring: Option<(i32, i32)>, // Where the current leg of an auto-explore ends
+    radial_menu: Option<RadialMenu>,
+    known_spells: Vec<Spell>,
+    spells_open: bool,
+    spell_selection: usize,
+    shop_open: bool,
+    shop_selection: usize,
+    shop_selling: bool, // Which side of the shop screen has focus
+    stash_open: bool,
+    stash_selection: usize,
+    stash_storing: bool, // Which side of the stash screen has focus
+    town_stocked_for: Option<i32>, // Deepest floor reached when the town shop last took in new wares
+    floor_stats: HashMap<FloorId, FloorStats>,
+    outclassed_kills: HashMap<String, u32>, // Kills of each kind of monster made after the player outgrew it
+    floor_summary: Option<FloorSummary>, // Shown for a moment after leaving a floor
+    shop_discount: f32, // From the Haggler forge upgrade
+    bounty_board: BountyBoard,
+    board_open: bool,
+    forge_open: bool,
+    forge_selection: usize, // Piece of equipment
+    forge_gem_selection: usize,
+    board_selection: usize,
+    reputation: u32, // Earned from bounties, sweetens the rewards of later ones
+    tool_cooldowns: HashMap<Tool, u32>, // Turn each tool is ready again
+    turn_profile: TurnProfile, // Where this frame's update time went
+    heard_noises: HashMap<Noise, u32>, // Turn each kind of noise was last reported
+    turn_taken: bool, // Lets the world answer a turn spent with a menu still open
+    clock: f32, // Seconds since the frontend started, moved on at the start of every frame
+    sneaking: bool, // Slower, but only seen by monsters facing the player
+    light_fuel: u32, // Turns the player's light has left to burn
+    parley: Option<EntityId>, // The monster being bargained with
+    parley_selection: usize,
+    message_history: Vec<LoggedMessage>, // Everything ever logged, the combat log only keeps the last few
+    log_open: bool,
+    log_scroll: usize, // Lines scrolled back from the newest
+    log_filter: Option<MessageCategory>,
+    rng: StdRng, // Every roll that shapes the run, seeded from it so a replay plays out the same way
+}
+
+impl GameState {
+    const SPUN_WEB_TURNS: u32 = 40; // Webs left by passing spiders, lair webs last
+    const BOULDER_DAMAGE: i32 = 15;
+    // Each floor's monsters add up to about this much threat, counted in XP
+    const THREAT_BASE: i32 = 300;
+    const THREAT_PER_DEPTH: i32 = 120;
+    const ELITE_CHANCE: f64 = 0.1;
+    const OUT_OF_DEPTH_CHANCE: f64 = 0.15;
+    const OUT_OF_DEPTH_FLOORS: i32 = 2;
+    const BURN_DAMAGE: i32 = 1; // Per turn, for whatever a fireball catches
+    const BURN_TURNS: i32 = 3;
+    const LOOT_BASE_CHANCE: f64 = 0.05;
+    const LOOT_CHANCE_PER_XP: f64 = 0.002;
+    const REPEAT_KILL_PENALTY: f32 = 0.25; // Each outclassed kill of a kind shaves this much more off the next
+    const ELITE_LOOT_BONUS: f64 = 0.25;
+    const MAX_LOOT_CHANCE: f64 = 0.75;
+    const CORPSE_ROT_TURNS: u32 = 80;
+    const SNEAK_SPEED: f32 = 0.5;
+    const BURROW_DETOUR: i32 = 2; // Burrowers dig rather than walk more than this many times the straight distance
+    const CORPSE_SCENT_RANGE: i32 = 10;
+    const STARTING_LIGHT_FUEL: u32 = 600;
+    const MAX_LIGHT_FUEL: u32 = 1000;
+    const GUTTERING_TURNS: u32 = 50; // The light shrinks over its last turns
+    const DARK_RADIUS: f32 = 1.0; // All that is seen without a light
+    const SEARCH_RADIUS: i32 = 2;
+    const SEARCH_CHANCE: f64 = 0.3;
+    const SEARCH_CHANCE_PER_PERCEPTION: f64 = 0.03;
+    const PASSIVE_SEARCH_CHANCE_PER_PERCEPTION: f64 = 0.01; // Only for doors right next to the player
+    const DAYLIGHT_RADIUS: f32 = 60.0; // The town is lit end to end by day
+    const TURNS_PER_HOUR: u32 = 25;
+    const START_HOUR: u32 = 8; // Runs begin in the morning
+    const DAWN_HOUR: u32 = 6;
+    const DUSK_HOUR: u32 = 20;
+    const HEALER_PRICE: u32 = 2; // Gold for each hit point restored
+
+    fn new(config: GameConfig, run_modifiers: Vec<Modifier>, upgrades: &HashMap<ForgeUpgrade, u32>) -> Self {
+        let item_registry = ItemRegistry::load();
+        let identification = Identification::new(&item_registry, config.seed);
+        let class = config.character_class;
+        let rng = StdRng::seed_from_u64(config.seed);
+        let scenario = config.scenario.clone();
+        let map_manager = MapManager::new(config);
+        let mut game_state = Self {
+            player: Entity::new_player(class),
+            monsters: Vec::new(),
+            combat_log: Vec::new(),
+            player_turn: true,
+            ground_items: Vec::new(),
+            inventory_open: false,
+            inventory_selection: 0,
+            map_manager,
+            level_states: HashMap::new(),
+            turn: 0,
+            spawned_artifacts: HashSet::new(),
+            artifact_recharge: HashMap::new(),
+            encyclopedia: Encyclopedia::load(),
+            encyclopedia_open: false,
+            hinted_monsters: HashSet::new(),
+            feedback_open: false,
+            feedback_text: String::new(),
+            monster_registry: MonsterRegistry::load(),
+            item_registry,
+            identification,
+            run_modifiers,
+            auto_path: Vec::new(),
+            auto_path_visible_monsters: 0,
+            action_queue: Vec::new(),
+            action_queue_danger: (0, 0),
+            recording: None,
+            events: Vec::new(),
+            kills: 0,
+            ally_kills: 0,
+            deepest_level: 0,
+            score_recorded: false,
+            victorious: false,
+            items_collected: 0,
+            player_dijkstra: None,
+            projectiles: Vec::new(),
+            strike_marks: Vec::new(),
+            damage_popups: Vec::new(),
+            held_verb: None,
+            allies: Vec::new(),
+            party_open: false,
+            party_selection: 0,
+            targeting: None,
+            look_cursor: None,
+            spawn_preview: None,
+            scenario: scenario.map(ScenarioController::new),
+            digging: None,
+            escape: None,
+            exploring: None,
+            radial_menu: None,
+            known_spells: Vec::new(),
+            spells_open: false,
+            spell_selection: 0,
+            tool_cooldowns: HashMap::new(),
+            turn_profile: TurnProfile::default(),
+            heard_noises: HashMap::new(),
+            shop_open: false,
+            shop_selection: 0,
+            shop_selling: false,
+            stash_open: false,
+            stash_selection: 0,
+            stash_storing: false,
+            town_stocked_for: None,
+            floor_stats: HashMap::new(),
+            outclassed_kills: HashMap::new(),
+            floor_summary: None,
+            shop_discount: 0.0,
+            bounty_board: BountyBoard::new(),
+            board_open: false,
+            forge_open: false,
+            forge_selection: 0,
+            forge_gem_selection: 0,
+            board_selection: 0,
+            reputation: 0,
+            turn_taken: false,
+            clock: 0.0,
+            sneaking: false,
+            light_fuel: Self::STARTING_LIGHT_FUEL,
+            parley: None,
+            parley_selection: 0,
+            message_history: Vec::new(),
+            log_open: false,
+            log_scroll: 0,
+            log_filter: None,
+            rng,
+        };
+
+        for modifier in game_state.run_modifiers.clone() {
+            game_state.add_log_message(MessageCategory::System, modifier.announcement().to_string());
+        }
+        if let Some(scenario) = game_state.scenario.as_ref().map(|controller| &controller.scenario) {
+            let message = format!("Scenario: {}. {}", scenario.name, scenario.description);
+            game_state.add_log_message(MessageCategory::System, message);
+        }
+        game_state.give_starting_kit(class);
+        game_state.apply_forge_upgrades(upgrades);
+        game_state.enforce_run_modifiers();
+        game_state.open_shared_stash();
+        game_state.initialize_current_level();
+        game_state
+    }
+
+    fn visible_monster_count(&self) -> usize {
+        self.monsters
+            .iter()
+            .filter(|m| m.is_alive() && m.faction == Faction::Hostile && self.is_lit(m.x, m.y))
+            .count()
+    }
+
+    fn has_item_at(&self, x: f32, y: f32) -> bool {
+        self.ground_items.iter().any(|(ix, iy, _)| *ix == x && *iy == y)
+    }
+
+    // Plan a walk to a clicked tile, which has to be lit and walkable
+    fn start_auto_path(&mut self, target: (i32, i32)) {
+        self.exploring = None;
+        let map = self.map_manager.current_map();
+        if !map.is_walkable(target.0, target.1) || !self.is_lit(target.0 as f32, target.1 as f32) {
+            return;
+        }
+
+        let start = (self.player.x as i32, self.player.y as i32);
+        if let Some(path) = map.find_player_path(start, target) {
+            self.auto_path = path.into_iter().skip(1).collect();
+            self.auto_path_visible_monsters = self.visible_monster_count();
+        }
+    }
+
+    // Walk towards the closest part of the floor not seen yet. Each leg heads for one unexplored
+    // tile and the next is planned on arrival, a walk cut short along the way ends the exploring
+    fn start_exploring(&mut self) {
+        if self.visible_monster_count() > 0 {
+            self.add_log_message(MessageCategory::Warning, "Not with a monster in view.".to_string());
+            return;
+        }
+        let start = (self.player.x as i32, self.player.y as i32);
+        let radius = self.light_radius();
+        let map = self.map_manager.current_map_mut();
+        map.explore_around(start, radius);
+        let path = map.nearest_unexplored(start).and_then(|goal| Some((goal, map.find_player_path(start, goal)?)));
+        match path {
+            Some((goal, path)) => {
+                self.auto_path = path.into_iter().skip(1).collect();
+                self.auto_path_visible_monsters = 0;
+                self.exploring = Some(goal);
+            }
+            None => {
+                self.exploring = None;
+                self.add_log_message(MessageCategory::System, "There is nothing left to explore here.".to_string());
+            }
+        }
+    }
+
+    // Next step of the current walk, stopping early if a new monster comes into view
+    fn next_auto_path_step(&mut self) -> Option<(f32, f32)> {
+        if self.auto_path.is_empty() {
+            let arrived = self.exploring == Some((self.player.x as i32, self.player.y as i32));
+            self.exploring = None;
+            if !arrived {
+                return None;
+            }
+            self.start_exploring();
+            if self.auto_path.is_empty() {
+                return None;
+            }
+        }
+        if self.visible_monster_count() > self.auto_path_visible_monsters {
+            self.exploring = None;
+            self.auto_path.clear();
+            self.add_log_message(MessageCategory::Warning, "You stop, a monster comes into view.".to_string());
+            return None;
+        }
+
+        let (x, y) = self.auto_path.remove(0);
+        Some((x as f32, y as f32))
+    }
+
+    // Shift+F1 to F4 starts or stops recording into that slot, F1 to F4 alone plays it back
+    fn handle_macro_keys(&mut self) {
+        let shift = shift_down();
+        let Some(slot) = KeyMacros::KEYS.iter().position(|&key| is_key_pressed(key)) else {
+            return;
+        };
+
+        if shift {
+            match self.recording.take() {
+                Some((recorded, actions)) if recorded == slot => self.save_macro(slot, actions),
+                _ => {
+                    self.recording = Some((slot, Vec::new()));
+                    self.add_log_message(MessageCategory::System, format!("Recording macro F{}, Shift+F{} again to stop.", slot + 1, slot + 1));
+                }
+            }
+            return;
+        }
+
+        let actions = self.map_manager.config.macros.slots[slot].clone();
+        if actions.is_empty() {
+            self.add_log_message(MessageCategory::Warning, format!("No macro recorded on F{}.", slot + 1));
+            return;
+        }
+        self.auto_path.clear();
+        self.action_queue = actions;
+        self.action_queue_danger = (self.visible_monster_count(), self.player.stats.hp);
+        self.add_log_message(MessageCategory::System, format!("Playing macro F{}.", slot + 1));
+    }
+
+    fn save_macro(&mut self, slot: usize, actions: Vec<MacroAction>) {
+        self.add_log_message(MessageCategory::System, format!("Macro F{} saved, {} actions.", slot + 1, actions.len()));
+        self.map_manager.config.macros.slots[slot] = actions;
+        self.map_manager.config.macros.save();
+    }
+
+    // Keyboard actions go into the macro being recorded, which stops itself when full
+    fn record_action(&mut self, action: MacroAction) {
+        let Some((slot, actions)) = self.recording.as_mut() else {
+            return;
+        };
+        actions.push(action);
+        if actions.len() >= KeyMacros::MAX_ACTIONS {
+            let (slot, actions) = (*slot, std::mem::take(actions));
+            self.recording = None;
+            self.save_macro(slot, actions);
+        }
+    }
+
+    // Next action of the playing macro, which stops as soon as a monster shows up or the player gets hurt
+    fn next_queued_action(&mut self) -> Option<MacroAction> {
+        if self.action_queue.is_empty() {
+            return None;
+        }
+        let (visible_monsters, hp) = self.action_queue_danger;
+        if self.visible_monster_count() > visible_monsters || self.player.stats.hp < hp {
+            self.action_queue.clear();
+            self.add_log_message(MessageCategory::Warning, "You stop the macro, danger is near.".to_string());
+            return None;
+        }
+
+        Some(self.action_queue.remove(0))
+    }
+
+    fn emit_event(&mut self, event: GameEvent) {
+        self.events.push(event);
+    }
+
+    fn drain_events(&mut self) -> Vec<GameEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // Scenarios hear the same events as the sounds and effects, and look the run over on the ones
+    // their goal cares about to see if it has been reached, or can't be any more
+    fn handle_scenario_event(&mut self, event: &GameEvent) {
+        let Some(controller) = self.scenario.as_ref().filter(|controller| !controller.settled) else {
+            return;
+        };
+        if !controller.scenario.goal.judged_on(event) {
+            return;
+        }
+        let outcome = match &controller.scenario.goal {
+            ScenarioGoal::Survive { turns } => (self.turn >= *turns).then_some(true),
+            ScenarioGoal::Collect { item, count } => {
+                let name = self.item_registry.create(item).map(|item| item.name);
+                let carried = self.player.inventory
+                    .as_ref()
+                    .map_or(0, |inv| inv.items.iter().filter(|i| Some(&i.name) == name.as_ref()).count());
+                (carried as u32 >= *count).then_some(true)
+            }
+            // Lost once they die or are sent away, won when they reach the town with the player
+            ScenarioGoal::Escort { .. } => controller.escort.and_then(|id| {
+                if self.allies.iter().any(|a| a.entity.id == id && a.entity.is_alive()) {
+                    self.in_town().then_some(true)
+                } else {
+                    Some(false)
+                }
+            }),
+        };
+        let Some(won) = outcome else {
+            return;
+        };
+
+        let message = match &controller.scenario.goal {
+            _ if won => format!("Scenario complete: {}!", controller.scenario.name),
+            ScenarioGoal::Escort { npc, .. } => format!("The {} is gone. {} can no longer be won.", npc, controller.scenario.name),
+            _ => format!("{} can no longer be won.", controller.scenario.name),
+        };
+        if let Some(controller) = self.scenario.as_mut() {
+            controller.settled = true;
+        }
+        if won {
+            self.victorious = true;
+            self.add_log_message(MessageCategory::System, message);
+        } else {
+            self.add_log_message(MessageCategory::Warning, message);
+        }
+    }
+
+    fn player_level(&self) -> i32 {
+        self.player.stats.level_system.as_ref().map_or(1, |ls| ls.level)
+    }
+
+    // Compare the player against the start of the frame and emit events for what changed
+    fn emit_player_events(&mut self, hp_before: i32, level_before: i32) {
+        if self.player.stats.hp < hp_before {
+            self.emit_event(GameEvent::PlayerDamaged {
+                amount: hp_before - self.player.stats.hp,
+                max_hp: self.player.stats.max_hp,
+            });
+        }
+        if self.player_level() > level_before {
+            self.emit_event(GameEvent::LevelUp);
+        }
+    }
+
+    fn give_starting_kit(&mut self, class: CharacterClass) {
+        self.known_spells = class.starting_spells().to_vec();
+        let Some(inventory) = self.player.inventory.as_mut() else {
+            return;
+        };
+        for item in class.starting_kit().iter().filter_map(|id| self.item_registry.create(id)) {
+            let equip = item.is_equipment();
+            if inventory.add_item(item).is_ok() && equip {
+                let _ = inventory.equip_item(inventory.items.len() - 1);
+            }
+        }
+        // Starting gear is known to be free of curses
+        for item in inventory.items.iter_mut().chain(inventory.equipment.values_mut()) {
+            item.curse_known = true;
+        }
+    }
+
+    fn apply_forge_upgrades(&mut self, upgrades: &HashMap<ForgeUpgrade, u32>) {
+        let rank = |upgrade| upgrades.get(&upgrade).copied().unwrap_or(0);
+        self.shop_discount = 0.05 * rank(ForgeUpgrade::Haggler) as f32;
+
+        let bonus_hp = 5 * rank(ForgeUpgrade::Vitality) as i32;
+        self.player.stats.max_hp += bonus_hp;
+        self.player.stats.hp += bonus_hp;
+
+        for _ in 0..rank(ForgeUpgrade::StartingPotion) {
+            if let (Some(potion), Some(inventory)) = (self.item_registry.create("health_potion"), self.player.inventory.as_mut()) {
+                let _ = inventory.add_item(potion);
+            }
+        }
+    }
+
+    // Embers are the meta currency, one per hundred points of score
+    fn embers_earned(&self) -> u32 {
+        (self.score() / 100).max(1)
+    }
+
+    fn enforce_run_modifiers(&mut self) {
+        if self.has_modifier(Modifier::OneHp) {
+            self.player.stats.max_hp = 1;
+            self.player.stats.hp = self.player.stats.hp.min(1);
+        }
+    }
+
+    // A generator of its own for code that rolls while holding parts of the state, drawn
+    // from the run's so it stays reproducible
+    fn fork_rng(&mut self) -> StdRng {
+        StdRng::seed_from_u64(self.rng.gen())
+    }
+
+    // With fragile equipment, every swing or hit taken risks breaking the item involved
+    fn wear_equipment(&mut self, weapon: bool) {
+        if !self.has_modifier(Modifier::FragileEquipment) || !self.rng.gen_bool(0.03) {
+            return;
+        }
+
+        // A hit taken lands on one of the pieces that protect
+        let slots: &[EquipSlot] = if weapon {
+            &[EquipSlot::Weapon]
+        } else {
+            &[EquipSlot::Armor, EquipSlot::Helmet, EquipSlot::Shield, EquipSlot::Boots]
+        };
+        let broken = self.player.inventory.as_mut().and_then(|inv| {
+            let worn: Vec<EquipSlot> = slots.iter().copied().filter(|slot| inv.equipment.contains_key(slot)).collect();
+            let slot = worn.choose(&mut self.rng)?;
+            inv.equipment.remove(slot)
+        });
+        if let Some(item) = broken {
+            self.add_log_message(MessageCategory::Item, format!("Your {} breaks!", item.name));
+        }
+    }
+
+    // A fingerprint of all gameplay state, two runs that agree on it every turn haven't diverged
+    fn state_hash(&self) -> u64 {
+        let mut hasher = StableHasher::new();
+        hasher.write_u64(self.map_manager.config.seed);
+        hasher.write_u32(self.turn);
+        hasher.write_i32(self.map_manager.current_level);
+        hasher.write_i32(self.map_manager.current_branch.map_or(-1, |branch| branch as i32));
+        for stat in [self.kills, self.items_collected, self.reputation] {
+            hasher.write_u32(stat);
+        }
+
+        self.player.hash_state(&mut hasher);
+        for monster in &self.monsters {
+            monster.hash_state(&mut hasher);
+        }
+        for ally in &self.allies {
+            ally.entity.hash_state(&mut hasher);
+            // Ids count up over the whole process, so only the kind of order goes in
+            let _ = write!(hasher, "{}|", ally.order.name());
+        }
+        for (x, y, item) in &self.ground_items {
+            hasher.write_u32(x.to_bits());
+            hasher.write_u32(y.to_bits());
+            let _ = write!(hasher, "{:?}|", item);
+        }
+
+        // Hash maps and sets iterate in a random order, so sort them first
+        let map = self.map_manager.current_map();
+        let _ = write!(hasher, "{:?}|", map.tiles);
+        let mut overlays: Vec<_> = map.terrain_overlays.iter().map(|(pos, o)| (*pos, o.kind, o.turns_left)).collect();
+        overlays.sort_by_key(|(pos, _, _)| *pos);
+        let mut boulders: Vec<_> = map.boulders.iter().copied().collect();
+        boulders.sort();
+        let _ = write!(hasher, "{:?}|{:?}|", overlays, boulders);
+        if let Some(shop) = &map.shop {
+            let _ = write!(hasher, "{:?}|", shop.stock);
+        }
+
+        hasher.finish()
+    }
+
+    // Tell the player about a noise out of sight, if it carries far enough to reach them
+    fn make_noise(&mut self, noise: Noise, origin: (i32, i32)) {
+        if self.is_lit(origin.0 as f32, origin.1 as f32) {
+            return;
+        }
+        let player = (self.player.x as i32, self.player.y as i32);
+        let Some(distance) = self.map_manager.current_map().sound_distance(origin, player, noise.loudness()) else {
+            return;
+        };
+        if self.heard_noises.get(&noise).is_some_and(|&turn| self.turn < turn + Noise::REPEAT_TURNS) {
+            return;
+        }
+        self.heard_noises.insert(noise, self.turn);
+        self.add_log_message(MessageCategory::System, format!("You hear {} to the {}.", noise.describe(), compass_direction(player, origin)));
+        self.emit_event(GameEvent::NoiseHeard {
+            noise,
+            volume: 1.0 - distance as f32 / (noise.loudness() + 1) as f32,
+        });
+    }
+
+    // Monsters within earshot of a noise come to see what made it
+    fn alert_monsters(&mut self, noise: Noise, origin: (i32, i32)) {
+        let map = self.map_manager.current_map();
+        for monster in self.monsters.iter_mut().filter(|m| m.is_alive()) {
+            let pos = (monster.x as i32, monster.y as i32);
+            if map.sound_distance(origin, pos, noise.loudness()).is_some() {
+                monster.heard_noise = Some(origin);
+            }
+        }
+    }
+
+    // Catch the drawn positions up with any moves made this frame
+    fn update_tweens(&mut self, now: f32) {
+        let entities = std::iter::once(&mut self.player)
+            .chain(self.monsters.iter_mut())
+            .chain(self.allies.iter_mut().map(|a| &mut a.entity));
+        for entity in entities {
+            entity.tween.retarget((entity.x, entity.y), now);
+        }
+    }
+
+    fn log_state_hash(&self) {
+        if self.map_manager.config.debug_mode {
+            eprintln!("turn {} state {:016x}", self.turn, self.state_hash());
+        }
+    }
+
+    fn score(&self) -> u32 {
+        let level = self.player_level();
+        let victory_bonus = if self.victorious { 1000 } else { 0 };
+        let base = (self.deepest_level + 1) * 100 + self.kills as i32 * 10 + level * 50 + victory_bonus;
+        let multiplier: f32 = self.run_modifiers.iter().map(|m| m.score_multiplier()).product();
+        (base as f32 * multiplier) as u32
+    }
+
+    // Record the score and pay out embers, once per run
+    fn finish_run(&mut self) {
+        if self.score_recorded {
+            return;
+        }
+        self.score_recorded = true;
+
+        let score = self.score();
+        let mut high_scores = HighScores::load();
+        high_scores.add(HighScoreEntry {
+            score,
+            floor: self.deepest_level + 1,
+            level: self.player_level(),
+            modifiers: self.run_modifiers
+                .iter()
+                .map(|m| m.name().to_string())
+                .chain(self.scenario.as_ref().map(|controller| controller.scenario.name.clone()))
+                .collect(),
+        });
+        high_scores.save();
+        if self.victorious {
+            self.add_log_message(MessageCategory::System, format!("You have won! Final score: {}", score));
+        } else {
+            self.add_log_message(MessageCategory::System, format!("You have died! Final score: {}", score));
+        }
+
+        let embers = self.embers_earned();
+        let mut profile = Profile::load();
+        profile.embers += embers;
+        profile.save();
+        self.add_log_message(MessageCategory::System, format!("You earned {} embers for the Forge.", embers));
+    }
+
+    fn save_current_level_state(&mut self) {
+        // Create new state with cloned data
+        let new_state = LevelState {
+            monsters: self.monsters.clone(),
+            ground_items: self.ground_items.clone(),
+        };
+
+        // Save the state
+        self.level_states.insert(self.map_manager.current_floor(), new_state);
+    }
+
+    // Everything that happens on the way out of a floor: hunters next to the stairs get a free swing,
+    // the climb takes a turn, and whatever is chasing the player follows them. False if it killed them
+    fn leave_floor(&mut self, floor: FloorId, going_down: bool) -> bool {
+        let player_pos = (self.player.x as i32, self.player.y as i32);
+        let directions = self.map_manager.current_map().directions();
+        for i in 0..self.monsters.len() {
+            let monster = &self.monsters[i];
+            let pos = (monster.x as i32, monster.y as i32);
+            let adjacent = directions.iter().any(|(dx, dy)| (pos.0 + dx, pos.1 + dy) == player_pos);
+            if !adjacent || !monster.is_alive() || monster.ai_state != AiState::Hunting || monster.has_status(StatusKind::Confused) {
+                continue;
+            }
+            self.add_log_message(MessageCategory::Combat, format!("The {} strikes as you turn for the stairs!", monster.name));
+            let hp_before = self.player.stats.hp;
+            let messages = self.monsters[i].attack(&mut self.player, &mut self.rng);
+            self.damage_popups.extend(DamagePopup::after_hit(&self.player, hp_before, true));
+            for message in messages {
+                self.add_log_message(MessageCategory::Combat, message);
+            }
+            if !self.player.is_alive() {
+                return false;
+            }
+        }
+
+        self.take_turn();
+        self.map_manager.redirect_pursuers(self.map_manager.current_floor(), floor, self.turn);
+        self.send_pursuers(floor, going_down);
+        self.summarize_floor();
+        self.save_current_level_state();
+        true
+    }
+
+    // Taken on the way out, after anything following the player has left with them
+    fn summarize_floor(&mut self) {
+        if self.in_town() {
+            return;
+        }
+        let floor = self.map_manager.current_floor();
+        self.floor_summary = Some(FloorSummary {
+            floor: self.map_manager.floor_label(),
+            stats: self.floor_stats.get(&floor).copied().unwrap_or_default(),
+            monsters_left: self.monsters.iter().filter(|m| m.is_alive()).count(),
+            items_left: self.ground_items.iter().filter(|(_, _, item)| !matches!(item.item_type, ItemType::Corpse(_, _))).count(),
+            unexplored_percent: self.map_manager.current_map().unexplored_percent(),
+            shown_at: self.clock,
+        });
+    }
+
+    fn draw_floor_summary(&self) {
+        let Some(summary) = &self.floor_summary else {
+            return;
+        };
+        let age = self.clock - summary.shown_at;
+        if age > FloorSummary::SECONDS {
+            return;
+        }
+        let alpha = ((FloorSummary::SECONDS - age) / FloorSummary::FADE_SECONDS).min(1.0);
+        let fade = |color: Color| Color::new(color.r, color.g, color.b, color.a * alpha);
+
+        let (width, height) = (260.0, 120.0);
+        let (x, y) = (screen_width() - width - 10.0, TOP_BAR_HEIGHT + 10.0);
+        draw_rectangle(x, y, width, height, fade(Color::new(0.0, 0.0, 0.0, 0.8)));
+        draw_rectangle_lines(x, y, width, height, 2.0, fade(GRAY));
+        draw_text(&format!("Left floor {}", summary.floor), x + 10.0, y + 22.0, 20.0, fade(YELLOW));
+        let lines = [
+            format!("Monsters slain: {}, still there: {}", summary.stats.kills, summary.monsters_left),
+            format!("Items left behind: {}", summary.items_left),
+            format!("Unexplored: {}%", summary.unexplored_percent),
+            format!("Turns spent: {}", summary.stats.turns),
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            draw_text(line, x + 10.0, y + 46.0 + i as f32 * 20.0, 16.0, fade(LIGHTGRAY));
+        }
+    }
+
+    // Hunters close behind the player follow them through the stairs a few turns later
+    fn send_pursuers(&mut self, floor: FloorId, going_down: bool) {
+        const PURSUIT_RANGE: i32 = 6;
+        let player_pos = (self.player.x as i32, self.player.y as i32);
+
+        let (pursuers, staying): (Vec<Entity>, Vec<Entity>) = std::mem::take(&mut self.monsters)
+            .into_iter()
+            .partition(|m| {
+                m.is_alive()
+                    && m.ai_state == AiState::Hunting
+                    && !m.has_status(StatusKind::Confused)
+                    && manhattan_distance(player_pos, (m.x as i32, m.y as i32)) <= PURSUIT_RANGE
+            });
+        self.monsters = staying;
+
+        let from = self.map_manager.current_floor();
+        for mut entity in pursuers {
+            let distance = manhattan_distance(player_pos, (entity.x as i32, entity.y as i32)) as u32;
+            entity.last_seen_player = None;
+            self.map_manager.send_to_floor(floor, FloorTransfer {
+                entity,
+                arrive_turn: self.turn + distance + 2,
+                going_down,
+                pursuing: true,
+                from,
+            });
+        }
+    }
+
+    // Bring in monsters that took the stairs to this floor, next to the stairs they came by
+    fn process_floor_arrivals(&mut self) {
+        let floor = self.map_manager.current_floor();
+        for transfer in self.map_manager.take_arrivals(floor, self.turn) {
+            let map = self.map_manager.current_map();
+            let stairs = match (transfer.going_down, transfer.from.branch != floor.branch) {
+                (true, _) => map.up_stairs,
+                (false, true) => map.branch_stairs,
+                (false, false) => map.down_stairs,
+            };
+            let Some((stairs_x, stairs_y)) = stairs else {
+                continue;
+            };
+
+            let is_free = |(x, y): (i32, i32)| {
+                map.is_walkable(x, y)
+                    && (self.player.x as i32, self.player.y as i32) != (x, y)
+                    && !self.monsters.iter().any(|m| m.is_alive() && (m.x as i32, m.y as i32) == (x, y))
+            };
+            let stairs = (stairs_x as i32, stairs_y as i32);
+            let spot = [(0, 0), (0, 1), (1, 0), (0, -1), (-1, 0), (1, 1), (-1, 1), (1, -1), (-1, -1)]
+                .iter()
+                .map(|(dx, dy)| (stairs.0 + dx, stairs.1 + dy))
+                .find(|&tile| is_free(tile));
+            let Some((x, y)) = spot else {
+                // Stairs are crowded, try again next turn
+                self.map_manager.send_to_floor(floor, transfer);
+                continue;
+            };
+
+            let mut entity = transfer.entity;
+            entity.x = x as f32;
+            entity.y = y as f32;
+            if transfer.pursuing && self.is_lit(entity.x, entity.y) {
+                let direction = if transfer.going_down { "down" } else { "up" };
+                self.add_log_message(MessageCategory::Warning, format!("The {} follows you {} the stairs!", entity.name, direction));
+            }
+            self.monsters.push(entity);
+        }
+    }
+
+    // A floor never left before has nothing on it yet, which going up only happens with the town
+    fn load_level_state(&mut self, floor: FloorId) {
+        match self.level_states.get(&floor) {
+            Some(state) => {
+                self.monsters = state.monsters.clone();
+                self.ground_items = state.ground_items.clone();
+                self.rot_corpses();
+            }
+            None => {
+                self.monsters.clear();
+                self.ground_items.clear();
+            }
+        }
+    }
+
+    // Corpses left lying too long are gone, this floor's while the player is here and the rest on return
+    fn rot_corpses(&mut self) {
+        let turn = self.turn;
+        self.ground_items.retain(|(_, _, item)| !matches!(item.item_type, ItemType::Corpse(_, rots_at) if rots_at <= turn));
+    }
+
+    fn corpse_at(&self, x: f32, y: f32) -> Option<usize> {
+        self.ground_items
+            .iter()
+            .position(|(ix, iy, item)| (*ix, *iy) == (x, y) && matches!(item.item_type, ItemType::Corpse(_, _)))
+    }
+
+    // Use the fountain, altar or chest the player stands on or next to
+    fn use_feature(&mut self) {
+        let (px, py) = (self.player.x as i32, self.player.y as i32);
+        let map = self.map_manager.current_map();
+        let found = std::iter::once((0, 0))
+            .chain(ALL_DIRECTIONS)
+            .map(|(dx, dy)| (px + dx, py + dy))
+            .find_map(|position| map.features.get(&position).map(|feature| (position, *feature)));
+        let Some((position, feature)) = found else {
+            self.add_log_message(MessageCategory::Warning, "There is nothing here to use.".to_string());
+            return;
+        };
+
+        match feature {
+            _ if feature.spent() => self.add_log_message(MessageCategory::Warning, format!("The {} has nothing more to give.", feature.name())),
+            Feature::Fountain { .. } => self.drink_from_fountain(position),
+            Feature::Altar { holy, .. } => self.kneel_at_altar(position, holy),
+            Feature::Chest { locked, .. } => self.open_chest(position, locked),
+        }
+    }
+
+    fn drink_from_fountain(&mut self, position: (i32, i32)) {
+        self.take_turn();
+        let mut rng = self.fork_rng();
+        let message = match rng.gen_range(0..5) {
+            0 => {
+                let healed = (self.player.stats.max_hp / 4).min(self.player.stats.max_hp - self.player.stats.hp);
+                self.player.stats.hp += healed;
+                match healed {
+                    0 => "The water is cool and clear.".to_string(),
+                    healed => format!("The water is cool and clear. You recover {} HP.", healed),
+                }
+            }
+            1 => {
+                self.player.stats.mana = self.player.stats.max_mana;
+                "The water tingles on your tongue. Your mana is restored.".to_string()
+            }
+            2 => {
+                self.player.add_status(StatusKind::Hasted, 10);
+                "The water fizzes. You feel quick on your feet!".to_string()
+            }
+            3 => {
+                self.player.afflict(StatusKind::Poisoned(1), 5, None);
+                "The water is foul! You feel sick.".to_string()
+            }
+            _ => "The water tastes of nothing much.".to_string(),
+        };
+        self.add_log_message(MessageCategory::System, message);
+
+        if rng.gen_bool(Feature::DRY_UP_CHANCE) {
+            self.map_manager.current_map_mut().features.insert(position, Feature::Fountain { dry: true });
+            self.add_log_message(MessageCategory::System, "The fountain gurgles and runs dry.".to_string());
+        }
+    }
+
+    // A holy altar lifts every curse from the player's gear and shows what was clean all along.
+    // Any other curses a piece they have on
+    fn kneel_at_altar(&mut self, position: (i32, i32), holy: bool) {
+        let Some(inventory) = self.player.inventory.as_mut() else {
+            return;
+        };
+        let message = if holy {
+            let lifted = inventory.remove_curses();
+            for item in inventory.items.iter_mut().chain(inventory.equipment.values_mut()) {
+                item.curse_known = true;
+            }
+            match lifted {
+                0 => "Light washes over you. Nothing you carry is cursed.".to_string(),
+                1 => "Light washes over you, and the curse on your gear lifts.".to_string(),
+                lifted => format!("Light washes over you, and {} curses lift from your gear.", lifted),
+            }
+        } else {
+            let worn: Vec<&mut Item> = inventory.equipment.values_mut().filter(|item| !item.cursed).collect();
+            match worn.into_iter().choose(&mut self.rng) {
+                Some(item) => {
+                    item.cursed = true;
+                    item.curse_known = true;
+                    format!("Shadows coil around your {}. It is cursed!", item.name)
+                }
+                None => "Shadows coil around you, but find nothing to take hold of.".to_string(),
+            }
+        };
+        self.map_manager.current_map_mut().features.insert(position, Feature::Altar { holy, used: true });
+        self.add_log_message(MessageCategory::System, message);
+        self.take_turn();
+    }
+
+    // Locked chests take a chest key, so the floor's door key is kept for the treasure room. The loot spills out where the chest stands
+    fn open_chest(&mut self, position: (i32, i32), locked: bool) {
+        if locked {
+            let key = self.player.inventory.as_mut().and_then(|inventory| {
+                let index = inventory.items.iter().position(|item| item.item_type == ItemType::ChestKey)?;
+                inventory.remove_item(index)
+            });
+            match key {
+                Some(key) => self.add_log_message(MessageCategory::Item, format!("You unlock the chest with the {}.", key.name)),
+                None => {
+                    self.add_log_message(MessageCategory::Warning, "The chest is locked. There must be a key somewhere on this floor.".to_string());
+                    return;
+                }
+            }
+        }
+
+        let mut rng = self.fork_rng();
+        let depth = self.map_manager.current_level;
+        let mut loot: Vec<Item> = (0..Feature::CHEST_ITEMS).filter_map(|_| self.item_registry.roll((depth + 1).min(FINAL_FLOOR), &mut rng)).collect();
+        loot.push(Item::new_gold(rng.gen_range(10..=25) * (depth as u32 + 1)));
+        let names: Vec<String> = loot.iter().map(|item| self.identification.name(item)).collect();
+        for item in loot {
+            self.ground_items.push((position.0 as f32, position.1 as f32, item));
+        }
+
+        self.map_manager.current_map_mut().features.insert(position, Feature::Chest { locked: false, opened: true });
+        self.add_log_message(MessageCategory::Item, format!("The chest creaks open. Inside: {}.", names.join(", ")));
+        self.check_and_pickup_items();
+        self.take_turn();
+    }
+
+    // Spend a turn going over the walls close by for hidden doors, the sharper the player's eyes the likelier
+    fn search(&mut self) {
+        self.take_turn();
+        let chance = (Self::SEARCH_CHANCE + Self::SEARCH_CHANCE_PER_PERCEPTION * self.player.stats.perception as f64).min(1.0);
+        if self.reveal_secret_doors(Self::SEARCH_RADIUS, chance) == 0 {
+            self.add_log_message(MessageCategory::System, "You search the walls around you, but find nothing.".to_string());
+        }
+    }
+
+    // Each hidden door within the radius turns up on a roll of the chance. Returns how many did
+    fn reveal_secret_doors(&mut self, radius: i32, chance: f64) -> usize {
+        let (px, py) = (self.player.x as i32, self.player.y as i32);
+        let mut nearby: Vec<(i32, i32)> = self.map_manager
+            .current_map()
+            .secret_doors
+            .iter()
+            .copied()
+            .filter(|&(x, y)| (x - px).abs().max((y - py).abs()) <= radius)
+            .collect();
+        nearby.sort();
+        nearby.retain(|_| self.rng.gen_bool(chance));
+
+        let map = self.map_manager.current_map_mut();
+        for &(x, y) in &nearby {
+            map.secret_doors.remove(&(x, y));
+            map.set_tile(x, y, Tile::DoorClosed);
+        }
+        for _ in &nearby {
+            self.add_log_message(MessageCategory::System, "You find a hidden door in the wall!".to_string());
+        }
+        nearby.len()
+    }
+
+    fn eat_corpse(&mut self) {
+        let Some(index) = self.corpse_at(self.player.x, self.player.y) else {
+            self.add_log_message(MessageCategory::Warning, "There is nothing here to eat.".to_string());
+            return;
+        };
+        let (_, _, corpse) = self.ground_items.remove(index);
+        let ItemType::Corpse(carcass, _) = corpse.item_type else {
+            return;
+        };
+
+        let healed = carcass.heals.min(self.player.stats.max_hp - self.player.stats.hp).max(0);
+        self.player.stats.hp += healed;
+        self.add_log_message(MessageCategory::Item, format!("You eat the {}. (+{} HP)", corpse.name, healed));
+        if let Some(risk) = carcass.risk.filter(|risk| self.rng.gen_bool(risk.chance)) {
+            self.player.afflict(risk.status, risk.turns, None);
+            self.add_log_message(MessageCategory::Warning, format!("Something in the {} disagrees with you. You are {}!", corpse.name, risk.status.name()));
+        }
+        self.take_turn();
+    }
+
+    fn get_current_level_state(&self) -> Option<&LevelState> {
+        self.level_states.get(&self.map_manager.current_floor())
+    }
+
+    // Helper method to get current level state mutably
+    fn get_current_level_state_mut(&mut self) -> Option<&mut LevelState> {
+        self.level_states.get_mut(&self.map_manager.current_floor())
+    }
+
+    fn initialize_current_level(&mut self) {
+        if let Some(first_row) = self.map_manager.current_map().rooms.first() {
+            if let Some(first_room) = first_row.first() {
+                let (center_x, center_y) = first_room.center();
+                self.player.x = center_x as f32;
+                self.player.y = center_y as f32;
+            }
+        }
+
+        let mut rng = self.fork_rng();
+        self.monsters = self.roll_monsters(&mut rng);
+        self.spawn_items_for_current_level();
+        self.spawn_treasure_room_loot();
+        self.spawn_secret_room_loot();
+        self.spawn_prefab_contents();
+        self.spawn_shop();
+        self.spawn_shrine();
+        self.spawn_vault_loot();
+        self.spawn_spider_lair();
+        self.spawn_cellar();
+        self.spawn_final_floor();
+        self.spawn_branch_reward();
+        self.spawn_bounty_board();
+        self.spawn_ore_veins();
+        self.spawn_forge();
+        self.spawn_features();
+        self.spawn_scenario_pieces();
+
+        let depth = self.map_manager.current_level;
+        self.map_manager.current_map_mut().modifiers = Modifier::roll_floor(depth, &mut rng);
+        self.announce_floor_modifiers();
+    }
+
+    // The floor's monsters as the spawn tables roll them, placed but not yet in play
+    fn roll_monsters(&self, rng: &mut impl Rng) -> Vec<Entity> {
+        let mut new_monsters = Vec::new();
+        let map = self.map_manager.current_map();
+        let branch = self.map_manager.current_branch_template();
+
+        // Spend the floor's threat budget on monsters scattered over every room but the first,
+        // so each floor is about as dangerous as the last one at its depth
+        let mut budget = self.threat_budget();
+        let mut rooms: Vec<&Room> = map.rooms.iter().flat_map(|row| row.iter().skip(1)).collect();
+        // Barracks draw twice the share of monsters
+        rooms.extend(map.room_purposes.iter().filter(|(_, p)| **p == RoomPurpose::Barracks).map(|(r, _)| r));
+
+        let mut taken = HashSet::new();
+        while !rooms.is_empty() {
+            let index = rng.gen_range(0..rooms.len());
+            let room = rooms[index];
+            let Some(template) = self.monster_registry.choose_affordable(map.level, budget, map.room_purpose(room), branch, rng) else {
+                break;
+            };
+            // Only monsters that get placed are paid for, a room with no room left is passed over from then on
+            let Some((x, y)) = map.free_position(room, &taken, rng) else {
+                rooms.remove(index);
+                continue;
+            };
+            taken.insert((x, y));
+
+            // Monsters from shallower floors come out tougher, and are charged for what they came out as
+            let mut monster = Entity::new_monster(template, x as f32, y as f32).scaled_for_depth(map.level - template.min_depth);
+            if rng.gen_bool(0.4) {
+                monster.ai_state = AiState::Asleep;
+            }
+            if budget >= monster.stats.xp_value.max(1) * 2 && rng.gen_bool(Self::ELITE_CHANCE) {
+                monster = monster.make_elite();
+            }
+            budget -= monster.stats.xp_value.max(1);
+            new_monsters.push(monster);
+        }
+
+        // Now and then something has wandered up from further down, outside the budget, but never into a branch
+        let deeper: Vec<&MonsterTemplate> = self.monster_registry
+            .for_depth(map.level + Self::OUT_OF_DEPTH_FLOORS)
+            .into_iter()
+            .filter(|t| t.min_depth > map.level)
+            .collect();
+        if let (true, Some(template), Some(room)) = (branch.is_none() && rng.gen_bool(Self::OUT_OF_DEPTH_CHANCE), deeper.choose(rng), rooms.choose(rng)) {
+            if let Some((x, y)) = map.free_position(room, &taken, rng) {
+                new_monsters.push(Entity::new_monster(template, x as f32, y as f32));
+            }
+        }
+
+        new_monsters
+    }
+
+    fn open_spawn_preview(&mut self) {
+        if self.map_manager.current_floor() == FloorId::TOWN {
+            self.add_log_message(MessageCategory::System, "Nothing spawns in town.".to_string());
+            return;
+        }
+        let mut rng = thread_rng();
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for _ in 0..SpawnPreview::SAMPLES {
+            for monster in self.roll_monsters(&mut rng) {
+                *counts.entry(SpawnPreview::kind(&monster).to_string()).or_default() += 1;
+            }
+        }
+        let total = counts.values().sum::<u32>().max(1);
+
+        // Everything the floor can roll, and whatever else turned up from further down
+        let map = self.map_manager.current_map();
+        let eligible = self.monster_registry.for_floor(map.level, self.map_manager.current_branch_template());
+        let mut rows: Vec<SpawnRow> = self.monster_registry.templates
+            .iter()
+            .filter(|t| eligible.iter().any(|e| e.name == t.name) || counts.contains_key(&t.name))
+            .map(|t| {
+                let count = counts.get(&t.name).copied().unwrap_or(0);
+                SpawnRow {
+                    name: t.name.clone(),
+                    color: Color::from_rgba(t.color.0, t.color.1, t.color.2, 255),
+                    threat: t.threat(),
+                    haunts: t.haunts.clone(),
+                    share: count as f32 / total as f32,
+                    expected: count as f32 / SpawnPreview::SAMPLES as f32,
+                }
+            })
+            .collect();
+        rows.sort_by(|a, b| b.expected.total_cmp(&a.expected));
+
+        let mut themes: Vec<RoomPurpose> = map.room_purposes.values().copied().filter(|p| *p != RoomPurpose::Plain).collect();
+        themes.sort_by_key(|p| RoomPurpose::THEMED.iter().position(|t| t == p));
+        themes.dedup();
+        let themes = if themes.is_empty() {
+            "plain rooms".to_string()
+        } else {
+            themes.iter().map(|p| format!("{:?}", p)).collect::<Vec<_>>().join(", ")
+        };
+        self.spawn_preview = Some(SpawnPreview {
+            floor: format!("Floor {}, {}", self.map_manager.floor_label(), themes),
+            budget: self.threat_budget(),
+            rows,
+        });
+    }
+
+    // Rolls the floor's monsters again, to see one outcome of the table next to the averages
+    fn handle_spawn_preview_input(&mut self) {
+        if is_key_pressed(KeyCode::R) {
+            let player = (self.player.x as i32, self.player.y as i32);
+            let mut monsters = self.roll_monsters(&mut thread_rng());
+            monsters.retain(|m| (m.x as i32, m.y as i32) != player);
+            self.monsters = monsters;
+            self.add_log_message(MessageCategory::System, format!("The floor's monsters are rolled again, {} of them.", self.monsters.len()));
+        }
+    }
+
+    fn draw_spawn_preview(&self) {
+        let Some(preview) = &self.spawn_preview else {
+            return;
+        };
+        draw_rectangle(
+            screen_width() * 0.1,
+            screen_height() * 0.1,
+            screen_width() * 0.8,
+            screen_height() * 0.8,
+            Color::new(0.0, 0.0, 0.0, 0.9),
+        );
+        let x = screen_width() * 0.15;
+        draw_text("Spawn preview", x, screen_height() * 0.15, 30.0, WHITE);
+        draw_text(
+            &format!("{}, threat budget {}, averaged over {} rolls", preview.floor, preview.budget, SpawnPreview::SAMPLES),
+            x,
+            screen_height() * 0.15 + 25.0,
+            18.0,
+            LIGHTGRAY,
+        );
+
+        let columns = [0.0, 0.2, 0.28, 0.36, 0.45, 0.52];
+        let header_y = screen_height() * 0.15 + 55.0;
+        for (column, heading) in columns.iter().zip(["Monster", "Threat", "Share", "Expected", "Here", "Haunts"]) {
+            draw_text(heading, x + column * screen_width(), header_y, 18.0, GRAY);
+        }
+        for (i, row) in preview.rows.iter().enumerate() {
+            let y = header_y + 22.0 + i as f32 * 20.0;
+            if y > screen_height() * 0.82 {
+                break;
+            }
+            let here = self.monsters.iter().filter(|m| m.is_alive() && SpawnPreview::kind(m) == row.name).count();
+            let haunts = row.haunts.iter().map(|p| format!("{:?}", p)).collect::<Vec<_>>().join(", ");
+            let cells = [
+                row.name.clone(),
+                row.threat.to_string(),
+                format!("{:.0}%", row.share * 100.0),
+                format!("{:.2}", row.expected),
+                here.to_string(),
+                haunts,
+            ];
+            let color = if row.expected > 0.0 { row.color } else { DARKGRAY };
+            for (column, cell) in columns.iter().zip(cells) {
+                draw_text(&cell, x + column * screen_width(), y, 18.0, color);
+            }
+        }
+
+        draw_text("[R] Reroll this floor's monsters  [Esc] Close", x, screen_height() * 0.85, 20.0, LIGHTGRAY);
+    }
+
+    // How much threat the current floor's monsters are rolled up to
+    fn threat_budget(&self) -> i32 {
+        let monster_multiplier = if self.has_modifier(Modifier::DoubleMonsters) { 2 } else { 1 };
+        let difficulty = self.map_manager.config.ai_difficulty;
+        let base = Self::THREAT_BASE + Self::THREAT_PER_DEPTH * self.map_manager.current_level;
+        (base as f32 * difficulty.threat_scale()) as i32 * monster_multiplier
+    }
+
+    // Floor modifiers only apply on their own floor, run modifiers apply everywhere
+    fn has_modifier(&self, modifier: Modifier) -> bool {
+        self.run_modifiers.contains(&modifier) || self.map_manager.current_map().modifiers.contains(&modifier)
+    }
+
+    // The first time a kind of monster comes into view this run, note it in the encyclopedia and
+    // warn about what makes it dangerous: with full hints every run, otherwise only if it is new
+    fn hint_new_monsters(&mut self) {
+        let in_view: Vec<String> = self.monsters
+            .iter()
+            .filter(|m| m.is_alive() && m.faction == Faction::Hostile && self.is_lit(m.x, m.y))
+            .map(|m| m.name.strip_prefix("Elite ").unwrap_or(&m.name).to_string())
+            .filter(|name| !self.hinted_monsters.contains(name))
+            .collect();
+
+        for name in in_view {
+            if !self.hinted_monsters.insert(name.clone()) {
+                continue;
+            }
+            // Named bounty targets and the like aren't a kind of their own
+            let Some(dangers) = self.monster_registry.templates.iter().find(|t| t.name == name).map(MonsterTemplate::dangers) else {
+                continue;
+            };
+            // The demo plays with hints off and mustn't spoil the first meetings for the player
+            let first_meeting = !self.map_manager.config.demo && self.encyclopedia.record_monster(&name);
+            let Some(dangers) = dangers else {
+                continue;
+            };
+            let article = if name.starts_with(['A', 'E', 'I', 'O', 'U']) { "an" } else { "a" };
+            let message = match self.map_manager.config.hints {
+                HintMode::Off => continue,
+                _ if first_meeting => format!("You've never fought {} {} before: {}.", article, name, dangers),
+                HintMode::Full => format!("Careful, the {}: {}.", name, dangers),
+                HintMode::FirstTime => continue,
+            };
+            self.add_log_message(MessageCategory::Warning, message);
+        }
+    }
+
+    fn announce_floor_modifiers(&mut self) {
+        let modifiers = self.map_manager.current_map().modifiers.clone();
+        for modifier in modifiers {
+            self.add_log_message(MessageCategory::System, modifier.announcement().to_string());
+        }
+    }
+
+    fn light_radius(&self) -> f32 {
+        // The town's lamps light it at night, no worse than the player's own light would
+        if self.in_town() {
+            return if self.is_daytime() { Self::DAYLIGHT_RADIUS } else { LIGHT_RADIUS };
+        }
+        let radius = if self.has_modifier(Modifier::Darkness) {
+            LIGHT_RADIUS / 2.0
+        } else {
+            LIGHT_RADIUS
+        };
+        let burning = self.light_fuel.min(Self::GUTTERING_TURNS) as f32 / Self::GUTTERING_TURNS as f32;
+        (radius * burning).max(Self::DARK_RADIUS)
+    }
+
+    // The player's light burns down a turn at a time, with warnings as it gutters and when it goes out
+    fn burn_light(&mut self) {
+        if self.light_fuel == 0 || self.in_town() {
+            return;
+        }
+        self.light_fuel -= 1;
+        match self.light_fuel {
+            Self::GUTTERING_TURNS => self.add_log_message(MessageCategory::Warning, "Your light is guttering. Find a torch or lamp oil soon.".to_string()),
+            0 => self.add_log_message(MessageCategory::Warning, "Your light goes out! Something stirs in the darkness.".to_string()),
+            _ => {}
+        }
+    }
+
+    // Sneaking takes twice as long a step
+    fn player_ready(&self, current_time: f32) -> bool {
+        let speed = if self.sneaking { Self::SNEAK_SPEED } else { 1.0 };
+        self.player.can_move_with_multiplier(current_time, speed)
+    }
+
+    fn toggle_sneak(&mut self) {
+        self.sneaking = !self.sneaking;
+        let message = if self.sneaking { "You start sneaking, keeping out of monsters' sight." } else { "You stop sneaking." };
+        self.add_log_message(MessageCategory::System, message.to_string());
+    }
+
+    // Open tiles that monsters in the light are watching, shown while the player sneaks
+    fn watched_tiles(&self) -> HashSet<(i32, i32)> {
+        if !self.sneaking {
+            return HashSet::new();
+        }
+        let map = self.map_manager.current_map();
+        let difficulty = self.map_manager.config.ai_difficulty;
+        self.monsters
+            .iter()
+            .filter(|m| m.is_alive() && m.faction == Faction::Hostile && self.is_lit(m.x, m.y))
+            .flat_map(|m| m.watched_tiles(map, difficulty))
+            .filter(|&(x, y)| map.tile_at(x, y).is_some_and(|tile| *tile != Tile::Wall))
+            .collect()
+    }
+
+    fn is_lit(&self, x: f32, y: f32) -> bool {
+        let distance = ((x - self.player.x).powi(2) + (y - self.player.y).powi(2)).sqrt();
+        distance <= self.light_radius()
+    }
+
+    // Some floors past the first have a shop in a side room, unless the run forbids them
+    fn spawn_shop(&mut self) {
+        let mut rng = self.fork_rng();
+        let map = self.map_manager.current_map();
+        if map.level == 0 || self.has_modifier(Modifier::NoShops) || !rng.gen_bool(0.4) {
+            return;
+        }
+
+        let candidates = map.side_rooms();
+        let Some(room) = candidates.choose(&mut rng) else {
+            return;
+        };
+        let keeper = room.center();
+        let depth = map.level;
+
+        // The shopkeeper keeps their room clear
+        self.monsters.retain(|m| !room.inner_tiles().contains(&(m.x as i32, m.y as i32)) && (m.x as i32, m.y as i32) != keeper);
+
+        let mut stock: Vec<Item> = self.item_registry.create("health_potion").into_iter().collect();
+        stock.extend(self.item_registry.create("torch"));
+        for _ in 0..rng.gen_range(3..=5) {
+            if let Some(item) = self.item_registry.roll((depth + 1).min(9), &mut rng) {
+                stock.push(item);
+            }
+        }
+
+        self.map_manager.current_map_mut().shop = Some(Shop { keeper, stock });
+        self.add_log_message(MessageCategory::System, "You hear a shopkeeper calling out their wares.".to_string());
+    }
+
+    // Treasure at the heart of each shrine vault, or one piece either side of a shrine standing there.
+    // The boss's lair already has the Heart of the Forge at its center
+    fn spawn_vault_loot(&mut self) {
+        let mut rng = self.fork_rng();
+        let map = self.map_manager.current_map();
+        let depth = map.level;
+        let mut spots = Vec::new();
+        for vault in map.vaults.iter().filter(|room| map.room_purpose(room) == RoomPurpose::Shrine) {
+            let (x, y) = vault.center();
+            if map.shrine.as_ref().is_some_and(|shrine| shrine.room == *vault) {
+                spots.extend([(x - 1, y), (x + 1, y)]);
+            } else {
+                spots.push((x, y));
+            }
+        }
+
+        for (x, y) in spots {
+            if let Some(item) = self.item_registry.roll_for_room((depth + 1).min(9), RoomPurpose::Shrine, &mut rng) {
+                self.ground_items.push((x as f32, y as f32, item));
+            }
+        }
+    }
+
+    // Shrines of challenge sit in a quiet side room of their own
+    fn spawn_shrine(&mut self) {
+        let mut rng = self.fork_rng();
+        let map = self.map_manager.current_map();
+        if map.level == 0 || !rng.gen_bool(0.3) {
+            return;
+        }
+
+        // A room already laid out as a shrine is the natural place for one
+        let side_rooms = map.side_rooms();
+        let shrine_rooms: Vec<&Room> = side_rooms.iter().filter(|r| map.room_purpose(r) == RoomPurpose::Shrine).collect();
+        let room = match shrine_rooms.choose(&mut rng) {
+            Some(&room) => room.clone(),
+            None => match side_rooms.choose(&mut rng) {
+                Some(room) => room.clone(),
+                None => return,
+            },
+        };
+
+        self.monsters.retain(|m| !room.contains(m.x as i32, m.y as i32));
+        self.map_manager.current_map_mut().shrine = Some(Shrine {
+            position: room.center(),
+            room,
+            trial: None,
+            used: false,
+        });
+    }
+
+    // Some floors have a side room thick with webs and the spiders that spun them
+    fn spawn_spider_lair(&mut self) {
+        let mut rng = self.fork_rng();
+        let map = self.map_manager.current_map();
+        if map.level == 0 || !rng.gen_bool(0.25) {
+            return;
+        }
+        let branch = self.map_manager.current_branch_template();
+        let spiders: Vec<&MonsterTemplate> = self.monster_registry.for_floor(map.level, branch).into_iter().filter(|t| t.spins_webs).collect();
+        let (Some(room), false) = (map.side_rooms().choose(&mut rng).cloned(), spiders.is_empty()) else {
+            return;
+        };
+
+        let mut new_spiders = Vec::new();
+        for _ in 0..rng.gen_range(2..=3) {
+            let (x, y) = map.open_position(&room, &mut rng);
+            if let Some(template) = spiders.choose(&mut rng) {
+                new_spiders.push(Entity::new_monster(template, x as f32, y as f32));
+            }
+        }
+        self.monsters.extend(new_spiders);
+
+        let map = self.map_manager.current_map_mut();
+        for (x, y) in room.inner_tiles() {
+            if rng.gen_bool(0.4) {
+                map.spin_web(x, y, None);
+            }
+        }
+        map.claimed_rooms.push(room);
+    }
+
+    // Now and then a fountain, an altar or a chest stands in one of the plain side rooms, each in a
+    // room of its own. A locked chest comes with a chest key somewhere else on the floor
+    fn spawn_features(&mut self) {
+        let mut rng = self.fork_rng();
+        let map = self.map_manager.current_map();
+        let mut rooms: Vec<Room> = map.side_rooms()
+            .into_iter()
+            .filter(|room| map.room_purpose(room) == RoomPurpose::Plain && !map.vaults.contains(room))
+            .collect();
+        rooms.shuffle(&mut rng);
+
+        let mut rolled = Vec::new();
+        if rng.gen_bool(Feature::FOUNTAIN_CHANCE) {
+            rolled.push(Feature::Fountain { dry: false });
+        }
+        if map.level > 0 && rng.gen_bool(Feature::ALTAR_CHANCE) {
+            rolled.push(Feature::Altar { holy: rng.gen_bool(Feature::HOLY_CHANCE), used: false });
+        }
+        if rng.gen_bool(Feature::CHEST_CHANCE) {
+            rolled.push(Feature::Chest { locked: rng.gen_bool(Feature::LOCKED_CHANCE), opened: false });
+        }
+
+        let mut features = HashMap::new();
+        let mut key_spot = None;
+        for (feature, room) in rolled.into_iter().zip(&rooms) {
+            // Room centers are kept clear for stairs and the like
+            let position = map.open_position(room, &mut rng);
+            if position == room.center() {
+                continue;
+            }
+            if let Feature::Chest { locked: true, .. } = feature {
+                let others: Vec<&Room> = map.rooms.iter().flatten().filter(|other| *other != room).collect();
+                key_spot = others.choose(&mut rng).map(|other| map.open_position(other, &mut rng));
+            }
+            features.insert(position, feature);
+        }
+
+        if let (Some((x, y)), Some(key)) = (key_spot, self.item_registry.create("chest_key")) {
+            self.ground_items.push((x as f32, y as f32, key));
+        }
+        self.map_manager.current_map_mut().features = features;
+    }
+
+    // Whatever was stashed behind a secret door is a little better than the floor's usual finds
+    fn spawn_secret_room_loot(&mut self) {
+        let mut rng = self.fork_rng();
+        let map = self.map_manager.current_map();
+        let depth = map.level;
+        let spots: Vec<(i32, i32)> = map.secret_rooms.iter().flat_map(|room| [room.center(), room.random_position(&mut rng)]).collect();
+        for (x, y) in spots {
+            if let Some(item) = self.item_registry.roll((depth + 1).min(FINAL_FLOOR), &mut rng) {
+                self.ground_items.push((x as f32, y as f32, item));
+            }
+        }
+    }
+
+    // A cellar hides loot inside a ring of chasm, with boulders lined up to be pushed in as a bridge
+    fn spawn_cellar(&mut self) {
+        let mut rng = self.fork_rng();
+        let map = self.map_manager.current_map();
+        if map.level == 0 || !rng.gen_bool(0.2) {
+            return;
+        }
+        // The room's items are cleared out, so it can't be where a door or chest key was left
+        let holds_key = |room: &Room| self.ground_items.iter().any(|(x, y, item)| matches!(item.item_type, ItemType::Key | ItemType::ChestKey) && room.contains(*x as i32, *y as i32));
+        let big_enough: Vec<Room> = map.side_rooms().into_iter().filter(|r| r.width >= 7 && r.height >= 7 && !holds_key(r)).collect();
+        let Some(room) = big_enough.choose(&mut rng).cloned() else {
+            return;
+        };
+        let (cx, cy) = room.center();
+        let depth = map.level;
+
+        // A boulder three tiles out on each side, if there is room to stand behind it and push
+        let inner = room.inner_tiles();
+        let boulders: Vec<(i32, i32)> = [(0, 1), (1, 0), (0, -1), (-1, 0)]
+            .iter()
+            .map(|(dx, dy)| ((cx + dx * 3, cy + dy * 3), (cx + dx * 4, cy + dy * 4)))
+            .filter(|&(boulder, behind)| inner.contains(&boulder) && room.contains(behind.0, behind.1))
+            .map(|(boulder, _)| boulder)
+            .collect();
+        if boulders.is_empty() {
+            return;
+        }
+
+        self.monsters.retain(|m| !room.contains(m.x as i32, m.y as i32));
+        self.ground_items.retain(|&(x, y, _)| !room.contains(x as i32, y as i32));
+        if let Some(item) = self.item_registry.roll((depth + 2).min(FINAL_FLOOR), &mut rng) {
+            self.ground_items.push((cx as f32, cy as f32, item));
+        }
+
+        let map = self.map_manager.current_map_mut();
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let (x, y) = (cx + dx, cy + dy);
+                map.tiles[y as usize][x as usize] = if (dx, dy) == (0, 0) { Tile::Floor } else { Tile::Chasm };
+                map.terrain_overlays.remove(&(x, y));
+            }
+        }
+        for &(x, y) in &boulders {
+            map.tiles[y as usize][x as usize] = Tile::Floor;
+            map.boulders.insert((x, y));
+        }
+        map.claimed_rooms.push(room);
+        self.add_log_message(MessageCategory::System, "Stone grates on stone somewhere nearby.".to_string());
+    }
+
+    // Walking into a boulder shoves it one tile on. It fills a chasm, crushes a monster
+    // that doesn't survive the blow, or stays put against anything else. Returns whether it moved
+    fn push_boulder(&mut self, from: (i32, i32), boulder: (i32, i32)) -> bool {
+        let direction = (boulder.0 - from.0, boulder.1 - from.1);
+        let target = (boulder.0 + direction.0, boulder.1 + direction.1);
+        let map = self.map_manager.current_map();
+
+        if map.tile_at(target.0, target.1) == Some(&Tile::Chasm) {
+            let map = self.map_manager.current_map_mut();
+            map.boulders.remove(&boulder);
+            map.set_tile(target.0, target.1, Tile::Floor);
+            self.add_log_message(MessageCategory::System, "The boulder tumbles into the chasm and wedges fast, leaving a path.".to_string());
+            return true;
+        }
+        let onto_stairs = matches!(map.tile_at(target.0, target.1), Some(Tile::StairsUp | Tile::StairsDown));
+        if !map.is_walkable(target.0, target.1) || onto_stairs || self.shopkeeper_at(target.0, target.1) || self.shrine_at(target.0, target.1) {
+            self.add_log_message(MessageCategory::Warning, "The boulder won't budge.".to_string());
+            return false;
+        }
+        let blocked_by_ally = self.allies.iter().any(|a| (a.entity.x as i32, a.entity.y as i32) == target);
+        if blocked_by_ally {
+            self.add_log_message(MessageCategory::Warning, "Your ally is in the way of the boulder.".to_string());
+            return false;
+        }
+
+        let crush_damage = Self::BOULDER_DAMAGE + map.level * 2;
+        if let Some(monster) = self.monsters.iter_mut().find(|m| m.is_alive() && (m.x as i32, m.y as i32) == target) {
+            let hp_before = monster.stats.hp;
+            monster.stats.hp -= crush_damage;
+            self.damage_popups.extend(DamagePopup::after_hit(monster, hp_before, false));
+            monster.last_hit_by = Some(DamageSource::Used(self.player.id, "a boulder"));
+            let name = monster.name.clone();
+            if monster.is_alive() {
+                self.add_log_message(MessageCategory::Combat, format!("The boulder slams into the {} for {} damage!", name, crush_damage));
+                return false;
+            }
+            self.add_log_message(MessageCategory::Combat, format!("The boulder crushes the {}!", name));
+        }
+
+        let map = self.map_manager.current_map_mut();
+        map.boulders.remove(&boulder);
+        map.boulders.insert(target);
+        map.revision += 1; // Boulders block paths like walls do
+        true
+    }
+
+    fn boulder_at(&self, x: i32, y: i32) -> bool {
+        self.map_manager.current_map().boulders.contains(&(x, y))
+    }
+
+    // The player's turn goes into the web holding them
+    fn struggle_free_of_web(&mut self) {
+        if self.player.struggle_free(&mut self.rng) {
+            let (x, y) = (self.player.x as i32, self.player.y as i32);
+            self.map_manager.current_map_mut().terrain_overlays.remove(&(x, y));
+            self.add_log_message(MessageCategory::System, "You tear free of the web.".to_string());
+        } else {
+            self.add_log_message(MessageCategory::Warning, "You struggle against the web.".to_string());
+        }
+    }
+
+    fn shrine_at(&self, x: i32, y: i32) -> bool {
+        self.map_manager.current_map().shrine.as_ref().is_some_and(|shrine| shrine.position == (x, y))
+    }
+
+    // Touching an unused shrine seals its room and starts the trial
+    fn activate_shrine(&mut self) {
+        let turn = self.turn;
+        let map = self.map_manager.current_map_mut();
+        let Some(shrine) = map.shrine.as_ref() else {
+            return;
+        };
+        if shrine.used || shrine.trial.is_some() {
+            self.add_log_message(MessageCategory::Warning, "The shrine lies dormant.".to_string());
+            return;
+        }
+
+        let entrances = map.room_entrances(&shrine.room);
+        let sealed: Vec<((i32, i32), Tile)> = entrances
+            .into_iter()
+            .map(|(x, y)| {
+                let before = map.tiles[y as usize][x as usize].clone();
+                map.set_tile(x, y, Tile::Wall);
+                ((x, y), before)
+            })
+            .collect();
+        if let Some(shrine) = map.shrine.as_mut() {
+            shrine.trial = Some(ShrineTrial {
+                waves_left: Shrine::WAVES,
+                deadline: turn + Shrine::TURN_LIMIT,
+                sealed,
+            });
+        }
+        self.add_log_message(MessageCategory::Warning, format!(
+            "Stone grinds shut behind you! Survive {} waves within {} turns.",
+            Shrine::WAVES,
+            Shrine::TURN_LIMIT
+        ));
+    }
+
+    // Calls the next wave once the room is clear, and ends the trial on victory or when time runs out
+    fn update_shrine_trial(&mut self) {
+        let map = self.map_manager.current_map();
+        let Some(shrine) = map.shrine.as_ref() else {
+            return;
+        };
+        let Some(trial) = shrine.trial.as_ref() else {
+            return;
+        };
+        let room = shrine.room.clone();
+        let depth = map.level;
+        let room_clear = !self.monsters.iter().any(|m| m.is_alive() && m.faction == Faction::Hostile && room.contains(m.x as i32, m.y as i32));
+
+        if self.turn > trial.deadline {
+            self.end_shrine_trial();
+            self.add_log_message(MessageCategory::Warning, "The shrine's light gutters out. You have failed the trial.".to_string());
+        } else if room_clear && trial.waves_left > 0 {
+            let wave = Shrine::WAVES - trial.waves_left + 1;
+            let mut rng = self.fork_rng();
+            for _ in 0..wave + 1 {
+                let free: Vec<(i32, i32)> = room
+                    .inner_tiles()
+                    .into_iter()
+                    .filter(|&(x, y)| !self.is_occupied(x, y) && !self.shrine_at(x, y))
+                    .collect();
+                let branch = self.map_manager.current_branch_template();
+                let candidates = self.monster_registry.for_floor((depth + 1).min(9), branch);
+                let (Some(&(x, y)), Some(template)) = (free.choose(&mut rng), candidates.choose(&mut rng)) else {
+                    continue;
+                };
+                let mut monster = Entity::new_monster(template, x as f32, y as f32);
+                monster.ai_state = AiState::Hunting;
+                self.monsters.push(monster);
+            }
+            if let Some(trial) = self.map_manager.current_map_mut().shrine.as_mut().and_then(|s| s.trial.as_mut()) {
+                trial.waves_left -= 1;
+            }
+            self.add_log_message(MessageCategory::Combat, format!("Wave {} of {} answers the shrine's call!", wave, Shrine::WAVES));
+        } else if room_clear {
+            self.end_shrine_trial();
+            let mut rng = self.fork_rng();
+            let reward = self.shrine_reward(&mut rng);
+            self.add_log_message(MessageCategory::Item, format!("The trial is won! A {} rests on the shrine.", self.identification.name(&reward)));
+            self.ground_items.push((self.player.x, self.player.y, reward));
+            self.check_and_pickup_items();
+        }
+    }
+
+    // Opens the room back up either way, a shrine only offers one trial
+    fn end_shrine_trial(&mut self) {
+        let map = self.map_manager.current_map_mut();
+        let Some(trial) = map.shrine.as_mut().and_then(|shrine| {
+            shrine.used = true;
+            shrine.trial.take()
+        }) else {
+            return;
+        };
+        for ((x, y), tile) in trial.sealed {
+            map.set_tile(x, y, tile);
+        }
+    }
+
+    // An unfound artifact if any are left, otherwise the best the deepest loot table has to offer
+    fn shrine_reward(&mut self, rng: &mut impl Rng) -> Item {
+        let available: Vec<Artifact> = Artifact::ALL
+            .into_iter()
+            .filter(|a| !self.spawned_artifacts.contains(a))
+            .collect();
+        if let Some(&artifact) = available.choose(rng) {
+            self.spawned_artifacts.insert(artifact);
+            return Item::new_artifact(artifact);
+        }
+        (0..5)
+            .filter_map(|_| self.item_registry.roll(9, rng))
+            .max_by_key(|item| item.value())
+            .unwrap_or_else(|| Item::new_gold(200))
+    }
+
+    fn shopkeeper_at(&self, x: i32, y: i32) -> bool {
+        self.map_manager.current_map().shop.as_ref().is_some_and(|shop| shop.keeper == (x, y))
+    }
+
+    fn buy_price(&self, item: &Item) -> u32 {
+        ((item.value() as f32 * (1.0 - self.shop_discount)).round() as u32).max(1)
+    }
+
+    fn sell_price(&self, item: &Item) -> u32 {
+        (item.value() as f32 * Shop::SELL_RATE) as u32
+    }
+
+    fn handle_shop_input(&mut self) {
+        if is_key_pressed(KeyCode::Tab) {
+            self.shop_selling = !self.shop_selling;
+            self.shop_selection = 0;
+        }
+
+        let count = if self.shop_selling {
+            self.player.inventory.as_ref().map_or(0, |inv| inv.items.len())
+        } else {
+            self.map_manager.current_map().shop.as_ref().map_or(0, |shop| shop.stock.len())
+        };
+        self.shop_selection = self.shop_selection.min(count.saturating_sub(1));
+        if is_key_pressed(KeyCode::Up) && self.shop_selection > 0 {
+            self.shop_selection -= 1;
+        }
+        if is_key_pressed(KeyCode::Down) && self.shop_selection + 1 < count {
+            self.shop_selection += 1;
+        }
+
+        if count > 0 && is_key_pressed(KeyCode::Enter) {
+            if self.shop_selling {
+                self.sell_item(self.shop_selection);
+            } else {
+                self.buy_item(self.shop_selection);
+            }
+        }
+    }
+
+    fn buy_item(&mut self, index: usize) {
+        let Some(item) = self.map_manager.current_map().shop.as_ref().and_then(|shop| shop.stock.get(index)).cloned() else {
+            return;
+        };
+        let price = self.buy_price(&item);
+        let Some(inventory) = self.player.inventory.as_mut() else {
+            return;
+        };
+        if inventory.gold < price {
+            self.add_log_message(MessageCategory::Warning, format!("You can't afford the {}.", self.identification.name(&item)));
+            return;
+        }
+        if let Err(e) = inventory.add_item(item.clone()) {
+            self.add_log_message(MessageCategory::Warning, e);
+            return;
+        }
+
+        inventory.gold -= price;
+        if let Some(shop) = self.map_manager.current_map_mut().shop.as_mut() {
+            shop.stock.remove(index);
+        }
+        self.add_log_message(MessageCategory::Item, format!("You buy the {} for {} gold.", self.identification.name(&item), price));
+    }
+
+    fn sell_item(&mut self, index: usize) {
+        let item_type = self.player.inventory.as_ref().and_then(|inv| inv.items.get(index)).map(|item| item.item_type.clone());
+        match item_type {
+            Some(ItemType::Relic) => {
+                self.add_log_message(MessageCategory::Warning, "The shopkeeper won't touch the Heart of the Forge.".to_string());
+                return;
+            }
+            Some(ItemType::Keepsake(_)) => {
+                self.add_log_message(MessageCategory::Warning, "The shopkeeper knows that's wanted on the bounty board.".to_string());
+                return;
+            }
+            Some(ItemType::Curio) => {
+                self.add_log_message(MessageCategory::Warning, "You came down here for that, it isn't for sale.".to_string());
+                return;
+            }
+            _ => {}
+        }
+        let Some(item) = self.player.inventory.as_mut().and_then(|inv| inv.remove_item(index)) else {
+            return;
+        };
+        let price = self.sell_price(&item);
+        if let Some(inventory) = self.player.inventory.as_mut() {
+            inventory.gold += price;
+        }
+        self.add_log_message(MessageCategory::Item, format!("You sell the {} for {} gold.", self.identification.name(&item), price));
+        if let Some(shop) = self.map_manager.current_map_mut().shop.as_mut() {
+            shop.stock.push(item);
+        }
+    }
+
+    fn draw_shop(&self) {
+        let (Some(shop), Some(inventory)) = (self.map_manager.current_map().shop.as_ref(), self.player.inventory.as_ref()) else {
+            return;
+        };
+
+        draw_rectangle(
+            screen_width() * 0.1,
+            screen_height() * 0.1,
+            screen_width() * 0.8,
+            screen_height() * 0.8,
+            Color::new(0.0, 0.0, 0.0, 0.9),
+        );
+
+        draw_text("Shop", screen_width() * 0.15, screen_height() * 0.15, 30.0, WHITE);
+        draw_text(&format!("Gold: {}", inventory.gold), screen_width() * 0.6, screen_height() * 0.15, 20.0, GOLD);
+
+        let columns = [
+            ("For sale:", screen_width() * 0.15, false),
+            ("Your items:", screen_width() * 0.5, true),
+        ];
+        for (title, x, selling) in columns {
+            let focused = self.shop_selling == selling;
+            draw_text(title, x, screen_height() * 0.22, 20.0, if focused { WHITE } else { GRAY });
+
+            let entries: Vec<(&Item, u32)> = if selling {
+                inventory.items.iter().map(|item| (item, self.sell_price(item))).collect()
+            } else {
+                shop.stock.iter().map(|item| (item, self.buy_price(item))).collect()
+            };
+            for (i, (item, price)) in entries.iter().enumerate() {
+                let y_pos = screen_height() * 0.22 + 25.0 + i as f32 * 25.0;
+                if focused && i == self.shop_selection {
+                    draw_text(">", x - 15.0, y_pos, 20.0, WHITE);
+                }
+                let (name, color) = (self.identification.name(item), self.identification.color(item));
+                draw_text(&format!("{} {} - {} gold", item.symbol, name, price), x, y_pos, 20.0, color);
+            }
+        }
+
+        draw_text(
+            "[Tab] Switch buy/sell  [Up/Down] Select  [Enter] Buy or sell  [Esc] Leave",
+            screen_width() * 0.15,
+            screen_height() * 0.85,
+            20.0,
+            LIGHTGRAY,
+        );
+    }
+
+    // The first floor has a bounty board next to where the run starts
+    fn spawn_bounty_board(&mut self) {
+        let map = self.map_manager.current_map();
+        if map.level != 0 {
+            return;
+        }
+        let Some(room) = map.rooms.first().and_then(|row| row.first()) else {
+            return;
+        };
+        let (cx, cy) = room.center();
+        let spot = [(cx + 1, cy - 1), (cx - 1, cy - 1), (cx + 1, cy + 1), (cx - 1, cy + 1)]
+            .into_iter()
+            .chain(room.inner_tiles())
+            .find(|&(x, y)| map.tile_at(x, y) == Some(&Tile::Floor) && (x, y) != (cx, cy));
+        self.map_manager.current_map_mut().bounty_board = spot;
+    }
+
+    // Cave floors have ore veins in the walls around their rooms, and a pickaxe
+    // lying somewhere if the player has yet to find one
+    fn spawn_ore_veins(&mut self) {
+        let mut rng = self.fork_rng();
+        let map = self.map_manager.current_map();
+        if !map.cave {
+            return;
+        }
+        let mut seams: Vec<(i32, i32)> = map.rooms
+            .iter()
+            .flatten()
+            .flat_map(|room| room.inner_tiles())
+            .flat_map(|(x, y)| ORTHOGONAL_DIRECTIONS.iter().map(move |(dx, dy)| (x + dx, y + dy)))
+            .filter(|&(x, y)| map.tile_at(x, y) == Some(&Tile::Wall))
+            .collect();
+        seams.sort();
+        seams.dedup();
+        let count = rng.gen_range(4..=7);
+        let veins: Vec<(i32, i32)> = seams.choose_multiple(&mut rng, count).copied().collect();
+        let pickaxe_spot = map.side_rooms().choose(&mut rng).map(|room| map.open_position(room, &mut rng));
+
+        let map = self.map_manager.current_map_mut();
+        map.ore_veins = veins.into_iter().map(|pos| (pos, OreVein::new())).collect();
+        self.add_log_message(MessageCategory::System, "Rough cave walls here glitter with veins of ore.".to_string());
+
+        let has_pickaxe = self.player.inventory.as_ref().is_some_and(|inv| inv.items.iter().any(|i| i.item_type == ItemType::Pickaxe));
+        if let (false, Some((x, y)), Some(pickaxe)) = (has_pickaxe, pickaxe_spot, self.item_registry.create("pickaxe")) {
+            self.ground_items.push((x as f32, y as f32, pickaxe));
+        }
+    }
+
+    // Every cave has a forge by its entrance, for setting the gems dug out of it
+    fn spawn_forge(&mut self) {
+        let map = self.map_manager.current_map();
+        if !map.cave {
+            return;
+        }
+        let Some(room) = map.rooms.first().and_then(|row| row.first()) else {
+            return;
+        };
+        let (cx, cy) = room.center();
+        let spot = room
+            .inner_tiles()
+            .into_iter()
+            .find(|&(x, y)| map.tile_at(x, y) == Some(&Tile::Floor) && (x, y) != (cx, cy) && !self.has_item_at(x as f32, y as f32));
+        self.map_manager.current_map_mut().forge = spot;
+    }
+
+    fn forge_at(&self, x: i32, y: i32) -> bool {
+        self.map_manager.current_map().forge == Some((x, y))
+    }
+
+    fn open_forge(&mut self) {
+        self.forge_open = true;
+        self.forge_selection = 0;
+        self.forge_gem_selection = 0;
+    }
+
+    fn gems_in_pouch(&self) -> Vec<(GemKind, MaterialQuality, u32)> {
+        self.player.inventory.as_ref().map_or(Vec::new(), |inv| {
+            inv.materials
+                .entries()
+                .into_iter()
+                .filter_map(|(kind, quality, count)| match kind {
+                    MaterialKind::Gem(gem) => Some((gem, quality, count)),
+                    _ => None,
+                })
+                .collect()
+        })
+    }
+
+    fn handle_forge_input(&mut self) {
+        let pieces = self.player.inventory.as_ref().map_or(0, |inv| inv.equipment().count());
+        let gems = self.gems_in_pouch().len();
+        self.forge_selection = self.forge_selection.min(pieces.saturating_sub(1));
+        self.forge_gem_selection = self.forge_gem_selection.min(gems.saturating_sub(1));
+        if is_key_pressed(KeyCode::Up) && self.forge_selection > 0 {
+            self.forge_selection -= 1;
+        }
+        if is_key_pressed(KeyCode::Down) && self.forge_selection + 1 < pieces {
+            self.forge_selection += 1;
+        }
+        if is_key_pressed(KeyCode::Left) && self.forge_gem_selection > 0 {
+            self.forge_gem_selection -= 1;
+        }
+        if is_key_pressed(KeyCode::Right) && self.forge_gem_selection + 1 < gems {
+            self.forge_gem_selection += 1;
+        }
+
+        if pieces == 0 {
+            return;
+        }
+        if is_key_pressed(KeyCode::Enter) && gems > 0 {
+            self.socket_gem(self.forge_selection, self.forge_gem_selection);
+        } else if is_key_pressed(KeyCode::X) {
+            self.pry_out_gem(self.forge_selection);
+        }
+    }
+
+    fn socket_gem(&mut self, piece: usize, gem_index: usize) {
+        let Some((gem, quality, _)) = self.gems_in_pouch().get(gem_index).copied() else {
+            return;
+        };
+        let Some(inventory) = self.player.inventory.as_mut() else {
+            return;
+        };
+        let Some(item) = inventory.equipment_mut(piece) else {
+            return;
+        };
+        if item.gems.len() >= item.sockets as usize {
+            let message = if item.sockets == 0 {
+                format!("The {} has no sockets.", item.name)
+            } else {
+                format!("Every socket in the {} is already filled.", item.name)
+            };
+            self.add_log_message(MessageCategory::Warning, message);
+            return;
+        }
+
+        item.gems.push((gem, quality));
+        let message = format!(
+            "You set the {} {} into the {}: {}.",
+            quality.name(),
+            gem.name(),
+            item.name,
+            gem.describe(quality, item.is_weapon())
+        );
+        inventory.materials.take(MaterialKind::Gem(gem), quality);
+        self.add_log_message(MessageCategory::Item, message);
+    }
+
+    // Gems come out in pieces, so removing one destroys it
+    fn pry_out_gem(&mut self, piece: usize) {
+        let Some(item) = self.player.inventory.as_mut().and_then(|inv| inv.equipment_mut(piece)) else {
+            return;
+        };
+        let message = match item.gems.pop() {
+            Some((gem, quality)) => format!("You pry the {} {} out of the {}. It shatters.", quality.name(), gem.name(), item.name),
+            None => format!("There are no gems in the {}.", item.name),
+        };
+        self.add_log_message(MessageCategory::Item, message);
+    }
+
+    fn draw_forge(&self) {
+        let Some(inventory) = self.player.inventory.as_ref() else {
+            return;
+        };
+
+        draw_rectangle(
+            screen_width() * 0.1,
+            screen_height() * 0.1,
+            screen_width() * 0.8,
+            screen_height() * 0.8,
+            Color::new(0.0, 0.0, 0.0, 0.9),
+        );
+        draw_text("Forge", screen_width() * 0.15, screen_height() * 0.15, 30.0, ORANGE);
+
+        draw_text("Equipment:", screen_width() * 0.15, screen_height() * 0.22, 20.0, WHITE);
+        for (i, item) in inventory.equipment().enumerate() {
+            let y_pos = screen_height() * 0.22 + 25.0 + i as f32 * 45.0;
+            if i == self.forge_selection {
+                draw_text(">", screen_width() * 0.15 - 15.0, y_pos, 20.0, WHITE);
+            }
+            draw_text(&format!("{} {}", item.symbol, item.name), screen_width() * 0.15, y_pos, 20.0, item.color);
+            let sockets = if item.sockets == 0 { "No sockets".to_string() } else { item.socket_summary() };
+            draw_text(&sockets, screen_width() * 0.17, y_pos + 20.0, 18.0, GRAY);
+        }
+
+        draw_text("Gems:", screen_width() * 0.55, screen_height() * 0.22, 20.0, WHITE);
+        let selected = inventory.equipment().nth(self.forge_selection);
+        let in_weapon = selected.is_some_and(|item| item.is_weapon());
+        for (i, (gem, quality, count)) in self.gems_in_pouch().into_iter().enumerate() {
+            let y_pos = screen_height() * 0.22 + 25.0 + i as f32 * 25.0;
+            if i == self.forge_gem_selection {
+                draw_text(">", screen_width() * 0.55 - 15.0, y_pos, 20.0, WHITE);
+            }
+            let line = format!("{} {} x{}, {}", quality.name(), gem.name(), count, gem.describe(quality, in_weapon));
+            draw_text(&line, screen_width() * 0.55, y_pos, 20.0, quality.color());
+        }
+
+        draw_text(
+            "[Up/Down] Equipment  [Left/Right] Gem  [Enter] Set gem  [X] Pry out, destroying it  [Esc] Leave",
+            screen_width() * 0.15,
+            screen_height() * 0.85,
+            18.0,
+            LIGHTGRAY,
+        );
+    }
+
+    fn ore_vein_at(&self, x: i32, y: i32) -> bool {
+        self.map_manager.current_map().ore_veins.contains_key(&(x, y))
+    }
+
+    // Walking into a vein with a pickaxe swings at it. The ringing carries, and the last
+    // swing breaks loose ore for the forge, now and then with a gem in it
+    fn mine_ore_vein(&mut self, x: i32, y: i32) {
+        let has_pickaxe = self.player.inventory.as_ref().is_some_and(|inv| inv.items.iter().any(|i| i.item_type == ItemType::Pickaxe));
+        if !has_pickaxe {
+            self.add_log_message(MessageCategory::Warning, "The ore is locked in solid rock. You need a pickaxe.".to_string());
+            return;
+        }
+        let depth = self.map_manager.current_level;
+        let turn = self.turn;
+        let Some(vein) = self.map_manager.current_map_mut().ore_veins.get_mut(&(x, y)) else {
+            return;
+        };
+        if vein.swings_left == 0 {
+            self.add_log_message(MessageCategory::Warning, "This vein is mined out. It may grow back in time.".to_string());
+            return;
+        }
+
+        vein.swings_left -= 1;
+        let mined_out = vein.swings_left == 0;
+        if mined_out {
+            vein.regrows_at = Some(turn + OreVein::REGROW_TURNS);
+        }
+        // Heard from where the player stands, since nothing can walk into the vein itself
+        self.alert_monsters(Noise::Mining, (self.player.x as i32, self.player.y as i32));
+        if !mined_out {
+            self.add_log_message(MessageCategory::System, "Your pickaxe rings against the ore vein.".to_string());
+            return;
+        }
+
+        let mut rng = self.fork_rng();
+        let mut finds = vec![(MaterialKind::Ore, MaterialQuality::roll(depth, false, &mut rng), rng.gen_range(1..=2))];
+        if rng.gen_bool(OreVein::GEM_CHANCE) {
+            let gem = *GemKind::ALL.choose(&mut rng).unwrap_or(&GemKind::Diamond);
+            finds.push((MaterialKind::Gem(gem), MaterialQuality::roll(depth, true, &mut rng), 1));
+        }
+        if let Some(inventory) = self.player.inventory.as_mut() {
+            for &(kind, quality, amount) in &finds {
+                inventory.materials.add(kind, quality, amount);
+            }
+        }
+        let names: Vec<String> = finds.iter().map(|(kind, quality, amount)| format!("{} {} {}", amount, quality.name(), kind.name())).collect();
+        self.add_log_message(MessageCategory::Item, format!("The vein breaks open: {}.", names.join(", ")));
+    }
+
+    // Post fresh bounties for floors just past the deepest one reached
+    fn refresh_bounties(&mut self) {
+        if self.turn < self.bounty_board.refresh_turn {
+            return;
+        }
+        let mut rng = self.fork_rng();
+        let low = self.deepest_level.max(1);
+        let high = (self.deepest_level + 3).min(FINAL_FLOOR).max(low);
+        let mut offers = Vec::new();
+
+        for _ in 0..BountyBoard::OFFERS {
+            let floor = rng.gen_range(low..=high);
+            let target = if rng.gen_bool(0.5) {
+                let Some(template) = self.monster_registry.choose(floor, &mut rng) else {
+                    continue;
+                };
+                let name = BountyBoard::ELITE_NAMES.choose(&mut rng).copied().unwrap_or("Grukk");
+                BountyTarget::Slay {
+                    name: format!("{} the {}", name, template.name),
+                    template: template.name.clone(),
+                }
+            } else {
+                let item = BountyBoard::KEEPSAKES.choose(&mut rng).copied().unwrap_or("Silver Locket");
+                BountyTarget::Recover { item: item.to_string() }
+            };
+            let base_gold = 40 + 25 * floor as u32 + rng.gen_range(0..=20);
+            offers.push(Bounty {
+                id: self.bounty_board.next_id,
+                floor,
+                target,
+                gold: base_gold + base_gold * self.reputation / 10,
+                reputation: 1 + floor as u32 / 3,
+                placed: false,
+                completed: false,
+            });
+            self.bounty_board.next_id += 1;
+        }
+
+        self.bounty_board.offers = offers;
+        self.bounty_board.refresh_turn = self.turn + BountyBoard::REFRESH_TURNS;
+        self.board_selection = 0;
+    }
+
+    fn open_bounty_board(&mut self) {
+        self.turn_in_bounties();
+        self.refresh_bounties();
+        self.board_open = true;
+    }
+
+    // Pay out every finished bounty, keepsakes count as finished once they're in the pack
+    fn turn_in_bounties(&mut self) {
+        let (mut gold, mut reputation) = (0, 0);
+        let active = std::mem::take(&mut self.bounty_board.active);
+        for mut bounty in active {
+            if let (BountyTarget::Recover { .. }, Some(inventory)) = (&bounty.target, self.player.inventory.as_mut()) {
+                if let Some(index) = inventory.items.iter().position(|item| item.item_type == ItemType::Keepsake(bounty.id)) {
+                    inventory.items.remove(index);
+                    bounty.completed = true;
+                }
+            }
+            if bounty.completed {
+                gold += bounty.gold;
+                reputation += bounty.reputation;
+                self.add_log_message(MessageCategory::System, format!("Bounty claimed: {}.", bounty.describe()));
+            } else {
+                self.bounty_board.active.push(bounty);
+            }
+        }
+
+        if gold > 0 {
+            if let Some(inventory) = self.player.inventory.as_mut() {
+                inventory.gold += gold;
+            }
+            self.reputation += reputation;
+            self.add_log_message(MessageCategory::Item, format!("You collect {} gold and {} reputation.", gold, reputation));
+        }
+    }
+
+    fn handle_board_input(&mut self) {
+        let count = self.bounty_board.offers.len();
+        self.board_selection = self.board_selection.min(count.saturating_sub(1));
+        if is_key_pressed(KeyCode::Up) && self.board_selection > 0 {
+            self.board_selection -= 1;
+        }
+        if is_key_pressed(KeyCode::Down) && self.board_selection + 1 < count {
+            self.board_selection += 1;
+        }
+
+        if count > 0 && is_key_pressed(KeyCode::Enter) {
+            if self.bounty_board.active.len() >= BountyBoard::MAX_ACTIVE {
+                self.add_log_message(MessageCategory::Warning, "You already have as many bounties as you can chase.".to_string());
+                return;
+            }
+            let bounty = self.bounty_board.offers.remove(self.board_selection);
+            self.add_log_message(MessageCategory::System, format!("Bounty accepted: {}.", bounty.describe()));
+            self.bounty_board.active.push(bounty);
+        }
+    }
+
+    // Put the targets of accepted bounties on this floor the first time the player is here with them
+    fn place_bounty_targets(&mut self) {
+        let mut rng = self.fork_rng();
+        let floor = self.map_manager.current_level;
+        let rooms = self.map_manager.current_map().side_rooms();
+        // Bounties are posted for main dungeon floors only
+        if rooms.is_empty() || self.map_manager.current_branch.is_some() {
+            return;
+        }
+
+        for index in 0..self.bounty_board.active.len() {
+            let bounty = &self.bounty_board.active[index];
+            if bounty.floor != floor || bounty.placed || bounty.completed {
+                continue;
+            }
+            let (id, target) = (bounty.id, bounty.target.clone());
+            let Some((x, y)) = rooms.choose(&mut rng).map(|room| self.map_manager.current_map().open_position(room, &mut rng)) else {
+                continue;
+            };
+            match target {
+                BountyTarget::Slay { name, template } => {
+                    let Some(template) = self.monster_registry.templates.iter().find(|t| t.name == template) else {
+                        continue;
+                    };
+                    let mut monster = Entity::new_monster(template, x as f32, y as f32).make_elite();
+                    monster.name = name.clone();
+                    monster.bounty = Some(id);
+                    self.monsters.push(monster);
+                    self.add_log_message(MessageCategory::System, format!("Somewhere on this floor, {} is waiting.", name));
+                }
+                BountyTarget::Recover { item } => {
+                    self.ground_items.push((x as f32, y as f32, Item {
+                        name: item.clone(),
+                        item_type: ItemType::Keepsake(id),
+                        symbol: '&',
+                        color: GOLD,
+                        set: None,
+                        cursed: false,
+                        curse_known: false,
+                        sockets: 0,
+                        gems: Vec::new(),
+                        dice: None,
+                        inflicts: None,
+                        weapon_kind: None,
+                    }));
+                    self.add_log_message(MessageCategory::System, format!("The {} should be somewhere on this floor.", item));
+                }
+            }
+            self.bounty_board.active[index].placed = true;
+        }
+    }
+
+    fn draw_bounty_board(&self) {
+        draw_rectangle(
+            screen_width() * 0.1,
+            screen_height() * 0.1,
+            screen_width() * 0.8,
+            screen_height() * 0.8,
+            Color::new(0.0, 0.0, 0.0, 0.9),
+        );
+
+        draw_text("Bounty Board", screen_width() * 0.15, screen_height() * 0.15, 30.0, WHITE);
+        draw_text(&format!("Reputation: {}", self.reputation), screen_width() * 0.6, screen_height() * 0.15, 20.0, GOLD);
+
+        draw_text("Posted:", screen_width() * 0.15, screen_height() * 0.22, 20.0, WHITE);
+        for (i, bounty) in self.bounty_board.offers.iter().enumerate() {
+            let y_pos = screen_height() * 0.22 + 25.0 + i as f32 * 25.0;
+            if i == self.board_selection {
+                draw_text(">", screen_width() * 0.15 - 15.0, y_pos, 20.0, WHITE);
+            }
+            let line = format!("{} - {} gold, {} reputation", bounty.describe(), bounty.gold, bounty.reputation);
+            draw_text(&line, screen_width() * 0.15, y_pos, 20.0, LIGHTGRAY);
+        }
+
+        let active_y = screen_height() * 0.5;
+        draw_text("Accepted:", screen_width() * 0.15, active_y, 20.0, WHITE);
+        for (i, bounty) in self.bounty_board.active.iter().enumerate() {
+            let (status, color) = if bounty.completed { ("done", GREEN) } else { ("open", GRAY) };
+            let line = format!("[{}] {} - {} gold", status, bounty.describe(), bounty.gold);
+            draw_text(&line, screen_width() * 0.15, active_y + 25.0 + i as f32 * 25.0, 20.0, color);
+        }
+
+        let turns_left = self.bounty_board.refresh_turn.saturating_sub(self.turn);
+        draw_text(
+            &format!("New bounties posted in {} turns", turns_left),
+            screen_width() * 0.15,
+            screen_height() * 0.8,
+            20.0,
+            GRAY,
+        );
+        draw_text(
+            "[Up/Down] Select  [Enter] Accept  [Esc] Leave",
+            screen_width() * 0.15,
+            screen_height() * 0.85,
+            20.0,
+            LIGHTGRAY,
+        );
+    }
+
+    // The monsters and items a prefab room's legend called for
+    fn spawn_prefab_contents(&mut self) {
+        let mut rng = self.fork_rng();
+        let map = self.map_manager.current_map();
+        let depth = map.level;
+        for ((x, y), spawn) in map.prefab_spawns.clone() {
+            let template = match &spawn {
+                PrefabSpawn::Monster(name) => self.monster_registry.templates.iter().find(|t| t.name == *name),
+                PrefabSpawn::Guard => self.monster_registry.choose(depth, &mut rng),
+                _ => None,
+            };
+            if let Some(template) = template {
+                let monster = Entity::new_monster(template, x as f32, y as f32).scaled_for_depth(depth - template.min_depth);
+                self.monsters.push(monster);
+            }
+            let item = match &spawn {
+                PrefabSpawn::Item(id) => self.item_registry.create(id),
+                PrefabSpawn::Loot => self.item_registry.roll((depth + 2).min(9), &mut rng),
+                _ => None,
+            };
+            if let Some(item) = item {
+                self.ground_items.push((x as f32, y as f32, item));
+            }
+        }
+    }
+
+    // Better loot behind the locked doors, and a key to them in another room
+    fn spawn_treasure_room_loot(&mut self) {
+        let map = self.map_manager.current_map();
+        let Some(treasure_room) = map.treasure_room.clone() else {
+            return;
+        };
+        let depth = map.level;
+        let key_rooms: Vec<Room> = map.rooms
+            .iter()
+            .flatten()
+            .skip(1)
+            .filter(|r| **r != treasure_room)
+            .cloned()
+            .collect();
+        let mut rng = self.fork_rng();
+
+        for _ in 0..3 {
+            let (x, y) = treasure_room.random_position(&mut rng);
+            if let Some(item) = self.item_registry.roll((depth + 2).min(9), &mut rng) {
+                self.ground_items.push((x as f32, y as f32, item));
+            }
+        }
+
+        if let (Some(room), Some(key)) = (key_rooms.choose(&mut rng), self.item_registry.create("key")) {
+            let (x, y) = self.map_manager.current_map().open_position(room, &mut rng);
+            self.ground_items.push((x as f32, y as f32, key));
+        }
+    }
+
+    // Bumping a door opens it, locked ones need a key. Returns whether there was a door
+    fn try_open_door(&mut self, x: i32, y: i32) -> bool {
+        let tile = self.map_manager.current_map().tile_at(x, y).cloned();
+        match tile {
+            Some(Tile::DoorClosed) => {
+                self.map_manager.current_map_mut().set_tile(x, y, Tile::DoorOpen);
+                self.add_log_message(MessageCategory::System, "You open the door.".to_string());
+                true
+            }
+            Some(Tile::DoorLocked) => {
+                let key = self.player.inventory.as_mut().and_then(|inventory| {
+                    let index = inventory.items.iter().position(|item| item.item_type == ItemType::Key)?;
+                    inventory.remove_item(index)
+                });
+                if let Some(key) = key {
+                    self.map_manager.current_map_mut().set_tile(x, y, Tile::DoorOpen);
+                    self.add_log_message(MessageCategory::Item, format!("You unlock the door with the {}.", key.name));
+                } else {
+                    self.add_log_message(MessageCategory::Warning, "The door is locked. There must be a key somewhere on this floor.".to_string());
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn spawn_items_for_current_level(&mut self) {
+        let mut rng = self.fork_rng();
+        self.ground_items.clear();
+
+        let rooms = self.map_manager.current_map().rooms.clone();
+        let depth = self.map_manager.current_level;
+
+        for row in &rooms {
+            for room in row {
+                let purpose = self.map_manager.current_map().room_purpose(room);
+                if rng.gen_bool(0.3) {
+                    let (x, y) = self.map_manager.current_map().open_position(room, &mut rng);
+                    let amount = rng.gen_range(5..=15) * (depth as u32 + 1);
+                    self.ground_items.push((x as f32, y as f32, Item::new_gold(amount)));
+                }
+                // Storerooms are always stocked, and with more than one thing
+                let rolls = if purpose == RoomPurpose::Storeroom { 2 } else { 1 };
+                for _ in 0..rolls {
+                    if purpose != RoomPurpose::Storeroom && !rng.gen_bool(0.6) {
+                        continue;
+                    }
+                    let (x, y) = self.map_manager.current_map().open_position(room, &mut rng);
+                    let item = self.roll_set_piece(&mut rng)
+                        .or_else(|| self.item_registry.roll_for_room(depth, purpose, &mut rng));
+                    if let Some(item) = item {
+                        self.ground_items.push((x as f32, y as f32, item));
+                    }
+                }
+            }
+        }
+
+        self.spawn_artifact_for_current_level(&rooms, &mut rng);
+    }
+
+    // Each artifact can only appear once per run, and they get more likely deeper down
+    fn spawn_artifact_for_current_level(&mut self, rooms: &[Vec<Room>], rng: &mut impl Rng) {
+        let depth = self.map_manager.current_level;
+        if !rng.gen_bool((0.1 + depth as f64 * 0.05).min(0.6)) {
+            return;
+        }
+
+        let available: Vec<Artifact> = Artifact::ALL
+            .into_iter()
+            .filter(|a| !self.spawned_artifacts.contains(a))
+            .collect();
+        let room = rooms.iter().flatten().skip(1).choose(rng);
+
+        if let (Some(&artifact), Some(room)) = (available.choose(rng), room) {
+            let (x, y) = self.map_manager.current_map().open_position(room, rng);
+            self.spawned_artifacts.insert(artifact);
+            self.ground_items.push((x as f32, y as f32, Item::new_artifact(artifact)));
+        }
+    }
+
+    fn carried_artifacts(&self) -> Vec<Artifact> {
+        self.player.inventory
+            .iter()
+            .flat_map(|inv| inv.items.iter())
+            .filter_map(|item| match item.item_type {
+                ItemType::Artifact(artifact) => Some(artifact),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn run_artifact_turn_hooks(&mut self) {
+        for artifact in self.carried_artifacts() {
+            if let Some(message) = artifact.on_player_turn(&mut self.player, self.turn) {
+                self.add_log_message(MessageCategory::System, message);
+            }
+        }
+    }
+
+    fn run_artifact_kill_hooks(&mut self) {
+        for artifact in self.carried_artifacts() {
+            if let Some(message) = artifact.on_kill(&mut self.player) {
+                self.add_log_message(MessageCategory::System, message);
+            }
+        }
+    }
+
+    // Set pieces are rare, but missing pieces of a set the player has started are
+    // weighted up so finishing a set in one run is possible without being a given
+    fn roll_set_piece(&self, rng: &mut impl Rng) -> Option<Item> {
+        if !rng.gen_bool(0.05) {
+            return None;
+        }
+
+        let owned: Vec<String> = self.player.inventory
+            .iter()
+            .flat_map(|inv| inv.items.iter().chain(inv.equipped_items()))
+            .filter(|item| item.set.is_some())
+            .map(|item| item.name.clone())
+            .collect();
+
+        let mut candidates = Vec::new();
+        for set in ItemSet::ALL {
+            let pieces = set.pieces();
+            let started = pieces.iter().any(|piece| owned.contains(&piece.name));
+            for piece in pieces {
+                if !owned.contains(&piece.name) {
+                    candidates.push((piece, if started { 4 } else { 1 }));
+                }
+            }
+        }
+
+        candidates.choose_weighted(rng, |(_, weight)| *weight)
+            .ok()
+            .map(|(piece, _)| piece.clone())
+    }
+
+    // Tougher monsters are likelier to be carrying something worth having
+    fn loot_chance(monster: &Entity) -> f64 {
+        let chance = Self::LOOT_BASE_CHANCE + monster.stats.xp_value as f64 * Self::LOOT_CHANCE_PER_XP;
+        let chance = if monster.elite { chance + Self::ELITE_LOOT_BONUS } else { chance };
+        chance.min(Self::MAX_LOOT_CHANCE)
+    }
+
+    // Roll material drops for monsters that died this frame, then clear them out
+    fn handle_monster_deaths(&mut self) {
+        let mut rng = self.fork_rng();
+        let depth = self.map_manager.current_level;
+        let floor = self.map_manager.current_floor();
+        let mut drops = Vec::new();
+
+        // Kills by the player or anything fighting for them count, whatever struck the last blow
+        let player = self.player.id;
+        let allies: Vec<EntityId> = self.allies.iter().map(|a| a.entity.id).collect();
+        let mut experience = Vec::new();
+
+        let mut claimed = Vec::new();
+        let mut corpses = Vec::new();
+        for monster in self.monsters.iter().filter(|m| !m.is_alive()) {
+            let credit = monster.last_hit_by.as_ref().and_then(|source| source.responsible());
+            let by_ally = credit.is_some_and(|id| allies.contains(&id));
+            if credit == Some(player) || by_ally {
+                self.kills += 1;
+                self.floor_stats.entry(floor).or_default().kills += 1;
+                if by_ally {
+                    self.ally_kills += 1;
+                }
+                experience.push((monster.name.clone(), monster.level, monster.stats.xp_value));
+            }
+            if let Some(id) = monster.bounty {
+                claimed.push((id, monster.name.clone()));
+            }
+            if let Some(carcass) = monster.corpse {
+                corpses.push((monster.x, monster.y, Item::new_corpse(&monster.name, monster.color, carcass, self.turn + Self::CORPSE_ROT_TURNS)));
+            }
+            let drop_chance = if monster.elite { 1.0 } else { 0.5 };
+            if rng.gen_bool(drop_chance) {
+                if let Some(&kind) = monster.material_drops.choose(&mut rng) {
+                    let quality = MaterialQuality::roll(depth, monster.elite, &mut rng);
+                    drops.push((monster.name.clone(), monster.x, monster.y, Item::new_material(kind, quality)));
+                }
+            }
+            let gold_chance = if monster.elite { 1.0 } else { 0.4 };
+            if rng.gen_bool(gold_chance) {
+                let amount = (monster.stats.xp_value / 5).max(1) as u32 + rng.gen_range(0..=depth as u32 * 2);
+                drops.push((monster.name.clone(), monster.x, monster.y, Item::new_gold(amount)));
+            }
+            if rng.gen_bool(Self::loot_chance(monster)) {
+                if let Some(item) = self.item_registry.roll_entries(&monster.loot, &mut rng) {
+                    drops.push((monster.name.clone(), monster.x, monster.y, item));
+                }
+            }
+        }
+
+        self.monsters.retain(|m| m.is_alive());
+        self.ground_items.extend(corpses);
+
+        for (name, level, xp) in experience {
+            match self.diminished_xp(&name, level, xp) {
+                reduced if reduced < xp => {
+                    self.add_log_message(MessageCategory::System, format!("The {} was no match for you, it is worth less.", name));
+                    self.grant_xp(reduced);
+                }
+                _ => self.grant_xp(xp),
+            }
+        }
+
+        for (id, name) in claimed {
+            if let Some(bounty) = self.bounty_board.active.iter_mut().find(|b| b.id == id) {
+                bounty.completed = true;
+                self.add_log_message(MessageCategory::System, format!("{} is dead. Return to the bounty board for your reward.", name));
+            }
+        }
+
+        for (name, x, y, item) in drops {
+            self.add_log_message(MessageCategory::Item, format!("{} dropped {}!", name, self.identification.name(&item)));
+            self.ground_items.push((x, y, item));
+        }
+    }
+
+    fn handle_level_transition(&mut self) {
+        if is_key_pressed(KeyCode::Period) {
+            self.use_stairs(true);
+        } else if is_key_pressed(KeyCode::Comma) {
+            self.use_stairs(false);
+        }
+    }
+
+    // Take the stairs the player stands on, if they lead the asked way
+    fn use_stairs(&mut self, down: bool) {
+        match self.map_manager.current_map().tile_at(self.player.x as i32, self.player.y as i32) {
+            Some(Tile::StairsDown) if down => self.descend_stairs(),
+            Some(Tile::StairsUp) if !down => self.ascend_stairs(),
+            _ => {}
+        }
+    }
+
+    fn descend_stairs(&mut self) {
+        let stairs = (self.player.x as usize, self.player.y as usize);
+        let next_floor = self.map_manager.floor_beyond(stairs, true);
+        if next_floor.level > FINAL_FLOOR {
+            return;
+        }
+        if !self.leave_floor(next_floor, true) {
+            return;
+        }
+        let is_new_level = !self.level_states.contains_key(&next_floor);
+        let entering_branch = next_floor.branch != self.map_manager.current_branch;
+
+        if let Some((new_x, new_y)) = self.map_manager.change_floor(next_floor) {
+            self.player.x = new_x;
+            self.player.y = new_y;
+            self.auto_path.clear();
+            self.deepest_level = self.deepest_level.max(next_floor.level);
+
+            match self.map_manager.current_branch_template() {
+                Some(branch) if entering_branch => {
+                    let message = format!("You take the side stairs down into {}.", branch.name);
+                    self.add_log_message(MessageCategory::System, message);
+                }
+                _ => self.add_log_message(MessageCategory::System, format!("Descended to level {}", next_floor.level + 1)),
+            }
+            self.emit_event(GameEvent::TookStairs);
+
+            if is_new_level {
+                self.initialize_current_level();
+            } else {
+                self.load_level_state(next_floor);
+                self.announce_floor_modifiers();
+            }
+            self.place_bounty_targets();
+            self.regroup_allies();
+        }
+    }
+
+    fn ascend_stairs(&mut self) {
+        let stairs = (self.player.x as usize, self.player.y as usize);
+        let prev_floor = self.map_manager.floor_beyond(stairs, false);
+        if !self.leave_floor(prev_floor, false) {
+            return;
+        }
+        let leaving_branch = self.map_manager.current_branch_template().filter(|_| prev_floor.branch.is_none()).map(|branch| branch.name.clone());
+        if let Some((new_x, new_y)) = self.map_manager.change_floor(prev_floor) {
+            self.player.x = new_x;
+            self.player.y = new_y;
+            self.auto_path.clear();
+            self.load_level_state(prev_floor);
+            match leaving_branch {
+                Some(name) => self.add_log_message(MessageCategory::System, format!("You climb out of {} to level {}", name, prev_floor.level + 1)),
+                None if prev_floor == FloorId::TOWN => self.arrive_in_town("climb out"),
+                None => self.add_log_message(MessageCategory::System, format!("Ascended to level {}", prev_floor.level + 1)),
+            }
+            self.emit_event(GameEvent::TookStairs);
+            self.announce_floor_modifiers();
+            self.place_bounty_targets();
+            self.regroup_allies();
+        }
+    }
+
+    fn in_town(&self) -> bool {
+        self.map_manager.current_floor() == FloorId::TOWN
+    }
+
+    // A day passes above ground while the player is below, though only the town shows it
+    fn hour(&self) -> u32 {
+        (Self::START_HOUR + self.turn / Self::TURNS_PER_HOUR) % 24
+    }
+
+    fn is_daytime(&self) -> bool {
+        (Self::DAWN_HOUR..Self::DUSK_HOUR).contains(&self.hour())
+    }
+
+    fn town_clock(&self) -> Option<String> {
+        self.in_town().then(|| format!("{:02}:00", self.hour()))
+    }
+
+    fn announce_hour(&mut self) {
+        if !self.in_town() || !self.turn.is_multiple_of(Self::TURNS_PER_HOUR) {
+            return;
+        }
+        match self.hour() {
+            Self::DAWN_HOUR => self.add_log_message(MessageCategory::System, "Dawn breaks over the town.".to_string()),
+            Self::DUSK_HOUR => self.add_log_message(MessageCategory::System, "Night falls, and lamps are lit around the square.".to_string()),
+            _ => {}
+        }
+    }
+
+    // Each resident takes a step towards where the hour has them: their post while they are open,
+    // a spot on the square in the evening and their bed at night. They step around the player and
+    // each other, and wait when the way is blocked
+    fn move_townsfolk(&mut self) {
+        let hour = self.hour();
+        let player = (self.player.x as i32, self.player.y as i32);
+        let mut rng = self.fork_rng();
+        for index in 0..self.map_manager.current_map().residents.len() {
+            let map = self.map_manager.current_map();
+            let resident = &map.residents[index];
+            let mut stroll_to = resident.stroll_to;
+            let goal = match resident.kind.activity(hour) {
+                Activity::Working => resident.post,
+                Activity::Sleeping => resident.home,
+                Activity::Strolling => {
+                    let square = map.rooms.first().and_then(|row| row.first());
+                    let arrived = stroll_to.is_none_or(|spot| spot == resident.position);
+                    if let (true, false, Some(square)) = (arrived, rng.gen_bool(Resident::LINGER_CHANCE), square) {
+                        stroll_to = Some(square.random_position(&mut rng)).filter(|&(x, y)| map.is_walkable(x, y));
+                    }
+                    stroll_to.unwrap_or(resident.position)
+                }
+            };
+            let occupied: Vec<(i32, i32)> = map.residents.iter().map(|other| other.position).chain([player]).collect();
+            let step = (goal != resident.position)
+                .then(|| map.find_path_through(resident.position, goal, |x, y| map.is_walkable(x, y) && !occupied.contains(&(x, y))))
+                .flatten()
+                .and_then(|path| path.get(1).copied());
+
+            let map = self.map_manager.current_map_mut();
+            map.residents[index].stroll_to = stroll_to;
+            if let Some(step) = step {
+                map.move_resident(index, step);
+            }
+        }
+    }
+
+    // The town keeps hours, out of them its shopkeeper and healer turn the player away
+    fn off_duty_message(&self, x: i32, y: i32) -> Option<String> {
+        let resident = self.map_manager.current_map().resident_at(x, y)?;
+        let (opens, _, _) = resident.kind.hours();
+        let name = resident.kind.name();
+        match resident.kind.activity(self.hour()) {
+            Activity::Working if resident.position == resident.post => None,
+            Activity::Working => Some(format!("The {} is on their way to work.", name)),
+            Activity::Strolling => Some(format!("The {} is done for the day. Come back at {:02}:00.", name, opens)),
+            Activity::Sleeping => Some(format!("The {} is fast asleep. Come back at {:02}:00.", name, opens)),
+        }
+    }
+
+    // The first floor's up stairs lead out to the town, and bringing the Heart of the Forge there wins
+    // the run. Otherwise the shop takes in new wares whenever the player has been deeper than last time
+    fn arrive_in_town(&mut self, how: &str) {
+        let has_relic = self.player.inventory
+            .as_ref()
+            .is_some_and(|inv| inv.items.iter().any(|item| item.item_type == ItemType::Relic));
+        if let Some(controller) = &self.scenario {
+            let message = format!("You {} into the town. {}", how, controller.scenario.description);
+            self.add_log_message(MessageCategory::System, message);
+        } else if has_relic {
+            self.victorious = true;
+            self.add_log_message(MessageCategory::System, format!("You {} into the daylight with the Heart of the Forge!", how));
+            return;
+        } else {
+            self.add_log_message(MessageCategory::System, format!("You {} into the town. The Heart of the Forge still lies on floor {}.", how, FINAL_FLOOR + 1));
+        }
+
+        // The town went on without the player, so everyone is already wherever the hour has them
+        let hour = self.hour();
+        let map = self.map_manager.current_map_mut();
+        for index in 0..map.residents.len() {
+            let resident = &map.residents[index];
+            match resident.kind.activity(hour) {
+                Activity::Working => map.move_resident(index, resident.post),
+                Activity::Sleeping => map.move_resident(index, resident.home),
+                Activity::Strolling => {}
+            }
+        }
+
+        if self.has_modifier(Modifier::NoShops) {
+            self.map_manager.current_map_mut().shop = None;
+            return;
+        }
+        if self.town_stocked_for == Some(self.deepest_level) {
+            return;
+        }
+        let mut rng = self.fork_rng();
+        let mut stock: Vec<Item> = self.item_registry.create("health_potion").into_iter().collect();
+        stock.extend(self.item_registry.create("torch"));
+        stock.extend(self.item_registry.create("recall_scroll"));
+        stock.extend(self.item_registry.create("town_portal_scroll"));
+        for _ in 0..rng.gen_range(4..=6) {
+            stock.extend(self.item_registry.roll((self.deepest_level + 1).min(FINAL_FLOOR), &mut rng));
+        }
+        if let Some(shop) = self.map_manager.current_map_mut().shop.as_mut() {
+            shop.stock = stock;
+        }
+        if self.town_stocked_for.is_some() {
+            self.add_log_message(MessageCategory::System, "The shopkeeper has new wares in.".to_string());
+        }
+        self.town_stocked_for = Some(self.deepest_level);
+    }
+
+    fn healer_at(&self, x: i32, y: i32) -> bool {
+        self.map_manager.current_map().healer == Some((x, y))
+    }
+
+    // Wounds are tended for gold, as far as the player's purse goes
+    fn visit_healer(&mut self) {
+        let missing = (self.player.stats.max_hp - self.player.stats.hp).max(0) as u32;
+        let Some(inventory) = self.player.inventory.as_mut() else {
+            return;
+        };
+        let healed = missing.min(inventory.gold / Self::HEALER_PRICE);
+        let message = if missing == 0 {
+            "The healer looks you over. \"Not a scratch on you.\"".to_string()
+        } else if healed == 0 {
+            format!("The healer asks {} gold for each hit point healed. You can't pay.", Self::HEALER_PRICE)
+        } else {
+            let price = healed * Self::HEALER_PRICE;
+            inventory.gold -= price;
+            self.player.stats.hp += healed as i32;
+            format!("The healer tends your wounds for {} gold, restoring {} HP.", price, healed)
+        };
+        self.add_log_message(MessageCategory::System, message);
+    }
+
+    fn stash_at(&self, x: i32, y: i32) -> bool {
+        self.map_manager.current_map().stash.as_ref().is_some_and(|stash| stash.position == (x, y))
+    }
+
+    fn open_stash(&mut self) {
+        self.stash_open = true;
+        self.stash_selection = 0;
+    }
+
+    fn handle_stash_input(&mut self) {
+        if is_key_pressed(KeyCode::Tab) {
+            self.stash_storing = !self.stash_storing;
+            self.stash_selection = 0;
+        }
+
+        let count = if self.stash_storing {
+            self.player.inventory.as_ref().map_or(0, |inv| inv.items.len())
+        } else {
+            self.map_manager.current_map().stash.as_ref().map_or(0, |stash| stash.items.len())
+        };
+        self.stash_selection = self.stash_selection.min(count.saturating_sub(1));
+        if is_key_pressed(KeyCode::Up) && self.stash_selection > 0 {
+            self.stash_selection -= 1;
+        }
+        if is_key_pressed(KeyCode::Down) && self.stash_selection + 1 < count {
+            self.stash_selection += 1;
+        }
+
+        if count > 0 && is_key_pressed(KeyCode::Enter) {
+            if self.stash_storing {
+                self.store_item(self.stash_selection);
+            } else {
+                self.take_stashed_item(self.stash_selection);
+            }
+        }
+    }
+
+    fn store_item(&mut self, index: usize) {
+        let full = self.map_manager.current_map().stash.as_ref().is_none_or(|stash| stash.items.len() >= Stash::CAPACITY);
+        if full {
+            self.add_log_message(MessageCategory::Warning, "The stash is full.".to_string());
+            return;
+        }
+        let keeps = self.player.inventory.as_ref().and_then(|inv| inv.items.get(index)).is_none_or(|item| SharedStash::keeps(item, &self.item_registry));
+        if self.map_manager.config.shared_stash && !keeps {
+            self.add_log_message(MessageCategory::Warning, "That won't keep in the stash between lives.".to_string());
+            return;
+        }
+        let Some(item) = self.player.inventory.as_mut().and_then(|inv| inv.remove_item(index)) else {
+            return;
+        };
+        self.add_log_message(MessageCategory::Item, format!("You put the {} in the stash.", self.identification.name(&item)));
+        if let Some(stash) = self.map_manager.current_map_mut().stash.as_mut() {
+            stash.items.push(item);
+        }
+        self.save_shared_stash();
+    }
+
+    fn take_stashed_item(&mut self, index: usize) {
+        let Some(item) = self.map_manager.current_map().stash.as_ref().and_then(|stash| stash.items.get(index)).cloned() else {
+            return;
+        };
+        let Some(inventory) = self.player.inventory.as_mut() else {
+            return;
+        };
+        if let Err(e) = inventory.add_item(item.clone()) {
+            self.add_log_message(MessageCategory::Warning, e);
+            return;
+        }
+        if let Some(stash) = self.map_manager.current_map_mut().stash.as_mut() {
+            stash.items.remove(index);
+        }
+        self.add_log_message(MessageCategory::Item, format!("You take the {} from the stash.", self.identification.name(&item)));
+        self.save_shared_stash();
+    }
+
+    // Fill the town stash with whatever earlier characters left in it
+    fn open_shared_stash(&mut self) {
+        if !self.map_manager.config.shared_stash {
+            return;
+        }
+        let items = SharedStash::load().restore(&self.item_registry);
+        if let Some(stash) = self.map_manager.town.stash.as_mut() {
+            stash.items = items;
+        }
+    }
+
+    fn save_shared_stash(&self) {
+        if !self.map_manager.config.shared_stash {
+            return;
+        }
+        if let Some(stash) = self.map_manager.town.stash.as_ref() {
+            SharedStash::from_items(&stash.items).save();
+        }
+    }
+
+    fn draw_stash(&self) {
+        let (Some(stash), Some(inventory)) = (self.map_manager.current_map().stash.as_ref(), self.player.inventory.as_ref()) else {
+            return;
+        };
+
+        draw_rectangle(
+            screen_width() * 0.1,
+            screen_height() * 0.1,
+            screen_width() * 0.8,
+            screen_height() * 0.8,
+            Color::new(0.0, 0.0, 0.0, 0.9),
+        );
+
+        draw_text("Stash", screen_width() * 0.15, screen_height() * 0.15, 30.0, WHITE);
+        let space = format!("{}/{} stored", stash.items.len(), Stash::CAPACITY);
+        draw_text(&space, screen_width() * 0.6, screen_height() * 0.15, 20.0, GRAY);
+
+        let columns = [
+            ("In the stash:", screen_width() * 0.15, &stash.items, false),
+            ("Your items:", screen_width() * 0.5, &inventory.items, true),
+        ];
+        for (title, x, items, storing) in columns {
+            let focused = self.stash_storing == storing;
+            draw_text(title, x, screen_height() * 0.22, 20.0, if focused { WHITE } else { GRAY });
+            for (i, item) in items.iter().enumerate() {
+                let y_pos = screen_height() * 0.22 + 25.0 + i as f32 * 25.0;
+                if focused && i == self.stash_selection {
+                    draw_text(">", x - 15.0, y_pos, 20.0, WHITE);
+                }
+                let (name, color) = (self.identification.name(item), self.identification.color(item));
+                draw_text(&format!("{} {}", item.symbol, name), x, y_pos, 20.0, color);
+            }
+        }
+
+        draw_text(
+            "[Tab] Switch take/store  [Up/Down] Select  [Enter] Take or store  [Esc] Close",
+            screen_width() * 0.15,
+            screen_height() * 0.85,
+            20.0,
+            LIGHTGRAY,
+        );
+    }
+
+    // Off the floor without the stairs: a recall lands on the first floor's up stairs, a rope by the
+    // down stairs of the floor above and a portal on its other end. Nothing standing next to the player gets to follow
+    fn escape_floor(&mut self, escape: Escape) {
+        let here = self.map_manager.current_floor();
+        let portal_exit = self.map_manager.portal_exit((self.player.x as i32, self.player.y as i32));
+        let destination = match (escape, portal_exit) {
+            (Escape::Recall, _) => FloorId::FIRST,
+            // Going up, which stairs doesn't matter
+            (Escape::Rope, _) => self.map_manager.floor_beyond((0, 0), false),
+            (Escape::Portal, Some((floor, _))) => floor,
+            (Escape::Portal, None) => return,
+        };
+        if destination == here {
+            self.add_log_message(MessageCategory::Warning, "The humming fades. You are already on the first floor.".to_string());
+            return;
+        }
+
+        self.summarize_floor();
+        self.save_current_level_state();
+        let arrival = self.map_manager.change_floor(destination);
+        let landing = match escape {
+            Escape::Recall => self.map_manager.current_map().up_stairs.map(|(x, y)| (x as f32, y as f32)),
+            Escape::Rope => arrival,
+            Escape::Portal => portal_exit.map(|(_, (x, y))| (x as f32, y as f32)),
+        };
+        if let Some((x, y)) = landing {
+            self.player.x = x;
+            self.player.y = y;
+        }
+        self.auto_path.clear();
+        self.digging = None;
+        self.targeting = None;
+        self.load_level_state(destination);
+        match escape {
+            Escape::Recall => self.add_log_message(MessageCategory::System, "The scroll's magic takes hold and pulls you back to the first floor!".to_string()),
+            Escape::Rope => self.add_log_message(MessageCategory::System, format!("You haul yourself up the rope to level {}.", destination.level + 1)),
+            Escape::Portal if destination == FloorId::TOWN => self.arrive_in_town("step through the portal"),
+            // Back where it was opened the portal has done its job
+            Escape::Portal => {
+                self.map_manager.close_portal();
+                self.add_log_message(MessageCategory::System, format!("You step back through the portal to level {}. It winks shut behind you.", destination.level + 1));
+            }
+        }
+        self.announce_floor_modifiers();
+        self.place_bounty_targets();
+        self.regroup_allies();
+    }
+
+    // The deepest floor has no way down, instead its last room holds the boss and the relic
+    fn spawn_final_floor(&mut self) {
+        let map = self.map_manager.current_map();
+        if map.level != FINAL_FLOOR || self.map_manager.current_branch.is_some() {
+            return;
+        }
+        let Some(lair) = map.rooms.iter().flatten().last().cloned() else {
+            return;
+        };
+        let (x, y) = lair.center();
+
+        self.monsters.retain(|m| !lair.contains(m.x as i32, m.y as i32));
+        if let Some(relic) = self.item_registry.create("heart_of_the_forge") {
+            self.ground_items.push((x as f32, y as f32, relic));
+        }
+        if let Some(template) = self.monster_registry.templates.iter().find(|t| t.boss) {
+            let (bx, by) = lair.inner_tiles().into_iter().filter(|&tile| tile != (x, y) && map.is_walkable(tile.0, tile.1)).choose(&mut self.rng).unwrap_or((x, y));
+            self.monsters.push(Entity::new_monster(template, bx as f32, by as f32));
+            self.add_log_message(MessageCategory::Warning, format!("The air shimmers with heat. The {} guards the Heart of the Forge.", template.name));
+        }
+    }
+
+    // What a scenario needs laid out on a newly made floor of the main dungeon
+    fn spawn_scenario_pieces(&mut self) {
+        let floor = self.map_manager.current_floor();
+        let mut rng = self.fork_rng();
+        let Some(controller) = self.scenario.as_ref() else {
+            return;
+        };
+        if floor.branch.is_some() || floor == FloorId::TOWN {
+            return;
+        }
+        match controller.scenario.goal.clone() {
+            ScenarioGoal::Collect { item, count } if controller.placed < count => {
+                let map = self.map_manager.current_map();
+                let (Some(room), Some(piece)) = (map.rooms.iter().flatten().skip(1).choose(&mut rng), self.item_registry.create(&item)) else {
+                    return;
+                };
+                let (x, y) = map.open_position(room, &mut rng);
+                self.add_log_message(MessageCategory::System, format!("A {} lies somewhere on this floor.", piece.name.to_lowercase()));
+                self.ground_items.push((x as f32, y as f32, piece));
+                if let Some(controller) = self.scenario.as_mut() {
+                    controller.placed += 1;
+                }
+            }
+            ScenarioGoal::Escort { npc, from_floor } if floor.level == from_floor && controller.escort.is_none() => {
+                let template = self.monster_registry.templates.iter().find(|t| t.name == npc);
+                let (Some(template), Some((x, y))) = (template, self.free_tile_near(self.player.x as i32, self.player.y as i32)) else {
+                    return;
+                };
+                let entity = Entity::new_monster(template, x as f32, y as f32);
+                let id = entity.id;
+                // The escort always joins, so with a full party the newest companion steps aside
+                if self.allies.len() >= Ally::MAX_PARTY_SIZE {
+                    if let Some(ally) = self.allies.pop() {
+                        self.add_log_message(MessageCategory::System, format!("The {} leaves your party to make room.", ally.entity.name));
+                    }
+                }
+                self.allies.push(Ally { entity, order: AllyOrder::Follow, leaves_at: None });
+                if let Some(controller) = self.scenario.as_mut() {
+                    controller.escort = Some(id);
+                }
+                self.add_log_message(MessageCategory::System, format!("You find the {}, lost down here. Bring them up to the town alive!", npc));
+            }
+            _ => {}
+        }
+    }
+
+    // The bottom of a branch holds its reward, in the last room
+    fn spawn_branch_reward(&mut self) {
+        let Some(branch) = self.map_manager.current_branch_template() else {
+            return;
+        };
+        let map = self.map_manager.current_map();
+        if map.level != branch.deepest_floor() {
+            return;
+        }
+        let Some((x, y)) = map.rooms.iter().flatten().last().map(Room::center) else {
+            return;
+        };
+        let message = format!("This is the bottom of {}. Something of worth lies here.", branch.name);
+        if let Some(reward) = self.item_registry.create(&branch.reward) {
+            self.ground_items.push((x as f32, y as f32, reward));
+            self.add_log_message(MessageCategory::System, message);
+        }
+    }
+
+    fn spawn_entities(&mut self, map: &Map) {
+        // Spawn player in first room of first row
+        if let Some(first_row) = map.rooms.first() {
+            if let Some(first_room) = first_row.first() {
+                let (center_x, center_y) = first_room.center();
+                self.player.x = center_x as f32;
+                self.player.y = center_y as f32;
+            } else {
+                // Fallback: scan for walkable tile
+                for y in 0..map.height {
+                    for x in 0..map.width {
+                        if map.is_walkable(x as i32, y as i32) {
+                            self.player.x = x as f32;
+                            self.player.y = y as f32;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.monsters.clear();
+        let rooms = map.rooms.clone();
+        let mut rng = self.fork_rng();
+
+        // Skip first row for monster spawning
+        for row in rooms.iter().skip(1) {
+            for room in row.iter() { // Changed from row to row.iter()
+                let num_monsters = rng.gen_range(0..3);
+                for _ in 0..num_monsters {
+                    let mut tries = 0;
+                    let max_tries = 10;
+
+                    while tries < max_tries {
+                        let (x, y) = room.random_position(&mut rng);
+                        if map.is_walkable(x, y) {
+                            let is_occupied = self.monsters.iter()
+                                .any(|m| m.x == x as f32 && m.y == y as f32);
+
+                            if !is_occupied {
+                                if let Some(template) = self.monster_registry.choose(map.level, &mut rng) {
+                                    self.monsters.push(Entity::new_monster(template, x as f32, y as f32));
+                                }
+                                break;
+                            }
+                        }
+                        tries += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_and_pickup_items(&mut self) {
+        let mut items_to_pickup = Vec::new();
+
+        // Find all items at player's position, corpses stay where they fell
+        for (i, (x, y, item)) in self.ground_items.iter().enumerate() {
+            if *x == self.player.x && *y == self.player.y && !matches!(item.item_type, ItemType::Corpse(_, _)) {
+                items_to_pickup.push(i);
+            }
+        }
+
+        for &i in items_to_pickup.iter().rev() {
+            if let Some((_, _, item)) = self.ground_items.get(i) {
+                if let Some(ref mut inventory) = self.player.inventory {
+                    match inventory.add_item(item.clone()) {
+                        Ok(_) => {
+                            let artifact = match item.item_type {
+                                ItemType::Artifact(artifact) => Some(artifact),
+                                _ => None,
+                            };
+                            if !matches!(item.item_type, ItemType::Gold(_)) {
+                                self.items_collected += 1;
+                            }
+                            self.events.push(GameEvent::ItemPickedUp);
+                            self.add_log_message(MessageCategory::Item, format!("Picked up {}!", self.identification.name(item)));
+                            if let Some(artifact) = artifact.filter(|_| !self.map_manager.config.demo) {
+                                if self.encyclopedia.record_artifact(artifact) {
+                                    self.add_log_message(MessageCategory::System, format!("{} has been recorded in the encyclopedia.", artifact.name()));
+                                }
+                            }
+                            self.ground_items.remove(i);
+                        }
+                        Err(e) => {
+                            self.add_log_message(MessageCategory::Warning, e);
+                            break; // Stop picking up if inventory is full
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Add this method to display inventory
+    fn draw_inventory(&self) {
+        if let Some(ref inventory) = self.player.inventory {
+            // Draw semi-transparent background
+            draw_rectangle(
+                screen_width() * 0.1,
+                screen_height() * 0.1,
+                screen_width() * 0.8,
+                screen_height() * 0.8,
+                Color::new(0.0, 0.0, 0.0, 0.9),
+            );
+
+            // Draw title
+            draw_text(
+                "Inventory",
+                screen_width() * 0.15,
+                screen_height() * 0.15,
+                30.0,
+                WHITE,
+            );
+
+            // Draw equipped items
+            let equipped_y = screen_height() * 0.2;
+            draw_text(
+                "Equipped:",
+                screen_width() * 0.15,
+                equipped_y,
+                20.0,
+                LIGHTGRAY,
+            );
+
+            for (i, slot) in EquipSlot::ALL.iter().enumerate() {
+                let (text, color) = match inventory.equipment.get(slot) {
+                    Some(item) => (
+                        format!("{}: {}{}", slot.name(), item.name, item.curse_marker()),
+                        if item.cursed && item.curse_known { RED } else { item.color },
+                    ),
+                    None => (format!("{}: -", slot.name()), DARKGRAY),
+                };
+                draw_text(&text, screen_width() * 0.15, equipped_y + 22.0 + (i as f32 * 20.0), 18.0, color);
+            }
+
+            // Draw inventory items
+            let items_y = equipped_y + 190.0;
+            draw_text(
+                "Items:",
+                screen_width() * 0.15,
+                items_y,
+                20.0,
+                LIGHTGRAY,
+            );
+
+            // The rows that fit above the instructions, scrolled to keep the selection in view
+            let rows = (((screen_height() * 0.85 - 30.0 - (items_y + 25.0)) / 25.0).max(0.0) as usize + 1).max(2);
+            let first = (self.inventory_selection + 1).saturating_sub(rows);
+            let shown = inventory.items.iter().enumerate().skip(first).take(rows);
+            if first > 0 {
+                draw_text("...", screen_width() * 0.25, items_y, 20.0, GRAY);
+            }
+            if first + rows < inventory.items.len() {
+                draw_text("...", screen_width() * 0.15, items_y + 25.0 * (rows as f32 + 0.8), 20.0, GRAY);
+            }
+
+            for (row, (i, item)) in shown.enumerate() {
+                let y_pos = items_y + 25.0 + (row as f32 * 25.0);
+                if i == self.inventory_selection {
+                    draw_text(">", screen_width() * 0.13, y_pos, 20.0, WHITE);
+                }
+                draw_text(
+                    &format!("{}) {} {}{}",
+                             i + 1,
+                             item.symbol,
+                             self.identification.name(item),
+                             item.curse_marker()
+                    ),
+                    screen_width() * 0.15,
+                    y_pos,
+                    20.0,
+                    if item.curse_marker().is_empty() { self.identification.color(item) } else { RED },
+                );
+            }
+
+            // Draw details of the selected item in a second column
+            if let Some(item) = inventory.items.get(self.inventory_selection) {
+                let details_x = screen_width() * 0.55;
+                draw_text(&self.identification.name(item), details_x, equipped_y, 20.0, self.identification.color(item));
+                draw_text(&self.identification.describe(item), details_x, equipped_y + 25.0, 20.0, LIGHTGRAY);
+
+                if let ItemType::Artifact(artifact) = item.item_type {
+                    draw_text(artifact.lore(), details_x, equipped_y + 50.0, 18.0, GRAY);
+                }
+
+                if item.sockets > 0 {
+                    let in_weapon = item.is_weapon();
+                    draw_text(&format!("Sockets: {}", item.socket_summary()), details_x, equipped_y + 50.0, 20.0, LIGHTGRAY);
+                    for (i, (gem, quality)) in item.gems.iter().enumerate() {
+                        draw_text(&gem.describe(*quality, in_weapon), details_x, equipped_y + 75.0 + (i as f32 * 25.0), 20.0, quality.color());
+                    }
+                }
+
+                if let Some(set) = item.set {
+                    let equipped = inventory.equipped_set_pieces(set);
+                    draw_text(
+                        &format!("{} ({}/{} equipped)", set.name(), equipped, set.pieces().len()),
+                        details_x,
+                        equipped_y + 50.0,
+                        20.0,
+                        LIME,
+                    );
+
+                    for (i, bonus) in set.bonuses().iter().enumerate() {
+                        let color = if equipped >= bonus.pieces { GREEN } else { GRAY };
+                        draw_text(
+                            &format!("({}) +{} attack, +{} defense", bonus.pieces, bonus.attack, bonus.defense),
+                            details_x,
+                            equipped_y + 75.0 + (i as f32 * 25.0),
+                            20.0,
+                            color,
+                        );
+                    }
+                }
+            }
+
+            // Draw the materials pouch below the item details
+            let materials_y = equipped_y + 190.0;
+            draw_text(
+                "Materials:",
+                screen_width() * 0.55,
+                materials_y,
+                20.0,
+                LIGHTGRAY,
+            );
+
+            for (i, (kind, quality, count)) in inventory.materials.entries().iter().enumerate() {
+                draw_text(
+                    &format!("{} {} x{}", quality.name(), kind.name(), count),
+                    screen_width() * 0.55,
+                    materials_y + 25.0 + (i as f32 * 25.0),
+                    20.0,
+                    quality.color(),
+                );
+            }
+
+            // Draw usage instructions
+            draw_text(
+                "[Up/Down] Select  [E] Equip  [U] Use  [T] Throw  [D] Drop  [Esc] Close",
+                screen_width() * 0.15,
+                screen_height() * 0.85,
+                20.0,
+                LIGHTGRAY,
+            );
+        }
+    }
+
+    fn handle_inventory_input(&mut self) {
+        let item_count = self.player.inventory.as_ref().map_or(0, |inv| inv.items.len());
+        self.inventory_selection = self.inventory_selection.min(item_count.saturating_sub(1));
+
+        if is_key_pressed(KeyCode::Up) && self.inventory_selection > 0 {
+            self.inventory_selection -= 1;
+        }
+        if is_key_pressed(KeyCode::Down) && self.inventory_selection + 1 < item_count {
+            self.inventory_selection += 1;
+        }
+        if item_count == 0 {
+            return;
+        }
+
+        let index = self.inventory_selection;
+        let mut cost = ActionCost::Free;
+        if is_key_pressed(KeyCode::E) {
+            if let Some(ref mut inventory) = self.player.inventory {
+                let result = inventory.equip_item(index);
+                if result.is_ok() {
+                    cost = ActionCost::Turn;
+                }
+                match result {
+                    Ok(message) => self.add_log_message(MessageCategory::Item, message),
+                    Err(message) => self.add_log_message(MessageCategory::Warning, message),
+                }
+            }
+        } else if is_key_pressed(KeyCode::U) {
+            self.use_inventory_item(index);
+        } else if is_key_pressed(KeyCode::T) {
+            self.begin_throw(index);
+        } else if is_key_pressed(KeyCode::D) {
+            let dropped = self.player.inventory.as_mut().and_then(|inv| inv.remove_item(index));
+            if let Some(item) = dropped {
+                self.add_log_message(MessageCategory::Item, format!("Dropped {}.", self.identification.name(&item)));
+                self.ground_items.push((self.player.x, self.player.y, item));
+                cost = ActionCost::Turn;
+            }
+        }
+        if cost == ActionCost::Turn {
+            self.take_turn();
+        }
+    }
+
+    // Drinking, eating and reading take a turn. Items that need aiming spend it when the aim lands
+    fn use_inventory_item(&mut self, index: usize) {
+        // Take the inventory out of the player so the item can act on both the player and the game state
+        if let Some(mut inventory) = self.player.inventory.take() {
+            let used = inventory.items.get(index).map(|item| (self.identification.name(item), item.name.clone()));
+            let mut player = self.player.clone();
+            let result = inventory.use_item(index, &mut player, self);
+            if let (Ok(_), Some((appearance, name))) = (&result, used) {
+                if self.identification.learn(&name) {
+                    self.add_log_message(MessageCategory::Item, format!("The {} was a {}!", appearance, name));
+                }
+            }
+            self.player = player;
+            self.player.inventory = Some(inventory);
+
+            // Aiming happens on the map
+            if self.targeting.is_some() {
+                self.inventory_open = false;
+            } else if result.is_ok() {
+                self.take_turn();
+            }
+
+            match result {
+                Ok(message) => self.add_log_message(MessageCategory::Item, message),
+                Err(message) => self.add_log_message(MessageCategory::Warning, message),
+            }
+        }
+    }
+
+    fn draw_encyclopedia(&self) {
+        draw_rectangle(
+            screen_width() * 0.1,
+            screen_height() * 0.1,
+            screen_width() * 0.8,
+            screen_height() * 0.8,
+            Color::new(0.0, 0.0, 0.0, 0.9),
+        );
+
+        draw_text(
+            "Encyclopedia - Artifacts",
+            screen_width() * 0.15,
+            screen_height() * 0.15,
+            30.0,
+            WHITE,
+        );
+
+        for (i, artifact) in Artifact::ALL.iter().enumerate() {
+            let y_pos = screen_height() * 0.2 + (i as f32 * 75.0);
+            if self.encyclopedia.has_found(*artifact) {
+                draw_text(artifact.name(), screen_width() * 0.15, y_pos, 20.0, MAGENTA);
+                draw_text(artifact.power(), screen_width() * 0.15, y_pos + 22.0, 18.0, LIGHTGRAY);
+                draw_text(artifact.lore(), screen_width() * 0.15, y_pos + 44.0, 18.0, GRAY);
+            } else {
+                draw_text("??? (not yet found)", screen_width() * 0.15, y_pos, 20.0, DARKGRAY);
+            }
+        }
+
+        draw_text(
+            "[Esc] Close",
+            screen_width() * 0.15,
+            screen_height() * 0.85,
+            20.0,
+            LIGHTGRAY,
+        );
+    }
+
+    fn open_message_log(&mut self) {
+        self.log_open = true;
+        self.log_scroll = 0;
+    }
+
+    fn logged_messages(&self) -> Vec<&LoggedMessage> {
+        self.message_history
+            .iter()
+            .filter(|message| self.log_filter.is_none_or(|category| message.category == category))
+            .collect()
+    }
+
+    fn handle_log_input(&mut self) {
+        let last = self.logged_messages().len().saturating_sub(1);
+        if is_key_pressed(KeyCode::Up) {
+            self.log_scroll += 1;
+        }
+        if is_key_pressed(KeyCode::Down) {
+            self.log_scroll = self.log_scroll.saturating_sub(1);
+        }
+        if is_key_pressed(KeyCode::PageUp) {
+            self.log_scroll += LoggedMessage::PAGE_LINES;
+        }
+        if is_key_pressed(KeyCode::PageDown) {
+            self.log_scroll = self.log_scroll.saturating_sub(LoggedMessage::PAGE_LINES);
+        }
+        if is_key_pressed(KeyCode::Home) {
+            self.log_scroll = last;
+        }
+        if is_key_pressed(KeyCode::End) {
+            self.log_scroll = 0;
+        }
+        // Tab steps through the categories and back to showing everything
+        if is_key_pressed(KeyCode::Tab) {
+            self.log_filter = match self.log_filter {
+                None => Some(MessageCategory::ALL[0]),
+                Some(category) => MessageCategory::ALL.iter().skip_while(|c| **c != category).nth(1).copied(),
+            };
+            self.log_scroll = 0;
+        }
+        self.log_scroll = self.log_scroll.min(self.logged_messages().len().saturating_sub(1));
+    }
+
+    // The newest messages sit at the bottom, scrolling back pages up through older ones
+    fn draw_message_log(&self) {
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.92));
+        let filter = self.log_filter.map_or("All", |category| category.name());
+        draw_text(&format!("Message log - {}", filter), 20.0, 40.0, 30.0, WHITE);
+
+        let messages = self.logged_messages();
+        let top = 70.0;
+        let line_height = 20.0;
+        let rows = ((screen_height() - top - 50.0) / line_height).max(1.0) as usize;
+        let end = messages.len().saturating_sub(self.log_scroll);
+        let start = end.saturating_sub(rows);
+        for (i, message) in messages[start..end].iter().enumerate() {
+            let y = top + i as f32 * line_height;
+            draw_text(&format!("{:>5}", message.turn), 20.0, y, 16.0, DARKGRAY);
+            draw_text(&message.text, 80.0, y, 18.0, message.category.color());
+        }
+        if messages.is_empty() {
+            draw_text("Nothing here yet.", 80.0, top, 18.0, GRAY);
+        }
+
+        draw_text(
+            &format!("{}-{} of {}   [Up/Down] Scroll  [PgUp/PgDn] Page  [Home/End] Oldest/newest  [Tab] Category  [Esc] Close", start + 1.min(end), end, messages.len()),
+            20.0,
+            screen_height() - 20.0,
+            18.0,
+            LIGHTGRAY,
+        );
+    }
+
+    fn begin_targeting(&mut self, tool: Tool) -> Result<String, String> {
+        if let Some(&ready_turn) = self.tool_cooldowns.get(&tool) {
+            if self.turn < ready_turn {
+                return Err(format!("The {} isn't ready yet ({} turns left)", tool.name(), ready_turn - self.turn));
+            }
+        }
+        self.targeting = Some(Targeting::Tool(tool));
+        Ok(format!("Click a target for the {}, [Esc] to cancel.", tool.name()))
+    }
+
+    // The tiles a tool would carry the player over, if the target is valid
+    fn tool_line(&self, tool: Tool, target: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+        let map = self.map_manager.current_map();
+        let start = (self.player.x as i32, self.player.y as i32);
+        let (dx, dy) = (target.0 - start.0, target.1 - start.1);
+        let distance = dx.abs().max(dy.abs());
+        if distance == 0 || distance > tool.range() || !self.is_lit(target.0 as f32, target.1 as f32) {
+            return None;
+        }
+
+        let line = bresenham_line(start, target);
+        match tool {
+            Tool::GrapplingHook => {
+                // The hook bites into a wall and pulls the player up to it
+                let path = &line[..line.len() - 1];
+                let anchored = map.tile_at(target.0, target.1) == Some(&Tile::Wall);
+                let clear = path.iter().all(|&(x, y)| map.is_walkable(x, y));
+                (anchored && clear && !path.is_empty()).then(|| path.to_vec())
+            }
+            Tool::DashBoots => {
+                let straight = dx == 0 || dy == 0 || dx.abs() == dy.abs();
+                let clear = line.iter().all(|&(x, y)| map.is_walkable(x, y));
+                let landing_free = !self.monsters.iter().any(|m| m.is_alive() && (m.x as i32, m.y as i32) == target);
+                (straight && clear && landing_free).then_some(line)
+            }
+        }
+    }
+
+    fn aim_at(&mut self, target: (i32, i32)) {
+        match self.targeting {
+            Some(Targeting::Tool(tool)) => self.use_tool_at(tool, target),
+            Some(Targeting::Throw(index)) => self.throw_at(index, target),
+            Some(Targeting::Cast(spell)) => self.cast_at(spell, target),
+            Some(Targeting::Dig) => self.start_digging(target),
+            None => {}
+        }
+    }
+
+    // The tiles an aimed tool, throw or spell would cross, if the target is valid
+    fn aim_line(&self, target: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+        match self.targeting? {
+            Targeting::Tool(tool) => self.tool_line(tool, target),
+            Targeting::Throw(_) => self.throw_path(target),
+            Targeting::Cast(spell) => self.spell_line(spell, target),
+            Targeting::Dig => self.diggable_next_to_player(target).then(|| vec![target]),
+        }
+    }
+
+    fn has_pickaxe(&self) -> bool {
+        self.player.inventory.as_ref().is_some_and(|inv| inv.items.iter().any(|i| i.item_type == ItemType::Pickaxe))
+    }
+
+    // Walls next to the player can be dug through, but not the map's edge, and ore veins are mined instead
+    fn diggable_next_to_player(&self, tile: (i32, i32)) -> bool {
+        let map = self.map_manager.current_map();
+        let player = (self.player.x as i32, self.player.y as i32);
+        let adjacent = map.directions().iter().any(|(dx, dy)| (player.0 + dx, player.1 + dy) == tile);
+        adjacent && map.is_diggable(tile.0, tile.1) && !map.ore_veins.contains_key(&tile)
+    }
+
+    fn begin_digging(&mut self) {
+        if !self.has_pickaxe() {
+            self.add_log_message(MessageCategory::Warning, "You need a pickaxe to dig.".to_string());
+            return;
+        }
+        self.targeting = Some(Targeting::Dig);
+        self.add_log_message(MessageCategory::System, "Click a wall next to you to dig through, [Esc] to cancel.".to_string());
+    }
+
+    fn start_digging(&mut self, tile: (i32, i32)) {
+        if !self.diggable_next_to_player(tile) {
+            self.add_log_message(MessageCategory::Warning, "You can't dig there.".to_string());
+            return;
+        }
+        self.targeting = None;
+        self.auto_path.clear();
+        self.digging = Some(Digging {
+            tile,
+            swings_left: Digging::SWINGS,
+            visible_monsters: self.visible_monster_count(),
+            hp: self.player.stats.hp,
+        });
+        self.add_log_message(MessageCategory::System, "You start digging into the wall.".to_string());
+    }
+
+    // One turn's swing at the wall being dug, breaking through on the last. Stops when the player
+    // has moved away, a monster comes into view or something hurts them
+    fn continue_digging(&mut self) {
+        let Some(digging) = self.digging.as_ref() else {
+            return;
+        };
+        let tile = digging.tile;
+        let interrupted = self.visible_monster_count() > digging.visible_monsters || self.player.stats.hp < digging.hp;
+        if interrupted || !self.diggable_next_to_player(tile) {
+            self.digging = None;
+            if interrupted {
+                self.add_log_message(MessageCategory::System, "You stop digging.".to_string());
+            }
+            return;
+        }
+
+        let broke_through = self.digging.as_mut().is_some_and(|digging| {
+            digging.swings_left -= 1;
+            digging.swings_left == 0
+        });
+        self.take_turn();
+        self.alert_monsters(Noise::Mining, (self.player.x as i32, self.player.y as i32));
+        if broke_through {
+            self.digging = None;
+            self.map_manager.current_map_mut().dig(tile.0, tile.1);
+            self.add_log_message(MessageCategory::System, "You break through the wall.".to_string());
+        }
+    }
+
+    fn begin_throw(&mut self, index: usize) {
+        let Some(item) = self.player.inventory.as_ref().and_then(|inv| inv.items.get(index)) else {
+            return;
+        };
+        let message = format!("Click where to throw the {}, [Esc] to cancel.", self.identification.name(item));
+        self.targeting = Some(Targeting::Throw(index));
+        self.inventory_open = false;
+        self.add_log_message(MessageCategory::System, message);
+    }
+
+    fn throw_path(&self, target: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+        self.projectile_path(target, Item::THROW_RANGE)
+    }
+
+    // The tiles something thrown or shot flies over. It drops short of a wall and stops at the first monster in its way
+    fn projectile_path(&self, target: (i32, i32), range: i32) -> Option<Vec<(i32, i32)>> {
+        let map = self.map_manager.current_map();
+        let start = (self.player.x as i32, self.player.y as i32);
+        let distance = (target.0 - start.0).abs().max((target.1 - start.1).abs());
+        if distance == 0 || distance > range || !self.is_lit(target.0 as f32, target.1 as f32) {
+            return None;
+        }
+
+        let mut path = Vec::new();
+        for (x, y) in bresenham_line(start, target) {
+            if !map.is_walkable(x, y) {
+                break;
+            }
+            path.push((x, y));
+            if self.monsters.iter().any(|m| m.is_alive() && (m.x as i32, m.y as i32) == (x, y)) {
+                break;
+            }
+        }
+        (!path.is_empty()).then_some(path)
+    }
+
+    // Potions shatter where they land, weapons hurt whatever they hit, and everything else
+    // comes to rest on the floor
+    fn throw_at(&mut self, index: usize, target: (i32, i32)) {
+        let Some(landing) = self.throw_path(target).and_then(|path| path.last().copied()) else {
+            self.add_log_message(MessageCategory::Warning, "You can't throw it there.".to_string());
+            return;
+        };
+        let Some(item) = self.player.inventory.as_mut().and_then(|inv| inv.remove_item(index)) else {
+            self.targeting = None;
+            return;
+        };
+
+        self.targeting = None;
+        self.auto_path.clear();
+        let name = self.identification.name(&item);
+        self.projectiles.push(ProjectileTrace {
+            from: (self.player.x, self.player.y),
+            to: (landing.0 as f32, landing.1 as f32),
+            color: self.identification.color(&item),
+            time_left: ProjectileTrace::DURATION,
+        });
+
+        if let ItemType::Potion(amount) = item.item_type {
+            let creature = self.monsters
+                .iter_mut()
+                .chain(self.allies.iter_mut().map(|a| &mut a.entity))
+                .find(|e| e.is_alive() && (e.x as i32, e.y as i32) == landing);
+            let message = match creature {
+                Some(creature) => {
+                    creature.stats.hp = (creature.stats.hp + amount).min(creature.stats.max_hp);
+                    format!("The {} shatters over the {}, healing it for {} HP.", name, creature.name, amount)
+                }
+                None => format!("The {} shatters on the floor.", name),
+            };
+            self.add_log_message(MessageCategory::Item, message);
+            if self.identification.learn(&item.name) {
+                self.add_log_message(MessageCategory::Item, format!("The {} was a {}!", name, item.name));
+            }
+        } else {
+            let damage_against = |defense: i32| match item.item_type {
+                ItemType::Weapon(bonus) => (Item::THROWN_WEAPON_DAMAGE + bonus - defense).max(1),
+                _ => 1,
+            };
+            let target = self.monsters.iter_mut().find(|m| m.is_alive() && (m.x as i32, m.y as i32) == landing);
+            let mut killed = false;
+            let message = match target {
+                Some(monster) => {
+                    let damage = damage_against(monster.get_total_defense());
+                    let hp_before = monster.stats.hp;
+                    monster.stats.hp -= damage;
+                    self.damage_popups.extend(DamagePopup::after_hit(monster, hp_before, false));
+                    monster.last_hit_by = Some(DamageSource::Used(self.player.id, "a thrown weapon"));
+                    killed = !monster.is_alive();
+                    format!("The {} hits the {} for {} damage!", name, monster.name, damage)
+                }
+                None => format!("The {} lands on the floor.", name),
+            };
+            self.add_log_message(MessageCategory::Combat, message);
+            if killed {
+                self.run_artifact_kill_hooks();
+            }
+            self.ground_items.push((landing.0 as f32, landing.1 as f32, item));
+        }
+
+        self.take_turn();
+    }
+
+    fn open_spell_menu(&mut self) {
+        if self.known_spells.is_empty() {
+            self.add_log_message(MessageCategory::Warning, "You don't know any spells. Spellbooks can teach you some.".to_string());
+            return;
+        }
+        self.spell_selection = self.spell_selection.min(self.known_spells.len() - 1);
+        self.spells_open = true;
+    }
+
+    fn handle_spell_input(&mut self) {
+        let count = self.known_spells.len();
+        if is_key_pressed(KeyCode::Up) && self.spell_selection > 0 {
+            self.spell_selection -= 1;
+        }
+        if is_key_pressed(KeyCode::Down) && self.spell_selection + 1 < count {
+            self.spell_selection += 1;
+        }
+        if is_key_pressed(KeyCode::Enter) {
+            if let Some(&spell) = self.known_spells.get(self.spell_selection) {
+                self.begin_cast(spell);
+            }
+        }
+    }
+
+    // Spells cast on the player take effect at once, the rest are aimed like tools
+    fn begin_cast(&mut self, spell: Spell) {
+        if self.player.stats.mana < spell.mana_cost() {
+            self.add_log_message(MessageCategory::Warning, format!("Not enough mana for {} ({} needed).", spell.name(), spell.mana_cost()));
+            return;
+        }
+        self.spells_open = false;
+        if spell.range() > 0 {
+            self.targeting = Some(Targeting::Cast(spell));
+            self.add_log_message(MessageCategory::System, format!("Click a target for {}, [Esc] to cancel.", spell.name()));
+            return;
+        }
+
+        let healed = (self.player.stats.max_hp - self.player.stats.hp).min(Spell::HEAL_AMOUNT);
+        self.player.stats.hp += healed;
+        self.add_log_message(MessageCategory::System, format!("Warmth flows through you. Healed for {} HP.", healed));
+        self.finish_cast(spell);
+    }
+
+    // The tiles an aimed spell would cross, if the target is valid
+    fn spell_line(&self, spell: Spell, target: (i32, i32)) -> Option<Vec<(i32, i32)>> {
+        match spell {
+            Spell::MagicMissile => self.projectile_path(target, spell.range()),
+            Spell::Blink => {
+                let start = (self.player.x as i32, self.player.y as i32);
+                let distance = (target.0 - start.0).abs().max((target.1 - start.1).abs());
+                let occupied = self.monsters.iter().any(|m| m.is_alive() && (m.x as i32, m.y as i32) == target)
+                    || self.allies.iter().any(|a| (a.entity.x as i32, a.entity.y as i32) == target);
+                let open = self.map_manager.current_map().is_walkable(target.0, target.1) && !occupied;
+                let reachable = distance > 0 && distance <= spell.range() && self.is_lit(target.0 as f32, target.1 as f32);
+                (open && reachable).then(|| bresenham_line(start, target))
+            }
+            Spell::Heal => None,
+        }
+    }
+
+    fn cast_at(&mut self, spell: Spell, target: (i32, i32)) {
+        let Some(line) = self.spell_line(spell, target) else {
+            self.add_log_message(MessageCategory::Warning, format!("{} can't reach there.", spell.name()));
+            return;
+        };
+        let Some(&landing) = line.last() else {
+            return;
+        };
+
+        self.targeting = None;
+        self.auto_path.clear();
+        match spell {
+            Spell::MagicMissile => {
+                self.projectiles.push(ProjectileTrace {
+                    from: (self.player.x, self.player.y),
+                    to: (landing.0 as f32, landing.1 as f32),
+                    color: SKYBLUE,
+                    time_left: ProjectileTrace::DURATION,
+                });
+                let target = self.monsters.iter_mut().find(|m| m.is_alive() && (m.x as i32, m.y as i32) == landing);
+                let mut killed = false;
+                let message = match target {
+                    Some(monster) => {
+                        let hp_before = monster.stats.hp;
+                        monster.stats.hp -= Spell::MAGIC_MISSILE_DAMAGE;
+                        self.damage_popups.extend(DamagePopup::after_hit(monster, hp_before, false));
+                        monster.last_hit_by = Some(DamageSource::Used(self.player.id, "a magic missile"));
+                        killed = !monster.is_alive();
+                        format!("The magic missile strikes the {} for {} damage!", monster.name, Spell::MAGIC_MISSILE_DAMAGE)
+                    }
+                    None => "The magic missile fizzles out.".to_string(),
+                };
+                self.add_log_message(MessageCategory::Combat, message);
+                if killed {
+                    self.run_artifact_kill_hooks();
+                }
+            }
+            Spell::Blink => {
+                self.player.x = landing.0 as f32;
+                self.player.y = landing.1 as f32;
+                self.add_log_message(MessageCategory::System, "You blink across the room!".to_string());
+                self.check_and_pickup_items();
+            }
+            Spell::Heal => {}
+        }
+        self.finish_cast(spell);
+    }
+
+    fn finish_cast(&mut self, spell: Spell) {
+        self.player.stats.mana -= spell.mana_cost();
+        self.take_turn();
+    }
+
+    fn draw_spell_menu(&self) {
+        draw_rectangle(
+            screen_width() * 0.2,
+            screen_height() * 0.2,
+            screen_width() * 0.6,
+            screen_height() * 0.5,
+            Color::new(0.0, 0.0, 0.0, 0.9),
+        );
+
+        draw_text("Spells", screen_width() * 0.25, screen_height() * 0.25, 30.0, WHITE);
+        let mana = format!("Mana: {}/{}", self.player.stats.mana, self.player.stats.max_mana);
+        draw_text(&mana, screen_width() * 0.55, screen_height() * 0.25, 20.0, SKYBLUE);
+
+        for (i, spell) in self.known_spells.iter().enumerate() {
+            let y_pos = screen_height() * 0.32 + i as f32 * 45.0;
+            if i == self.spell_selection {
+                draw_text(">", screen_width() * 0.25 - 15.0, y_pos, 20.0, WHITE);
+            }
+            let affordable = self.player.stats.mana >= spell.mana_cost();
+            let color = if affordable { WHITE } else { DARKGRAY };
+            draw_text(&format!("{} - {} mana", spell.name(), spell.mana_cost()), screen_width() * 0.25, y_pos, 20.0, color);
+            draw_text(&spell.describe(), screen_width() * 0.25, y_pos + 20.0, 16.0, GRAY);
+        }
+
+        draw_text(
+            "[Up/Down] Select  [Enter] Cast  [Esc] Close",
+            screen_width() * 0.25,
+            screen_height() * 0.66,
+            20.0,
+            LIGHTGRAY,
+        );
+    }
+
+    fn open_radial_menu(&mut self, now: f32) {
+        self.radial_menu = Some(RadialMenu { selection: None, opened_at: now, held: true, mouse: mouse_position() });
+    }
+
+    // Aimed with the movement keys, the arrows or the mouse. Letting go of a held [Tab] runs the
+    // command picked, after a tap the menu stays up until [Enter], a click, [Tab] or [Esc]
+    fn handle_radial_input(&mut self, now: f32) {
+        let Some(menu) = self.radial_menu.as_mut() else {
+            return;
+        };
+        let count = RadialCommand::ALL.len();
+        if let Some((dx, dy)) = held_movement(true) {
+            menu.selection = Some(RadialMenu::slice_toward(dx as f32, dy as f32));
+        }
+        if is_key_pressed(KeyCode::Right) {
+            menu.selection = Some(menu.selection.map_or(0, |selection| (selection + 1) % count));
+        }
+        if is_key_pressed(KeyCode::Left) {
+            menu.selection = Some(menu.selection.map_or(count - 1, |selection| (selection + count - 1) % count));
+        }
+        let mouse = mouse_position();
+        let from_center = (mouse.0 - screen_width() / 2.0, mouse.1 - screen_height() / 2.0);
+        if mouse != menu.mouse && from_center.0.hypot(from_center.1) > RadialMenu::INNER_RADIUS {
+            menu.selection = Some(RadialMenu::slice_toward(from_center.0, from_center.1));
+        }
+        menu.mouse = mouse;
+
+        let released = menu.held && is_key_released(KeyCode::Tab);
+        if released && now - menu.opened_at < RadialMenu::TAP_SECONDS {
+            menu.held = false;
+            return;
+        }
+        // On the button coming up, so the click can't also start a walk once the menu is gone
+        let confirmed = released || is_key_pressed(KeyCode::Enter) || is_mouse_button_released(MouseButton::Left);
+        let selection = menu.selection;
+        if is_key_pressed(KeyCode::Escape) || (!menu.held && is_key_pressed(KeyCode::Tab)) {
+            self.radial_menu = None;
+        } else if confirmed {
+            self.radial_menu = None;
+            if let Some(selection) = selection {
+                self.run_radial_command(RadialCommand::ALL[selection]);
+            }
+        }
+    }
+
+    fn run_radial_command(&mut self, command: RadialCommand) {
+        match command {
+            RadialCommand::Rest => self.player_step(self.player.x, self.player.y, false),
+            RadialCommand::Search => self.search(),
+            RadialCommand::Explore => self.start_exploring(),
+            RadialCommand::Inventory => self.inventory_open = true,
+            RadialCommand::Cast => self.open_spell_menu(),
+            RadialCommand::Throw => {
+                self.inventory_open = true;
+                self.add_log_message(MessageCategory::System, "Pick something to throw and press [T].".to_string());
+            }
+            RadialCommand::Look => self.open_look(),
+        }
+    }
+
+    fn draw_radial_menu(&self) {
+        let Some(menu) = &self.radial_menu else {
+            return;
+        };
+        let (center_x, center_y) = (screen_width() / 2.0, screen_height() / 2.0);
+        draw_circle(center_x, center_y, RadialMenu::RADIUS + 60.0, Color::new(0.0, 0.0, 0.0, 0.75));
+        draw_circle_lines(center_x, center_y, RadialMenu::INNER_RADIUS, 2.0, GRAY);
+
+        for (i, command) in RadialCommand::ALL.iter().enumerate() {
+            let angle = RadialMenu::slice_angle(i);
+            let (x, y) = (center_x + angle.cos() * RadialMenu::RADIUS, center_y + angle.sin() * RadialMenu::RADIUS);
+            let selected = menu.selection == Some(i);
+            if selected {
+                draw_circle(x, y, 42.0, Color::new(1.0, 1.0, 1.0, 0.15));
+            }
+            let color = if selected { YELLOW } else { WHITE };
+            let name = measure_text(command.name(), None, 22, 1.0);
+            draw_text(command.name(), x - name.width / 2.0, y, 22.0, color);
+            let key = measure_text(command.key(), None, 16, 1.0);
+            draw_text(command.key(), x - key.width / 2.0, y + 18.0, 16.0, GRAY);
+        }
+
+        let help = "Aim and let go of [Tab], or [Enter] to pick  [Esc] Close";
+        let width = measure_text(help, None, 18, 1.0).width;
+        draw_text(help, center_x - width / 2.0, center_y + RadialMenu::RADIUS + 90.0, 18.0, LIGHTGRAY);
+    }
+
+    fn use_tool_at(&mut self, tool: Tool, target: (i32, i32)) {
+        let Some(line) = self.tool_line(tool, target) else {
+            self.add_log_message(MessageCategory::Warning, format!("The {} can't reach there.", tool.name()));
+            return;
+        };
+
+        self.targeting = None;
+        self.auto_path.clear();
+        self.add_log_message(MessageCategory::System, match tool {
+            Tool::GrapplingHook => "You hook the wall and haul yourself across!".to_string(),
+            Tool::DashBoots => "You dash forward!".to_string(),
+        });
+        self.resolve_forced_movement(&line, tool == Tool::DashBoots);
+        self.tool_cooldowns.insert(tool, self.turn + Tool::COOLDOWN);
+        self.take_turn();
+    }
+
+    // Move the player along a line until something stops them. Monsters either block
+    // the way or, when passing through, get struck on the way past
+    fn resolve_forced_movement(&mut self, line: &[(i32, i32)], pass_through_monsters: bool) {
+        let mut landing = None;
+        let mut messages = Vec::new();
+        let mut killed = false;
+        let verb = self.attack_verb();
+        let mut struck = Vec::new();
+
+        for &(x, y) in line {
+            if !self.map_manager.current_map().is_walkable(x, y) {
+                break;
+            }
+            let monster = self.monsters
+                .iter_mut()
+                .find(|m| m.is_alive() && (m.x as i32, m.y as i32) == (x, y));
+            if let Some(monster) = monster {
+                if !pass_through_monsters {
+                    break;
+                }
+                let hp_before = monster.stats.hp;
+                messages.extend(self.player.attack_with(monster, &verb, &mut self.rng));
+                self.damage_popups.extend(DamagePopup::after_hit(monster, hp_before, false));
+                if monster.stats.hp < hp_before {
+                    struck.push((monster.x, monster.y));
+                }
+                killed |= !monster.is_alive();
+                continue;
+            }
+            // Allies can be passed over but not landed on
+            if self.allies.iter().any(|a| (a.entity.x as i32, a.entity.y as i32) == (x, y)) {
+                continue;
+            }
+            landing = Some((x, y));
+        }
+
+        for message in messages {
+            self.add_log_message(MessageCategory::Combat, message);
+        }
+        for target in struck {
+            self.weapon_hit_landed(target);
+        }
+        if killed {
+            self.run_artifact_kill_hooks();
+        }
+        if let Some((x, y)) = landing {
+            self.player.x = x as f32;
+            self.player.y = y as f32;
+            self.check_and_pickup_items();
+        }
+    }
+
+    // How a blow of the player's reads, livelier the more practiced they are with the weapon.
+    // Only flavour, so it leaves the run's rng alone. The verb holds until the weapon or the
+    // rank changes, so a run of blows collapses into one log line
+    fn attack_verb(&mut self) -> String {
+        let (kind, rank) = (self.player.weapon_kind(), self.player.weapon_rank());
+        if let Some((held_kind, held_rank, verb)) = &self.held_verb {
+            if (*held_kind, *held_rank) == (kind, rank) {
+                return verb.clone();
+            }
+        }
+        let verb = self.item_registry
+            .weapon_style(kind)
+            .and_then(|style| style.verbs.get(rank as usize).or(style.verbs.last()))
+            .and_then(|verbs| verbs.choose(&mut thread_rng()))
+            .cloned()
+            .unwrap_or_else(|| "hits".to_string());
+        self.held_verb = Some((kind, rank, verb.clone()));
+        verb
+    }
+
+    // Every blow landed trains the weapon's kind and leaves its mark on the target for a moment
+    fn weapon_hit_landed(&mut self, target: (f32, f32)) {
+        let kind = self.player.weapon_kind();
+        let before = self.player.weapon_rank();
+        if let Some(level_system) = self.player.stats.level_system.as_mut() {
+            *level_system.weapon_hits.entry(kind).or_default() += 1;
+        }
+        let rank = self.player.weapon_rank();
+        if rank > before {
+            self.add_log_message(MessageCategory::System, format!("Your skill with {} weapons grows to {}.", kind.name().to_lowercase(), rank.name()));
+        }
+        if let Some(style) = self.item_registry.weapon_style(kind) {
+            let (r, g, b) = style.color;
+            self.strike_marks.push(StrikeMark {
+                position: target,
+                glyph: style.glyph,
+                color: Color::from_rgba(r, g, b, 255),
+                flourish: rank.flourish(),
+                time_left: StrikeMark::DURATION,
+            });
+        }
+    }
+
+    fn overlay_open(&self) -> bool {
+        self.inventory_open
+            || self.encyclopedia_open
+            || self.feedback_open
+            || self.party_open
+            || self.shop_open
+            || self.board_open
+            || self.forge_open
+            || self.spells_open
+            || self.stash_open
+            || self.log_open
+            || self.parley.is_some()
+            || self.radial_menu.is_some()
+            || self.look_cursor.is_some()
+            || self.spawn_preview.is_some()
+    }
+
+    // Monsters the player has long outgrown are worth less each level beyond the difficulty's grace,
+    // and less again for every one of the kind already cut down while outclassed
+    fn diminished_xp(&mut self, name: &str, monster_level: i32, xp: i32) -> i32 {
+        let difficulty = self.map_manager.config.ai_difficulty;
+        let gap = self.player_level() - monster_level - difficulty.xp_grace_levels();
+        if gap <= 0 {
+            return xp;
+        }
+        let repeats = self.outclassed_kills.entry(name.to_string()).or_default();
+        let scale = difficulty.xp_falloff().powi(gap) / (1.0 + *repeats as f32 * Self::REPEAT_KILL_PENALTY);
+        *repeats += 1;
+        ((xp as f32 * scale).round() as i32).max(1)
+    }
+
+    fn grant_xp(&mut self, xp: i32) {
+        let Some(level_system) = self.player.stats.level_system.as_mut() else {
+            return;
+        };
+        let current_level = level_system.level;
+        let leveled = level_system.add_xp(xp);
+        self.add_log_message(MessageCategory::System, format!("Gained {} XP!", xp));
+        if leveled {
+            self.player.level_up();
+            self.add_log_message(MessageCategory::System, format!("Level Up! You are now level {}!", current_level + 1));
+        }
+    }
+
+    // Where a living monster currently sits in the list, which shifts as others die or leave
+    fn monster_index(&self, id: EntityId) -> Option<usize> {
+        self.monsters.iter().position(|m| m.id == id && m.is_alive())
+    }
+
+    // Everything that happens once per player turn, whichever action spent it
+    fn take_turn(&mut self) {
+        self.player.update_last_move(self.clock);
+        self.turn += 1;
+        self.turn_taken = true;
+        self.floor_stats.entry(self.map_manager.current_floor()).or_default().turns += 1;
+        let effects_started = Instant::now();
+        if self.player.status_effects.iter().any(|e| e.kind == StatusKind::Recalling && e.turns_left <= 1) {
+            self.escape = Some(Escape::Recall);
+        }
+        let hp_before = self.player.stats.hp;
+        let messages = self.player.tick_status_effects();
+        self.damage_popups.extend(DamagePopup::after_hit(&self.player, hp_before, true));
+        for message in messages {
+            self.add_log_message(MessageCategory::Combat, message);
+        }
+        self.run_artifact_turn_hooks();
+        self.map_manager.current_map_mut().tick_terrain_overlays();
+        let turn = self.turn;
+        self.map_manager.current_map_mut().regrow_ore_veins(turn);
+        self.player.regenerate_mana(turn);
+        self.burn_light();
+        self.announce_hour();
+        let (center, radius) = ((self.player.x as i32, self.player.y as i32), self.light_radius());
+        self.map_manager.current_map_mut().explore_around(center, radius);
+        // Walking past, a sharp eye can catch a hidden door without stopping to search
+        let chance = Self::PASSIVE_SEARCH_CHANCE_PER_PERCEPTION * self.player.stats.perception as f64;
+        self.reveal_secret_doors(1, chance.min(1.0));
+        self.rot_corpses();
+        self.emit_event(GameEvent::TurnEnded);
+        self.turn_profile.effects += effects_started.elapsed();
+    }
+
+    // The player's move onto a tile, or resting in place when it is their own: attacking whatever
+    // stands there, opening what is built there, or walking in. A walk carries on through doors
+    fn player_step(&mut self, mut new_x: f32, mut new_y: f32, walking: bool) {
+        self.take_turn();
+
+        // Caught in a web, the move goes into struggling instead
+        if self.player.has_status(StatusKind::Entangled) {
+            self.struggle_free_of_web();
+            (new_x, new_y) = (self.player.x, self.player.y);
+            self.auto_path.clear();
+        }
+        let mut combat_occurred = false;
+        let mut killed_monster = false;
+
+        // Check for combat
+        let verb = self.attack_verb();
+        let mut struck = None;
+        for monster in &mut self.monsters {
+            if monster.is_alive() && new_x == monster.x && new_y == monster.y {
+                let hp_before = monster.stats.hp;
+                let mut messages = self.player.attack_with(monster, &verb, &mut self.rng);
+                self.damage_popups.extend(DamagePopup::after_hit(monster, hp_before, false));
+                self.events.push(GameEvent::PlayerAttacked { hit: monster.stats.hp < hp_before });
+                if monster.stats.hp < hp_before {
+                    struck = Some((monster.x, monster.y));
+                }
+                if monster.faction == Faction::Neutral && monster.is_alive() {
+                    monster.faction = Faction::Hostile;
+                    messages.push(format!("The {} turns on you!", monster.name));
+                }
+                killed_monster = !monster.is_alive();
+                for message in messages {
+                    self.add_log_message(MessageCategory::Combat, message);
+                }
+                combat_occurred = true;
+                break;
+            }
+        }
+
+        if combat_occurred {
+            self.wear_equipment(true);
+            self.auto_path.clear();
+        }
+        if let Some(target) = struck {
+            self.weapon_hit_landed(target);
+        }
+
+        if killed_monster {
+            self.run_artifact_kill_hooks();
+        }
+
+        // Move if no combat and the tile is walkable
+        if !combat_occurred && self.shopkeeper_at(new_x as i32, new_y as i32) {
+            match self.off_duty_message(new_x as i32, new_y as i32) {
+                Some(message) => self.add_log_message(MessageCategory::Warning, message),
+                None => {
+                    self.shop_open = true;
+                    self.shop_selection = 0;
+                }
+            }
+            self.auto_path.clear();
+        } else if !combat_occurred && self.map_manager.current_map().bounty_board == Some((new_x as i32, new_y as i32)) {
+            self.open_bounty_board();
+            self.auto_path.clear();
+        } else if !combat_occurred && self.forge_at(new_x as i32, new_y as i32) {
+            self.open_forge();
+            self.auto_path.clear();
+        } else if !combat_occurred && self.healer_at(new_x as i32, new_y as i32) {
+            match self.off_duty_message(new_x as i32, new_y as i32) {
+                Some(message) => self.add_log_message(MessageCategory::Warning, message),
+                None => self.visit_healer(),
+            }
+            self.auto_path.clear();
+        } else if !combat_occurred && self.stash_at(new_x as i32, new_y as i32) {
+            self.open_stash();
+            self.auto_path.clear();
+        } else if !combat_occurred && self.shrine_at(new_x as i32, new_y as i32) {
+            self.activate_shrine();
+            self.auto_path.clear();
+        } else if !combat_occurred && self.boulder_at(new_x as i32, new_y as i32) {
+            let from = (self.player.x as i32, self.player.y as i32);
+            if self.push_boulder(from, (new_x as i32, new_y as i32)) {
+                self.player.x = new_x;
+                self.player.y = new_y;
+                self.check_and_pickup_items();
+            }
+            self.auto_path.clear();
+        } else if !combat_occurred && self.ore_vein_at(new_x as i32, new_y as i32) {
+            self.mine_ore_vein(new_x as i32, new_y as i32);
+            self.auto_path.clear();
+        } else if !combat_occurred && self.map_manager.current_map().is_walkable(new_x as i32, new_y as i32) {
+            let stepped = (new_x, new_y) != (self.player.x, self.player.y);
+            self.swap_with_ally((self.player.x, self.player.y), (new_x, new_y));
+            self.player.x = new_x;
+            self.player.y = new_y;
+            if stepped {
+                self.emit_event(GameEvent::PlayerStepped);
+                if let Some(message) = self.player.enter_tile(self.map_manager.current_map()) {
+                    self.add_log_message(MessageCategory::System, message);
+                    self.auto_path.clear();
+                }
+                if let Some(index) = self.corpse_at(new_x, new_y) {
+                    let message = format!("There is a {} here. Press [E] to eat it.", self.ground_items[index].2.name);
+                    self.add_log_message(MessageCategory::System, message);
+                }
+                if let Some(feature) = self.map_manager.current_map().features.get(&(new_x as i32, new_y as i32)).filter(|f| !f.spent()) {
+                    let message = format!("There is a {} here. Press [F] to {} it.", feature.name(), feature.verb());
+                    self.add_log_message(MessageCategory::System, message);
+                }
+                if self.map_manager.portal_exit((new_x as i32, new_y as i32)).is_some() {
+                    self.escape = Some(Escape::Portal);
+                    self.auto_path.clear();
+                }
+            }
+
+            // Check for items at the new position, stepping on one ends a walk
+            if self.has_item_at(new_x, new_y) {
+                self.auto_path.clear();
+            }
+            self.check_and_pickup_items();
+        } else if !combat_occurred && self.try_open_door(new_x as i32, new_y as i32) {
+            // Opening a door takes the move, a walk carries on through it afterwards
+            if walking {
+                self.auto_path.insert(0, (new_x as i32, new_y as i32));
+            }
+        } else if !combat_occurred {
+            self.auto_path.clear();
+        }
+        let auras_started = Instant::now();
+        self.recompute_auras();
+        self.turn_profile.effects += auras_started.elapsed();
+        self.log_state_hash();
+    }
+
+    // Everything that moves on its own once the player's input is in, then clearing away the dead
+    fn advance_world(&mut self, current_time: f32) {
+        if let Some(escape) = self.escape.take() {
+            self.escape_floor(escape);
+        }
+
+        // Free actions like looking through the pack leave everything else waiting
+        if self.world_advances() {
+            let arrivals_started = Instant::now();
+            self.process_floor_arrivals();
+            self.turn_profile.spawning += arrivals_started.elapsed();
+
+            // Pathfinding is timed inside the monster turns and taken back out of the AI share
+            let ai_started = Instant::now();
+            let pathfinding_before = self.turn_profile.pathfinding;
+            self.process_ally_turns(current_time);
+            self.process_monster_turns(current_time);
+            let pathfinding = self.turn_profile.pathfinding - pathfinding_before;
+            self.turn_profile.ai += ai_started.elapsed().saturating_sub(pathfinding);
+            self.hint_new_monsters();
+            if self.in_town() {
+                self.move_townsfolk();
+            }
+        }
+
+        // Remove dead monsters and drop their materials
+        let spawning_started = Instant::now();
+        self.handle_monster_deaths();
+        self.update_shrine_trial();
+        self.enforce_run_modifiers();
+        self.turn_profile.spawning += spawning_started.elapsed();
+    }
+
+    // The world holds still while the player is in a menu or aiming, unless a turn was just spent
+    fn world_advances(&mut self) -> bool {
+        let turn_taken = std::mem::take(&mut self.turn_taken);
+        turn_taken || (!self.overlay_open() && self.targeting.is_none())
+    }
+
+    // Index the aura bearers by tile, then look around every creature for the ones in range
+    fn recompute_auras(&mut self) {
+        let bearers: HashMap<(i32, i32), Aura> = self.monsters
+            .iter()
+            .filter(|m| m.is_alive())
+            .filter_map(|m| m.aura.map(|aura| ((m.x as i32, m.y as i32), aura)))
+            .collect();
+
+        let auras_around = |entity: &Entity, is_monster: bool| -> Vec<Aura> {
+            let (x, y) = (entity.x as i32, entity.y as i32);
+            let mut found = Vec::new();
+            for dy in -Aura::MAX_RADIUS..=Aura::MAX_RADIUS {
+                for dx in -Aura::MAX_RADIUS..=Aura::MAX_RADIUS {
+                    if (dx, dy) == (0, 0) {
+                        continue; // A bearer isn't under its own aura
+                    }
+                    if let Some(&aura) = bearers.get(&(x + dx, y + dy)) {
+                        if dx.abs().max(dy.abs()) <= aura.radius() && aura.affects(entity, is_monster) && !found.contains(&aura) {
+                            found.push(aura);
+                        }
+                    }
+                }
+            }
+            found
+        };
+
+        self.player.under_auras = auras_around(&self.player, false);
+        for i in 0..self.monsters.len() {
+            self.monsters[i].under_auras = auras_around(&self.monsters[i], true);
+        }
+        for i in 0..self.allies.len() {
+            self.allies[i].entity.under_auras = auras_around(&self.allies[i].entity, false);
+        }
+    }
+
+    fn open_look(&mut self) {
+        self.look_cursor = Some((self.player.x as i32, self.player.y as i32));
+        self.add_log_message(MessageCategory::System, "Look around with the movement keys, [Enter] to note it down, [Esc] to stop.".to_string());
+    }
+
+    // The cursor only goes where the player can see, looking costs no turn
+    fn handle_look_input(&mut self) {
+        let Some((x, y)) = self.look_cursor else {
+            return;
+        };
+        if is_key_pressed(KeyCode::Escape) || is_key_pressed(KeyCode::Semicolon) {
+            self.look_cursor = None;
+            return;
+        }
+        if is_key_pressed(KeyCode::Enter) {
+            let seen: Vec<String> = self.describe_tile((x, y)).into_iter().map(|(line, _)| line).collect();
+            self.add_log_message(MessageCategory::System, format!("You see: {}.", seen.join(", ")));
+            self.look_cursor = None;
+            return;
+        }
+        let (mut dx, mut dy) = (0, 0);
+        for (key, (kx, ky)) in MOVEMENT_KEYS {
+            if is_key_pressed(key) {
+                dx += kx;
+                dy += ky;
+            }
+        }
+        let target = (x + dx.clamp(-1, 1), y + dy.clamp(-1, 1));
+        let map = self.map_manager.current_map();
+        let inside = target.0 >= 0 && target.1 >= 0 && (target.0 as usize) < map.width && (target.1 as usize) < map.height;
+        if inside && self.is_lit(target.0 as f32, target.1 as f32) {
+            self.look_cursor = Some(target);
+        }
+    }
+
+    // Everything on a tile from the top down: who stands there, what lies there, then the ground itself
+    fn describe_tile(&self, tile: (i32, i32)) -> Vec<(String, Color)> {
+        let map = self.map_manager.current_map();
+        let at = |e: &Entity| e.is_alive() && (e.x as i32, e.y as i32) == tile;
+        let mut lines = Vec::new();
+        if at(&self.player) {
+            lines.push(("You".to_string(), self.player.color));
+        }
+        for entity in self.monsters.iter().chain(self.allies.iter().map(|a| &a.entity)).filter(|e| at(e)) {
+            lines.push((format!("{}, {}", entity.name, entity.health_state()), entity.color));
+        }
+        if map.shop.as_ref().is_some_and(|shop| shop.keeper == tile) {
+            lines.push(("The shopkeeper".to_string(), LIME));
+        }
+        for (_, _, item) in self.ground_items.iter().filter(|(ix, iy, _)| (*ix as i32, *iy as i32) == tile) {
+            lines.push((self.identification.name(item), self.identification.color(item)));
+        }
+        if let Some(feature) = map.features.get(&tile) {
+            let name = if feature.spent() { format!("A {}, spent", feature.name()) } else { format!("A {}", feature.name()) };
+            lines.push((name, feature.glyph().1));
+        }
+        if map.portal == Some(tile) {
+            lines.push(("A portal back to town".to_string(), VIOLET));
+        }
+        if map.boulders.contains(&tile) {
+            lines.push(("Boulder".to_string(), BEIGE));
+        }
+        if let Some(decoration) = map.decorations.get(&tile) {
+            lines.push((decoration.name().to_string(), decoration.glyph().1));
+        }
+        if let Some(overlay) = map.terrain_overlays.get(&tile) {
+            lines.push((overlay.kind.name().to_string(), overlay.kind.glyph().1));
+        }
+        if map.ore_veins.get(&tile).is_some_and(|vein| vein.swings_left > 0) {
+            lines.push(("Ore vein".to_string(), ORANGE));
+        }
+        let (_, color) = map.glyph_at(tile.0 as usize, tile.1 as usize, (tile.0 as f32, tile.1 as f32), 1.0);
+        lines.push((map.tiles[tile.1 as usize][tile.0 as usize].name().to_string(), color));
+        lines
+    }
+
+    fn draw_look_panel(&self, screen_x: f32, screen_y: f32) {
+        if let Some(tile) = self.look_cursor {
+            Self::draw_tooltip(&self.describe_tile(tile), screen_x, screen_y);
+        }
+    }
+
+    fn draw_tooltip(lines: &[(String, Color)], screen_x: f32, screen_y: f32) {
+        let width = 280.0;
+        let height = 10.0 + lines.len() as f32 * 20.0;
+        let x = screen_x.min(screen_width() - width - 5.0);
+        let y = (screen_y + 20.0).min(screen_height() - BOTTOM_BAR_HEIGHT - height);
+        draw_rectangle(x, y, width, height, Color::new(0.0, 0.0, 0.0, 0.85));
+        for (i, (text, color)) in lines.iter().enumerate() {
+            draw_text(text, x + 8.0, y + 20.0 + i as f32 * 20.0, 18.0, *color);
+        }
+    }
+
+    // Details of whatever creature is under the mouse
+    fn draw_examine_panel(&self, tile: (i32, i32), screen_x: f32, screen_y: f32) {
+        let at = |e: &Entity| e.is_alive() && (e.x as i32, e.y as i32) == tile;
+        let Some(entity) = self.monsters.iter().find(|m| at(m)).or_else(|| self.allies.iter().map(|a| &a.entity).find(|e| at(e))) else {
+            return;
+        };
+        if !self.is_lit(entity.x, entity.y) {
+            return;
+        }
+
+        let mut lines = vec![
+            (entity.name.clone(), entity.color),
+            (format!("HP {}/{}  ATK {}  DEF {}", entity.stats.hp, entity.stats.max_hp, entity.get_total_attack(), entity.get_total_defense()), LIGHTGRAY),
+        ];
+        if let Some(aura) = entity.aura {
+            lines.push((format!("{}: {}", aura.name(), aura.describe()), SKYBLUE));
+        }
+        if let Some(turn) = self.allies.iter().find(|a| a.entity.id == entity.id).and_then(|a| a.leaves_at) {
+            lines.push((format!("Mercenary, {} turns left", turn.saturating_sub(self.turn)), GOLD));
+        } else if entity.faction == Faction::Neutral {
+            lines.push(("Neutral".to_string(), GOLD));
+        } else if entity.bribable {
+            lines.push((format!("Could be bought off for {} gold", entity.truce_price()), GOLD));
+        }
+        for aura in &entity.under_auras {
+            lines.push((format!("Under a {}", aura.name().to_lowercase()), GRAY));
+        }
+        for effect in &entity.status_effects {
+            lines.push((format!("{} ({} turns)", effect.kind.name(), effect.turns_left), GRAY));
+        }
+        Self::draw_tooltip(&lines, screen_x, screen_y);
+    }
+
+    fn is_occupied(&self, x: i32, y: i32) -> bool {
+        let at = |e: &Entity| e.is_alive() && (e.x as i32, e.y as i32) == (x, y);
+        at(&self.player) || self.monsters.iter().any(at) || self.allies.iter().any(|a| at(&a.entity))
+    }
+
+    // Any open tile within a radius of a point, other than the point itself
+    fn random_free_tile_within(&mut self, (x, y): (i32, i32), radius: i32) -> Option<(i32, i32)> {
+        let mut rng = self.fork_rng();
+        let map = self.map_manager.current_map();
+        (-radius..=radius)
+            .flat_map(|dy| (-radius..=radius).map(move |dx| (x + dx, y + dy)))
+            .filter(|&(tx, ty)| (tx, ty) != (x, y) && map.is_walkable(tx, ty) && !self.is_occupied(tx, ty))
+            .choose(&mut rng)
+    }
+
+    // The closest open tile around a point, searching outwards a couple of rings
+    fn free_tile_near(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        let map = self.map_manager.current_map();
+        (1..=2).find_map(|radius| {
+            (-radius..=radius)
+                .flat_map(|dy| (-radius..=radius).map(move |dx| (x + dx, y + dy)))
+                .find(|&(tx, ty)| map.is_walkable(tx, ty) && !self.is_occupied(tx, ty))
+        })
+    }
+
+    fn summon_ally(&mut self, x: i32, y: i32) -> Result<String, String> {
+        if self.allies.len() >= Ally::MAX_PARTY_SIZE {
+            return Err("Your party is already full!".to_string());
+        }
+        let template = self.monster_registry.templates.iter().find(|t| t.name == "Wolf");
+        let (Some(template), Some((ax, ay))) = (template, self.free_tile_near(x, y)) else {
+            return Err("Nothing answers the call.".to_string());
+        };
+
+        let mut entity = Entity::new_monster(template, ax as f32, ay as f32);
+        entity.name = "Spirit Wolf".to_string();
+        entity.color = SKYBLUE;
+        self.allies.push(Ally { entity, order: AllyOrder::Follow, leaves_at: None });
+        Ok("A spirit wolf answers your call!".to_string())
+    }
+
+    // Start bargaining with a monster next to the player, if it is one that can be bargained with
+    fn open_parley(&mut self) {
+        let player = (self.player.x as i32, self.player.y as i32);
+        let directions = self.map_manager.current_map().directions();
+        let adjacent = |m: &&Entity| {
+            m.is_alive() && directions.iter().any(|(dx, dy)| (m.x as i32 + dx, m.y as i32 + dy) == player)
+        };
+        let Some(monster) = self.monsters.iter().filter(adjacent).max_by_key(|m| m.bribable) else {
+            self.add_log_message(MessageCategory::Warning, "There is no one here to bargain with.".to_string());
+            return;
+        };
+        if !monster.bribable {
+            self.add_log_message(MessageCategory::Warning, format!("The {} can't be reasoned with.", monster.name));
+            return;
+        }
+        self.parley = Some(monster.id);
+        self.parley_selection = 0;
+        self.auto_path.clear();
+    }
+
+    // Gold for either deal, then any item worth at least a truce. Items buy the best deal they cover
+    fn parley_offers(&self, monster: &Entity) -> Vec<(Deal, Payment)> {
+        let (truce, hire) = (monster.truce_price(), monster.hire_price());
+        let mut offers = Vec::new();
+        if monster.faction == Faction::Hostile {
+            offers.push((Deal::Truce, Payment::Gold(truce)));
+        }
+        offers.push((Deal::Hire, Payment::Gold(hire)));
+        let items = self.player.inventory.as_ref().map_or(&[][..], |inv| &inv.items[..]);
+        for (slot, item) in items.iter().enumerate() {
+            if matches!(item.item_type, ItemType::Relic | ItemType::Curio | ItemType::Keepsake(_)) {
+                continue;
+            }
+            let deal = if item.value() >= hire { Deal::Hire } else { Deal::Truce };
+            if item.value() >= truce && (deal == Deal::Hire || monster.faction == Faction::Hostile) {
+                offers.push((deal, Payment::Item(slot)));
+            }
+        }
+        offers
+    }
+
+    fn describe_offer(&self, deal: Deal, payment: Payment) -> String {
+        let payment = match payment {
+            Payment::Gold(gold) => format!("Pay {} gold", gold),
+            Payment::Item(slot) => {
+                let item = &self.player.inventory.as_ref().expect("only the player bargains").items[slot];
+                format!("Give your {}", self.identification.name(item))
+            }
+        };
+        match deal {
+            Deal::Truce => format!("{} to be left alone", payment),
+            Deal::Hire => format!("{} to hire it for {} turns", payment, Ally::MERCENARY_TURNS),
+        }
+    }
+
+    fn handle_parley_input(&mut self) {
+        let Some(index) = self.parley.and_then(|id| self.monster_index(id)) else {
+            self.parley = None;
+            return;
+        };
+        let count = self.parley_offers(&self.monsters[index]).len();
+        self.parley_selection = self.parley_selection.min(count.saturating_sub(1));
+        if is_key_pressed(KeyCode::Up) && self.parley_selection > 0 {
+            self.parley_selection -= 1;
+        }
+        if is_key_pressed(KeyCode::Down) && self.parley_selection + 1 < count {
+            self.parley_selection += 1;
+        }
+        if is_key_pressed(KeyCode::Enter) {
+            self.accept_offer(index, self.parley_selection);
+        }
+    }
+
+    // Hand over the payment and seal the deal, which takes the player's turn
+    fn accept_offer(&mut self, index: usize, choice: usize) {
+        let Some(&(deal, payment)) = self.parley_offers(&self.monsters[index]).get(choice) else {
+            return;
+        };
+        if deal == Deal::Hire && self.allies.len() >= Ally::MAX_PARTY_SIZE {
+            self.add_log_message(MessageCategory::Warning, "Your party is already full!".to_string());
+            return;
+        }
+        let Some(inventory) = self.player.inventory.as_mut() else {
+            return;
+        };
+        let given = match payment {
+            Payment::Gold(gold) if inventory.gold < gold => {
+                self.add_log_message(MessageCategory::Warning, "You can't afford that.".to_string());
+                return;
+            }
+            Payment::Gold(gold) => {
+                inventory.gold -= gold;
+                format!("{} gold", gold)
+            }
+            Payment::Item(slot) => match inventory.remove_item(slot) {
+                Some(item) => format!("your {}", self.identification.name(&item)),
+                None => return,
+            },
+        };
+
+        self.parley = None;
+        let monster = &mut self.monsters[index];
+        monster.faction = Faction::Neutral;
+        monster.ai_state = AiState::Wandering;
+        monster.last_seen_player = None;
+        match deal {
+            Deal::Truce => {
+                let message = format!("The {} takes {} and leaves you be.", monster.name, given);
+                self.add_log_message(MessageCategory::System, message);
+            }
+            Deal::Hire => {
+                let entity = self.monsters.remove(index);
+                self.add_log_message(MessageCategory::System, format!("The {} takes {} and joins your party.", entity.name, given));
+                let leaves_at = Some(self.turn + Ally::MERCENARY_TURNS);
+                self.allies.push(Ally { entity, order: AllyOrder::Follow, leaves_at });
+            }
+        }
+        self.take_turn();
+    }
+
+    fn draw_parley(&self) {
+        let Some(monster) = self.parley.and_then(|id| self.monster_index(id)).map(|index| &self.monsters[index]) else {
+            return;
+        };
+        draw_rectangle(
+            screen_width() * 0.1,
+            screen_height() * 0.1,
+            screen_width() * 0.8,
+            screen_height() * 0.8,
+            Color::new(0.0, 0.0, 0.0, 0.9),
+        );
+
+        draw_text(&format!("Bargain with the {}", monster.name), screen_width() * 0.15, screen_height() * 0.15, 30.0, WHITE);
+        let gold = self.player.inventory.as_ref().map_or(0, |inv| inv.gold);
+        draw_text(&format!("Gold: {}", gold), screen_width() * 0.6, screen_height() * 0.15, 20.0, GOLD);
+
+        for (i, (deal, payment)) in self.parley_offers(monster).into_iter().enumerate() {
+            let y_pos = screen_height() * 0.22 + 25.0 + i as f32 * 25.0;
+            if i == self.parley_selection {
+                draw_text(">", screen_width() * 0.15 - 15.0, y_pos, 20.0, WHITE);
+            }
+            let affordable = match payment {
+                Payment::Gold(price) => gold >= price,
+                Payment::Item(_) => true,
+            };
+            let color = if affordable { LIGHTGRAY } else { GRAY };
+            draw_text(&self.describe_offer(deal, payment), screen_width() * 0.15, y_pos, 20.0, color);
+        }
+
+        draw_text(
+            "[Up/Down] Select  [Enter] Offer  [Esc] Walk away",
+            screen_width() * 0.15,
+            screen_height() * 0.85,
+            20.0,
+            LIGHTGRAY,
+        );
+    }
+
+    // Allies travel with the player and gather around them on arrival
+    fn regroup_allies(&mut self) {
+        for i in 0..self.allies.len() {
+            self.allies[i].entity.x = -1.0;
+            if let Some((x, y)) = self.free_tile_near(self.player.x as i32, self.player.y as i32) {
+                self.allies[i].entity.x = x as f32;
+                self.allies[i].entity.y = y as f32;
+            }
+            if let AllyOrder::Attack(_) = self.allies[i].order {
+                self.allies[i].order = AllyOrder::Follow;
+            }
+        }
+        // Anyone who couldn't find room is left behind
+        self.allies.retain(|a| a.entity.x >= 0.0);
+    }
+
+    // Swap places with an ally standing where the player is moving
+    fn swap_with_ally(&mut self, from: (f32, f32), to: (f32, f32)) {
+        if let Some(ally) = self.allies.iter_mut().find(|a| (a.entity.x, a.entity.y) == to) {
+            ally.entity.x = from.0;
+            ally.entity.y = from.1;
+        }
+    }
+
+    fn process_ally_turns(&mut self, current_time: f32) {
+        let player = (self.player.x as i32, self.player.y as i32);
+
+        for i in 0..self.allies.len() {
+            if !self.allies[i].entity.can_move(current_time) {
+                continue;
+            }
+            let pos = (self.allies[i].entity.x as i32, self.allies[i].entity.y as i32);
+            let monster_at = |m: &Entity, near: (i32, i32), range: i32| {
+                m.is_alive() && manhattan_distance(near, (m.x as i32, m.y as i32)) <= range
+            };
+            let hostile_at = |m: &Entity, near: (i32, i32)| m.faction == Faction::Hostile && monster_at(m, near, 1);
+
+            // Pick a target: the ordered one while it is still on the floor, otherwise anything in reach
+            let target = match self.allies[i].order {
+                AllyOrder::Attack(id) => {
+                    let found = self.monster_index(id);
+                    if found.is_none() {
+                        self.allies[i].order = AllyOrder::Follow;
+                        self.add_log_message(MessageCategory::System, format!("The {} lost its target.", self.allies[i].entity.name));
+                    }
+                    found
+                }
+                AllyOrder::Follow | AllyOrder::Stay => self.monsters.iter().position(|m| hostile_at(m, pos)),
+                AllyOrder::Retreat => None,
+            };
+
+            let destination = match (target, self.allies[i].order) {
+                (Some(index), _) if monster_at(&self.monsters[index], pos, 1) => {
+                    let hp_before = self.monsters[index].stats.hp;
+                    let messages = self.allies[i].entity.attack(&mut self.monsters[index], &mut self.rng);
+                    self.damage_popups.extend(DamagePopup::after_hit(&self.monsters[index], hp_before, false));
+                    for message in messages {
+                        self.add_log_message(MessageCategory::Combat, message);
+                    }
+                    self.make_noise(Noise::Fight, pos);
+                    None
+                }
+                (Some(index), AllyOrder::Attack(_)) => Some((self.monsters[index].x as i32, self.monsters[index].y as i32)),
+                (_, AllyOrder::Follow) if manhattan_distance(pos, player) > 2 => Some(player),
+                (_, AllyOrder::Retreat) if manhattan_distance(pos, player) > 1 => Some(player),
+                _ => None,
+            };
+
+            if let Some(goal) = destination {
+                let step = self.map_manager.current_map()
+                    .find_path(pos, goal)
+                    .and_then(|path| path.get(1).copied());
+                if let Some((x, y)) = step.filter(|&(x, y)| !self.is_occupied(x, y)) {
+                    self.allies[i].entity.x = x as f32;
+                    self.allies[i].entity.y = y as f32;
+                }
+            }
+            self.allies[i].entity.update_last_move(current_time);
+        }
+
+        let fallen: Vec<String> = self.allies
+            .iter()
+            .filter(|a| !a.entity.is_alive())
+            .map(|a| format!("Your {} has fallen!", a.entity.name))
+            .collect();
+        self.allies.retain(|a| a.entity.is_alive());
+        for message in fallen {
+            self.add_log_message(MessageCategory::Warning, message);
+        }
+
+        // Mercenaries whose contract is up go their own way, without holding a grudge
+        let turn = self.turn;
+        let (departed, staying) = std::mem::take(&mut self.allies)
+            .into_iter()
+            .partition(|a| a.leaves_at.is_some_and(|at| turn >= at));
+        self.allies = staying;
+        for ally in departed {
+            self.add_log_message(MessageCategory::System, format!("The {}'s contract is up, and it goes its own way.", ally.entity.name));
+            self.monsters.push(ally.entity);
+        }
+    }
+
+    fn handle_party_input(&mut self) {
+        if self.allies.is_empty() {
+            return;
+        }
+        self.party_selection = self.party_selection.min(self.allies.len() - 1);
+
+        if is_key_pressed(KeyCode::Up) && self.party_selection > 0 {
+            self.party_selection -= 1;
+        }
+        if is_key_pressed(KeyCode::Down) && self.party_selection + 1 < self.allies.len() {
+            self.party_selection += 1;
+        }
+
+        let index = self.party_selection;
+        if is_key_pressed(KeyCode::F) {
+            self.allies[index].order = AllyOrder::Follow;
+        } else if is_key_pressed(KeyCode::S) {
+            self.allies[index].order = AllyOrder::Stay;
+        } else if is_key_pressed(KeyCode::R) {
+            self.allies[index].order = AllyOrder::Retreat;
+        } else if is_key_pressed(KeyCode::A) {
+            // Sic the ally on whatever lit monster is closest to the player
+            let player = (self.player.x as i32, self.player.y as i32);
+            let target = self.monsters
+                .iter()
+                .filter(|m| m.is_alive() && m.faction == Faction::Hostile && self.is_lit(m.x, m.y))
+                .min_by_key(|m| manhattan_distance(player, (m.x as i32, m.y as i32)))
+                .map(|m| m.id);
+            match target {
+                Some(target) => self.allies[index].order = AllyOrder::Attack(target),
+                None => self.add_log_message(MessageCategory::Warning, "There is nothing to attack.".to_string()),
+            }
+        } else if is_key_pressed(KeyCode::X) {
+            let ally = self.allies.remove(index);
+            self.add_log_message(MessageCategory::System, format!("You dismiss the {}.", ally.entity.name));
+        }
+    }
+
+    fn draw_party(&self) {
+        draw_rectangle(
+            screen_width() * 0.1,
+            screen_height() * 0.1,
+            screen_width() * 0.8,
+            screen_height() * 0.8,
+            Color::new(0.0, 0.0, 0.0, 0.9),
+        );
+
+        draw_text("Party", screen_width() * 0.15, screen_height() * 0.15, 30.0, WHITE);
+
+        if self.allies.is_empty() {
+            draw_text("You have no allies.", screen_width() * 0.15, screen_height() * 0.22, 20.0, GRAY);
+        }
+        for (i, ally) in self.allies.iter().enumerate() {
+            let color = if i == self.party_selection { YELLOW } else { WHITE };
+            draw_text(
+                &format!(
+                    "{} {}  HP {}/{}  {}",
+                    ally.entity.symbol,
+                    ally.entity.name,
+                    ally.entity.stats.hp,
+                    ally.entity.stats.max_hp,
+                    ally.order.name(),
+                ),
+                screen_width() * 0.15,
+                screen_height() * 0.22 + i as f32 * 25.0,
+                20.0,
+                color,
+            );
+        }
+
+        draw_text(
+            "[F] Follow  [S] Stay  [A] Attack nearest  [R] Retreat  [X] Dismiss  [Esc] Close",
+            screen_width() * 0.15,
+            screen_height() * 0.85,
+            20.0,
+            LIGHTGRAY,
+        );
+    }
+
+    fn open_feedback_form(&mut self) {
+        // Drop anything typed before the form opened
+        while get_char_pressed().is_some() {}
+        self.feedback_text.clear();
+        self.feedback_open = true;
+    }
+
+    fn handle_feedback_input(&mut self) {
+        while let Some(c) = get_char_pressed() {
+            if !c.is_control() && self.feedback_text.len() < 500 {
+                self.feedback_text.push(c);
+            }
+        }
+
+        if is_key_pressed(KeyCode::Backspace) {
+            self.feedback_text.pop();
+        }
+        if is_key_pressed(KeyCode::Escape) {
+            self.feedback_open = false;
+        } else if is_key_pressed(KeyCode::Enter) && !self.feedback_text.trim().is_empty() {
+            self.submit_feedback();
+            self.feedback_open = false;
+        }
+    }
+
+    // Everything needed to reproduce a reported problem
+    fn diagnostic_bundle(&self) -> String {
+        let map = self.map_manager.current_map();
+        let run_modifiers: Vec<&str> = self.run_modifiers.iter().map(|m| m.name()).collect();
+        let floor_modifiers: Vec<&str> = map.modifiers.iter().map(|m| m.name()).collect();
+
+        format!(
+            "version: {}\nseed: {}\nturn: {}\nfloor: {}\nplayer: {}/{} HP, level {}\nrun modifiers: {:?}\nfloor modifiers: {:?}\nconfig: {:?}\n",
+            env!("CARGO_PKG_VERSION"),
+            map.seed,
+            self.turn,
+            self.map_manager.floor_label(),
+            self.player.stats.hp,
+            self.player.stats.max_hp,
+            self.player_level(),
+            run_modifiers,
+            floor_modifiers,
+            self.map_manager.config,
+        )
+    }
+
+    fn export_run(&mut self) {
+        let inventory = self.player.inventory.as_ref();
+        let dump = RunDump {
+            seed: self.map_manager.config.seed,
+            turn: self.turn,
+            floor: self.map_manager.current_level,
+            branch: self.map_manager.current_branch,
+            gold: inventory.map_or(0, |inv| inv.gold),
+            player: EntityDump::of(&self.player),
+            inventory: inventory.map_or(Vec::new(), |inv| inv.items.iter().map(|item| item.name.clone()).collect()),
+            monsters: self.monsters.iter().map(EntityDump::of).collect(),
+        };
+        let written = ron::ser::to_string_pretty(&dump, ron::ser::PrettyConfig::default())
+            .map_err(|e| e.to_string())
+            .and_then(|contents| std::fs::write(RunDump::PATH, contents).map_err(|e| e.to_string()));
+        match written {
+            Ok(_) => self.add_log_message(MessageCategory::System, format!("Run dumped to {}.", RunDump::PATH)),
+            Err(e) => self.add_log_message(MessageCategory::Warning, format!("Could not dump the run: {}", e)),
+        }
+    }
+
+    // Read a dump back in, usually after editing it by hand
+    fn import_run(&mut self) {
+        let read = std::fs::read_to_string(RunDump::PATH)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| ron::from_str::<RunDump>(&contents).map_err(|e| e.to_string()));
+        let dump = match read {
+            Ok(dump) => dump,
+            Err(e) => {
+                self.add_log_message(MessageCategory::Warning, format!("Could not read {}: {}", RunDump::PATH, e));
+                return;
+            }
+        };
+        if dump.seed != self.map_manager.config.seed || dump.floor != self.map_manager.current_level || dump.branch != self.map_manager.current_branch {
+            self.add_log_message(MessageCategory::Warning, "That dump is from another run or floor.".to_string());
+            return;
+        }
+        // Monsters are matched up by order, so the same creatures have to be listed the same way
+        let same_monsters = self.monsters.len() == dump.monsters.len()
+            && self.monsters.iter().zip(&dump.monsters).all(|(monster, monster_dump)| monster.name == monster_dump.name);
+        if !same_monsters {
+            self.add_log_message(MessageCategory::Warning, "The dump's monsters don't match the ones on this floor.".to_string());
+            return;
+        }
+
+        let map = self.map_manager.current_map();
+        dump.player.apply(&mut self.player, map);
+        for (monster, monster_dump) in self.monsters.iter_mut().zip(&dump.monsters) {
+            monster_dump.apply(monster, map);
+        }
+        if let Some(inventory) = self.player.inventory.as_mut() {
+            inventory.gold = dump.gold;
+        }
+        self.player_dijkstra = None;
+        self.add_log_message(MessageCategory::System, format!("Run restored from {}.", RunDump::PATH));
+    }
+
+    // Reports always go to a file; the endpoint upload runs in the background
+    fn submit_feedback(&mut self) {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let report = format!("{}\n\n--- diagnostics ---\n{}", self.feedback_text.trim(), self.diagnostic_bundle());
+        let path = format!("feedback/feedback-{}.txt", timestamp);
+
+        let saved = std::fs::create_dir_all("feedback").and_then(|_| std::fs::write(&path, &report));
+        match saved {
+            Ok(_) => self.add_log_message(MessageCategory::System, format!("Feedback saved to {}. Thank you!", path)),
+            Err(e) => self.add_log_message(MessageCategory::Warning, format!("Could not save feedback: {}", e)),
+        }
+
+        if let Some(endpoint) = self.map_manager.config.feedback_endpoint.clone() {
+            std::thread::spawn(move || {
+                if let Err(e) = ureq::post(&endpoint).set("Content-Type", "text/plain").send_string(&report) {
+                    eprintln!("Failed to send feedback to {}: {}", endpoint, e);
+                }
+            });
+        }
+    }
+
+    fn draw_feedback_form(&self) {
+        draw_rectangle(
+            screen_width() * 0.2,
+            screen_height() * 0.3,
+            screen_width() * 0.6,
+            screen_height() * 0.3,
+            Color::new(0.0, 0.0, 0.0, 0.9),
+        );
+        draw_text("Send feedback", screen_width() * 0.22, screen_height() * 0.36, 30.0, WHITE);
+
+        // Show the tail of long messages so the cursor stays in view
+        let visible: String = self.feedback_text.chars().rev().take(60).collect::<Vec<_>>().into_iter().rev().collect();
+        let cursor = if (get_time() * 2.0) as i32 % 2 == 0 { "_" } else { " " };
+        draw_text(&format!("{}{}", visible, cursor), screen_width() * 0.22, screen_height() * 0.44, 20.0, YELLOW);
+
+        draw_text(
+            "Seed, turn and config are attached.  [Enter] Send  [Esc] Cancel",
+            screen_width() * 0.22,
+            screen_height() * 0.55,
+            18.0,
+            LIGHTGRAY,
+        );
+    }
+
+    fn add_log_message(&mut self, category: MessageCategory, message: String) {
+        self.message_history.push(LoggedMessage::new(category, message.clone(), self.turn));
+        let repeated = self.combat_log
+            .last_mut()
+            .filter(|last| category.collapses() && last.category == category && last.repeated_by(&message));
+        if let Some(last) = repeated {
+            last.text = message; // Shows the latest numbers
+            last.repeats += 1;
+            last.turn = self.turn;
+            return;
+        }
+        self.combat_log.push(LoggedMessage::new(category, message, self.turn));
+        if self.combat_log.len() > 5 {
+            self.combat_log.remove(0);
+        }
+    }
+
+    fn refresh_player_dijkstra(&mut self) {
+        let map = self.map_manager.current_map();
+        let origin = (self.player.x as i32, self.player.y as i32);
+        let stale = self.player_dijkstra
+            .as_ref()
+            .is_none_or(|d| d.origin != origin || d.level != map.level || d.revision != map.revision);
+        if stale {
+            self.player_dijkstra = Some(DijkstraMap::new(map, origin));
+        }
+    }
+
+    fn process_monster_turns(&mut self, current_time: f32) {
+        let refresh_started = Instant::now();
+        self.refresh_player_dijkstra();
+        let mut pathfinding = refresh_started.elapsed();
+        let player_pos = (self.player.x, self.player.y);
+        let map = self.map_manager.current_map();
+        let player_dijkstra = self.player_dijkstra.as_ref();
+
+        let monster_positions: Vec<(f32, f32)> = self.monsters.iter()
+            .filter(|m| m.is_alive())
+            .map(|m| (m.x, m.y))
+            .collect();
+        let mut monster_tiles: Vec<(i32, i32)> = monster_positions.iter().map(|&(x, y)| (x as i32, y as i32)).collect();
+        monster_tiles.extend(map.shop.as_ref().map(|shop| shop.keeper));
+        monster_tiles.extend(map.shrine.as_ref().map(|shrine| shrine.position));
+        monster_tiles.extend(map.bounty_board);
+        monster_tiles.extend(map.healer);
+        monster_tiles.extend(map.stash.as_ref().map(|stash| stash.position));
+        let potion_tiles: Vec<(i32, i32)> = self.ground_items
+            .iter()
+            .filter(|(_, _, item)| matches!(item.item_type, ItemType::Potion(_)))
+            .map(|&(x, y, _)| (x as i32, y as i32))
+            .collect();
+        let corpse_tiles: Vec<(i32, i32)> = self.ground_items
+            .iter()
+            .filter(|(_, _, item)| matches!(item.item_type, ItemType::Corpse(_, _)))
+            .map(|&(x, y, _)| (x as i32, y as i32))
+            .collect();
+        let difficulty = self.map_manager.config.ai_difficulty;
+        let ai_context = AiContext {
+            map,
+            player: (player_pos.0 as i32, player_pos.1 as i32),
+            monsters: &monster_tiles,
+            potions: &potion_tiles,
+            light_radius: self.light_radius(),
+            sneaking: self.sneaking,
+            in_darkness: self.light_fuel == 0,
+        };
+        let mut log_messages = Vec::new();
+        let mut hits_on_player = 0;
+        let mut escapes = Vec::new();
+        let mut torn_webs = Vec::new();
+        let mut spun_webs = Vec::new();
+        let mut fights = Vec::new();
+        let mut dug = Vec::new();
+
+        let speed_multiplier = if self.has_modifier(Modifier::Bloodlust) { 2.0 } else { 1.0 };
+
+        for i in 0..self.monsters.len() {
+            if !self.monsters[i].is_alive() || !self.monsters[i].can_move_with_multiplier(current_time, speed_multiplier) {
+                continue;
+            }
+
+            let monster = &mut self.monsters[i];
+            let hp_before = monster.stats.hp;
+            log_messages.extend(monster.tick_status_effects());
+            self.damage_popups.extend(DamagePopup::after_hit(monster, hp_before, false));
+            if !monster.is_alive() {
+                continue;
+            }
+            if monster.has_status(StatusKind::Entangled) {
+                if monster.struggle_free(&mut self.rng) {
+                    torn_webs.push((monster.x as i32, monster.y as i32));
+                }
+                monster.update_last_move(current_time);
+                continue;
+            }
+            let monster_pos = (monster.x as i32, monster.y as i32);
+            let player_grid_pos = (player_pos.0 as i32, player_pos.1 as i32);
+
+            let mut new_pos = monster_pos;
+
+            let goal = if monster.has_status(StatusKind::Confused) || monster.faction == Faction::Neutral {
+                MonsterGoal::Wander
+            } else {
+                monster.choose_goal(difficulty, &ai_context, &mut log_messages)
+            };
+
+            // Shoot instead of closing in when there's a clear shot
+            let distance = manhattan_distance(monster_pos, player_grid_pos);
+            let shot = monster.ranged.as_ref().filter(|r| {
+                matches!(goal, MonsterGoal::Chase)
+                    && distance > 1
+                    && distance <= r.range
+                    && map.has_line_of_sight(monster_pos, player_grid_pos)
+            });
+            if let Some(ranged) = shot {
+                let (r, g, b) = ranged.color;
+                self.projectiles.push(ProjectileTrace {
+                    from: (monster.x, monster.y),
+                    to: player_pos,
+                    color: Color::from_rgba(r, g, b, 255),
+                    time_left: ProjectileTrace::DURATION,
+                });
+                log_messages.push(format!("The {} {}!", monster.name, ranged.verb));
+                let element = ranged.element;
+                monster.face_towards(player_grid_pos);
+                let hp_before = self.player.stats.hp;
+                log_messages.extend(monster.attack(&mut self.player, &mut self.rng));
+                // An elemental shot still lands for at least 1, like any other hit
+                if let Some(element) = element {
+                    let resisted = self.player.gem_resistance(element).min(hp_before - self.player.stats.hp - 1);
+                    if resisted > 0 {
+                        self.player.stats.hp += resisted;
+                        log_messages.push(format!("Your armor's gems turn aside {} {} damage.", resisted, element.name()));
+                    }
+                }
+                self.damage_popups.extend(DamagePopup::after_hit(&self.player, hp_before, true));
+                hits_on_player += 1;
+                monster.update_last_move(current_time);
+                continue;
+            }
+
+            let is_occupied = |pos: (i32, i32)| monster_tiles.contains(&pos);
+            let planning_started = Instant::now();
+            match goal {
+                MonsterGoal::Chase => {
+                    let reach = player_dijkstra.filter(|d| d.distance(monster_pos).is_some());
+
+                    if let Some(dijkstra) = reach {
+                        // Roll downhill on the shared player map, sidestepping other monsters
+                        if let Some(step) = dijkstra.downhill(monster_pos, is_occupied) {
+                            new_pos = step;
+                        }
+                    } else if let Some(path) = map.find_path(monster_pos, player_grid_pos) {
+                        // Out of the shared map's range, so search on our own
+                        if path.len() > 1 {  // Check if we have a next step
+                            new_pos = path[1];  // Get the next position in the path
+                        }
+                    }
+                }
+                MonsterGoal::MoveTo(target) => {
+                    if let Some(path) = map.find_path(monster_pos, target) {
+                        new_pos = path.get(1).copied().unwrap_or(monster_pos);
+                    } else if monster.heard_noise == Some(target) {
+                        // No way through to the noise, so it isn't worth looking into
+                        monster.heard_noise = None;
+                    }
+                }
+                MonsterGoal::Flee => {
+                    if let Some(step) = player_dijkstra.and_then(|d| d.uphill(monster_pos, is_occupied)) {
+                        new_pos = step;
+                    }
+                }
+                MonsterGoal::Idle => {}
+                MonsterGoal::Wander => {
+                    // Random movement when player is not perceived or the monster is confused
+                    if let Some((dx, dy)) = map.directions().choose(&mut self.rng) {
+                        new_pos = (monster_pos.0 + dx, monster_pos.1 + dy);
+                    }
+                }
+            }
+
+            // Burrowers dig straight at what they are after whenever walking there is a long way round,
+            // and when they have nothing better to do they go after the nearest corpse they can smell
+            if monster.burrows {
+                let target = match goal {
+                    MonsterGoal::Chase => Some(player_grid_pos),
+                    MonsterGoal::MoveTo(target) => Some(target),
+                    MonsterGoal::Wander => corpse_tiles
+                        .iter()
+                        .copied()
+                        .filter(|&tile| manhattan_distance(tile, monster_pos) <= Self::CORPSE_SCENT_RANGE)
+                        .min_by_key(|&tile| manhattan_distance(tile, monster_pos)),
+                    MonsterGoal::Flee | MonsterGoal::Idle => None,
+                };
+                if let Some(target) = target.filter(|&target| target != monster_pos) {
+                    let path = if matches!(goal, MonsterGoal::Chase) { None } else { map.find_path(monster_pos, target) };
+                    let walk = match goal {
+                        MonsterGoal::Chase => player_dijkstra.and_then(|d| d.distance(monster_pos)),
+                        _ => path.as_ref().map(|path| (path.len() as i32 - 1) * STRAIGHT_STEP_COST),
+                    };
+                    if walk.is_none_or(|walk| walk > octile_distance(monster_pos, target) * Self::BURROW_DETOUR) {
+                        match map.straight_step(monster_pos, target) {
+                            Some((x, y)) if map.is_diggable(x, y) => {
+                                dug.push((x, y));
+                                new_pos = monster_pos;
+                                let distance = ((monster.x - player_pos.0).powi(2) + (monster.y - player_pos.1).powi(2)).sqrt();
+                                if distance <= ai_context.light_radius {
+                                    log_messages.push(format!("The {} tunnels into the rock!", monster.name));
+                                }
+                            }
+                            Some(step) => new_pos = step,
+                            None => {}
+                        }
+                    } else if matches!(goal, MonsterGoal::Wander) {
+                        new_pos = path.and_then(|path| path.get(1).copied()).unwrap_or(new_pos);
+                    }
+                }
+            }
+            pathfinding += planning_started.elapsed();
+
+            // Neutral monsters keep out of everyone's way
+            let onto_ally = self.allies.iter().any(|a| (a.entity.x as i32, a.entity.y as i32) == new_pos);
+            if monster.faction == Faction::Neutral && (new_pos == player_grid_pos || onto_ally) {
+                new_pos = monster_pos;
+            }
+            monster.face_towards(new_pos);
+
+            // Check if the new position is valid
+            if map.is_walkable(new_pos.0, new_pos.1) {
+                let new_pos_f = (new_pos.0 as f32, new_pos.1 as f32);
+
+                // Check for collisions with other monsters
+                let is_collision = monster_tiles.contains(&new_pos);
+
+                // Allies in the way get attacked
+                if let Some(ally) = self.allies.iter_mut().find(|a| (a.entity.x, a.entity.y) == new_pos_f) {
+                    let hp_before = ally.entity.stats.hp;
+                    log_messages.extend(monster.attack(&mut ally.entity, &mut self.rng));
+                    self.damage_popups.extend(DamagePopup::after_hit(&ally.entity, hp_before, false));
+                    fights.push(new_pos);
+                    monster.update_last_move(current_time);
+                    continue;
+                }
+
+                // Check for collision with player
+                if player_pos.0 == new_pos_f.0 && player_pos.1 == new_pos_f.1 {
+                    let hp_before = self.player.stats.hp;
+                    let message = monster.attack(&mut self.player, &mut self.rng);
+                    self.damage_popups.extend(DamagePopup::after_hit(&self.player, hp_before, true));
+                    hits_on_player += 1;
+                    if monster.is_alive() { // Only update if we haven't processed this monster in combat
+                        monster.update_last_move(current_time);
+                    }
+                    drop(monster); // Release the monster borrow before modifying self
+                    //self.add_log_message(message);
+                    continue;
+                } else if !is_collision {
+                    if monster.spins_webs && self.rng.gen_bool(0.3) {
+                        spun_webs.push(monster_pos);
+                    }
+                    monster.x = new_pos_f.0;
+                    monster.y = new_pos_f.1;
+                    if let Some(message) = monster.enter_tile(map) {
+                        let distance = ((monster.x - player_pos.0).powi(2) + (monster.y - player_pos.1).powi(2)).sqrt();
+                        if distance <= ai_context.light_radius {
+                            log_messages.push(message);
+                        }
+                    }
+
+                    // A fleeing monster that reaches the stairs takes them
+                    if monster.ai_state == AiState::Fleeing {
+                        let escape = match map.tile_at(new_pos.0, new_pos.1) {
+                            Some(Tile::StairsDown) => Some(true),
+                            Some(Tile::StairsUp) if map.level > 0 => Some(false),
+                            _ => None,
+                        };
+                        if let Some(going_down) = escape {
+                            let floor = self.map_manager.floor_beyond((new_pos.0 as usize, new_pos.1 as usize), going_down);
+                            escapes.push((monster.id, floor, going_down));
+                        }
+                    }
+
+                    // Burrowers stop to eat any corpse they come across
+                    let corpse_here = self.ground_items
+                        .iter()
+                        .position(|(x, y, item)| *x == monster.x && *y == monster.y && matches!(item.item_type, ItemType::Corpse(_, _)));
+                    if let (true, Some(index)) = (monster.burrows, corpse_here) {
+                        let (_, _, corpse) = self.ground_items.remove(index);
+                        if let ItemType::Corpse(carcass, _) = corpse.item_type {
+                            monster.stats.hp = (monster.stats.hp + carcass.heals).min(monster.stats.max_hp);
+                        }
+                        let distance = ((monster.x - player_pos.0).powi(2) + (monster.y - player_pos.1).powi(2)).sqrt();
+                        if distance <= ai_context.light_radius {
+                            log_messages.push(format!("The {} devours the {}.", monster.name, corpse.name));
+                        }
+                    }
+
+                    // Cunning monsters drink potions they reach while wounded
+                    let potion_here = self.ground_items
+                        .iter()
+                        .position(|(x, y, item)| *x == monster.x && *y == monster.y && matches!(item.item_type, ItemType::Potion(_)));
+                    if let (AiDifficulty::Cunning, Some(index)) = (difficulty, potion_here) {
+                        if monster.stats.hp < monster.stats.max_hp {
+                            let (_, _, potion) = self.ground_items.remove(index);
+                            if let ItemType::Potion(amount) = potion.item_type {
+                                monster.stats.hp = (monster.stats.hp + amount).min(monster.stats.max_hp);
+                            }
+                            log_messages.push(format!("The {} drinks a {}!", monster.name, potion.name));
+                        }
+                    }
+                }
+            }
+
+            monster.update_last_move(current_time);
+        }
+
+        for message in log_messages {
+            self.add_log_message(MessageCategory::Combat, message);
+        }
+        for _ in 0..hits_on_player {
+            self.wear_equipment(false);
+        }
+
+        for &(x, y) in &torn_webs {
+            self.make_noise(Noise::TearingWeb, (x, y));
+        }
+        for origin in fights {
+            self.make_noise(Noise::Fight, origin);
+        }
+        for &(x, y) in &dug {
+            self.make_noise(Noise::Burrowing, (x, y));
+        }
+
+        let map = self.map_manager.current_map_mut();
+        for (x, y) in torn_webs {
+            map.terrain_overlays.remove(&(x, y));
+        }
+        for (x, y) in dug {
+            map.dig(x, y);
+        }
+        for (x, y) in spun_webs {
+            map.spin_web(x, y, Some(Self::SPUN_WEB_TURNS));
+        }
+        self.turn_profile.pathfinding += pathfinding;
+
+        for (id, floor, going_down) in escapes {
+            let Some(i) = self.monsters.iter().position(|m| m.id == id) else {
+                continue;
+            };
+            let entity = self.monsters.remove(i);
+            if self.is_lit(entity.x, entity.y) {
+                let direction = if going_down { "down" } else { "up" };
+                self.add_log_message(MessageCategory::System, format!("The {} escapes {} the stairs!", entity.name, direction));
+            } else {
+                self.make_noise(Noise::Stairs, (entity.x as i32, entity.y as i32));
+            }
+            self.map_manager.send_to_floor(floor, FloorTransfer {
+                entity,
+                arrive_turn: self.turn,
+                going_down,
+                pursuing: false,
+                from: self.map_manager.current_floor(),
+            });
+        }
+    }
+
+    // How many times each nearby monster acts before the player can act again
+    fn initiative(&self, current_time: f32) -> Vec<(&Entity, u32)> {
+        const MAX_SHOWN: u32 = 9;
+        let speed_multiplier = if self.has_modifier(Modifier::Bloodlust) { 2.0 } else { 1.0 };
+        let player_pos = (self.player.x as i32, self.player.y as i32);
+        let player_ready = (self.player.stats.last_move + self.player.move_interval(1.0)).max(current_time);
+
+        self.monsters
+            .iter()
+            .filter(|m| m.is_alive() && self.is_lit(m.x, m.y))
+            .filter(|m| manhattan_distance(player_pos, (m.x as i32, m.y as i32)) <= 2)
+            .map(|m| {
+                let interval = m.move_interval(speed_multiplier);
+                let mut next_action = (m.stats.last_move + interval).max(current_time);
+                let mut actions = 0;
+                while next_action <= player_ready && actions < MAX_SHOWN {
+                    actions += 1;
+                    next_action += interval;
+                }
+                (m, actions)
+            })
+            .collect()
+    }
+
+    // A strip under the top bar so speed differences next to the player are never a surprise
+    fn draw_initiative_strip(&self, current_time: f32) {
+        let entries = self.initiative(current_time);
+        if entries.is_empty() {
+            return;
+        }
+
+        let x = screen_width() - 70.0 * entries.len() as f32 - 10.0;
+        draw_rectangle(x - 5.0, TOP_BAR_HEIGHT + 28.0, screen_width() - x, 24.0, Color::new(0.0, 0.0, 0.0, 0.6));
+        for (i, (monster, actions)) in entries.iter().enumerate() {
+            let color = match actions {
+                0 => GRAY,
+                1 => WHITE,
+                _ => RED,
+            };
+            let entry_x = x + i as f32 * 70.0;
+            draw_text(&monster.symbol.to_string(), entry_x, TOP_BAR_HEIGHT + 46.0, 20.0, monster.color);
+            draw_text(&format!("x{}", actions), entry_x + 16.0, TOP_BAR_HEIGHT + 46.0, 18.0, color);
+        }
+    }
+
+    fn update_projectiles(&mut self, dt: f32) {
+        for projectile in &mut self.projectiles {
+            projectile.time_left -= dt;
+        }
+        self.projectiles.retain(|p| p.time_left > 0.0);
+        for mark in &mut self.strike_marks {
+            mark.time_left -= dt;
+        }
+        self.strike_marks.retain(|m| m.time_left > 0.0);
+        for popup in &mut self.damage_popups {
+            popup.time_left -= dt;
+        }
+        self.damage_popups.retain(|p| p.time_left > 0.0);
+    }
+
+    fn spawn_items(&mut self, map: &Map) {
+        let mut rng = self.fork_rng();
+
+        for room_row in &map.rooms {
+            for room in room_row {
+                if rng.gen_bool(0.6) {
+                    let (x, y) = room.random_position(&mut rng);
+                    if let Some(item) = self.item_registry.roll(map.level, &mut rng) {
+                        self.ground_items.push((x as f32, y as f32, item));
+                    }
+                }
+            }
+        }
+    }
+
+    fn pick_up_item(&mut self, x: f32, y: f32) -> Option<String> {
+        if let Some(index) = self.ground_items
+            .iter()
+            .position(|(ix, iy, _)| *ix == x && *iy == y)
+        {
+            let (_, _, item) = self.ground_items.remove(index);
+            if let Some(ref mut inventory) = self.player.inventory {
+                match inventory.add_item(item.clone()) {
+                    Ok(_) => Some(format!("Picked up {}!", item.name)),
+                    Err(e) => Some(e),
+                }
+            } else {
+                Some("No inventory available!".to_string())
+            }
+        } else {
+            None
+        }
+    }
+
+    // Random walkable tile on the current map that no monster is standing on
+    fn random_free_tile(&mut self) -> Option<(f32, f32)> {
+        let map = self.map_manager.current_map();
+        let mut free_tiles = Vec::new();
+
+        for y in 0..map.height as i32 {
+            for x in 0..map.width as i32 {
+                let (fx, fy) = (x as f32, y as f32);
+                let occupied = self.monsters.iter().any(|m| m.is_alive() && m.x == fx && m.y == fy);
+                let is_player = self.player.x == fx && self.player.y == fy;
+                if map.is_walkable(x, y) && !occupied && !is_player {
+                    free_tiles.push((fx, fy));
+                }
+            }
+        }
+
+        free_tiles.choose(&mut self.rng).copied()
+    }
+
+    // Applies an element around a tile, hurting creatures there and changing the terrain by its
+    // properties. Returns the tiles it reached, so the caller can deal with the player
+    fn resolve_element(&mut self, element: Element, origin: (i32, i32), radius: i32, damage: i32, source: DamageSource) -> HashSet<(i32, i32)> {
+        let map = self.map_manager.current_map();
+        let mut area: HashSet<(i32, i32)> = HashSet::new();
+        for y in origin.1 - radius..=origin.1 + radius {
+            for x in origin.0 - radius..=origin.0 + radius {
+                if map.tile_at(x, y).is_some() {
+                    area.insert((x, y));
+                }
+            }
+        }
+
+        // Lightning runs through every tile connected to the origin that conducts
+        if element == Element::Lightning {
+            let conducts = |(x, y): (i32, i32)| map.tile_at(x, y).is_some_and(|t| t.properties().conducts);
+            if !conducts(origin) {
+                return HashSet::new();
+            }
+            let mut frontier = vec![origin];
+            while let Some((x, y)) = frontier.pop() {
+                for (dx, dy) in [(0, 1), (1, 0), (0, -1), (-1, 0)] {
+                    let next = (x + dx, y + dy);
+                    if conducts(next) && area.insert(next) {
+                        frontier.push(next);
+                    }
+                }
+            }
+            area.remove(&origin); // The bolt already struck there
+        }
+
+        let mut changed = HashSet::new();
+        let map = self.map_manager.current_map_mut();
+        for &(x, y) in &area {
+            let properties = map.tiles[y as usize][x as usize].properties();
+            let becomes = match element {
+                Element::Fire => properties.burns_to,
+                Element::Cold => properties.freezes_to,
+                Element::Lightning => None,
+            };
+            if let Some(tile) = becomes {
+                map.set_tile(x, y, tile);
+                changed.insert((x, y));
+            }
+            if element == Element::Fire && map.terrain_overlays.get(&(x, y)).is_some_and(|o| o.kind.burns()) {
+                map.terrain_overlays.remove(&(x, y));
+                changed.insert((x, y));
+            }
+        }
+
+        let mut hurt = 0;
+        if damage > 0 {
+            let in_area = |e: &Entity| e.is_alive() && area.contains(&(e.x as i32, e.y as i32));
+            for entity in self.monsters.iter_mut().chain(self.allies.iter_mut().map(|a| &mut a.entity)) {
+                if in_area(entity) {
+                    let hp_before = entity.stats.hp;
+                    entity.stats.hp -= damage;
+                    self.damage_popups.extend(DamagePopup::after_hit(entity, hp_before, false));
+                    entity.last_hit_by = Some(source.clone());
+                    if element == Element::Fire {
+                        entity.afflict(StatusKind::Burning(Self::BURN_DAMAGE), Self::BURN_TURNS, source.responsible());
+                    }
+                    hurt += 1;
+                }
+            }
+        }
+
+        match element {
+            Element::Lightning if hurt > 0 => self.add_log_message(MessageCategory::Combat, format!("The lightning arcs through the water, shocking {} more!", hurt)),
+            Element::Fire if !changed.is_empty() => self.add_log_message(MessageCategory::System, "The flames catch, burning away webs and grass.".to_string()),
+            _ => {}
+        }
+        // Cold only matters where it froze something
+        if element == Element::Cold {
+            changed
+        } else {
+            area
+        }
+    }
+
+    fn find_closest_monster(&mut self, x: f32, y: f32, max_range: f32) -> Option<&mut Entity> {
+        self.monsters
+            .iter_mut()
+            .filter(|m| m.is_alive())
+            .map(|m| (((m.x - x).powi(2) + (m.y - y).powi(2)).sqrt(), m))
+            .filter(|(distance, _)| *distance <= max_range)
+            .min_by_key(|(distance, _)| (distance * 100.0) as i32)
+            .map(|(_, m)| m)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct HighScoreEntry {
+    score: u32,
+    floor: i32,
+    level: i32,
+    modifiers: Vec<String>,
+}
+
+// The current floor's spawn table for wizard mode, with what rolling it many times comes to
+struct SpawnPreview {
+    floor: String, // The depth and theme the table was sampled for
+    budget: i32,
+    rows: Vec<SpawnRow>,
+}
+
+struct SpawnRow {
+    name: String,
+    color: Color,
+    threat: i32,
+    haunts: Vec<RoomPurpose>,
+    share: f32, // Of all the monsters rolled
+    expected: f32, // On one floor
+}
+
+impl SpawnPreview {
+    const SAMPLES: u32 = 200;
+
+    // Elites are counted with the rest of their kind
+    fn kind(monster: &Entity) -> &str {
+        monster.name.strip_prefix("Elite ").unwrap_or(&monster.name)
+    }
+}
+
+// Where monsters turn up on one floor over many seeds, and how much threat, for tuning the
+// spawn parameters with numbers. Run with --heatmap <floor> [seeds], floors counted from 1 as
+// on the HUD. Nothing opens on screen
+struct DensityHeatmap {
+    floor: i32,
+    seeds: u32,
+    width: usize,
+    height: usize,
+    spawns: Vec<Vec<u32>>, // Seeds that had a monster on each tile
+    threat: Vec<Vec<i32>>, // Threat of the monsters on each tile, summed over the seeds
+    budget: i32,
+    spent: Vec<i32>, // Threat on the whole floor, one entry per seed
+}
+
+impl DensityHeatmap {
+    const DEFAULT_SEEDS: u32 = 100;
+    const PIXELS_PER_TILE: usize = 8;
+
+    fn sample(floor: i32, seeds: u32) -> Self {
+        let config = GameConfig::default();
+        let (width, height) = (config.map_width, config.map_height);
+        let mut heatmap = Self {
+            floor,
+            seeds,
+            width,
+            height,
+            spawns: vec![vec![0; width]; height],
+            threat: vec![vec![0; width]; height],
+            budget: 0,
+            spent: Vec::new(),
+        };
+
+        for seed in 0..seeds as u64 {
+            let mut game_state = GameState::new(GameConfig { seed, ..GameConfig::default() }, Vec::new(), &HashMap::new());
+            if floor > 0 {
+                // Each floor's up stairs come out under the last one's down stairs, so they are made in turn
+                for level in 1..=floor {
+                    game_state.map_manager.change_floor(FloorId { branch: None, level });
+                }
+                game_state.initialize_current_level();
+            }
+            heatmap.budget = game_state.threat_budget();
+
+            let mut spent = 0;
+            for monster in game_state.monsters.iter().filter(|m| m.is_alive()) {
+                let (x, y) = (monster.x as usize, monster.y as usize);
+                let threat = monster.stats.xp_value.max(1);
+                heatmap.spawns[y][x] += 1;
+                heatmap.threat[y][x] += threat;
+                spent += threat;
+            }
+            heatmap.spent.push(spent);
+        }
+        heatmap
+    }
+
+    fn spawn_chance(&self, x: usize, y: usize) -> f32 {
+        self.spawns[y][x] as f32 / self.seeds.max(1) as f32
+    }
+
+    fn mean_threat(&self, x: usize, y: usize) -> f32 {
+        self.threat[y][x] as f32 / self.seeds.max(1) as f32
+    }
+
+    fn summary(&self) -> String {
+        let mean = self.spent.iter().sum::<i32>() as f32 / self.spent.len().max(1) as f32;
+        let (least, most) = (self.spent.iter().min().copied().unwrap_or(0), self.spent.iter().max().copied().unwrap_or(0));
+        format!(
+            "Floor {} over {} seeds: threat budget {}, monsters came to {:.0} on average ({} to {})",
+            self.floor + 1,
+            self.seeds,
+            self.budget,
+            mean,
+            least,
+            most
+        )
+    }
+
+    // Writes the spawn chance and threat grids as PNGs, and both side by side as a CSV. Returns the paths
+    fn export(&self) -> Result<Vec<String>, String> {
+        let prefix = format!("heatmap_floor{}", self.floor + 1);
+        let csv_path = format!("{}.csv", prefix);
+        let mut csv = String::from("x,y,spawn_chance,mean_threat\n");
+        for y in 0..self.height {
+            for x in 0..self.width {
+                csv += &format!("{},{},{:.4},{:.3}\n", x, y, self.spawn_chance(x, y), self.mean_threat(x, y));
+            }
+        }
+        std::fs::write(&csv_path, csv).map_err(|e| e.to_string())?;
+
+        let spawns_path = format!("{}_spawns.png", prefix);
+        let threat_path = format!("{}_threat.png", prefix);
+        self.render(|x, y| self.spawn_chance(x, y)).export_png(&spawns_path);
+        self.render(|x, y| self.mean_threat(x, y)).export_png(&threat_path);
+        Ok(vec![spawns_path, threat_path, csv_path])
+    }
+
+    // Black where nothing ever stood, through red to yellow at the grid's highest value
+    fn render(&self, value: impl Fn(usize, usize) -> f32) -> Image {
+        let scale = Self::PIXELS_PER_TILE;
+        let (pixels_wide, pixels_high) = (self.width * scale, self.height * scale);
+        let highest = (0..self.height).flat_map(|y| (0..self.width).map(move |x| (x, y))).map(|(x, y)| value(x, y)).fold(0.0, f32::max);
+        let mut image = Image::gen_image_color(pixels_wide as u16, pixels_high as u16, BLACK);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let heat = if highest > 0.0 { value(x, y) / highest } else { 0.0 };
+                let color = Color::new((heat * 2.0).min(1.0), (heat * 2.0 - 1.0).max(0.0), 0.0, 1.0);
+                for py in y * scale..(y + 1) * scale {
+                    for px in x * scale..(x + 1) * scale {
+                        // The export flips the rows, so they go in bottom up
+                        image.set_pixel(px as u32, (pixels_high - 1 - py) as u32, color);
+                    }
+                }
+            }
+        }
+        image
+    }
+}
+
+// How long a turn of monster pathing takes on the default 50x40 floor, for keeping A* fast enough
+// not to hitch. Run with --bench-path [seeds]. Each turn paths PATHERS monsters from random floor
+// tiles to the player, as a crowded floor does
+struct PathBenchmark {
+    seeds: u32,
+    turns: Vec<Duration>, // Every turn timed, over all the seeds
+    paths_found: usize,
+}
+
+impl PathBenchmark {
+    const DEFAULT_SEEDS: u32 = 20;
+    const TURNS_PER_SEED: usize = 100;
+    const PATHERS: usize = 30;
+
+    fn run(seeds: u32) -> Self {
+        let mut benchmark = Self { seeds, turns: Vec::new(), paths_found: 0 };
+        for seed in 0..seeds as u64 {
+            let game_state = GameState::new(GameConfig { seed, ..GameConfig::default() }, Vec::new(), &HashMap::new());
+            let map = game_state.map_manager.current_map();
+            let goal = (game_state.player.x as i32, game_state.player.y as i32);
+            let open: Vec<(i32, i32)> = (0..map.height as i32)
+                .flat_map(|y| (0..map.width as i32).map(move |x| (x, y)))
+                .filter(|&(x, y)| map.is_walkable(x, y))
+                .collect();
+
+            let mut rng = StdRng::seed_from_u64(seed);
+            for _ in 0..Self::TURNS_PER_SEED {
+                let starts: Vec<(i32, i32)> = open.choose_multiple(&mut rng, Self::PATHERS).copied().collect();
+                let started = Instant::now();
+                benchmark.paths_found += starts.iter().filter_map(|&start| map.find_path(start, goal)).count();
+                benchmark.turns.push(started.elapsed());
+            }
+        }
+        benchmark
+    }
+
+    fn summary(&self) -> String {
+        let mut turns = self.turns.clone();
+        turns.sort();
+        let mean = turns.iter().sum::<Duration>() / turns.len().max(1) as u32;
+        let slowest_tenth = turns.get(turns.len() * 9 / 10).copied().unwrap_or_default();
+        let slowest = turns.last().copied().unwrap_or_default();
+        format!(
+            "{} turns of {} paths over {} seeds, {} paths found: {:.2?} per turn on average, {:.2?} at the 90th percentile, {:.2?} at worst",
+            turns.len(),
+            Self::PATHERS,
+            self.seeds,
+            self.paths_found,
+            mean,
+            slowest_tenth,
+            slowest
+        )
+    }
+}
+
+// A readable dump of the run in progress for inspecting or hand-fixing it. There is no save
+// file yet, so this snapshots the live run, and importing it only writes back the numbers below
+#[derive(Serialize, Deserialize)]
+struct RunDump {
+    seed: u64,
+    turn: u32,
+    floor: i32,
+    #[serde(default)]
+    branch: Option<usize>, // Index into the branch registry, none on the main dungeon's floors
+    gold: u32,
+    player: EntityDump,
+    inventory: Vec<String>, // For reading only, items aren't rebuilt from names
+    monsters: Vec<EntityDump>, // Matched up with the floor's monsters by order
+}
+
+#[derive(Serialize, Deserialize)]
+struct EntityDump {
+    name: String,
+    x: i32,
+    y: i32,
+    hp: i32,
+    max_hp: i32,
+    attack: i32,
+    defense: i32,
+}
+
+impl EntityDump {
+    fn of(entity: &Entity) -> Self {
+        Self {
+            name: entity.name.clone(),
+            x: entity.x as i32,
+            y: entity.y as i32,
+            hp: entity.stats.hp,
+            max_hp: entity.stats.max_hp,
+            attack: entity.stats.attack,
+            defense: entity.stats.defense,
+        }
+    }
+
+    // Positions only change when the dump points at an open tile
+    fn apply(&self, entity: &mut Entity, map: &Map) {
+        entity.stats.hp = self.hp;
+        entity.stats.max_hp = self.max_hp;
+        entity.stats.attack = self.attack;
+        entity.stats.defense = self.defense;
+        if map.is_walkable(self.x, self.y) {
+            entity.x = self.x as f32;
+            entity.y = self.y as f32;
+        }
+    }
+}
+
+impl RunDump {
+    const PATH: &'static str = "run_dump.ron";
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct HighScores {
+    entries: Vec<HighScoreEntry>,
+}
+
+impl HighScores {
+    const PATH: &'static str = "highscores.ron";
+    const MAX_ENTRIES: usize = 10;
+
+    fn load() -> Self {
+        Saves::open()
+            .load(Self::PATH)
+            .and_then(|contents| ron::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        if let Ok(contents) = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()) {
+            Saves::open().save(Self::PATH, contents);
+        }
+    }
+
+    fn add(&mut self, entry: HighScoreEntry) {
+        self.entries.push(entry);
+        self.entries.sort_by_key(|e| std::cmp::Reverse(e.score));
+        self.entries.truncate(Self::MAX_ENTRIES);
+    }
+}
+
+// Permanent upgrades bought at the Forge between runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+enum ForgeUpgrade {
+    StartingPotion,
+    Vitality,
+    Haggler,
+}
+
+impl ForgeUpgrade {
+    const ALL: [ForgeUpgrade; 3] = [ForgeUpgrade::StartingPotion, ForgeUpgrade::Vitality, ForgeUpgrade::Haggler];
+
+    fn name(&self) -> &'static str {
+        match self {
+            ForgeUpgrade::StartingPotion => "Provisions",
+            ForgeUpgrade::Vitality => "Vitality",
+            ForgeUpgrade::Haggler => "Haggler",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            ForgeUpgrade::StartingPotion => "Start with an extra health potion",
+            ForgeUpgrade::Vitality => "+5 starting HP",
+            ForgeUpgrade::Haggler => "Shop prices 5% lower",
+        }
+    }
+
+    fn max_rank(&self) -> u32 {
+        match self {
+            ForgeUpgrade::StartingPotion => 3,
+            ForgeUpgrade::Vitality => 5,
+            ForgeUpgrade::Haggler => 3,
+        }
+    }
+
+    // Each rank costs more than the last
+    fn cost(&self, rank: u32) -> u32 {
+        let base = match self {
+            ForgeUpgrade::StartingPotion => 3,
+            ForgeUpgrade::Vitality => 5,
+            ForgeUpgrade::Haggler => 4,
+        };
+        base * (rank + 1)
+    }
+}
+
+// Meta progression that survives between runs
+#[derive(Default, Serialize, Deserialize)]
+struct Profile {
+    embers: u32,
+    upgrades: HashMap<ForgeUpgrade, u32>,
+    #[serde(default)]
+    hints: HintMode, // The last one picked on the menu
+}
+
+impl Profile {
+    const PATH: &'static str = "profile.ron";
+
+    fn load() -> Self {
+        Saves::open()
+            .load(Self::PATH)
+            .and_then(|contents| ron::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        if let Ok(contents) = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()) {
+            Saves::open().save(Self::PATH, contents);
+        }
+    }
+
+    fn rank(&self, upgrade: ForgeUpgrade) -> u32 {
+        self.upgrades.get(&upgrade).copied().unwrap_or(0)
+    }
+
+    fn buy(&mut self, upgrade: ForgeUpgrade) -> bool {
+        let rank = self.rank(upgrade);
+        let cost = upgrade.cost(rank);
+        if rank >= upgrade.max_rank() || self.embers < cost {
+            return false;
+        }
+        self.embers -= cost;
+        self.upgrades.insert(upgrade, rank + 1);
+        true
+    }
+}
+
+// Who lives in the town
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Townsfolk {
+    Shopkeeper,
+    Healer,
+}
+
+impl Townsfolk {
+    fn name(&self) -> &'static str {
+        match self {
+            Townsfolk::Shopkeeper => "shopkeeper",
+            Townsfolk::Healer => "healer",
+        }
+    }
+
+    // The hour they open, the hour they close and the hour they go to bed
+    fn hours(&self) -> (u32, u32, u32) {
+        match self {
+            Townsfolk::Shopkeeper => (8, 18, 22),
+            Townsfolk::Healer => (7, 20, 23),
+        }
+    }
+
+    fn activity(&self, hour: u32) -> Activity {
+        let (opens, closes, bedtime) = self.hours();
+        if (opens..closes).contains(&hour) {
+            Activity::Working
+        } else if (closes..bedtime).contains(&hour) {
+            Activity::Strolling
+        } else {
+            Activity::Sleeping
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Activity {
+    Working,
+    Strolling, // About the square between closing up and bedtime
+    Sleeping,
+}
+
+// A townsperson going about their day, a step at a time towards wherever the hour has them
+#[derive(Clone, Debug)]
+struct Resident {
+    kind: Townsfolk,
+    position: (i32, i32),
+    post: (i32, i32), // Where they serve customers
+    home: (i32, i32), // Their bed
+    stroll_to: Option<(i32, i32)>,
+}
+
+impl Resident {
+    const LINGER_CHANCE: f64 = 0.9; // Chance each turn to stay a while at the end of a stroll
+}
+
+// The chest in the town where items wait between dives, for as long as the run lasts
+struct Stash {
+    position: (i32, i32),
+    items: Vec<Item>,
+}
+
+impl Stash {
+    const CAPACITY: usize = 30;
+}
+
+// The town stash written to its own file, so what is left there outlives the character
+#[derive(Default, Serialize, Deserialize)]
+struct SharedStash {
+    items: Vec<StashedItem>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StashedItem {
+    name: String,
+    cursed: bool,
+    curse_known: bool,
+    gems: Vec<(GemKind, MaterialQuality)>,
+}
+
+impl SharedStash {
+    const PATH: &'static str = "stash.ron";
+
+    fn load() -> Self {
+        Saves::open()
+            .load(Self::PATH)
+            .and_then(|contents| ron::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        if let Ok(contents) = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()) {
+            Saves::open().save(Self::PATH, contents);
+        }
+    }
+
+    // Set pieces and one-of-a-kind finds have no template to rebuild them from. Relics, keys,
+    // keepsakes and scenario curios belong to the run they were found in
+    fn keeps(item: &Item, registry: &ItemRegistry) -> bool {
+        let run_bound = matches!(item.item_type, ItemType::Relic | ItemType::Curio | ItemType::Key | ItemType::ChestKey | ItemType::Keepsake(_));
+        !run_bound && item.set.is_none() && registry.create_named(&item.name).is_some()
+    }
+
+    fn from_items(items: &[Item]) -> Self {
+        let items = items
+            .iter()
+            .map(|item| StashedItem {
+                name: item.name.clone(),
+                cursed: item.cursed,
+                curse_known: item.curse_known,
+                gems: item.gems.clone(),
+            })
+            .collect();
+        Self { items }
+    }
+
+    fn restore(&self, registry: &ItemRegistry) -> Vec<Item> {
+        self.items
+            .iter()
+            .filter_map(|stashed| {
+                let mut item = registry.create_named(&stashed.name).filter(|item| Self::keeps(item, registry))?;
+                item.cursed = stashed.cursed;
+                item.curse_known = stashed.curse_known;
+                item.gems = stashed.gems.iter().copied().take(item.sockets as usize).collect();
+                Some(item)
+            })
+            .take(Stash::CAPACITY)
+            .collect()
+    }
+}
+
+// A room with a friendly shopkeeper, who opens the shop when bumped
+struct Shop {
+    keeper: (i32, i32),
+    stock: Vec<Item>,
+}
+
+impl Shop {
+    const SELL_RATE: f32 = 0.5; // Shops buy at half their own price
+}
+
+// An optional trial: activating it seals the room and calls waves of monsters against a turn limit
+struct Shrine {
+    position: (i32, i32),
+    room: Room,
+    trial: Option<ShrineTrial>,
+    used: bool,
+}
+
+struct ShrineTrial {
+    waves_left: u32,
+    deadline: u32,
+    sealed: Vec<((i32, i32), Tile)>, // Entrances walled up for the trial and what was there before
+}
+
+impl Shrine {
+    const WAVES: u32 = 3;
+    const TURN_LIMIT: u32 = 60;
+}
+
+// What a bounty asks for
+#[derive(Clone, Debug)]
+enum BountyTarget {
+    Slay { name: String, template: String }, // A named elite of the given monster kind
+    Recover { item: String },                // A keepsake lying somewhere on the floor
+}
+
+#[derive(Clone, Debug)]
+struct Bounty {
+    id: u32,
+    floor: i32,
+    target: BountyTarget,
+    gold: u32,
+    reputation: u32,
+    placed: bool, // Whether the target has been put on its floor yet
+    completed: bool,
+}
+
+impl Bounty {
+    fn describe(&self) -> String {
+        match &self.target {
+            BountyTarget::Slay { name, .. } => format!("Slay {} on floor {}", name, self.floor + 1),
+            BountyTarget::Recover { item } => format!("Recover the {} from floor {}", item, self.floor + 1),
+        }
+    }
+}
+
+// Offers on the first floor's board, swapped out for new ones every so often
+struct BountyBoard {
+    offers: Vec<Bounty>,
+    active: Vec<Bounty>,
+    next_id: u32,
+    refresh_turn: u32, // Turn the current offers are replaced, the first visit always fills the board
+}
+
+impl BountyBoard {
+    const REFRESH_TURNS: u32 = 300;
+    const OFFERS: usize = 3;
+    const MAX_ACTIVE: usize = 3;
+    const ELITE_NAMES: [&'static str; 8] = ["Grukk", "Vess", "Skarn", "Mordra", "Tibbet", "Huldr", "Ashka", "Brannoc"];
+    const KEEPSAKES: [&'static str; 6] = [
+        "Merchant's Ledger",
+        "Silver Locket",
+        "Miner's Lamp",
+        "Wedding Ring",
+        "Family Seal",
+        "Cracked Hourglass",
+    ];
+
+    fn new() -> Self {
+        Self {
+            offers: Vec::new(),
+            active: Vec::new(),
+            next_id: 0,
+            refresh_turn: 0,
+        }
+    }
+}
+
+// What the player has told an ally to do
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AllyOrder {
+    Follow,
+    Stay,
+    Attack(EntityId),
+    Retreat,
+}
+
+impl AllyOrder {
+    fn name(&self) -> &'static str {
+        match self {
+            AllyOrder::Follow => "Following",
+            AllyOrder::Stay => "Staying",
+            AllyOrder::Attack(_) => "Attacking",
+            AllyOrder::Retreat => "Retreating",
+        }
+    }
+}
+
+// Pets, summons and escorts fighting on the player's side
+struct Ally {
+    entity: Entity,
+    order: AllyOrder,
+    leaves_at: Option<u32>, // Turn a hired mercenary's contract runs out
+}
+
+impl Ally {
+    const MAX_PARTY_SIZE: usize = 3;
+    const MERCENARY_TURNS: u32 = 150;
+}
+
+// What a bribe buys from a monster
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Deal {
+    Truce, // It leaves the player be
+    Hire,  // It fights for the player for a while
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Payment {
+    Gold(u32),
+    Item(usize), // Inventory slot of the item handed over
+}
+
+struct LevelState {
+    monsters: Vec<Entity>,
+    ground_items: Vec<(f32, f32, Item)>,
+}
+
+// Options the player picks for this machine, read from settings.ron. A missing file is written
+// out with the defaults so there is something to edit
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct Settings {
+    photosensitive_safe_mode: bool, // No shake or flashing, only gentle fades
+    volume: f32, // From 0.0 for silence to 1.0
+    idle_pause_seconds: f32, // Seconds without input before a run pauses itself, 0.0 for never
+    tileset_path: Option<String>, // A 16x16 glyph atlas in code page order
+    ascii_renderer: bool,
+    feedback_endpoint: Option<String>, // A URL that also receives each feedback report
+    diagonal_movement: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            photosensitive_safe_mode: false,
+            volume: 0.7,
+            idle_pause_seconds: 120.0,
+            tileset_path: None,
+            ascii_renderer: false,
+            feedback_endpoint: None,
+            diagonal_movement: true,
+        }
+    }
+}
+
+impl Settings {
+    const PATH: &'static str = "settings.ron";
+
+    // Kept out of the synced saves, what suits one screen or desk may not suit another
+    fn load() -> Self {
+        match std::fs::read_to_string(Self::PATH) {
+            Ok(contents) => ron::from_str(&contents).unwrap_or_else(|e| {
+                eprintln!("Could not read {}, using the defaults: {}", Self::PATH, e);
+                Self::default()
+            }),
+            Err(_) => {
+                let settings = Self::default();
+                if let Ok(contents) = ron::ser::to_string_pretty(&settings, ron::ser::PrettyConfig::default()) {
+                    let _ = std::fs::write(Self::PATH, contents);
+                }
+                settings
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct GameConfig {
+    map_width: usize,
+    map_height: usize,
+    rumble_intensity: f32, // 0.0 disables controller rumble
+    volume: f32, // 0.0 mutes sound effects and music
+    photosensitive_safe_mode: bool, // No shake or flashing, only gentle fades
+    feedback_endpoint: Option<String>, // Feedback reports are also POSTed here when set
+    demo_turn_delay: f32, // Seconds between demo bot turns, slow enough to follow
+    ai_difficulty: AiDifficulty,
+    character_class: CharacterClass,
+    seed: u64, // Everything the run rolls derives from this, a fresh one for each run unless given with --seed
+    diagonal_movement: bool, // Off limits the player and monsters to the four cardinal directions
+    debug_mode: bool, // Logs the state hash every turn and slow turns, for comparing and profiling runs. Started with --debug
+    wizard_mode: bool, // Tools for content authors, started with --wizard
+    tileset_path: Option<String>, // The default tileset is tried when none is set
+    ascii_renderer: bool, // Draw plain text glyphs even when a tileset is available
+    macros: KeyMacros,
+    idle_pause_seconds: f32, // Pause after this long without input, 0.0 never does
+    hints: HintMode,
+    shared_stash: bool, // The town stash is kept between runs, off for purist play
+    scenario: Option<Scenario>, // Won on its own goal instead of the Heart of the Forge
+    demo: bool, // Played by the attract mode bot, kept out of the encyclopedia
+}
+
+impl GameConfig {
+    // The seed given with --seed, to replay or share a run
+    fn seed_arg() -> Option<u64> {
+        std::env::args().skip_while(|arg| arg != "--seed").nth(1).and_then(|arg| arg.parse().ok())
+    }
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        let settings = Settings::load();
+        Self {
+            map_width: 50,  // Larger map
+            map_height: 40, // Larger map
+            rumble_intensity: 0.0,
+            volume: settings.volume.clamp(0.0, 1.0),
+            photosensitive_safe_mode: settings.photosensitive_safe_mode,
+            feedback_endpoint: settings.feedback_endpoint,
+            demo_turn_delay: 0.25,
+            ai_difficulty: AiDifficulty::Basic,
+            character_class: CharacterClass::Warrior,
+            seed: Self::seed_arg().unwrap_or_else(|| thread_rng().gen()),
+            diagonal_movement: settings.diagonal_movement,
+            debug_mode: std::env::args().any(|arg| arg == "--debug"),
+            wizard_mode: std::env::args().any(|arg| arg == "--wizard"),
+            tileset_path: settings.tileset_path,
+            ascii_renderer: settings.ascii_renderer,
+            macros: KeyMacros::load(),
+            idle_pause_seconds: settings.idle_pause_seconds.max(0.0),
+            hints: HintMode::FirstTime,
+            shared_stash: true,
+            scenario: None,
+            demo: false,
+        }
+    }
+}
+
+// A custom challenge with its own way to win, picked on the main menu
+#[derive(Clone, Debug, Deserialize)]
+struct Scenario {
+    name: String,
+    description: String,
+    goal: ScenarioGoal,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+enum ScenarioGoal {
+    Survive { turns: u32 },
+    Collect { item: String, count: u32 }, // Laid out one per new floor of the main dungeon until there are enough
+    Escort { npc: String, from_floor: i32 }, // A monster kind that joins the party on that floor
+}
+
+struct ScenarioRegistry {
+    scenarios: Vec<Scenario>,
+}
+
+impl ScenarioRegistry {
+    const PATH: &'static str = "data/scenarios.ron";
+    const BUILTIN: &'static str = include_str!("../data/scenarios.ron");
+    const DIRECTORY: &'static str = "scenarios";
+
+    // The bundled scenarios, then any made by players, dropped into scenarios/ as one .ron file each
+    fn load() -> Self {
+        let contents = std::fs::read_to_string(Self::PATH).unwrap_or_else(|_| Self::BUILTIN.to_string());
+        let mut scenarios: Vec<Scenario> = ron::from_str(&contents).unwrap_or_else(|e| {
+            eprintln!("Failed to parse {}: {}, using built-in scenarios", Self::PATH, e);
+            ron::from_str(Self::BUILTIN).expect("built-in scenario definitions are valid")
+        });
+
+        let mut paths: Vec<std::path::PathBuf> = std::fs::read_dir(Self::DIRECTORY)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "ron"))
+            .collect();
+        paths.sort();
+        for path in paths {
+            let parsed = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|contents| ron::from_str(&contents).map_err(|e| e.to_string()));
+            match parsed {
+                Ok(scenario) => scenarios.push(scenario),
+                Err(error) => eprintln!("Skipping scenario {}: {}", path.display(), error),
+            }
+        }
+        Self { scenarios }
+    }
+}
+
+// Follows a scenario through the run, judging its goal again on every game event
+struct ScenarioController {
+    scenario: Scenario,
+    placed: u32, // Collect goals: how many of the items are out on floors so far
+    escort: Option<EntityId>, // Escort goals: once they have been found
+    settled: bool, // Won or lost, nothing more to judge
+}
+
+impl ScenarioGoal {
+    fn judged_on(&self, event: &GameEvent) -> bool {
+        match self {
+            ScenarioGoal::Survive { .. } => matches!(event, GameEvent::TurnEnded),
+            ScenarioGoal::Collect { .. } => matches!(event, GameEvent::ItemPickedUp | GameEvent::TurnEnded),
+            ScenarioGoal::Escort { .. } => matches!(event, GameEvent::TookStairs | GameEvent::TurnEnded),
+        }
+    }
+}
+
+impl ScenarioController {
+    fn new(scenario: Scenario) -> Self {
+        Self { scenario, placed: 0, escort: None, settled: false }
+    }
+}
+
+// Sounds of things happening out of sight. They carry along open ground,
+// so a fight behind a solid wall goes unheard even if it is close
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Noise {
+    Fight,
+    Stairs,
+    TearingWeb,
+    Mining,
+    Burrowing,
+}
+
+impl Noise {
+    const REPEAT_TURNS: u32 = 10; // The same kind of noise isn't reported again before this
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Noise::Fight => "the sounds of a fight",
+            Noise::Stairs => "footsteps on the stairs",
+            Noise::TearingWeb => "something tearing through webs",
+            Noise::Mining => "the ring of a pickaxe",
+            Noise::Burrowing => "something gnawing through rock",
+        }
+    }
+
+    // How many tiles of open ground the noise carries over
+    fn loudness(&self) -> i32 {
+        match self {
+            Noise::Fight => 16,
+            Noise::Stairs => 10,
+            Noise::TearingWeb => 6,
+            Noise::Mining => 12,
+            Noise::Burrowing => 8,
+        }
+    }
+}
+
+// The compass direction of one tile as seen from another
+fn compass_direction(from: (i32, i32), to: (i32, i32)) -> &'static str {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    // Within about 22 degrees of an axis counts as straight along it
+    let (east, south) = (dx * 5 > dy.abs() * 2, dy * 5 > dx.abs() * 2);
+    let (west, north) = (-dx * 5 > dy.abs() * 2, -dy * 5 > dx.abs() * 2);
+    match (north, south, east, west) {
+        (true, _, true, _) => "north-east",
+        (true, _, _, true) => "north-west",
+        (_, true, true, _) => "south-east",
+        (_, true, _, true) => "south-west",
+        (true, _, _, _) => "north",
+        (_, true, _, _) => "south",
+        (_, _, true, _) => "east",
+        _ => "west",
+    }
+}
+
+// Things that happened this frame, for systems that react to game logic
+// (haptics, audio) without the logic knowing about them
+#[derive(Clone, Debug, PartialEq)]
+enum GameEvent {
+    PlayerDamaged { amount: i32, max_hp: i32 },
+    LevelUp,
+    PlayerStepped,
+    PlayerAttacked { hit: bool },
+    ItemPickedUp,
+    TookStairs,
+    NoiseHeard { noise: Noise, volume: f32 }, // Volume falls off with distance, 1.0 right next to it
+    TurnEnded,
+}
+
+// Screen shake and full-screen flashes. Every visual effect goes through here so
+// photosensitive safe mode can drop the shakes and soften the flashes
+struct VisualEffects {
+    safe_mode: bool,
+    shake_strength: f32,
+    shake_remaining: f32,
+    flash_color: Color,
+    flash_duration: f32,
+    flash_remaining: f32,
+}
+
+impl VisualEffects {
+    fn new(safe_mode: bool) -> Self {
+        Self {
+            safe_mode,
+            shake_strength: 0.0,
+            shake_remaining: 0.0,
+            flash_color: BLANK,
+            flash_duration: 0.0,
+            flash_remaining: 0.0,
+        }
+    }
+
+    fn handle_event(&mut self, event: &GameEvent) {
+        match event {
+            GameEvent::PlayerDamaged { amount, max_hp } => {
+                let heavy = *amount * 5 >= *max_hp;
+                if heavy {
+                    self.shake(6.0, 0.25);
+                }
+                self.flash(RED, 0.2);
+            }
+            GameEvent::LevelUp => self.flash(GOLD, 0.4),
+            _ => {}
+        }
+    }
+
+    fn shake(&mut self, strength: f32, duration: f32) {
+        if self.safe_mode {
+            return;
+        }
+        self.shake_strength = self.shake_strength.max(strength);
+        self.shake_remaining = self.shake_remaining.max(duration);
+    }
+
+    // Flashes always fade out rather than strobe, safe mode makes them fainter and slower still
+    fn flash(&mut self, color: Color, duration: f32) {
+        let (alpha, duration) = if self.safe_mode { (0.15, duration * 3.0) } else { (0.4, duration) };
+        self.flash_color = Color::new(color.r, color.g, color.b, alpha);
+        self.flash_duration = duration;
+        self.flash_remaining = duration;
+    }
+
+    fn update(&mut self, dt: f32) {
+        self.shake_remaining = (self.shake_remaining - dt).max(0.0);
+        self.flash_remaining = (self.flash_remaining - dt).max(0.0);
+    }
+
+    fn shake_offset(&self) -> (f32, f32) {
+        if self.shake_remaining <= 0.0 {
+            return (0.0, 0.0);
+        }
+        let mut rng = thread_rng();
+        (
+            rng.gen_range(-self.shake_strength..=self.shake_strength),
+            rng.gen_range(-self.shake_strength..=self.shake_strength),
+        )
+    }
+
+    fn draw(&self) {
+        if self.flash_remaining <= 0.0 {
+            return;
+        }
+        let fade = self.flash_remaining / self.flash_duration;
+        let color = Color::new(self.flash_color.r, self.flash_color.g, self.flash_color.b, self.flash_color.a * fade);
+        draw_rectangle(0.0, 0.0, screen_width(), screen_height(), color);
+    }
+}
+
+// Turns game events into controller rumble pulses
+struct Haptics {
+    intensity: f32,
+    strength: f32,
+    remaining: f32,
+}
+
+impl Haptics {
+    fn new(intensity: f32) -> Self {
+        Self {
+            intensity,
+            strength: 0.0,
+            remaining: 0.0,
+        }
+    }
+
+    fn handle_event(&mut self, event: &GameEvent) {
+        let pulse = match event {
+            // Only hits taking a fifth of max HP or more count as heavy
+            GameEvent::PlayerDamaged { amount, max_hp } if *amount * 5 >= *max_hp => Some((1.0, 0.3)),
+            GameEvent::LevelUp => Some((0.5, 0.5)),
+            _ => None,
+        };
+
+        if let Some((strength, duration)) = pulse {
+            if self.intensity > 0.0 {
+                self.strength = self.strength.max(strength * self.intensity);
+                self.remaining = self.remaining.max(duration);
+            }
+        }
+    }
+
+    // Returns the rumble strength to apply this frame
+    fn update(&mut self, dt: f32) -> f32 {
+        self.remaining = (self.remaining - dt).max(0.0);
+        if self.remaining == 0.0 {
+            self.strength = 0.0;
+        }
+        self.strength
+    }
+}
+
+// Sound effects for game events and a music loop for each band of depths.
+// Missing sound files are skipped, so the game runs silently without them
+struct SoundManager {
+    volume: f32,
+    footstep: Option<Sound>,
+    hit: Option<Sound>,
+    miss: Option<Sound>,
+    pickup: Option<Sound>,
+    level_up: Option<Sound>,
+    stairs: Option<Sound>,
+    noises: HashMap<Noise, Option<Sound>>,
+    music: Vec<Option<Sound>>, // One track per band of MUSIC_BAND_DEPTH floors
+    playing: Option<usize>,
+}
+
+impl SoundManager {
+    const MUSIC_BAND_DEPTH: i32 = 3;
+    const MUSIC_VOLUME: f32 = 0.5; // Relative to the effects
+
+    async fn load(volume: f32) -> Self {
+        async fn sound(name: &str) -> Option<Sound> {
+            load_sound(&format!("data/sounds/{}.wav", name)).await.ok()
+        }
+
+        let mut noises = HashMap::new();
+        for (noise, name) in [(Noise::Fight, "distant_fight"), (Noise::Stairs, "distant_stairs"), (Noise::TearingWeb, "distant_web")] {
+            noises.insert(noise, sound(name).await);
+        }
+        let mut music = Vec::new();
+        for band in 0..=FINAL_FLOOR / Self::MUSIC_BAND_DEPTH {
+            music.push(load_sound(&format!("data/music/depth_{}.ogg", band)).await.ok());
+        }
+        Self {
+            volume,
+            footstep: sound("footstep").await,
+            hit: sound("hit").await,
+            miss: sound("miss").await,
+            pickup: sound("pickup").await,
+            level_up: sound("level_up").await,
+            stairs: sound("stairs").await,
+            noises,
+            music,
+            playing: None,
+        }
+    }
+
+    fn handle_event(&self, event: &GameEvent) {
+        let sound = match event {
+            GameEvent::PlayerStepped => self.footstep,
+            GameEvent::PlayerAttacked { hit: true } => self.hit,
+            GameEvent::PlayerAttacked { hit: false } => self.miss,
+            GameEvent::ItemPickedUp => self.pickup,
+            GameEvent::LevelUp => self.level_up,
+            GameEvent::TookStairs => self.stairs,
+            GameEvent::PlayerDamaged { .. } => self.hit,
+            GameEvent::TurnEnded => None,
+            GameEvent::NoiseHeard { noise, volume } => {
+                // macroquad's audio has no panning, so distance only shows in the volume
+                if let Some(Some(sound)) = self.noises.get(noise).filter(|_| self.volume > 0.0) {
+                    play_sound(*sound, PlaySoundParams { looped: false, volume: self.volume * volume });
+                }
+                return;
+            }
+        };
+        if let Some(sound) = sound.filter(|_| self.volume > 0.0) {
+            play_sound(sound, PlaySoundParams { looped: false, volume: self.volume });
+        }
+    }
+
+    // Switch tracks when the player crosses into another band of depths
+    fn update_music(&mut self, depth: i32) {
+        let band = (depth / Self::MUSIC_BAND_DEPTH) as usize;
+        if self.playing == Some(band) || self.volume <= 0.0 {
+            return;
+        }
+        if let Some(Some(track)) = self.playing.and_then(|playing| self.music.get(playing)) {
+            stop_sound(*track);
+        }
+        if let Some(Some(track)) = self.music.get(band) {
+            play_sound(*track, PlaySoundParams { looped: true, volume: self.volume * Self::MUSIC_VOLUME });
+        }
+        self.playing = Some(band);
+    }
+
+    // Leaving a run for the title screen leaves it in silence
+    fn stop_music(&mut self) {
+        if let Some(Some(track)) = self.playing.take().and_then(|playing| self.music.get(playing)) {
+            stop_sound(*track);
+        }
+    }
+}
+
+// Draws the play view: the map, everything on it, the stat bar and the combat log. Menus and the
+// screens around a run are still drawn straight to the window
+trait Renderer {
+    fn draw_play_view(&mut self, game_state: &GameState, now: f32);
+}
+
+// The default frontend, glyphs in a macroquad window
+struct MacroquadRenderer {
+    glyphs: GlyphRenderer,
+    camera: Camera,
+    tile_size: f32,
+    zoom: f32, // One of ZOOM_LEVELS, scaling the tile size that fits the window
+    free_look: Option<(f32, f32)>, // Where the camera looks while detached from the player
+}
+
+impl MacroquadRenderer {
+    const ZOOM_LEVELS: [f32; 5] = [0.5, 0.75, 1.0, 1.5, 2.0];
+    const PAN_SPEED: f32 = 15.0; // Tiles a second while an arrow key is held
+
+    // Tiles grow and shrink with the zoom, and the viewport takes in however many fit the window now
+    fn fit_to_window(&mut self, map_width: usize, map_height: usize) {
+        self.tile_size = calculate_tile_size(map_width, map_height, screen_width(), screen_height()) * self.zoom;
+        self.camera.viewport_width = (screen_width() / self.tile_size).floor() as usize;
+        self.camera.viewport_height = ((screen_height() - TOP_BAR_HEIGHT - BOTTOM_BAR_HEIGHT) / self.tile_size).floor() as usize;
+    }
+
+    fn step_zoom(&mut self, steps: i32) {
+        let current = Self::ZOOM_LEVELS.iter().position(|zoom| *zoom == self.zoom).unwrap_or(2) as i32;
+        let next = (current + steps).clamp(0, Self::ZOOM_LEVELS.len() as i32 - 1);
+        self.zoom = Self::ZOOM_LEVELS[next as usize];
+    }
+
+    // [=]/[-] or the mouse wheel zoom, [C] lets go of the player to scout with the arrow keys,
+    // and any movement key brings the camera back to them. None of it takes a turn
+    fn handle_camera_input(&mut self, game_state: &GameState, dt: f32) {
+        let wheel = mouse_wheel().1;
+        if is_key_pressed(KeyCode::Equal) || wheel > 0.0 {
+            self.step_zoom(1);
+        }
+        if is_key_pressed(KeyCode::Minus) || wheel < 0.0 {
+            self.step_zoom(-1);
+        }
+
+        if is_key_pressed(KeyCode::C) {
+            self.free_look = match self.free_look {
+                Some(_) => None,
+                None => Some((game_state.player.x, game_state.player.y)),
+            };
+        }
+        if MOVEMENT_KEYS.iter().any(|(key, _)| is_key_down(*key)) {
+            self.free_look = None;
+        }
+        let Some((x, y)) = self.free_look.as_mut() else {
+            return;
+        };
+        let arrows = [(KeyCode::Left, (-1.0, 0.0)), (KeyCode::Right, (1.0, 0.0)), (KeyCode::Up, (0.0, -1.0)), (KeyCode::Down, (0.0, 1.0))];
+        for (key, (dx, dy)) in arrows {
+            if is_key_down(key) {
+                *x += dx * Self::PAN_SPEED * dt;
+                *y += dy * Self::PAN_SPEED * dt;
+            }
+        }
+        let map = game_state.map_manager.current_map();
+        *x = x.clamp(0.0, map.width as f32 - 1.0);
+        *y = y.clamp(0.0, map.height as f32 - 1.0);
+    }
+}
+
+impl Renderer for MacroquadRenderer {
+    fn draw_play_view(&mut self, game_state: &GameState, now: f32) {
+        let (camera, glyphs, tile_size) = (&mut self.camera, &self.glyphs, self.tile_size);
+
+        // Follow the player, sliding along with them, unless the camera has been let go to look around
+        let (player_x, player_y) = game_state.player.draw_position(now);
+        let (look_x, look_y) = self.free_look.unwrap_or((player_x, player_y));
+        camera.follow(
+            look_x,
+            look_y,
+            game_state.map_manager.current_map().width,
+            game_state.map_manager.current_map().height
+        );
 
-                    if let Some((new_x, new_y)) = self.map_manager.change_level(next_level) {
-                        self.player.x = new_x;
-                        self.player.y = new_y;
+        // Clear screen
+        clear_background(BLACK);
 
-                        if is_new_level {
-                            self.initialize_current_level();
-                        } else {
-                            self.load_level_state(next_level as usize);
+        // Draw the current map
+        game_state.map_manager.current_map().draw(
+            camera,
+            glyphs,
+            tile_size,
+            (game_state.player.x, game_state.player.y),
+            game_state.light_radius(),
+        );
+
+        // Shade what the monsters in sight are watching, so a sneaking player can keep out of it
+        for (x, y) in game_state.watched_tiles() {
+            let (x, y) = (x as f32, y as f32);
+            if camera.is_visible(x, y) {
+                let (screen_x, screen_y) = camera.world_to_screen(x, y, tile_size);
+                draw_rectangle(screen_x, screen_y, tile_size, tile_size, Color::new(1.0, 0.2, 0.1, 0.2));
+            }
+        }
+
+        // Draw monsters
+        for monster in &game_state.monsters {
+            if monster.is_alive() && camera.is_visible(monster.x, monster.y) && game_state.is_lit(monster.x, monster.y) {
+                let (x, y) = monster.draw_position(now);
+                let (screen_x, screen_y) = camera.world_to_screen(x, y, tile_size);
+                glyphs.draw(monster.symbol, screen_x, screen_y, tile_size, monster.color);
+
+                // A thin health bar along the top of the tile, once it has been hurt
+                if monster.stats.hp < monster.stats.max_hp {
+                    let health = monster.stats.hp.max(0) as f32 / monster.stats.max_hp.max(1) as f32;
+                    let color = if health > 0.6 { GREEN } else if health > 0.25 { YELLOW } else { RED };
+                    draw_rectangle(screen_x + 1.0, screen_y - 4.0, tile_size - 2.0, 3.0, Color::new(0.2, 0.0, 0.0, 0.8));
+                    draw_rectangle(screen_x + 1.0, screen_y - 4.0, (tile_size - 2.0) * health, 3.0, color);
+                }
+            }
+        }
+
+        // Preview where an aimed tool would take the player, or a throw would fly
+        if game_state.targeting.is_some() {
+            let (mouse_x, mouse_y) = mouse_position();
+            let target = camera.screen_to_world(mouse_x, mouse_y, tile_size);
+            let (tiles, color) = match game_state.aim_line(target) {
+                Some(line) => (line, GREEN),
+                None => (vec![target], RED),
+            };
+            for (x, y) in tiles {
+                let (screen_x, screen_y) = camera.world_to_screen(x as f32, y as f32, tile_size);
+                draw_rectangle_lines(screen_x, screen_y, tile_size, tile_size, 2.0, color);
+            }
+        }
+
+        // Draw the shopkeeper
+        if let Some(shop) = &game_state.map_manager.current_map().shop {
+            let (x, y) = (shop.keeper.0 as f32, shop.keeper.1 as f32);
+            if camera.is_visible(x, y) && game_state.is_lit(x, y) {
+                let (screen_x, screen_y) = camera.world_to_screen(x, y, tile_size);
+                glyphs.draw('@', screen_x, screen_y, tile_size, LIME);
+            }
+        }
+
+        // Draw the bounty board
+        if let Some((x, y)) = game_state.map_manager.current_map().bounty_board {
+            let (x, y) = (x as f32, y as f32);
+            if camera.is_visible(x, y) && game_state.is_lit(x, y) {
+                let (screen_x, screen_y) = camera.world_to_screen(x, y, tile_size);
+                glyphs.draw('&', screen_x, screen_y, tile_size, GOLD);
+            }
+        }
+
+        // Draw the healer and the stash chest in the town
+        let map = game_state.map_manager.current_map();
+        let townsfolk = map.healer.map(|spot| (spot, '@', PINK)).into_iter().chain(map.stash.as_ref().map(|stash| (stash.position, ']', GOLD)));
+        for ((x, y), glyph, color) in townsfolk {
+            let (x, y) = (x as f32, y as f32);
+            if camera.is_visible(x, y) && game_state.is_lit(x, y) {
+                let (screen_x, screen_y) = camera.world_to_screen(x, y, tile_size);
+                glyphs.draw(glyph, screen_x, screen_y, tile_size, color);
+            }
+        }
+
+        // Draw the forge
+        if let Some((x, y)) = game_state.map_manager.current_map().forge {
+            let (x, y) = (x as f32, y as f32);
+            if camera.is_visible(x, y) && game_state.is_lit(x, y) {
+                let (screen_x, screen_y) = camera.world_to_screen(x, y, tile_size);
+                glyphs.draw('^', screen_x, screen_y, tile_size, ORANGE);
+            }
+        }
+
+        // Draw the shrine, burning while its trial runs
+        if let Some(shrine) = &game_state.map_manager.current_map().shrine {
+            let (x, y) = (shrine.position.0 as f32, shrine.position.1 as f32);
+            if camera.is_visible(x, y) && game_state.is_lit(x, y) {
+                let (screen_x, screen_y) = camera.world_to_screen(x, y, tile_size);
+                let color = if shrine.trial.is_some() { ORANGE } else if shrine.used { GRAY } else { GOLD };
+                glyphs.draw('_', screen_x, screen_y, tile_size, color);
+            }
+        }
+
+        // Draw allies
+        for ally in &game_state.allies {
+            if camera.is_visible(ally.entity.x, ally.entity.y) {
+                let (x, y) = ally.entity.draw_position(now);
+                let (screen_x, screen_y) = camera.world_to_screen(x, y, tile_size);
+                glyphs.draw(ally.entity.symbol, screen_x, screen_y, tile_size, ally.entity.color);
+            }
+        }
+
+        // Draw shots in flight, fading out
+        for projectile in &game_state.projectiles {
+            let (from_x, from_y) = camera.world_to_screen(projectile.from.0, projectile.from.1, tile_size);
+            let (to_x, to_y) = camera.world_to_screen(projectile.to.0, projectile.to.1, tile_size);
+            let mut color = projectile.color;
+            color.a = projectile.time_left / ProjectileTrace::DURATION;
+            let half = tile_size / 2.0;
+            draw_line(from_x + half, from_y + half, to_x + half, to_y + half, 2.0, color);
+        }
+
+        // Draw the marks of blows just landed, a flourish throws sparks out to the corners
+        for mark in &game_state.strike_marks {
+            let mut color = mark.color;
+            color.a = mark.time_left / StrikeMark::DURATION;
+            let (screen_x, screen_y) = camera.world_to_screen(mark.position.0, mark.position.1, tile_size);
+            glyphs.draw(mark.glyph, screen_x, screen_y, tile_size, color);
+            if mark.flourish {
+                let spread = (1.0 - mark.time_left / StrikeMark::DURATION) * tile_size * 0.6;
+                for (dx, dy) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+                    let spark_x = screen_x + tile_size * 0.25 + dx * spread;
+                    let spark_y = screen_y + tile_size * 0.25 + dy * spread;
+                    glyphs.draw('+', spark_x, spark_y, tile_size * 0.5, color);
+                }
+            }
+        }
+
+        // Draw damage numbers floating up off whatever was hit
+        for popup in &game_state.damage_popups {
+            let progress = 1.0 - popup.time_left / DamagePopup::DURATION;
+            let mut color = popup.color;
+            color.a = popup.time_left / DamagePopup::DURATION;
+            let (screen_x, screen_y) = camera.world_to_screen(popup.position.0, popup.position.1 - progress * DamagePopup::RISE, tile_size);
+            draw_text(&popup.amount.to_string(), screen_x + tile_size * 0.3, screen_y, tile_size * 0.8, color);
+        }
+
+        // Draw items on ground, corpses first so anything lying on them shows on top
+        let (corpses, items): (Vec<_>, Vec<_>) = game_state.ground_items
+            .iter()
+            .partition(|(_, _, item)| matches!(item.item_type, ItemType::Corpse(_, _)));
+        for (x, y, item) in corpses.into_iter().chain(items) {
+            if camera.is_visible(*x, *y) && game_state.is_lit(*x, *y) {
+                let (screen_x, screen_y) = camera.world_to_screen(*x, *y, tile_size);
+                glyphs.draw(item.symbol, screen_x, screen_y, tile_size, game_state.identification.color(item));
+            }
+        }
+
+        // Draw the player
+        if camera.is_visible(game_state.player.x, game_state.player.y) {
+            let (screen_x, screen_y) = camera.world_to_screen(player_x, player_y, tile_size);
+            glyphs.draw(game_state.player.symbol, screen_x, screen_y, tile_size, game_state.player.color);
+        }
+
+        // Constants for UI text
+        const LOG_PADDING: f32 = 10.0;
+        const TEXT_SIZE: f32 = 15.0;
+
+        // Draw top stats bar background
+        draw_rectangle(
+            0.0,
+            0.0,
+            screen_width(),
+            TOP_BAR_HEIGHT,
+            Color::new(0.0, 0.0, 0.0, 0.8)
+        );
+
+        // Draw top stats bar content
+        let hp_text = format!("HP: {}/{}", game_state.player.stats.hp, game_state.player.stats.max_hp);
+        let mana_text = format!("MP: {}/{}", game_state.player.stats.mana, game_state.player.stats.max_mana);
+        let stats_text = format!("ATK: {} DEF: {}",
+                                 game_state.player.get_total_attack(),
+                                 game_state.player.get_total_defense()
+        );
+        let floor_text = format!("Floor: {}", game_state.map_manager.floor_label());
+        let gold_text = format!("Gold: {}", game_state.player.inventory.as_ref().map_or(0, |inv| inv.gold));
+        let xp_text = format!("Level: {} XP: {}/{}",
+                              game_state.player.stats.level_system.as_ref().map_or(1, |ls| ls.level),
+                              game_state.player.stats.level_system.as_ref().map_or(0, |ls| ls.current_xp),
+                              game_state.player.stats.level_system.as_ref().map_or(100, |ls| ls.xp_to_next_level)
+        );
+
+        // Position text with proper spacing
+        draw_text(&hp_text, 10.0, TOP_BAR_HEIGHT/2.0 + TEXT_SIZE/2.0, TEXT_SIZE, GREEN);
+        draw_text(&mana_text, 110.0, TOP_BAR_HEIGHT/2.0 + TEXT_SIZE/2.0, TEXT_SIZE, SKYBLUE);
+        // What is ailing or helping the player, with the turns it has left
+        for (i, effect) in game_state.player.status_effects.iter().enumerate() {
+            let label = format!("{} {}", effect.kind.abbreviation(), effect.turns_left);
+            draw_text(&label, 10.0 + i as f32 * 50.0, TOP_BAR_HEIGHT - 4.0, 13.0, effect.kind.color());
+        }
+        draw_text(&stats_text, screen_width()/4.0, TOP_BAR_HEIGHT/2.0 + TEXT_SIZE/2.0, TEXT_SIZE, GREEN);
+        let skill_text = format!("{}: {}", game_state.player.weapon_kind().name(), game_state.player.weapon_rank().name());
+        draw_text(&skill_text, screen_width()/4.0, TOP_BAR_HEIGHT - 4.0, 13.0, LIGHTGRAY);
+        draw_text(&floor_text, screen_width()/2.0, TOP_BAR_HEIGHT/2.0 + TEXT_SIZE/2.0, TEXT_SIZE, YELLOW);
+        if let Some(clock) = game_state.town_clock() {
+            draw_text(&clock, screen_width()/2.0, TOP_BAR_HEIGHT - 4.0, 13.0, if game_state.is_daytime() { YELLOW } else { SKYBLUE });
+        }
+        draw_text(&gold_text, screen_width()/2.0 + 80.0, TOP_BAR_HEIGHT/2.0 + TEXT_SIZE/2.0, TEXT_SIZE, GOLD);
+        let seed_text = format!("Seed: {}", game_state.map_manager.config.seed);
+        draw_text(&seed_text, screen_width()/2.0 + 80.0, TOP_BAR_HEIGHT - 4.0, 13.0, GRAY);
+        draw_text(&xp_text, 2.0*screen_width()/3.0, TOP_BAR_HEIGHT/2.0 + TEXT_SIZE/2.0, TEXT_SIZE, GREEN);
+
+        if let Some((slot, actions)) = game_state.recording.as_ref() {
+            let recording_text = format!("REC F{} {}/{}", slot + 1, actions.len(), KeyMacros::MAX_ACTIONS);
+            draw_text(&recording_text, 10.0, TOP_BAR_HEIGHT + 70.0, TEXT_SIZE, RED);
+        }
+
+        if game_state.sneaking {
+            draw_text("Sneaking", 10.0, TOP_BAR_HEIGHT + 22.0, TEXT_SIZE, SKYBLUE);
+        }
+
+        let (light_text, light_color) = match game_state.light_fuel {
+            0 => ("Light: out".to_string(), RED),
+            fuel if fuel <= GameState::GUTTERING_TURNS => (format!("Light: {}", fuel), ORANGE),
+            fuel => (format!("Light: {}", fuel), YELLOW),
+        };
+        draw_text(&light_text, 10.0, TOP_BAR_HEIGHT + 94.0, TEXT_SIZE, light_color);
+
+        if let Some(trial) = game_state.map_manager.current_map().shrine.as_ref().and_then(|s| s.trial.as_ref()) {
+            let trial_text = format!("Trial: {} turns left", trial.deadline.saturating_sub(game_state.turn));
+            draw_text(&trial_text, 10.0, TOP_BAR_HEIGHT + 46.0, TEXT_SIZE, ORANGE);
+        }
+
+        game_state.draw_initiative_strip(now);
+
+        if self.free_look.is_some() {
+            let text = "Free look: [Arrows] Pan  [C] or move to return";
+            let width = measure_text(text, None, TEXT_SIZE as u16, 1.0).width;
+            draw_text(text, (screen_width() - width) / 2.0, TOP_BAR_HEIGHT + 20.0, TEXT_SIZE, SKYBLUE);
+        }
+
+        // Draw bottom combat log background
+        draw_rectangle(
+            0.0,
+            screen_height() - BOTTOM_BAR_HEIGHT,
+            screen_width(),
+            BOTTOM_BAR_HEIGHT,
+            Color::new(0.0, 0.0, 0.0, 0.8)
+        );
+
+        // Draw combat log title
+        draw_text(
+            "Combat Log",
+            LOG_PADDING,
+            screen_height() - BOTTOM_BAR_HEIGHT + 20.0,
+            TEXT_SIZE,
+            GRAY,
+        );
+
+        // Draw combat log messages
+        for (i, message) in game_state.combat_log.iter().enumerate() {
+            draw_text(
+                &message.display(),
+                LOG_PADDING,
+                screen_height() - BOTTOM_BAR_HEIGHT + 40.0 + (i as f32 * 20.0),
+                TEXT_SIZE,
+                message.category.color(),
+            );
+        }
+    }
+}
+
+// Plays a run in the terminal instead of a window, so it works over SSH. Built with
+// `--features terminal` and started with `--terminal`. Shops, the forge and the other menus
+// still need the window, walking into one only says so
+#[cfg(feature = "terminal")]
+mod terminal {
+    use super::*;
+    use crossterm::event::{self, Event, KeyCode as Key, KeyEventKind};
+    use ratatui::style::{Color as Rgb, Style};
+    use ratatui::DefaultTerminal;
+
+    const FRAME: Duration = Duration::from_millis(33);
+    const LOG_LINES: u16 = 5;
+
+    enum Command {
+        Act(MacroAction),
+        Stairs { down: bool },
+        Eat,
+        Sneak,
+        Search,
+        UseFeature,
+        Quit,
+    }
+
+    // Arrows, vi-keys and the number row laid out like a numpad, the same moves as the window
+    fn command(key: Key) -> Option<Command> {
+        let step = |dx, dy| Some(Command::Act(MacroAction::Step(dx, dy)));
+        match key {
+            Key::Up | Key::Char('k') | Key::Char('8') => step(0, -1),
+            Key::Down | Key::Char('j') | Key::Char('2') => step(0, 1),
+            Key::Left | Key::Char('h') | Key::Char('4') => step(-1, 0),
+            Key::Right | Key::Char('l') | Key::Char('6') => step(1, 0),
+            Key::Char('y') | Key::Char('7') => step(-1, -1),
+            Key::Char('u') | Key::Char('9') => step(1, -1),
+            Key::Char('b') | Key::Char('1') => step(-1, 1),
+            Key::Char('n') | Key::Char('3') => step(1, 1),
+            Key::Char(' ') | Key::Char('5') => Some(Command::Act(MacroAction::Rest)),
+            Key::Char('>') => Some(Command::Stairs { down: true }),
+            Key::Char('<') => Some(Command::Stairs { down: false }),
+            Key::Char('e') => Some(Command::Eat),
+            Key::Char('v') => Some(Command::Sneak),
+            Key::Char('s') => Some(Command::Search),
+            Key::Char('f') => Some(Command::UseFeature),
+            Key::Char('q') | Key::Esc => Some(Command::Quit),
+            _ => None,
+        }
+    }
+
+    fn rgb(color: Color) -> Rgb {
+        Rgb::Rgb((color.r * 255.0) as u8, (color.g * 255.0) as u8, (color.b * 255.0) as u8)
+    }
+
+    // A grid of characters, one per tile, with the stat line above and the combat log below
+    struct TerminalRenderer {
+        terminal: DefaultTerminal,
+        camera: Camera,
+    }
+
+    impl Renderer for TerminalRenderer {
+        fn draw_play_view(&mut self, game_state: &GameState, _now: f32) {
+            let camera = &mut self.camera;
+            // A frame that fails to draw is simply drawn again on the next one
+            let _ = self.terminal.draw(|frame| {
+                let area = frame.area();
+                let map_rows = area.height.saturating_sub(1 + LOG_LINES);
+                let map = game_state.map_manager.current_map();
+                camera.viewport_width = area.width as usize;
+                camera.viewport_height = map_rows as usize;
+                camera.follow(game_state.player.x, game_state.player.y, map.width, map.height);
+                let (left, top) = (camera.x as i32, camera.y as i32);
+                let buffer = frame.buffer_mut();
+
+                let light = (game_state.player.x, game_state.player.y);
+                let watched = game_state.watched_tiles();
+                for row in 0..map_rows {
+                    for column in 0..area.width {
+                        let (x, y) = ((left + column as i32) as usize, (top + row as i32) as usize);
+                        if x < map.width && y < map.height {
+                            let (symbol, color) = map.glyph_at(x, y, light, game_state.light_radius());
+                            let mut style = Style::new().fg(rgb(color));
+                            if watched.contains(&(x as i32, y as i32)) {
+                                style = style.bg(Rgb::Rgb(80, 16, 8));
+                            }
+                            buffer.set_string(column, row + 1, symbol.to_string(), style);
                         }
+                    }
+                }
+
+                // Later glyphs cover earlier ones on the same tile
+                let lit = |x: f32, y: f32| game_state.is_lit(x, y);
+                let mut glyphs: Vec<(f32, f32, char, Color)> = Vec::new();
+                let (corpses, items): (Vec<_>, Vec<_>) = game_state.ground_items
+                    .iter()
+                    .partition(|(_, _, item)| matches!(item.item_type, ItemType::Corpse(_, _)));
+                for (x, y, item) in corpses.into_iter().chain(items) {
+                    glyphs.push((*x, *y, item.symbol, game_state.identification.color(item)));
+                }
+                if let Some(shop) = &map.shop {
+                    glyphs.push((shop.keeper.0 as f32, shop.keeper.1 as f32, '@', LIME));
+                }
+                if let Some((x, y)) = map.bounty_board {
+                    glyphs.push((x as f32, y as f32, '&', GOLD));
+                }
+                if let Some((x, y)) = map.forge {
+                    glyphs.push((x as f32, y as f32, '^', ORANGE));
+                }
+                if let Some((x, y)) = map.healer {
+                    glyphs.push((x as f32, y as f32, '@', PINK));
+                }
+                if let Some(stash) = &map.stash {
+                    glyphs.push((stash.position.0 as f32, stash.position.1 as f32, ']', GOLD));
+                }
+                if let Some(shrine) = &map.shrine {
+                    let color = if shrine.trial.is_some() { ORANGE } else if shrine.used { GRAY } else { GOLD };
+                    glyphs.push((shrine.position.0 as f32, shrine.position.1 as f32, '_', color));
+                }
+                glyphs.retain(|&(x, y, _, _)| lit(x, y));
+                for monster in game_state.monsters.iter().filter(|m| m.is_alive() && lit(m.x, m.y)) {
+                    glyphs.push((monster.x, monster.y, monster.symbol, monster.color));
+                }
+                for ally in &game_state.allies {
+                    glyphs.push((ally.entity.x, ally.entity.y, ally.entity.symbol, ally.entity.color));
+                }
+                glyphs.push((game_state.player.x, game_state.player.y, game_state.player.symbol, game_state.player.color));
 
-                        self.add_log_message(format!("Descended to level {}", next_level + 1));
+                for (x, y, symbol, color) in glyphs {
+                    let (column, row) = (x as i32 - left, y as i32 - top);
+                    if (0..area.width as i32).contains(&column) && (0..map_rows as i32).contains(&row) {
+                        buffer.set_string(column as u16, row as u16 + 1, symbol.to_string(), Style::new().fg(rgb(color)));
                     }
                 }
-            },
-            Tile::StairsUp => {
-                if is_key_pressed(KeyCode::Comma) {
-                    self.save_current_level_state();
-                    let prev_level = current_level - 1;
-                    if let Some((new_x, new_y)) = self.map_manager.change_level(prev_level) {
-                        self.player.x = new_x;
-                        self.player.y = new_y;
-                        self.load_level_state(prev_level as usize);
-                        self.add_log_message(format!("Ascended to level {}", prev_level + 1));
+
+                let player = &game_state.player;
+                let mut stats = format!(
+                    "HP: {}/{}  MP: {}/{}  ATK: {} DEF: {}  Floor: {}  Gold: {}  Level: {}",
+                    player.stats.hp,
+                    player.stats.max_hp,
+                    player.stats.mana,
+                    player.stats.max_mana,
+                    player.get_total_attack(),
+                    player.get_total_defense(),
+                    game_state.map_manager.floor_label(),
+                    player.inventory.as_ref().map_or(0, |inv| inv.gold),
+                    game_state.player_level(),
+                );
+                if let Some(clock) = game_state.town_clock() {
+                    let _ = write!(stats, "  {}", clock);
+                }
+                for effect in &player.status_effects {
+                    let _ = write!(stats, "  {} {}", effect.kind.abbreviation(), effect.turns_left);
+                }
+                if game_state.sneaking {
+                    stats.push_str("  Sneaking");
+                }
+                match game_state.light_fuel {
+                    0 => stats.push_str("  Light: out"),
+                    fuel => {
+                        let _ = write!(stats, "  Light: {}", fuel);
                     }
                 }
-            },
-            _ => {}
+                buffer.set_string(0, 0, stats, Style::new().fg(rgb(GREEN)));
+
+                let log_top = area.height.saturating_sub(LOG_LINES);
+                for (i, message) in game_state.combat_log.iter().enumerate() {
+                    buffer.set_string(0, log_top + i as u16, message.display(), Style::new().fg(rgb(message.category.color())));
+                }
+            });
         }
     }
 
-    fn spawn_entities(&mut self, map: &Map) {
-        // Spawn player in first room of first row
-        if let Some(first_row) = map.rooms.first() {
-            if let Some(first_room) = first_row.first() {
-                let (center_x, center_y) = first_room.center();
-                self.player.x = center_x as f32;
-                self.player.y = center_y as f32;
-            } else {
-                // Fallback: scan for walkable tile
-                for y in 0..map.height {
-                    for x in 0..map.width {
-                        if map.is_walkable(x as i32, y as i32) {
-                            self.player.x = x as f32;
-                            self.player.y = y as f32;
-                            break;
+    // The menus are drawn by the window frontend only, so anything that opened one is turned away
+    fn close_menus(game_state: &mut GameState) {
+        if game_state.overlay_open() {
+            game_state.inventory_open = false;
+            game_state.encyclopedia_open = false;
+            game_state.feedback_open = false;
+            game_state.party_open = false;
+            game_state.shop_open = false;
+            game_state.board_open = false;
+            game_state.forge_open = false;
+            game_state.stash_open = false;
+            game_state.log_open = false;
+            game_state.spells_open = false;
+            game_state.parley = None;
+            game_state.look_cursor = None;
+            game_state.spawn_preview = None;
+            game_state.add_log_message(MessageCategory::Warning, "That needs the window, start the game without --terminal to use it.".to_string());
+        }
+    }
+
+    pub fn run() -> std::io::Result<()> {
+        let mut game_state = GameState::new(GameConfig::default(), Vec::new(), &HashMap::new());
+        let mut renderer = TerminalRenderer { terminal: ratatui::try_init()?, camera: Camera::new(0, 0) };
+        let result = play(&mut game_state, &mut renderer);
+        ratatui::restore();
+
+        // The last words of the run stay on screen once the terminal is handed back
+        for message in &game_state.combat_log {
+            println!("{}", message.display());
+        }
+        result
+    }
+
+    fn play(game_state: &mut GameState, renderer: &mut TerminalRenderer) -> std::io::Result<()> {
+        let started = Instant::now();
+        let mut pending = None;
+        loop {
+            let current_time = started.elapsed().as_secs_f32();
+            game_state.clock = current_time;
+
+            if event::poll(FRAME)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        pending = command(key.code).or(pending);
+                    }
+                }
+            }
+
+            // A key pressed before the player is ready waits for them, like a held key in the window
+            if matches!(pending, Some(Command::Quit)) {
+                return Ok(());
+            }
+            if game_state.player_ready(current_time) {
+                let diagonals = game_state.map_manager.current_map().diagonal_movement;
+                match pending.take() {
+                    Some(Command::Act(action)) => {
+                        let (dx, dy) = action.offset();
+                        if diagonals || dx == 0 || dy == 0 {
+                            game_state.player_step(game_state.player.x + dx as f32, game_state.player.y + dy as f32, false);
                         }
                     }
+                    Some(Command::Stairs { down }) => game_state.use_stairs(down),
+                    Some(Command::Eat) => game_state.eat_corpse(),
+                    Some(Command::Sneak) => game_state.toggle_sneak(),
+                    Some(Command::Search) => game_state.search(),
+                    Some(Command::UseFeature) => game_state.use_feature(),
+                    Some(Command::Quit) | None => {}
                 }
+                close_menus(game_state);
+            }
+
+            game_state.advance_world(current_time);
+            // Budget warnings would scribble over the screen and nothing plays sounds here,
+            // but a scenario still has to hear what happened
+            std::mem::take(&mut game_state.turn_profile);
+            for event in game_state.drain_events() {
+                game_state.handle_scenario_event(&event);
             }
+
+            if !game_state.player.is_alive() || game_state.victorious {
+                game_state.finish_run();
+                return Ok(());
+            }
+            renderer.draw_play_view(game_state, current_time);
+        }
+    }
+}
+
+fn window_conf() -> Conf {
+    Conf {
+        window_title: "Roguelike".to_string(),
+        window_width: 1280,
+        window_height: 720,
+        window_resizable: true,
+        ..Default::default()
+    }
+}
+
+// Ends the frame on every screen, so [F11] switches fullscreen wherever the player is.
+// The play view fits itself to the new window size on the next frame
+async fn present_frame() {
+    static FULLSCREEN: AtomicBool = AtomicBool::new(false);
+    if is_key_pressed(KeyCode::F11) {
+        let was_fullscreen = FULLSCREEN.fetch_xor(true, Ordering::Relaxed);
+        set_fullscreen(!was_fullscreen);
+    }
+    next_frame().await;
+}
+
+// Plays the game by itself from the main menu, showing each decision on screen
+struct DemoBot {
+    turn_delay: f32,
+    last_step: f32,
+    decision: String,
+    died_at: Option<f32>,
+}
+
+impl DemoBot {
+    const MIN_TURN_DELAY: f32 = 0.05;
+    const MAX_TURN_DELAY: f32 = 1.0;
+    const RESTART_DELAY: f32 = 4.0; // Seconds to linger on a death before returning to the menu
+
+    fn new(turn_delay: f32) -> Self {
+        Self {
+            turn_delay,
+            last_step: 0.0,
+            decision: "Looking around".to_string(),
+            died_at: None,
+        }
+    }
+
+    fn handle_speed_keys(&mut self) {
+        if is_key_pressed(KeyCode::Equal) {
+            self.turn_delay = (self.turn_delay * 0.5).max(Self::MIN_TURN_DELAY);
+        }
+        if is_key_pressed(KeyCode::Minus) {
+            self.turn_delay = (self.turn_delay * 2.0).min(Self::MAX_TURN_DELAY);
+        }
+    }
+
+    // Esc leaves the demo, and so does a death once it has been on screen for a moment
+    fn finished(&mut self, current_time: f32, player_alive: bool) -> bool {
+        if is_key_pressed(KeyCode::Escape) {
+            return true;
+        }
+        if player_alive {
+            return false;
         }
+        if self.died_at.is_none() {
+            self.decision = "Died, returning to the menu".to_string();
+        }
+        let died_at = *self.died_at.get_or_insert(current_time);
+        current_time - died_at > Self::RESTART_DELAY
+    }
+
+    // The tile to step onto this turn; actions taken in place return None
+    fn next_step(&mut self, game_state: &mut GameState, current_time: f32) -> Option<(f32, f32)> {
+        if current_time - self.last_step < self.turn_delay {
+            return None;
+        }
+        self.last_step = current_time;
+        let player = (game_state.player.x as i32, game_state.player.y as i32);
+
+        if game_state.player.stats.hp * 3 < game_state.player.stats.max_hp {
+            let potion = game_state.player.inventory.as_ref().and_then(|inventory| {
+                inventory.items.iter().position(|item| matches!(item.item_type, ItemType::Potion(_)))
+            });
+            if let Some(index) = potion {
+                self.decision = "Badly hurt, drinking a potion".to_string();
+                game_state.use_inventory_item(index);
+                return None;
+            }
+        }
+
+        if game_state.light_fuel <= GameState::GUTTERING_TURNS {
+            let fuel = game_state.player.inventory.as_ref().and_then(|inventory| {
+                inventory.items.iter().position(|item| matches!(item.item_type, ItemType::Fuel(_)))
+            });
+            if let Some(index) = fuel {
+                self.decision = "Light running low, refueling".to_string();
+                game_state.use_inventory_item(index);
+                return None;
+            }
+        }
+
+        let closest_monster = game_state.monsters
+            .iter()
+            .filter(|m| m.is_alive() && game_state.is_lit(m.x, m.y))
+            .map(|m| (m.name.clone(), (m.x as i32, m.y as i32)))
+            .min_by_key(|(_, pos)| manhattan_distance(player, *pos));
+        if let Some((name, pos)) = closest_monster {
+            if manhattan_distance(player, pos) == 1 {
+                self.decision = format!("Attacking the {}", name);
+                return Some((pos.0 as f32, pos.1 as f32));
+            }
+            if let Some(step) = Self::step_towards(game_state, player, pos) {
+                self.decision = format!("Hunting the {}", name);
+                return Some(step);
+            }
+        }
+
+        let closest_item = game_state.ground_items
+            .iter()
+            .filter(|(x, y, item)| game_state.is_lit(*x, *y) && !matches!(item.item_type, ItemType::Corpse(_, _)))
+            .map(|(x, y, item)| (item.name.clone(), (*x as i32, *y as i32)))
+            .min_by_key(|(_, pos)| manhattan_distance(player, *pos));
+        if let Some((name, pos)) = closest_item {
+            if let Some(step) = Self::step_towards(game_state, player, pos) {
+                self.decision = format!("Going for the {}", name);
+                return Some(step);
+            }
+        }
+
+        if let Some((x, y)) = game_state.map_manager.current_map().down_stairs {
+            let stairs = (x as i32, y as i32);
+            if stairs == player {
+                self.decision = "Taking the stairs down".to_string();
+                game_state.descend_stairs();
+                return None;
+            }
+            if let Some(step) = Self::step_towards(game_state, player, stairs) {
+                self.decision = "Heading for the stairs down".to_string();
+                return Some(step);
+            }
+        }
+
+        self.decision = "Nothing to do, waiting".to_string();
+        None
+    }
+
+    fn step_towards(game_state: &GameState, from: (i32, i32), to: (i32, i32)) -> Option<(f32, f32)> {
+        let path = game_state.map_manager.current_map().find_player_path(from, to)?;
+        path.get(1).map(|&(x, y)| (x as f32, y as f32))
+    }
+
+    fn draw(&self) {
+        draw_rectangle(0.0, TOP_BAR_HEIGHT, screen_width(), 26.0, Color::new(0.0, 0.0, 0.0, 0.6));
+        draw_text(
+            &format!("DEMO  {}   [+/-] Speed ({:.2}s)  [Esc] Menu", self.decision, self.turn_delay),
+            10.0,
+            TOP_BAR_HEIGHT + 18.0,
+            18.0,
+            ORANGE,
+        );
+    }
+}
 
-        self.monsters.clear();
-        let rooms = map.rooms.clone();
-        let mut rng = thread_rng();
+// What the player picked on the main menu
+#[derive(Clone)]
+struct RunSetup {
+    modifiers: Vec<Modifier>,
+    upgrades: HashMap<ForgeUpgrade, u32>,
+    ai_difficulty: AiDifficulty,
+    class: CharacterClass,
+    hints: HintMode,
+    shared_stash: bool,
+    scenario: Option<Scenario>,
+    demo: bool,
+}
 
-        // Skip first row for monster spawning
-        for row in rooms.iter().skip(1) {
-            for room in row.iter() { // Changed from row to row.iter()
-                let num_monsters = rng.gen_range(0..3);
-                for _ in 0..num_monsters {
-                    let mut tries = 0;
-                    let max_tries = 10;
+// Between-run shop for permanent upgrades, paid for in embers
+async fn forge_screen(profile: &mut Profile) {
+    let number_keys = [KeyCode::Key1, KeyCode::Key2, KeyCode::Key3];
+    let mut message = String::new();
 
-                    while tries < max_tries {
-                        let (x, y) = room.random_position(&mut rng);
-                        if map.is_walkable(x, y) {
-                            let is_occupied = self.monsters.iter()
-                                .any(|m| m.x == x as f32 && m.y == y as f32);
+    loop {
+        if is_key_pressed(KeyCode::Escape) {
+            return;
+        }
 
-                            if !is_occupied {
-                                self.monsters.push(Entity::new_monster(x as f32, y as f32));
-                                break;
-                            }
-                        }
-                        tries += 1;
-                    }
-                }
+        for (i, key) in number_keys.iter().enumerate() {
+            if is_key_pressed(*key) {
+                let upgrade = ForgeUpgrade::ALL[i];
+                message = if profile.buy(upgrade) {
+                    profile.save();
+                    format!("{} is now rank {}.", upgrade.name(), profile.rank(upgrade))
+                } else {
+                    format!("You can't forge {} right now.", upgrade.name())
+                };
             }
         }
+
+        clear_background(BLACK);
+        draw_text("The Forge", screen_width() * 0.1, screen_height() * 0.12, 50.0, ORANGE);
+        draw_text(&format!("Embers: {}", profile.embers), screen_width() * 0.1, screen_height() * 0.2, 25.0, YELLOW);
+
+        for (i, upgrade) in ForgeUpgrade::ALL.iter().enumerate() {
+            let rank = profile.rank(*upgrade);
+            let (price, color) = if rank >= upgrade.max_rank() {
+                ("maxed".to_string(), GRAY)
+            } else {
+                let cost = upgrade.cost(rank);
+                (format!("{} embers", cost), if profile.embers >= cost { WHITE } else { GRAY })
+            };
+            draw_text(
+                &format!("{}) {} {}/{} - {} ({})", i + 1, upgrade.name(), rank, upgrade.max_rank(), upgrade.description(), price),
+                screen_width() * 0.1,
+                screen_height() * 0.3 + i as f32 * 25.0,
+                20.0,
+                color,
+            );
+        }
+
+        draw_text(&message, screen_width() * 0.1, screen_height() * 0.5, 20.0, GREEN);
+        draw_text("[1-3] Forge upgrade  [Esc] Back", screen_width() * 0.1, screen_height() * 0.92, 20.0, LIGHTGRAY);
+
+        present_frame().await;
     }
+}
 
-    fn check_and_pickup_items(&mut self) {
-        let mut items_to_pickup = Vec::new();
+// Pre-run screen where run mutators are toggled, showing past high scores
+async fn character_creation_screen() -> RunSetup {
+    // With no input for a while the menu falls through to the demo, arcade style
+    const ATTRACT_DELAY: f64 = 30.0;
+
+    let high_scores = HighScores::load();
+    let mut profile = Profile::load();
+    let conflicts = Saves::conflicts();
+    let mut enabled = [false; Modifier::RUN.len()];
+    let number_keys = [KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4];
+    let mut last_input = get_time();
+    let mut ai_difficulty = AiDifficulty::Basic;
+    let mut class = CharacterClass::Warrior;
+    let mut hints = profile.hints;
+    let mut shared_stash = true;
+    let scenarios = ScenarioRegistry::load().scenarios;
+    let mut scenario: Option<usize> = None; // Index into the scenarios, none hunts the Heart of the Forge
 
-        // Find all items at player's position
-        for (i, (x, y, _)) in self.ground_items.iter().enumerate() {
-            if *x == self.player.x && *y == self.player.y {
-                items_to_pickup.push(i);
+    loop {
+        if get_last_key_pressed().is_some() {
+            last_input = get_time();
+        }
+        if is_key_pressed(KeyCode::D) || get_time() - last_input > ATTRACT_DELAY {
+            return RunSetup {
+                modifiers: Vec::new(),
+                upgrades: HashMap::new(),
+                ai_difficulty,
+                class,
+                hints: HintMode::Off,
+                shared_stash: false,
+                scenario: None,
+                demo: true,
+            };
+        }
+        if is_key_pressed(KeyCode::A) {
+            ai_difficulty = ai_difficulty.next();
+        }
+        if is_key_pressed(KeyCode::C) {
+            class = class.next();
+        }
+        if is_key_pressed(KeyCode::H) {
+            hints = hints.next();
+        }
+        if is_key_pressed(KeyCode::S) {
+            shared_stash = !shared_stash;
+        }
+        if is_key_pressed(KeyCode::G) {
+            scenario = match scenario {
+                None if !scenarios.is_empty() => Some(0),
+                Some(index) if index + 1 < scenarios.len() => Some(index + 1),
+                _ => None,
+            };
+        }
+        if is_key_pressed(KeyCode::F) {
+            forge_screen(&mut profile).await;
+            last_input = get_time();
+        }
+
+        for (i, key) in number_keys.iter().enumerate() {
+            if is_key_pressed(*key) {
+                enabled[i] = !enabled[i];
             }
         }
 
-        for &i in items_to_pickup.iter().rev() {
-            if let Some((_, _, item)) = self.ground_items.get(i) {
-                if let Some(ref mut inventory) = self.player.inventory {
-                    match inventory.add_item(item.clone()) {
-                        Ok(_) => {
-                            self.add_log_message(format!("Picked up {}!", item.name));
-                            self.ground_items.remove(i);
-                        }
-                        Err(e) => {
-                            self.add_log_message(e);
-                            break; // Stop picking up if inventory is full
-                        }
-                    }
-                }
+        let chosen: Vec<Modifier> = Modifier::RUN
+            .iter()
+            .zip(enabled.iter())
+            .filter(|(_, &on)| on)
+            .map(|(m, _)| *m)
+            .collect();
+
+        if is_key_pressed(KeyCode::Enter) {
+            if hints != profile.hints {
+                profile.hints = hints;
+                profile.save();
             }
+            return RunSetup {
+                modifiers: chosen,
+                upgrades: profile.upgrades,
+                ai_difficulty,
+                class,
+                hints,
+                shared_stash,
+                scenario: scenario.map(|index| scenarios[index].clone()),
+                demo: false,
+            };
         }
-    }
 
-    // Add this method to display inventory
-    fn draw_inventory(&self) {
-        if let Some(ref inventory) = self.player.inventory {
-            // Draw semi-transparent background
-            draw_rectangle(
-                screen_width() * 0.1,
-                screen_height() * 0.1,
-                screen_width() * 0.8,
-                screen_height() * 0.8,
-                Color::new(0.0, 0.0, 0.0, 0.9),
-            );
+        clear_background(BLACK);
+        draw_text("Forge", screen_width() * 0.1, screen_height() * 0.12, 50.0, YELLOW);
+        draw_text(&format!("Embers: {}", profile.embers), screen_width() * 0.6, screen_height() * 0.12, 25.0, ORANGE);
+        draw_text("Run mutators:", screen_width() * 0.1, screen_height() * 0.22, 25.0, WHITE);
 
-            // Draw title
+        for (i, modifier) in Modifier::RUN.iter().enumerate() {
+            let (marker, color) = if enabled[i] { ("[x]", GREEN) } else { ("[ ]", GRAY) };
             draw_text(
-                "Inventory",
-                screen_width() * 0.15,
-                screen_height() * 0.15,
-                30.0,
-                WHITE,
+                &format!("{}) {} {} (x{:.2})", i + 1, marker, modifier.announcement(), modifier.score_multiplier()),
+                screen_width() * 0.1,
+                screen_height() * 0.28 + i as f32 * 25.0,
+                20.0,
+                color,
             );
+        }
 
-            // Draw equipped items
-            let equipped_y = screen_height() * 0.2;
+        draw_text(
+            &format!("Monster AI: {}", ai_difficulty.name()),
+            screen_width() * 0.1,
+            screen_height() * 0.28 + Modifier::RUN.len() as f32 * 25.0 + 40.0,
+            20.0,
+            WHITE,
+        );
+        draw_text(
+            &format!("Hints: {}", hints.name()),
+            screen_width() * 0.1,
+            screen_height() * 0.28 + Modifier::RUN.len() as f32 * 25.0 + 65.0,
+            20.0,
+            WHITE,
+        );
+        draw_text(
+            &format!("Stash: {}", if shared_stash { "Kept between runs" } else { "This run only" }),
+            screen_width() * 0.3,
+            screen_height() * 0.28 + Modifier::RUN.len() as f32 * 25.0 + 65.0,
+            20.0,
+            WHITE,
+        );
+        draw_text(
+            &format!("Goal: {}", scenario.map_or("The Heart of the Forge", |index| &scenarios[index].name)),
+            screen_width() * 0.1,
+            screen_height() * 0.28 + Modifier::RUN.len() as f32 * 25.0 + 90.0,
+            20.0,
+            WHITE,
+        );
+        if let Some(index) = scenario {
             draw_text(
-                "Equipped:",
-                screen_width() * 0.15,
-                equipped_y,
-                20.0,
+                &scenarios[index].description,
+                screen_width() * 0.1,
+                screen_height() * 0.28 + Modifier::RUN.len() as f32 * 25.0 + 112.0,
+                18.0,
                 LIGHTGRAY,
             );
+        }
 
-            if let Some(ref weapon) = inventory.equipped_weapon {
-                draw_text(
-                    &format!("Weapon: {}", weapon.name),
-                    screen_width() * 0.15,
-                    equipped_y + 25.0,
-                    20.0,
-                    weapon.color,
-                );
-            }
+        let multiplier: f32 = chosen.iter().map(|m| m.score_multiplier()).product();
+        draw_text(
+            &format!("Score multiplier: x{:.2}", multiplier),
+            screen_width() * 0.1,
+            screen_height() * 0.28 + Modifier::RUN.len() as f32 * 25.0 + 15.0,
+            20.0,
+            YELLOW,
+        );
 
-            if let Some(ref armor) = inventory.equipped_armor {
-                draw_text(
-                    &format!("Armor: {}", armor.name),
-                    screen_width() * 0.15,
-                    equipped_y + 50.0,
-                    20.0,
-                    armor.color,
-                );
-            }
+        // The chosen class, its starting stats and everything it begins the run with
+        let (max_hp, attack, defense, max_mana, perception) = class.starting_stats();
+        let mut class_lines = vec![
+            (format!("Class: {}", class.name()), WHITE, 25.0),
+            (class.describe().to_string(), LIGHTGRAY, 18.0),
+            (format!("HP {}  ATK {}  DEF {}  MP {}  Perception {}", max_hp, attack, defense, max_mana, perception), GREEN, 18.0),
+            ("Starts with:".to_string(), WHITE, 18.0),
+        ];
+        class_lines.extend(class.starting_kit().iter().map(|id| (format!("  {}", id.replace('_', " ")), GRAY, 18.0)));
+        class_lines.extend(class.starting_spells().iter().map(|spell| (format!("  {} spell", spell.name()), SKYBLUE, 18.0)));
+        for (i, (line, color, size)) in class_lines.iter().enumerate() {
+            draw_text(line, screen_width() * 0.6, screen_height() * 0.22 + i as f32 * 22.0, *size, *color);
+        }
 
-            // Draw inventory items
+        // Pushed down below the goal on short windows, keeping as many scores as fit above the footer
+        let scores_top = (screen_height() * 0.55).max(screen_height() * 0.28 + Modifier::RUN.len() as f32 * 25.0 + 150.0);
+        let rows_that_fit = ((screen_height() * 0.81 - scores_top) / 22.0).max(0.0) as usize + 1;
+        draw_text("High scores:", screen_width() * 0.1, scores_top, 25.0, WHITE);
+        for (i, entry) in high_scores.entries.iter().take(rows_that_fit).enumerate() {
+            let flags = if entry.modifiers.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", entry.modifiers.join(", "))
+            };
             draw_text(
-                "Items:",
-                screen_width() * 0.15,
-                equipped_y + 90.0,
-                20.0,
+                &format!("{:>2}. {:>6}  floor {}  level {}{}", i + 1, entry.score, entry.floor, entry.level, flags),
+                screen_width() * 0.1,
+                scores_top + screen_height() * 0.05 + i as f32 * 22.0,
+                18.0,
                 LIGHTGRAY,
             );
+        }
 
-            for (i, item) in inventory.items.iter().enumerate() {
-                let y_pos = equipped_y + 115.0 + (i as f32 * 25.0);
-                draw_text(
-                    &format!("{}) {} {}",
-                             i + 1,
-                             item.symbol,
-                             item.name
-                    ),
-                    screen_width() * 0.15,
-                    y_pos,
-                    20.0,
-                    item.color,
-                );
-            }
-
-            // Draw usage instructions
+        // Another machine's copy was set aside rather than overwritten
+        if !conflicts.is_empty() {
             draw_text(
-                "[E] Equip  [U] Use  [D] Drop  [Esc] Close",
-                screen_width() * 0.15,
-                screen_height() * 0.85,
-                20.0,
-                LIGHTGRAY,
+                &format!("Save conflict, other machine's copy kept in: {}", conflicts.join(", ")),
+                screen_width() * 0.1,
+                screen_height() * 0.88,
+                18.0,
+                ORANGE,
             );
         }
+
+        draw_text(
+            "[1-4] Toggle mutator  [A] Monster AI  [C] Class  [H] Hints  [S] Stash  [G] Goal  [F] Forge  [Enter] Begin  [D] Watch a demo",
+            screen_width() * 0.1,
+            screen_height() * 0.92,
+            20.0,
+            LIGHTGRAY,
+        );
+
+        present_frame().await;
     }
+}
 
-    fn add_log_message(&mut self, message: String) {
-        self.combat_log.push(message);
-        if self.combat_log.len() > 5 {
-            self.combat_log.remove(0);
+async fn victory_screen(game_state: &GameState) {
+    let modifiers: Vec<&str> = game_state.run_modifiers.iter().map(|m| m.name()).collect();
+    let stats = [
+        format!("Score: {}", game_state.score()),
+        format!("Turns taken: {}", game_state.turn),
+        format!("Monsters slain: {} ({} by allies)", game_state.kills, game_state.ally_kills),
+        format!("Character level: {}", game_state.player_level()),
+        format!("Gold carried: {}", game_state.player.inventory.as_ref().map_or(0, |inv| inv.gold)),
+        format!("Embers earned: {}", game_state.embers_earned()),
+        format!("Mutators: {}", if modifiers.is_empty() { "none".to_string() } else { modifiers.join(", ") }),
+        format!("Seed: {}", game_state.map_manager.config.seed),
+    ];
+    let headline = match &game_state.scenario {
+        Some(controller) => format!("You completed {}.", controller.scenario.name),
+        None => "You carried the Heart of the Forge out of the dungeon.".to_string(),
+    };
+
+    loop {
+        if is_key_pressed(KeyCode::Enter) || is_key_pressed(KeyCode::Escape) {
+            present_frame().await;
+            return;
+        }
+
+        clear_background(BLACK);
+        draw_text("Victory!", screen_width() * 0.1, screen_height() * 0.12, 50.0, GOLD);
+        draw_text(
+            &headline,
+            screen_width() * 0.1,
+            screen_height() * 0.2,
+            25.0,
+            WHITE,
+        );
+        for (i, line) in stats.iter().enumerate() {
+            draw_text(line, screen_width() * 0.1, screen_height() * 0.3 + i as f32 * 28.0, 22.0, LIGHTGRAY);
         }
+        draw_text("[Enter] Back to the menu", screen_width() * 0.1, screen_height() * 0.92, 20.0, LIGHTGRAY);
+
+        present_frame().await;
     }
+}
 
-    fn process_monster_turns(&mut self, current_time: f32) {
-        let player_pos = (self.player.x, self.player.y);
-        let map = self.map_manager.current_map();
+// What the player chose on the game over screen
+enum GameOverChoice {
+    Restart,
+    Quit,
+}
 
-        let monster_positions: Vec<(f32, f32)> = self.monsters.iter()
-            .filter(|m| m.is_alive())
-            .map(|m| (m.x, m.y))
-            .collect();
+async fn game_over_screen(game_state: &GameState) -> GameOverChoice {
+    let player = &game_state.player;
+    let cause = player.last_hit_by.as_ref().map_or_else(|| "unknown causes".to_string(), |source| source.describe());
+    let xp = player.stats.level_system.as_ref().map_or(0, |ls| ls.total_xp);
+    let stats = [
+        format!("Slain by {} on floor {}", cause, game_state.map_manager.current_level + 1),
+        format!("Deepest floor reached: {}", game_state.deepest_level + 1),
+        format!("Monsters slain: {} ({} by allies)", game_state.kills, game_state.ally_kills),
+        format!("Experience earned: {} (level {})", xp, game_state.player_level()),
+        format!("Items collected: {}", game_state.items_collected),
+        format!("Reputation: {}", game_state.reputation),
+        format!("Score: {}", game_state.score()),
+        format!("Seed: {}", game_state.map_manager.config.seed),
+    ];
+    let restart = if GameConfig::seed_arg().is_some() { "[R] Restart with the same seed  [Q] Quit" } else { "[R] Restart with a new seed  [Q] Quit" };
 
-        for i in 0..self.monsters.len() {
-            if !self.monsters[i].is_alive() || !self.monsters[i].can_move(current_time) {
-                continue;
-            }
+    loop {
+        if is_key_pressed(KeyCode::R) || is_key_pressed(KeyCode::Enter) {
+            return GameOverChoice::Restart;
+        }
+        if is_key_pressed(KeyCode::Q) || is_key_pressed(KeyCode::Escape) {
+            return GameOverChoice::Quit;
+        }
 
-            let monster = &mut self.monsters[i];
-            let monster_pos = (monster.x as i32, monster.y as i32);
-            let player_grid_pos = (player_pos.0 as i32, player_pos.1 as i32);
+        clear_background(BLACK);
+        draw_text("You have died", screen_width() * 0.1, screen_height() * 0.12, 50.0, RED);
+        for (i, line) in stats.iter().enumerate() {
+            draw_text(line, screen_width() * 0.1, screen_height() * 0.25 + i as f32 * 28.0, 22.0, LIGHTGRAY);
+        }
+        draw_text(restart, screen_width() * 0.1, screen_height() * 0.92, 20.0, LIGHTGRAY);
 
-            let mut new_pos = monster_pos;
+        present_frame().await;
+    }
+}
+
+// WASD, vi-keys and the numpad all move, held keys combine into diagonals
+const MOVEMENT_KEYS: [(KeyCode, (i32, i32)); 20] = [
+    (KeyCode::W, (0, -1)),
+    (KeyCode::A, (-1, 0)),
+    (KeyCode::S, (0, 1)),
+    (KeyCode::D, (1, 0)),
+    (KeyCode::K, (0, -1)),
+    (KeyCode::H, (-1, 0)),
+    (KeyCode::J, (0, 1)),
+    (KeyCode::L, (1, 0)),
+    (KeyCode::Y, (-1, -1)),
+    (KeyCode::U, (1, -1)),
+    (KeyCode::B, (-1, 1)),
+    (KeyCode::N, (1, 1)),
+    (KeyCode::Kp8, (0, -1)),
+    (KeyCode::Kp4, (-1, 0)),
+    (KeyCode::Kp2, (0, 1)),
+    (KeyCode::Kp6, (1, 0)),
+    (KeyCode::Kp7, (-1, -1)),
+    (KeyCode::Kp9, (1, -1)),
+    (KeyCode::Kp1, (-1, 1)),
+    (KeyCode::Kp3, (1, 1)),
+];
+
+fn held_movement(diagonals: bool) -> Option<(i32, i32)> {
+    // Shift turns the letters into commands, Shift+S searches instead of stepping down
+    if shift_down() {
+        return None;
+    }
+    let (mut dx, mut dy) = (0, 0);
+    for (key, (kx, ky)) in MOVEMENT_KEYS {
+        if is_key_pressed(key) || is_key_down(key) {
+            dx += kx;
+            dy += ky;
+        }
+    }
+    let (dx, dy) = (dx.clamp(-1, 1), dy.clamp(-1, 1));
+    if (dx, dy) == (0, 0) || (!diagonals && dx != 0 && dy != 0) {
+        return None;
+    }
+    Some((dx, dy))
+}
+
+fn shift_down() -> bool {
+    is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift)
+}
+
+fn rest_pressed() -> bool {
+    is_key_pressed(KeyCode::Space) || is_key_pressed(KeyCode::Kp5)
+}
+
+// One recorded turn of a keyboard macro
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+enum MacroAction {
+    Step(i32, i32),
+    Rest,
+    Search,
+}
+
+impl MacroAction {
+    // Searching stays put as resting does
+    fn offset(&self) -> (i32, i32) {
+        match self {
+            MacroAction::Step(dx, dy) => (*dx, *dy),
+            MacroAction::Rest | MacroAction::Search => (0, 0),
+        }
+    }
+}
+
+// The commands on the radial menu, clockwise from the top
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RadialCommand {
+    Rest,
+    Search,
+    Explore,
+    Inventory,
+    Cast,
+    Throw,
+    Look,
+}
+
+impl RadialCommand {
+    const ALL: [RadialCommand; 7] = [
+        RadialCommand::Rest,
+        RadialCommand::Search,
+        RadialCommand::Explore,
+        RadialCommand::Inventory,
+        RadialCommand::Cast,
+        RadialCommand::Throw,
+        RadialCommand::Look,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            RadialCommand::Rest => "Rest",
+            RadialCommand::Search => "Search",
+            RadialCommand::Explore => "Explore",
+            RadialCommand::Inventory => "Inventory",
+            RadialCommand::Cast => "Cast",
+            RadialCommand::Throw => "Throw",
+            RadialCommand::Look => "Look",
+        }
+    }
+
+    // The hotkey that does the same, shown so it can be learned along the way
+    fn key(&self) -> &'static str {
+        match self {
+            RadialCommand::Rest => "[Space]",
+            RadialCommand::Search => "[Shift+S]",
+            RadialCommand::Explore => "[X]",
+            RadialCommand::Inventory => "[I]",
+            RadialCommand::Cast => "[Z]",
+            RadialCommand::Throw => "[I] then [T]",
+            RadialCommand::Look => "[;]",
+        }
+    }
+}
+
+// A ring of common commands around the middle of the screen, opened with [Tab] so a growing set
+// of commands can be found without knowing every hotkey
+struct RadialMenu {
+    selection: Option<usize>, // Index into RadialCommand::ALL, nothing until aimed
+    opened_at: f32,
+    held: bool, // Still open from holding [Tab] down, rather than from a tap
+    mouse: (f32, f32), // Only moving the mouse aims with it
+}
+
+impl RadialMenu {
+    const RADIUS: f32 = 120.0;
+    const INNER_RADIUS: f32 = 30.0;
+    const TAP_SECONDS: f32 = 0.25; // Let go of [Tab] sooner and the menu stays open
+
+    // Screen angle of a slice's middle, the first one straight up
+    fn slice_angle(index: usize) -> f32 {
+        let step = std::f32::consts::TAU / RadialCommand::ALL.len() as f32;
+        index as f32 * step - std::f32::consts::FRAC_PI_2
+    }
+
+    fn slice_toward(dx: f32, dy: f32) -> usize {
+        let step = std::f32::consts::TAU / RadialCommand::ALL.len() as f32;
+        let from_top = (dy.atan2(dx) + std::f32::consts::FRAC_PI_2).rem_euclid(std::f32::consts::TAU);
+        (from_top / step).round() as usize % RadialCommand::ALL.len()
+    }
+}
+
+// Short input sequences bound to the function keys, kept between runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KeyMacros {
+    slots: [Vec<MacroAction>; 4],
+}
+
+impl KeyMacros {
+    const PATH: &'static str = "macros.ron";
+    const KEYS: [KeyCode; 4] = [KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4];
+    const MAX_ACTIONS: usize = 20;
+
+    fn load() -> Self {
+        Saves::open()
+            .load(Self::PATH)
+            .and_then(|contents| ron::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        if let Ok(contents) = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()) {
+            Saves::open().save(Self::PATH, contents);
+        }
+    }
+}
+
+// A fresh run from what was picked on the menu, on a newly seeded dungeon
+fn start_run(setup: &RunSetup) -> (Option<GameState>, Option<DemoBot>) {
+    let config = GameConfig {
+        ai_difficulty: setup.ai_difficulty,
+        character_class: setup.class,
+        hints: setup.hints,
+        shared_stash: setup.shared_stash,
+        scenario: setup.scenario.clone(),
+        demo: setup.demo,
+        ..GameConfig::default()
+    };
+    let demo = setup.demo.then(|| DemoBot::new(config.demo_turn_delay));
+    (Some(GameState::new(config, setup.modifiers.clone(), &setup.upgrades)), demo)
+}
+
+// Which screen the main loop is driving
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Screen {
+    Title,
+    Playing,
+    Paused,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TitleChoice {
+    NewGame,
+    Continue,
+    Quit,
+}
+
+impl TitleChoice {
+    const ALL: [TitleChoice; 3] = [TitleChoice::NewGame, TitleChoice::Continue, TitleChoice::Quit];
+
+    fn label(&self) -> &'static str {
+        match self {
+            TitleChoice::NewGame => "New Game",
+            TitleChoice::Continue => "Continue",
+            TitleChoice::Quit => "Quit",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PauseChoice {
+    Resume,
+    Feedback,
+    QuitToTitle,
+    QuitGame,
+}
+
+impl PauseChoice {
+    const ALL: [PauseChoice; 4] = [PauseChoice::Resume, PauseChoice::Feedback, PauseChoice::QuitToTitle, PauseChoice::QuitGame];
+
+    fn label(&self) -> &'static str {
+        match self {
+            PauseChoice::Resume => "Resume",
+            PauseChoice::Feedback => "Send Feedback",
+            PauseChoice::QuitToTitle => "Quit to Title",
+            PauseChoice::QuitGame => "Quit Game",
+        }
+    }
+}
 
-            if monster.can_perceive_target(player_pos.0, player_pos.1) {
-                // Use A* pathfinding when player is within perception range
-                if let Some(path) = map.find_path(monster_pos, player_grid_pos) {
-                    if path.len() > 1 {  // Check if we have a next step
-                        new_pos = path[1];  // Get the next position in the path
-                    }
-                }
-            } else {
-                // Random movement when player is not perceived
-                let mut rng = thread_rng();
-                let direction = rng.gen_range(0..4);
-                new_pos = match direction {
-                    0 => (monster_pos.0 + 1, monster_pos.1),
-                    1 => (monster_pos.0 - 1, monster_pos.1),
-                    2 => (monster_pos.0, monster_pos.1 + 1),
-                    _ => (monster_pos.0, monster_pos.1 - 1),
-                };
-            }
+// Notices when the player has stepped away, so a held key or a running clock can't play on without them
+struct IdleWatch {
+    last_input: f64,
+    last_mouse: (f32, f32),
+}
 
-            // Check if the new position is valid
-            if map.is_walkable(new_pos.0, new_pos.1) {
-                let new_pos_f = (new_pos.0 as f32, new_pos.1 as f32);
+impl IdleWatch {
+    // Macroquad doesn't report focus changes, but a hidden window stops drawing frames,
+    // so a frame this long means the window was put away
+    const STALLED_FRAME_SECONDS: f32 = 2.0;
 
-                // Check for collisions with other monsters
-                let is_collision = monster_positions.iter()
-                    .any(|&pos| pos.0 == new_pos_f.0 && pos.1 == new_pos_f.1);
+    fn new() -> Self {
+        Self { last_input: get_time(), last_mouse: mouse_position() }
+    }
 
-                // Check for collision with player
-                if player_pos.0 == new_pos_f.0 && player_pos.1 == new_pos_f.1 {
-                    let message = monster.attack(&mut self.player);
-                    if monster.is_alive() { // Only update if we haven't processed this monster in combat
-                        monster.update_last_move(current_time);
-                    }
-                    drop(monster); // Release the monster borrow before modifying self
-                    //self.add_log_message(message);
-                    continue;
-                } else if !is_collision {
-                    monster.x = new_pos_f.0;
-                    monster.y = new_pos_f.1;
-                }
-            }
+    fn should_pause(&mut self, idle_seconds: f32) -> bool {
+        let mouse = mouse_position();
+        let clicked = [MouseButton::Left, MouseButton::Right].into_iter().any(is_mouse_button_pressed);
+        // Held keys don't count, only fresh presses show someone is there
+        if get_last_key_pressed().is_some() || clicked || mouse != self.last_mouse {
+            self.last_input = get_time();
+        }
+        self.last_mouse = mouse;
 
-            monster.update_last_move(current_time);
+        let idle = idle_seconds > 0.0 && get_time() - self.last_input > idle_seconds as f64;
+        let stalled = get_frame_time() > Self::STALLED_FRAME_SECONDS;
+        if idle || stalled {
+            self.last_input = get_time();
+            return true;
         }
+        false
     }
+}
 
-    fn spawn_items(&mut self, map: &Map) {
-        let mut rng = thread_rng();
+async fn title_screen(can_continue: bool) -> TitleChoice {
+    let available = |choice: &TitleChoice| *choice != TitleChoice::Continue || can_continue;
+    let mut selection = if can_continue { 1 } else { 0 };
 
-        for room_row in &map.rooms {
-            for room in room_row {
-                if rng.gen_bool(0.6) {
-                    let (x, y) = room.random_position(&mut rng);
-                    let item = match rng.gen_range(0..4) {
-                        0 => Item::new_sword(),
-                        1 => Item::new_armor(),
-                        2 => Item::new_health_potion(),
-                        _ => Item::new_lightning_scroll(),
-                    };
-                    self.ground_items.push((x as f32, y as f32, item));
-                }
-            }
+    loop {
+        if is_key_pressed(KeyCode::Up) {
+            selection = (0..selection).rev().find(|&i| available(&TitleChoice::ALL[i])).unwrap_or(selection);
+        }
+        if is_key_pressed(KeyCode::Down) {
+            selection = (selection + 1..TitleChoice::ALL.len()).find(|&i| available(&TitleChoice::ALL[i])).unwrap_or(selection);
+        }
+        if is_key_pressed(KeyCode::Enter) {
+            // Let the key go before the next screen starts reading input
+            present_frame().await;
+            return TitleChoice::ALL[selection];
         }
-    }
 
-    fn pick_up_item(&mut self, x: f32, y: f32) -> Option<String> {
-        if let Some(index) = self.ground_items
-            .iter()
-            .position(|(ix, iy, _)| *ix == x && *iy == y)
-        {
-            let (_, _, item) = self.ground_items.remove(index);
-            if let Some(ref mut inventory) = self.player.inventory {
-                match inventory.add_item(item.clone()) {
-                    Ok(_) => Some(format!("Picked up {}!", item.name)),
-                    Err(e) => Some(e),
-                }
+        clear_background(BLACK);
+        draw_text("FORGE", screen_width() * 0.1, screen_height() * 0.25, 80.0, ORANGE);
+        for (i, choice) in TitleChoice::ALL.iter().enumerate() {
+            let color = if !available(choice) {
+                DARKGRAY
+            } else if i == selection {
+                WHITE
             } else {
-                Some("No inventory available!".to_string())
+                GRAY
+            };
+            let y_pos = screen_height() * 0.45 + i as f32 * 40.0;
+            if i == selection {
+                draw_text(">", screen_width() * 0.1 - 25.0, y_pos, 30.0, WHITE);
             }
-        } else {
-            None
+            draw_text(choice.label(), screen_width() * 0.1, y_pos, 30.0, color);
         }
-    }
+        draw_text("[Up/Down] Select  [Enter] Confirm", screen_width() * 0.1, screen_height() * 0.92, 20.0, LIGHTGRAY);
 
-    fn find_closest_monster(&mut self, x: f32, y: f32, max_range: f32) -> Option<&mut Entity> {
-        self.monsters
-            .iter_mut()
-            .filter(|m| m.is_alive())
-            .min_by_key(|m| {
-                let distance = ((m.x - x).powi(2) + (m.y - y).powi(2)).sqrt();
-                if distance <= max_range {
-                    (distance * 100.0) as i32
-                } else {
-                    i32::MAX
-                }
-            })
+        present_frame().await;
     }
 }
 
-struct LevelState {
-    monsters: Vec<Entity>,
-    ground_items: Vec<(f32, f32, Item)>,
-}
-
-struct GameConfig {
-    map_width: usize,
-    map_height: usize,
+fn draw_pause_menu(selection: usize) {
+    draw_rectangle(0.0, 0.0, screen_width(), screen_height(), Color::new(0.0, 0.0, 0.0, 0.6));
+    draw_text("Paused", screen_width() * 0.4, screen_height() * 0.35, 40.0, WHITE);
+    for (i, choice) in PauseChoice::ALL.iter().enumerate() {
+        let y_pos = screen_height() * 0.45 + i as f32 * 35.0;
+        let color = if i == selection { WHITE } else { GRAY };
+        if i == selection {
+            draw_text(">", screen_width() * 0.4 - 20.0, y_pos, 25.0, WHITE);
+        }
+        draw_text(choice.label(), screen_width() * 0.4, y_pos, 25.0, color);
+    }
+    let below = PauseChoice::ALL.len() as f32 * 35.0 + 10.0;
+    draw_text("[Esc] Resume", screen_width() * 0.4, screen_height() * 0.45 + below, 20.0, LIGHTGRAY);
 }
 
-impl Default for GameConfig {
-    fn default() -> Self {
-        Self {
-            map_width: 50,  // Larger map
-            map_height: 40, // Larger map
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(position) = args.iter().position(|arg| arg == "--heatmap") {
+        let floor: i32 = args.get(position + 1).and_then(|arg| arg.parse().ok()).unwrap_or(1);
+        let seeds = args.get(position + 2).and_then(|arg| arg.parse().ok()).unwrap_or(DensityHeatmap::DEFAULT_SEEDS);
+        if !(1..=FINAL_FLOOR + 1).contains(&floor) {
+            eprintln!("Floors go from 1 to {}.", FINAL_FLOOR + 1);
+            return;
         }
+        let heatmap = DensityHeatmap::sample(floor - 1, seeds);
+        println!("{}", heatmap.summary());
+        match heatmap.export() {
+            Ok(paths) => println!("Wrote {}", paths.join(", ")),
+            Err(error) => eprintln!("Could not write the heatmap: {}", error),
+        }
+        return;
     }
-}
 
-fn window_conf() -> Conf {
-    Conf {
-        window_title: "Roguelike".to_string(),
-        window_width: 1280,
-        window_height: 720,
-        ..Default::default()
+    if let Some(position) = args.iter().position(|arg| arg == "--bench-path") {
+        let seeds = args.get(position + 1).and_then(|arg| arg.parse().ok()).unwrap_or(PathBenchmark::DEFAULT_SEEDS);
+        println!("{}", PathBenchmark::run(seeds).summary());
+        return;
+    }
+
+    Saves::sync_all();
+
+    #[cfg(feature = "terminal")]
+    if args.iter().any(|arg| arg == "--terminal") {
+        if let Err(error) = terminal::run() {
+            eprintln!("The terminal frontend failed: {}", error);
+        }
+        return;
     }
+    macroquad::Window::from_config(window_conf(), run_window());
 }
 
-#[macroquad::main(window_conf)]
-async fn main() {
+async fn run_window() {
     let config = GameConfig::default();
     let map_width = config.map_width;    // Store the values we need
     let map_height = config.map_height;  // before moving config
-    let mut game_state = GameState::new(config);
-
-    let tile_size = calculate_tile_size(
-        map_width,          // Now using the stored values
-        map_height,
-        screen_width(),
-        screen_height()
-    );
-
-    let viewport_width = (screen_width() / tile_size).floor() as usize;
-    let viewport_height = ((screen_height() - TOP_BAR_HEIGHT - BOTTOM_BAR_HEIGHT) / tile_size).floor() as usize;
-    let mut camera = Camera::new(viewport_width, viewport_height);
+    let mut haptics = Haptics::new(config.rumble_intensity);
+    let mut visual_effects = VisualEffects::new(config.photosensitive_safe_mode);
+    let mut sounds = SoundManager::load(config.volume).await;
+    let mut screen = Screen::Title;
+    let mut setup: Option<RunSetup> = None;
+    let mut run: Option<GameState> = None;
+    let mut demo: Option<DemoBot> = None;
+    let mut pause_selection = 0;
+    let idle_pause_seconds = config.idle_pause_seconds;
+    let mut idle_watch = IdleWatch::new();
+
+    let mut renderer = MacroquadRenderer {
+        glyphs: GlyphRenderer::load(&config).await,
+        camera: Camera::new(0, 0),
+        tile_size: DESIRED_TILE_SIZE,
+        zoom: 1.0,
+        free_look: None,
+    };
+    renderer.fit_to_window(map_width, map_height);
 
     loop {
+        if screen == Screen::Title {
+            sounds.stop_music();
+            renderer.free_look = None;
+            // A run left from the pause menu can be picked up again
+            let can_continue = demo.is_none() && run.as_ref().is_some_and(|state| state.player.is_alive() && !state.victorious);
+            match title_screen(can_continue).await {
+                TitleChoice::NewGame => {
+                    let chosen = character_creation_screen().await;
+                    (run, demo) = start_run(&chosen);
+                    setup = Some(chosen);
+                }
+                TitleChoice::Continue => {}
+                TitleChoice::Quit => break,
+            }
+            idle_watch = IdleWatch::new();
+            screen = Screen::Playing;
+            continue;
+        }
+        let Some(game_state) = run.as_mut() else {
+            screen = Screen::Title;
+            continue;
+        };
+
         let current_time = get_time() as f32;
+        game_state.clock = current_time;
+        let hp_before = game_state.player.stats.hp;
+        let level_before = game_state.player_level();
+        let floor_before = game_state.map_manager.current_floor();
+
+        // The demo goes back to the title screen when it ends
+        if demo.as_mut().is_some_and(|bot| bot.finished(current_time, game_state.player.is_alive())) {
+            (run, demo) = (None, None);
+            screen = Screen::Title;
+            continue;
+        }
 
-        if game_state.player.is_alive() && game_state.player.can_move(current_time)  {
-            let mut new_x = game_state.player.x;
-            let mut new_y = game_state.player.y;
-            let mut moved = false;
+        // Watched on every frame, so the key that resumes counts as input
+        let away = idle_watch.should_pause(idle_pause_seconds);
+        if screen == Screen::Playing && demo.is_none() && away && game_state.player.is_alive() {
+            screen = Screen::Paused;
+            pause_selection = 0;
+            game_state.auto_path.clear();
+            game_state.action_queue.clear();
+            game_state.add_log_message(MessageCategory::System, "Paused while you were away.".to_string());
+        }
 
-            if is_key_pressed(KeyCode::W) || is_key_down(KeyCode::W)  {
-                new_y -= 1.0;
-                moved = true;
+        // The key that closes the pause menu is still down for the rest of the frame
+        let mut menu_closed = false;
+        if screen == Screen::Paused {
+            let choices = PauseChoice::ALL;
+            if is_key_pressed(KeyCode::Up) && pause_selection > 0 {
+                pause_selection -= 1;
+            }
+            if is_key_pressed(KeyCode::Down) && pause_selection + 1 < choices.len() {
+                pause_selection += 1;
+            }
+            if is_key_pressed(KeyCode::Escape) {
+                screen = Screen::Playing;
+                menu_closed = true;
+            } else if is_key_pressed(KeyCode::Enter) {
+                menu_closed = true;
+                match choices[pause_selection] {
+                    PauseChoice::Resume => screen = Screen::Playing,
+                    PauseChoice::Feedback => {
+                        screen = Screen::Playing;
+                        game_state.open_feedback_form();
+                    }
+                    PauseChoice::QuitToTitle => screen = Screen::Title,
+                    PauseChoice::QuitGame => break,
+                }
             }
-            if is_key_pressed(KeyCode::S) || is_key_down(KeyCode::S) {
-                new_y += 1.0;
-                moved = true;
+        }
+
+        // Nothing moves while the game is paused, the frame is still drawn underneath the menu
+        if screen == Screen::Playing && !menu_closed {
+            if let Some(bot) = demo.as_mut() {
+                bot.handle_speed_keys();
+            } else if game_state.inventory_open {
+                game_state.handle_inventory_input();
+            } else if game_state.feedback_open {
+                game_state.handle_feedback_input();
+            } else if game_state.party_open {
+                game_state.handle_party_input();
+            } else if game_state.shop_open {
+                game_state.handle_shop_input();
+            } else if game_state.board_open {
+                game_state.handle_board_input();
+            } else if game_state.forge_open {
+                game_state.handle_forge_input();
+            } else if game_state.stash_open {
+                game_state.handle_stash_input();
+            } else if game_state.log_open {
+                game_state.handle_log_input();
+            } else if game_state.spells_open {
+                game_state.handle_spell_input();
+            } else if game_state.parley.is_some() {
+                game_state.handle_parley_input();
+            } else if game_state.radial_menu.is_some() {
+                game_state.handle_radial_input(current_time);
+            } else if game_state.look_cursor.is_some() {
+                game_state.handle_look_input();
+            } else if game_state.spawn_preview.is_some() {
+                game_state.handle_spawn_preview_input();
+            } else if game_state.targeting.is_some() {
+                if is_key_pressed(KeyCode::Escape) {
+                    game_state.targeting = None;
+                    game_state.add_log_message(MessageCategory::System, "Never mind.".to_string());
+                }
+            } else if !game_state.overlay_open() {
+                if is_key_pressed(KeyCode::I) {
+                    game_state.inventory_open = true;
+                } else if is_key_pressed(KeyCode::Slash) {
+                    game_state.encyclopedia_open = true;
+                } else if is_key_pressed(KeyCode::M) {
+                    game_state.open_message_log();
+                } else if is_key_pressed(KeyCode::Semicolon) {
+                    game_state.open_look();
+                } else if is_key_pressed(KeyCode::F7) && game_state.map_manager.config.wizard_mode {
+                    game_state.open_spawn_preview();
+                } else if is_key_pressed(KeyCode::F8) {
+                    game_state.open_feedback_form();
+                } else if is_key_pressed(KeyCode::F9) {
+                    game_state.export_run();
+                } else if is_key_pressed(KeyCode::F10) {
+                    game_state.import_run();
+                } else if is_key_pressed(KeyCode::P) {
+                    game_state.party_open = true;
+                } else if is_key_pressed(KeyCode::Z) {
+                    game_state.open_spell_menu();
+                } else if is_key_pressed(KeyCode::E) && game_state.player.is_alive() {
+                    game_state.eat_corpse();
+                } else if is_key_pressed(KeyCode::V) {
+                    game_state.toggle_sneak();
+                } else if is_key_pressed(KeyCode::O) && game_state.player.is_alive() {
+                    game_state.open_parley();
+                } else if is_key_pressed(KeyCode::G) && game_state.player.is_alive() {
+                    game_state.begin_digging();
+                } else if shift_down() && is_key_pressed(KeyCode::S) && game_state.player.is_alive() {
+                    game_state.record_action(MacroAction::Search);
+                    game_state.search();
+                } else if is_key_pressed(KeyCode::F) && game_state.player.is_alive() {
+                    game_state.use_feature();
+                } else if is_key_pressed(KeyCode::X) && game_state.player.is_alive() {
+                    game_state.start_exploring();
+                } else if is_key_pressed(KeyCode::Tab) && game_state.player.is_alive() {
+                    game_state.open_radial_menu(current_time);
+                } else if is_key_pressed(KeyCode::Escape) {
+                    screen = Screen::Paused;
+                    pause_selection = 0;
+                } else {
+                    game_state.handle_macro_keys();
+                }
             }
-            if is_key_pressed(KeyCode::A) || is_key_down(KeyCode::A) {
-                new_x -= 1.0;
-                moved = true;
+
+            if demo.is_none() && !game_state.overlay_open() {
+                renderer.handle_camera_input(game_state, get_frame_time());
             }
-            if is_key_pressed(KeyCode::D) || is_key_down(KeyCode::D) {
-                new_x += 1.0;
-                moved = true;
+
+            // Click a lit tile to walk there
+            if demo.is_none() && !game_state.overlay_open() && is_mouse_button_pressed(MouseButton::Left) {
+                let (mouse_x, mouse_y) = mouse_position();
+                let target = renderer.camera.screen_to_world(mouse_x, mouse_y, renderer.tile_size);
+                if game_state.targeting.is_some() {
+                    game_state.aim_at(target);
+                } else {
+                    game_state.start_auto_path(target);
+                }
             }
 
-            if moved {
-                game_state.player.update_last_move(current_time);
-                let mut combat_occurred = false;
+            if !game_state.overlay_open() && game_state.targeting.is_none() && game_state.player.is_alive() && game_state.player_ready(current_time) {
+                let mut new_x = game_state.player.x;
+                let mut new_y = game_state.player.y;
+                let mut moved = false;
+                let mut walking = false;
+
+                if let Some(bot) = demo.as_mut() {
+                    if let Some((step_x, step_y)) = bot.next_step(game_state, current_time) {
+                        new_x = step_x;
+                        new_y = step_y;
+                        moved = true;
+                    }
+                } else {
+                    let diagonals = game_state.map_manager.current_map().diagonal_movement;
+                    let pressed = held_movement(diagonals)
+                        .map(|(dx, dy)| MacroAction::Step(dx, dy))
+                        .or_else(|| rest_pressed().then_some(MacroAction::Rest));
+
+                    // Keyboard input cancels a click-to-move walk, exploring, a playing macro or digging
+                    let action = if let Some(action) = pressed {
+                        game_state.auto_path.clear();
+                        game_state.exploring = None;
+                        game_state.action_queue.clear();
+                        game_state.digging = None;
+                        game_state.record_action(action);
+                        Some(action)
+                    } else if let Some((step_x, step_y)) = game_state.next_auto_path_step() {
+                        new_x = step_x;
+                        new_y = step_y;
+                        moved = true;
+                        walking = true;
+                        None
+                    } else if game_state.digging.is_some() {
+                        game_state.continue_digging();
+                        None
+                    } else {
+                        game_state.next_queued_action()
+                    };
 
-                // Check for combat
-                for monster in &mut game_state.monsters {
-                    if monster.is_alive() && new_x == monster.x && new_y == monster.y {
-                        let messages = game_state.player.attack(monster);
-                        for message in messages {
-                            game_state.add_log_message(message);
+                    // Resting is a move onto the tile the player already stands on
+                    match action {
+                        Some(MacroAction::Search) => game_state.search(),
+                        Some(action) => {
+                            let (dx, dy) = action.offset();
+                            new_x += dx as f32;
+                            new_y += dy as f32;
+                            moved = true;
                         }
-                        combat_occurred = true;
-                        break;
+                        None => {}
                     }
                 }
 
-                // Move if no combat and the tile is walkable
-                if !combat_occurred && game_state.map_manager.current_map().is_walkable(new_x as i32, new_y as i32) {
-                    game_state.player.x = new_x;
-                    game_state.player.y = new_y;
+                if moved {
+                    game_state.player_step(new_x, new_y, walking);
+                }
+
+                // Check for level transition
+                game_state.handle_level_transition();
+            }
+
+            game_state.advance_world(current_time);
+
+            let profile = std::mem::take(&mut game_state.turn_profile);
+            if game_state.map_manager.config.debug_mode {
+                if let Some(warning) = profile.budget_warning() {
+                    eprintln!("{}", warning);
+                }
+            }
+
+            // Demo runs don't count towards the high scores or earn embers
+            if demo.is_none() && (!game_state.player.is_alive() || game_state.victorious) {
+                game_state.finish_run();
+            }
+
+            // A won run ends on the victory screen and goes back to the menu
+            if game_state.victorious {
+                victory_screen(game_state).await;
+                (run, demo) = (None, None);
+                screen = Screen::Title;
+                continue;
+            }
 
-                    // Check for items at the new position
-                    game_state.check_and_pickup_items();
+            // Death ends the run on the game over screen, the demo handles its own deaths
+            if demo.is_none() && !game_state.player.is_alive() {
+                match (game_over_screen(game_state).await, &setup) {
+                    (GameOverChoice::Restart, Some(chosen)) => (run, demo) = start_run(chosen),
+                    _ => break,
                 }
+                idle_watch = IdleWatch::new();
+                renderer.free_look = None;
+                continue;
             }
 
-            // Check for level transition
-            game_state.handle_level_transition();
+            game_state.emit_player_events(hp_before, level_before);
+            for event in game_state.drain_events() {
+                haptics.handle_event(&event);
+                visual_effects.handle_event(&event);
+                sounds.handle_event(&event);
+                game_state.handle_scenario_event(&event);
+            }
+            visual_effects.update(get_frame_time());
+            sounds.update_music(game_state.map_manager.current_level);
+            game_state.update_projectiles(get_frame_time());
+            // No gamepad backend exists yet, so the rumble strength has nowhere to go
+            let _rumble_strength = haptics.update(get_frame_time());
         }
 
-        game_state.process_monster_turns(current_time);
+        // Somewhere scouted on the floor just left means nothing on this one
+        if game_state.map_manager.current_floor() != floor_before {
+            renderer.free_look = None;
+        }
 
-        // Remove dead monsters
-        game_state.monsters.retain(|m| m.is_alive());
+        let now = get_time() as f32;
+        game_state.update_tweens(now);
+        renderer.camera.shake_offset = visual_effects.shake_offset();
+        let map = game_state.map_manager.current_map();
+        renderer.fit_to_window(map.width, map.height);
+        renderer.draw_play_view(game_state, now);
 
-        // Update camera to follow player
-        camera.follow(
-            game_state.player.x,
-            game_state.player.y,
-            game_state.map_manager.current_map().width,
-            game_state.map_manager.current_map().height
-        );
+        visual_effects.draw();
 
-        // Clear screen
-        clear_background(BLACK);
+        if let Some(bot) = &demo {
+            bot.draw();
+        }
+        game_state.draw_floor_summary();
 
-        // Draw the current map
-        game_state.map_manager.current_map().draw(&camera, tile_size);
+        // Hovering a creature shows what it is and which auras are on it
+        if !game_state.overlay_open() && game_state.targeting.is_none() {
+            let (mouse_x, mouse_y) = mouse_position();
+            let tile = renderer.camera.screen_to_world(mouse_x, mouse_y, renderer.tile_size);
+            game_state.draw_examine_panel(tile, mouse_x, mouse_y);
+        }
+        if let Some((x, y)) = game_state.look_cursor {
+            let (screen_x, screen_y) = renderer.camera.world_to_screen(x as f32, y as f32, renderer.tile_size);
+            draw_rectangle_lines(screen_x, screen_y, renderer.tile_size, renderer.tile_size, 2.0, YELLOW);
+            game_state.draw_look_panel(screen_x + renderer.tile_size, screen_y);
+        }
 
-        // Draw monsters
-        for monster in &game_state.monsters {
-            if monster.is_alive() && camera.is_visible(monster.x, monster.y) {
-                let (screen_x, screen_y) = camera.world_to_screen(monster.x, monster.y, tile_size);
-                draw_text(
-                    &monster.symbol.to_string(),
-                    screen_x,
-                    screen_y + tile_size,
-                    tile_size,
-                    monster.color,
-                );
+        // If inventory is open, draw it
+        if game_state.inventory_open {
+            game_state.draw_inventory();
+            // Close inventory with Escape
+            if is_key_pressed(KeyCode::Escape) {
+                game_state.inventory_open = false;
             }
         }
 
-        // Draw items on ground
-        for (x, y, item) in &game_state.ground_items {
-            if camera.is_visible(*x, *y) {
-                let (screen_x, screen_y) = camera.world_to_screen(*x, *y, tile_size);
-                draw_text(
-                    &item.symbol.to_string(),
-                    screen_x,
-                    screen_y + tile_size,
-                    tile_size,
-                    item.color,
-                );
+        if game_state.encyclopedia_open {
+            game_state.draw_encyclopedia();
+            if is_key_pressed(KeyCode::Escape) {
+                game_state.encyclopedia_open = false;
             }
         }
 
-        // Draw the player
-        if camera.is_visible(game_state.player.x, game_state.player.y) {
-            let (screen_x, screen_y) = camera.world_to_screen(game_state.player.x, game_state.player.y, tile_size);
-            draw_text(
-                &game_state.player.symbol.to_string(),
-                screen_x,
-                screen_y + tile_size,
-                tile_size,
-                game_state.player.color,
-            );
+        if game_state.feedback_open {
+            game_state.draw_feedback_form();
         }
 
-        // Constants for UI text
-        const LOG_PADDING: f32 = 10.0;
-        const TEXT_SIZE: f32 = 15.0;
+        if game_state.shop_open {
+            game_state.draw_shop();
+            if is_key_pressed(KeyCode::Escape) {
+                game_state.shop_open = false;
+            }
+        }
 
-        // Draw top stats bar background
-        draw_rectangle(
-            0.0,
-            0.0,
-            screen_width(),
-            TOP_BAR_HEIGHT,
-            Color::new(0.0, 0.0, 0.0, 0.8)
-        );
+        if game_state.board_open {
+            game_state.draw_bounty_board();
+            if is_key_pressed(KeyCode::Escape) {
+                game_state.board_open = false;
+            }
+        }
 
-        // Draw top stats bar content
-        let hp_text = format!("HP: {}/{}", game_state.player.stats.hp, game_state.player.stats.max_hp);
-        let stats_text = format!("ATK: {} DEF: {}",
-                                 game_state.player.stats.attack,
-                                 game_state.player.stats.defense
-        );
-        let floor_text = format!("Floor: {}", game_state.map_manager.current_level + 1);
-        let xp_text = format!("Level: {} XP: {}/{}",
-                              game_state.player.stats.level_system.as_ref().map_or(1, |ls| ls.level),
-                              game_state.player.stats.level_system.as_ref().map_or(0, |ls| ls.current_xp),
-                              game_state.player.stats.level_system.as_ref().map_or(100, |ls| ls.xp_to_next_level)
-        );
+        if game_state.forge_open {
+            game_state.draw_forge();
+            if is_key_pressed(KeyCode::Escape) {
+                game_state.forge_open = false;
+            }
+        }
 
-        // Position text with proper spacing
-        draw_text(&hp_text, 10.0, TOP_BAR_HEIGHT/2.0 + TEXT_SIZE/2.0, TEXT_SIZE, GREEN);
-        draw_text(&stats_text, screen_width()/4.0, TOP_BAR_HEIGHT/2.0 + TEXT_SIZE/2.0, TEXT_SIZE, GREEN);
-        draw_text(&floor_text, screen_width()/2.0, TOP_BAR_HEIGHT/2.0 + TEXT_SIZE/2.0, TEXT_SIZE, YELLOW);
-        draw_text(&xp_text, 2.0*screen_width()/3.0, TOP_BAR_HEIGHT/2.0 + TEXT_SIZE/2.0, TEXT_SIZE, GREEN);
+        if game_state.stash_open {
+            game_state.draw_stash();
+            if is_key_pressed(KeyCode::Escape) {
+                game_state.stash_open = false;
+            }
+        }
 
-        // Draw bottom combat log background
-        draw_rectangle(
-            0.0,
-            screen_height() - BOTTOM_BAR_HEIGHT,
-            screen_width(),
-            BOTTOM_BAR_HEIGHT,
-            Color::new(0.0, 0.0, 0.0, 0.8)
-        );
+        if game_state.log_open {
+            game_state.draw_message_log();
+            if is_key_pressed(KeyCode::Escape) {
+                game_state.log_open = false;
+            }
+        }
 
-        // Draw combat log title
-        draw_text(
-            "Combat Log",
-            LOG_PADDING,
-            screen_height() - BOTTOM_BAR_HEIGHT + 20.0,
-            TEXT_SIZE,
-            GRAY,
-        );
+        if game_state.spawn_preview.is_some() {
+            game_state.draw_spawn_preview();
+            if is_key_pressed(KeyCode::Escape) {
+                game_state.spawn_preview = None;
+            }
+        }
 
-        // Draw combat log messages
-        for (i, message) in game_state.combat_log.iter().enumerate() {
-            draw_text(
-                message,
-                LOG_PADDING,
-                screen_height() - BOTTOM_BAR_HEIGHT + 40.0 + (i as f32 * 20.0),
-                TEXT_SIZE,
-                WHITE,
-            );
+        if game_state.party_open {
+            game_state.draw_party();
+            if is_key_pressed(KeyCode::Escape) {
+                game_state.party_open = false;
+            }
         }
 
-        // If inventory is open, draw it
-        if game_state.inventory_open {
-            game_state.draw_inventory();
-            // Close inventory with Escape
+        if game_state.spells_open {
+            game_state.draw_spell_menu();
             if is_key_pressed(KeyCode::Escape) {
-                game_state.inventory_open = false;
+                game_state.spells_open = false;
             }
         }
 
-        next_frame().await;
+        if game_state.parley.is_some() {
+            game_state.draw_parley();
+            if is_key_pressed(KeyCode::Escape) {
+                game_state.parley = None;
+            }
+        }
+
+        game_state.draw_radial_menu();
+
+        if screen == Screen::Paused {
+            draw_pause_menu(pause_selection);
+        }
+
+        present_frame().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two runs from the same seed given the same moves have to agree on the state hash every turn
+    #[test]
+    fn same_seed_and_moves_give_the_same_state() {
+        let directions = [(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1), (0, 0)];
+        let mut runs: Vec<GameState> = (0..2)
+            .map(|_| GameState::new(GameConfig { seed: 7, demo: true, ..GameConfig::default() }, Vec::new(), &HashMap::new()))
+            .collect();
+
+        for turn in 0..300 {
+            let (dx, dy) = directions[(turn / 4) % directions.len()];
+            let hashes: Vec<u64> = runs
+                .iter_mut()
+                .map(|run| {
+                    let now = turn as f32;
+                    run.clock = now;
+                    // Taking the stairs waits on a key press, so the runs stay on their first floor
+                    if run.player.is_alive() {
+                        run.player_step(run.player.x + dx as f32, run.player.y + dy as f32, false);
+                    }
+                    run.advance_world(now);
+                    run.state_hash()
+                })
+                .collect();
+            assert_eq!(hashes[0], hashes[1], "the runs came apart on turn {}", turn);
+        }
     }
-}
\ No newline at end of file
+}